@@ -0,0 +1,103 @@
+use crate::prelude::*;
+
+/// Sets up `agent <name>`: adds the system prompt of the agent, and fills the model, tools, and
+/// iterations that weren't given on the command line with the ones of the agent.
+pub fn apply(mut args: Args, config: &Config, name: &str) -> Result<Args> {
+    let agent = config
+        .agents
+        .as_ref()
+        .and_then(|agents| agents.get(name))
+        .cloned()
+        .ok_or_else(|| Error::AgentNotFound(name.to_string()))?;
+
+    // The api, model, and preset of the agent go together, so any of them replaces all three.
+    if args.api.is_none() && args.model.is_none() && args.preset.is_none() {
+        args.api = agent.api;
+        args.model = agent.model;
+        args.preset = agent.preset;
+    }
+
+    if let Some(system) = agent.system {
+        args.system.insert(0, system);
+    }
+
+    if args.tools.is_empty() {
+        args.tools = agent.tools;
+    }
+
+    args.max_iterations = agent.max_iterations;
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Agent;
+
+    fn config() -> Config {
+        let mut config = Config::new();
+        config.agents = Some(
+            [(
+                "researcher".to_string(),
+                Agent {
+                    system: Some("Research the task before answering.".to_string()),
+                    tools: vec!["search".to_string(), "read_file".to_string()],
+                    max_iterations: Some(4),
+                    api: Some(Api::Anthropic),
+                    model: Some("claude-3-5-sonnet-latest".to_string()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        );
+        config
+    }
+
+    #[test]
+    fn test_apply() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let args = apply(
+            Args {
+                system: vec!["Answer in Spanish.".to_string()],
+                ..Default::default()
+            },
+            &config(),
+            "researcher",
+        )?;
+
+        assert_eq!(args.api, Some(Api::Anthropic));
+        assert_eq!(args.model.as_deref(), Some("claude-3-5-sonnet-latest"));
+        assert_eq!(
+            args.system,
+            vec![
+                "Research the task before answering.".to_string(),
+                "Answer in Spanish.".to_string()
+            ]
+        );
+        assert_eq!(args.tools, vec!["search", "read_file"]);
+        assert_eq!(args.max_iterations, Some(4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_keeps_the_command_line() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let args = apply(
+            Args {
+                preset: Some("haiku".to_string()),
+                tools: vec!["search".to_string()],
+                ..Default::default()
+            },
+            &config(),
+            "researcher",
+        )?;
+
+        assert_eq!(args.api, None);
+        assert_eq!(args.model, None);
+        assert_eq!(args.preset.as_deref(), Some("haiku"));
+        assert_eq!(args.tools, vec!["search"]);
+        assert!(apply(Args::default(), &config(), "writer").is_err());
+
+        Ok(())
+    }
+}
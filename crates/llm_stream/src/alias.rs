@@ -0,0 +1,275 @@
+use cli_table::{format::Justify, Color, Table, WithTitle};
+use serde::Serialize;
+use std::ffi::OsString;
+use toml_edit::{DocumentMut, Item};
+
+use crate::args::AliasCommand;
+use crate::config::Alias;
+use crate::prelude::*;
+
+/// Alias as printed by `alias list`.
+#[derive(Debug, Serialize, Table)]
+struct AliasLine {
+    #[table(title = "Name", justify = "Justify::Left", color = "Color::Cyan")]
+    name: String,
+    #[table(title = "Flags", justify = "Justify::Left")]
+    flags: String,
+}
+
+/// Returns the flags an alias stands for, like `--api anthropic --template review`.
+fn flags(alias: &Alias) -> String {
+    let mut flags = Vec::new();
+
+    if let Some(api) = alias.api {
+        flags.push(format!("--api {}", api));
+    }
+    if let Some(model) = &alias.model {
+        flags.push(format!("--model {}", model));
+    }
+    if let Some(preset) = &alias.preset {
+        flags.push(format!("--preset {}", preset));
+    }
+    if let Some(template) = &alias.template {
+        flags.push(format!("--template {}", template));
+    }
+    for system in &alias.system {
+        flags.push(format!("--system {:?}", system));
+    }
+    if let Some(vars) = &alias.vars {
+        flags.push(format!("--vars '{}'", vars));
+    }
+
+    flags.join(" ")
+}
+
+/// Replaces a first argument like `@review` with `--alias review`, so `llm-stream @review "prompt"`
+/// uses the alias.
+pub fn expand(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.into_iter().collect::<Vec<OsString>>();
+
+    let name = args
+        .get(1)
+        .and_then(|arg| arg.to_str())
+        .and_then(|arg| arg.strip_prefix('@'))
+        .filter(|name| !name.is_empty())
+        .map(String::from);
+
+    if let Some(name) = name {
+        args.splice(1..2, [OsString::from("--alias"), OsString::from(name)]);
+    }
+
+    args
+}
+
+/// Fills the arguments that weren't set with the flags of the alias selected with `--alias`. Its
+/// vars are merged under the ones of `--vars`, and its system messages go before the ones of
+/// `--system`.
+pub fn apply(args: &mut Args, config: &Config) -> Result<()> {
+    let Some(name) = args.alias.clone() else {
+        return Ok(());
+    };
+
+    let alias = config
+        .alias
+        .as_ref()
+        .and_then(|aliases| aliases.get(&name))
+        .cloned()
+        .ok_or(Error::AliasNotFound(name))?;
+
+    args.api = args.api.or(alias.api);
+    args.model = args.model.take().or(alias.model);
+    args.preset = args.preset.take().or(alias.preset);
+    args.template = args.template.take().or(alias.template);
+    args.system.splice(0..0, alias.system);
+
+    if let Some(mut vars) = alias.vars.filter(|v| !v.is_null()) {
+        if let Some(overrides) = args.vars.take().filter(|v| !v.is_null()) {
+            merge(&mut vars, overrides);
+        }
+        args.vars = Some(vars);
+    }
+
+    Ok(())
+}
+
+/// Returns the alias of the flags given to `alias save`.
+fn capture(args: &Args) -> Result<Alias> {
+    let alias = Alias {
+        api: args.api,
+        model: args.model.clone(),
+        preset: args.preset.clone(),
+        template: args.template.clone(),
+        system: args.system.clone(),
+        vars: args
+            .vars
+            .clone()
+            .filter(|v| !v.is_null() && v.as_object().map_or(true, |o| !o.is_empty())),
+    };
+
+    if alias == Alias::default() {
+        return Err(Error::InvalidAlias(
+            "give the flags to save after its name, like `alias save review --template review`"
+                .to_string(),
+        ));
+    }
+
+    Ok(alias)
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidAlias(format!("invalid alias name: {name}")));
+    }
+
+    Ok(())
+}
+
+/// Reads the configuration document, changes it, and writes it back once it's still valid.
+fn edit_config(args: &Args, change: impl FnOnce(&mut DocumentMut) -> Result<()>) -> Result<()> {
+    let config_file = args.config_file.clone().expect("can't find config file");
+
+    let mut document = std::fs::read_to_string(&config_file)
+        .unwrap_or_default()
+        .parse::<DocumentMut>()?;
+
+    change(&mut document)?;
+
+    let contents = document.to_string();
+    toml::from_str::<Config>(&contents)?;
+    std::fs::write(&config_file, contents)?;
+
+    Ok(())
+}
+
+/// Runs the `alias` commands.
+pub fn run(command: AliasCommand, args: Args) -> Result<()> {
+    match command {
+        AliasCommand::Save { name, .. } => {
+            validate_name(&name)?;
+
+            let alias = capture(&args)?;
+            let table = toml::to_string(&alias)?.parse::<DocumentMut>()?;
+
+            edit_config(&args, |document| {
+                let aliases = document
+                    .entry("alias")
+                    .or_insert(toml_edit::table())
+                    .as_table_mut()
+                    .ok_or_else(|| Error::ConfigKey("alias".to_string()))?;
+
+                aliases.set_implicit(true);
+                aliases.insert(&name, Item::Table(table.as_table().clone()));
+
+                Ok(())
+            })?;
+
+            eprintln!("Saved alias: @{} ({})", name, flags(&alias));
+
+            Ok(())
+        }
+        AliasCommand::Delete { name } => edit_config(&args, |document| {
+            document
+                .get_mut("alias")
+                .and_then(Item::as_table_like_mut)
+                .and_then(|aliases| aliases.remove(&name))
+                .map(|_| ())
+                .ok_or(Error::AliasNotFound(name))
+        }),
+        AliasCommand::List => {
+            let (args, config) = build_config(args)?;
+
+            let lines = config
+                .alias
+                .unwrap_or_default()
+                .iter()
+                .map(|(name, alias)| AliasLine {
+                    name: name.clone(),
+                    flags: flags(alias),
+                })
+                .collect::<Vec<AliasLine>>();
+
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&lines)?);
+                return Ok(());
+            }
+
+            let table = if atty::is(atty::Stream::Stdout) {
+                lines.with_title()
+            } else {
+                lines.table()
+            };
+
+            print_table(table, &args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let expanded = |args: &[&str]| {
+            expand(args.iter().map(OsString::from))
+                .into_iter()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            expanded(&["llm-stream", "@review", "Look at this"]),
+            vec!["llm-stream", "--alias", "review", "Look at this"]
+        );
+        assert_eq!(
+            expanded(&["llm-stream", "Ask @support"]),
+            vec!["llm-stream", "Ask @support"]
+        );
+        assert_eq!(expanded(&["llm-stream", "@"]), vec!["llm-stream", "@"]);
+    }
+
+    #[test]
+    fn test_saved_alias_fills_unset_args() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let saved = Args {
+            api: Some(Api::Anthropic),
+            model: Some("claude-3-5-sonnet-latest".to_string()),
+            template: Some("review".to_string()),
+            system: vec!["Be brief.".to_string()],
+            vars: Some(serde_json::json!({ "language": "rust", "strict": true })),
+            ..Default::default()
+        };
+
+        let config = Config {
+            alias: Some([("review".to_string(), capture(&saved)?)].into()),
+            ..Default::default()
+        };
+
+        let mut args = Args {
+            alias: Some("review".to_string()),
+            model: Some("claude-3-5-haiku-latest".to_string()),
+            system: vec!["Answer in Spanish.".to_string()],
+            vars: Some(serde_json::json!({ "language": "go" })),
+            ..Default::default()
+        };
+
+        apply(&mut args, &config)?;
+
+        assert_eq!(args.api, Some(Api::Anthropic));
+        assert_eq!(args.model, Some("claude-3-5-haiku-latest".to_string()));
+        assert_eq!(args.template, Some("review".to_string()));
+        assert_eq!(args.system, vec!["Be brief.", "Answer in Spanish."]);
+        assert_eq!(
+            args.vars,
+            Some(serde_json::json!({ "language": "go", "strict": true }))
+        );
+
+        args.alias = Some("missing".to_string());
+        assert!(apply(&mut args, &config).is_err());
+
+        Ok(())
+    }
+}
@@ -2,9 +2,9 @@ use llm_stream::anthropic;
 
 use crate::prelude::*;
 
-const DEFAULT_URL: &str = "https://api.anthropic.com/v1";
+pub const DEFAULT_URL: &str = "https://api.anthropic.com/v1";
 const DEFAULT_MODEL: &str = "claude-3-5-sonnet-20240620";
-const DEFAULT_ENV: &str = "ANTHROPIC_API_KEY";
+pub const DEFAULT_ENV: &str = "ANTHROPIC_API_KEY";
 
 // From ConversationRole to anthropic::Role
 impl From<ConversationRole> for anthropic::Role {
@@ -17,18 +17,12 @@ impl From<ConversationRole> for anthropic::Role {
     }
 }
 
-pub async fn run(mut args: Args) -> Result<()> {
-    let key = match args.api_key.take() {
-        Some(key) => key,
-        None => {
-            let environment_variable = match args.api_env.take() {
-                Some(env) => env,
-                None => DEFAULT_ENV.to_string(),
-            };
-            std::env::var(environment_variable)?
-        }
-    };
-    log::info!("key: {}", key);
+/// Builds the `anthropic` client and request body from the arguments.
+fn prepare(
+    mut args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<(anthropic::Client, anthropic::MessageBody, Args)> {
+    let key = crate::keys::resolve(&mut args, Api::Anthropic, DEFAULT_ENV)?;
 
     let url = match args.api_base_url.take() {
         Some(url) => url,
@@ -38,11 +32,14 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let auth = anthropic::Auth::new(key, args.api_version.clone());
 
-    log::info!("auth: {:#?}", auth);
-
-    let client = anthropic::Client::new(auth, url);
-
-    log::info!("client: {:#?}", client);
+    let client = args.headers.iter().fold(
+        anthropic::Client::new(auth, url),
+        |client, (name, value)| client.header(name, value),
+    );
+    let client = args
+        .extra_query
+        .iter()
+        .fold(client, |client, (name, value)| client.query(name, value));
 
     let mut messages: Vec<anthropic::Message> = Default::default();
 
@@ -59,21 +56,108 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let mut body = anthropic::MessageBody::new(
         args.model
-            .take()
-            .unwrap_or(DEFAULT_MODEL.to_string())
-            .as_ref(),
+            .get_or_insert_with(|| DEFAULT_MODEL.to_string())
+            .as_str(),
         messages,
         args.max_tokens.unwrap_or(4096),
     );
 
-    body.system = args.system.take();
+    let system = args
+        .conversation
+        .iter()
+        .filter(|m| m.role == ConversationRole::System && !m.content.trim().is_empty())
+        .map(|m| m.content.clone())
+        .collect::<Vec<String>>();
+
+    body.system = (!system.is_empty()).then(|| system.join("\n\n"));
     body.temperature = args.temperature;
     body.top_p = args.top_p;
     body.top_k = args.top_k;
 
     log::info!("body: {:#?}", body);
 
-    let stream = client.delta(&body)?;
+    let client = request_hooks(&args, &body, tools)?
+        .into_iter()
+        .fold(client, |client, hook| client.on_request(hook));
+
+    Ok((client, body, args))
+}
+
+/// Streams the LLM response to the terminal, returning the answer when `--auto-continue` has to
+/// continue it.
+pub async fn run(args: Args) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::Anthropic, metadata.clone()))
+        .on_headers(crate::report::headers_hook(
+            Api::Anthropic,
+            metadata.clone(),
+        ));
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Streams the LLM response to the terminal, advertising the tools of the session and collecting
+/// the calls of the response.
+pub async fn run_with_tools(args: Args, tools: &crate::tools::Session) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, Some(tools))?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::Anthropic, metadata.clone()))
+        .on_headers(crate::report::headers_hook(
+            Api::Anthropic,
+            metadata.clone(),
+        ))
+        .on_event(tools.event_hook());
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Returns the LLM response without printing it, calling `on_delta` with every chunk of text and
+/// filling `metadata` with the finish reason and usage of the response.
+pub async fn complete(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let client = client
+        .on_event(crate::report::hook(Api::Anthropic, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::Anthropic, metadata));
+    let stream = open_stream(|| client.delta(&body), true, Timeouts::new(&args)).await?;
+
+    collect_stream(stream, on_delta).await
+}
+
+/// Returns the tokens of the conversation, counted by the `count_tokens` endpoint of the api.
+pub fn count_tokens(args: Args) -> Result<u32> {
+    let (client, body, _) = prepare(args, None)?;
+
+    Ok(client.count_tokens(&body)?.input_tokens)
+}
+
+/// Returns the request `run` sends, without sending it.
+pub fn request(
+    args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<llm_stream::request::Request> {
+    let (client, body, _) = prepare(args, tools)?;
 
-    handle_stream(stream, args).await
+    Ok(client.request(&body)?)
 }
@@ -1,4 +1,5 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::engine::ArgValueCompleter;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::str::FromStr;
@@ -16,6 +17,47 @@ fn parse_conversation(s: &str) -> std::result::Result<Conversation, serde_json::
     Ok(conversation)
 }
 
+/// Custom parser function for durations like `30d`, `12h`, `15m`, `45s`, or `2w`.
+pub fn parse_duration(s: &str) -> std::result::Result<std::time::Duration, String> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {s}"))?;
+
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return Err(format!("invalid duration unit: {unit}")),
+    };
+
+    Ok(std::time::Duration::from_secs(value * seconds))
+}
+
+/// Custom parser function for headers like `anthropic-beta: prompt-caching-2024-07-31`.
+pub fn parse_header(s: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header: {s}, use `name: value`"))?;
+
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Validates the value of `--wrap`: `auto`, `off`, or an amount of columns.
+pub fn parse_wrap(value: &str) -> std::result::Result<String, String> {
+    match value {
+        "auto" | "off" => Ok(value.to_string()),
+        _ => value
+            .parse::<usize>()
+            .map(|_| value.to_string())
+            .map_err(|_| format!("invalid wrap: {value}, use `auto`, `off`, or a number")),
+    }
+}
+
 #[derive(ValueEnum, Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Api {
@@ -25,6 +67,22 @@ pub enum Api {
     Google,
     Mistral,
     MistralFim,
+    OllamaFim,
+}
+
+impl std::fmt::Display for Api {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Api::OpenAi => "openai",
+            Api::Anthropic => "anthropic",
+            Api::Google => "google",
+            Api::Mistral => "mistral",
+            Api::MistralFim => "mistral-fim",
+            Api::OllamaFim => "ollama-fim",
+        };
+
+        write!(f, "{name}")
+    }
 }
 
 // From string to API enum
@@ -51,13 +109,489 @@ impl FromStr for Api {
             "Mistral_FIM" => Ok(Api::MistralFim),
             "Mistral_Fim" => Ok(Api::MistralFim),
             "MistralFIM" => Ok(Api::MistralFim),
+            "ollama-fim" => Ok(Api::OllamaFim),
+            "ollama_fim" => Ok(Api::OllamaFim),
+            "Ollama-FIM" => Ok(Api::OllamaFim),
+            "OllamaFim" => Ok(Api::OllamaFim),
             _ => Err(Error::InvalidAPI),
         }
     }
 }
 
-#[derive(Default, Clone, Debug, Parser, PartialEq, Serialize, Deserialize)]
-#[command(name = "e", version = "0.1.0")]
+/// Format used to print or edit a cached conversation.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ConversationFormat {
+    #[default]
+    Markdown,
+    Json,
+    Toml,
+}
+
+/// Format used to export a cached conversation.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    #[default]
+    Md,
+    Html,
+    Json,
+}
+
+/// Format of a conversation file given to `conversations import`.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ImportFormat {
+    /// A list of messages with the `--conversation` schema.
+    #[default]
+    Json,
+    /// The `conversations.json` file of a ChatGPT data export.
+    Chatgpt,
+    /// The `conversations.json` file of a Claude data export.
+    Claude,
+}
+
+/// Where the text read from stdin goes in the prompt.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StdinPlacement {
+    /// Before the prompt.
+    #[default]
+    Before,
+    /// After the prompt.
+    After,
+    /// Left out of the prompt. Templates still get it as the `stdin` variable.
+    None,
+}
+
+/// Field used to group the `usage` report.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UsageGroup {
+    #[default]
+    Day,
+    Model,
+    Api,
+    Tag,
+}
+
+/// What a model listed by `models --available` can be used for.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Chat,
+    Embeddings,
+    Fim,
+    Tools,
+    Vision,
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Chat => "chat",
+            Capability::Embeddings => "embeddings",
+            Capability::Fim => "fim",
+            Capability::Tools => "tools",
+            Capability::Vision => "vision",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Where the API keys are read from when `--api-key` isn't given.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    /// The environment variable of `--api-env`.
+    #[default]
+    Env,
+    /// The platform secret store, falling back to the environment variable.
+    Keyring,
+}
+
+/// Format of the diagnostics of `--log-format`.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Lines of text, like `2024-05-01T10:00:00Z  INFO llm_stream: url: ...`.
+    #[default]
+    Text,
+    /// A JSON object per line, with the `timestamp`, `level`, `target`, and `fields`.
+    Json,
+}
+
+/// Renderer used to print the streamed responses on the terminal.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    /// Formats headings, emphasis, lists, and tables, and highlights the code blocks.
+    Markdown,
+    /// Highlights the whole response with the syntax of `--language`.
+    #[default]
+    Syntax,
+    /// Prints the response as it is.
+    Plain,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Sends a prompt to the LLM and streams its answer. This is the default command.
+    Ask(Box<Args>),
+    /// Opens the interactive terminal interface.
+    Chat(Box<Args>),
+    /// Asks a fill-in-the-middle model to complete the text between the prompt and `--suffix`,
+    /// with Mistral's Codestral, or with a local model with `--api ollama-fim`.
+    Fim(Box<Args>),
+    /// Writes a Conventional Commits message for the staged changes with the `commit` template.
+    Commit {
+        /// Run `git commit` with the message, opening the editor to review it first.
+        #[clap(long)]
+        commit: bool,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Reviews a diff, the uncommitted changes by default, with the `review` template.
+    Review {
+        #[clap(flatten)]
+        diff: DiffArgs,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Writes a pull request description for a diff, the changes since the default branch by
+    /// default, with the `pr` template.
+    Pr {
+        #[clap(flatten)]
+        diff: DiffArgs,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Runs an agent defined under `[agents.<name>]` on a task, calling its tools until it
+    /// answers.
+    Agent {
+        /// Name of the agent.
+        name: String,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Inspects the configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Inspects the prompt templates.
+    #[command(subcommand)]
+    Templates(TemplatesCommand),
+    /// Inspects the presets.
+    #[command(subcommand)]
+    Presets(PresetsCommand),
+    /// Manage the aliases used with `@<name>`.
+    #[command(subcommand)]
+    Alias(AliasCommand),
+    /// Inspects the audit log of the requests and responses.
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// Manage the cached conversations.
+    #[command(subcommand)]
+    Conversations(ConversationsCommand),
+    /// Prints the models with known prices, or with `--available`, the models each api offers.
+    Models {
+        /// Ask each api, and Ollama when it's running, for the models it offers. Restrict it to a
+        /// single api with `--api` before `models`.
+        #[clap(long)]
+        available: bool,
+        /// Only print the models that support the capability.
+        #[clap(long, value_enum, requires = "available")]
+        capability: Option<Capability>,
+        /// Print the context window, output limit, and prices of the model of `--model` before
+        /// `models`. Google is asked for them.
+        #[clap(long, conflicts_with = "available")]
+        info: bool,
+    },
+    /// Manage the API keys stored in the platform secret store.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Serves an OpenAI compatible `/v1/chat/completions` endpoint that sends the requests to the
+    /// api of the requested preset or model.
+    Serve {
+        /// Address to listen on.
+        #[clap(long, default_value = "127.0.0.1")]
+        host: String,
+        /// Port to listen on.
+        #[clap(long, default_value_t = 8080)]
+        port: u16,
+        /// Bearer token required by every request. A random one is printed if it isn't set.
+        #[clap(long, env = "LLM_STREAM_SERVE_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+    /// Listens on a Unix socket for the prompts sent with `--daemon`, keeping the configuration,
+    /// templates, and keys in memory between them.
+    Daemon,
+    /// Chunks and embeds files into a local index, used with `--rag`. Files that didn't change
+    /// since they were indexed are skipped.
+    Index {
+        /// Files, directories, or globs to index.
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// Name of the index.
+        #[clap(long, default_value = "default")]
+        name: String,
+        /// Embeddings model. Defaults to the one of the api, set with `--api` before `index`.
+        #[clap(long)]
+        embedding_model: Option<String>,
+        /// Maximum amount of tokens of each chunk.
+        #[clap(long, default_value_t = 400)]
+        chunk_tokens: usize,
+    },
+    /// Stamps the current `schema_version` on the configuration and cache files written by older
+    /// versions, backing them up to the data directory first.
+    Migrate {
+        /// Print the files that would be stamped without changing them.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Replaces this executable with the binary of the latest GitHub release, after checking its
+    /// checksum.
+    SelfUpdate {
+        /// Print whether there's a newer release without installing it.
+        #[clap(long)]
+        check: bool,
+        /// Update without asking for confirmation.
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Summarizes the tokens and cost recorded in the usage ledger.
+    Usage {
+        /// Field used to group the usage.
+        #[clap(long, value_enum, default_value = "day")]
+        by: UsageGroup,
+        /// Only include the usage recorded in the given time (e.g. `7d`, `12h`).
+        #[clap(long, value_parser = parse_duration)]
+        since: Option<std::time::Duration>,
+    },
+}
+
+/// Diff sent by the `review` and `pr` commands.
+#[derive(Default, Clone, Debug, clap::Args, PartialEq)]
+pub struct DiffArgs {
+    /// Revision range to diff, like `main..HEAD`.
+    #[clap(long, conflicts_with = "diff")]
+    pub range: Option<String>,
+    /// File with the diff to send, instead of running `git diff`.
+    #[clap(long)]
+    pub diff: Option<String>,
+    /// Maximum amount of tokens of the diff sent in a request. Longer diffs are split by file and
+    /// sent in parts. Defaults to half of `--max-context-tokens`, or 16000.
+    #[clap(long)]
+    pub chunk_tokens: Option<usize>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ConfigCommand {
+    /// Opens the configuration file in `$EDITOR`.
+    Edit,
+    /// Prints the value of a configuration key. Presets and templates are selected by name, like
+    /// `presets.sonnet.model`.
+    Get {
+        /// Dotted path of the key.
+        key: String,
+    },
+    /// Sets the value of a configuration key, keeping the rest of the file as it is.
+    Set {
+        /// Dotted path of the key, like `model` or `presets.sonnet.temperature`.
+        key: String,
+        /// TOML value. Values that aren't valid TOML are stored as strings.
+        value: String,
+    },
+    /// Prints the configuration file in use.
+    Path,
+    /// Prints the configuration directory.
+    Dir,
+    /// Prints the themes available for `--theme`.
+    Themes,
+    /// Prints the languages available for `--language`.
+    Languages,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum AuditCommand {
+    /// Checks that no entry of the audit log was changed or removed.
+    Verify,
+    /// Prints the path of the audit log.
+    Path,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum KeysCommand {
+    /// Stores the API key of a provider, read from the terminal or from stdin.
+    Set {
+        /// The API provider.
+        #[clap(value_enum)]
+        api: Api,
+    },
+    /// Deletes the stored API key of a provider.
+    Delete {
+        /// The API provider.
+        #[clap(value_enum)]
+        api: Api,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum TemplatesCommand {
+    /// Prints the templates defined in the configuration file and the templates directory, with
+    /// the variables they require.
+    List,
+    /// Prints the definition of a template and the variables it uses.
+    Show {
+        /// Name of the template.
+        name: String,
+    },
+    /// Renders a template and prints the resulting conversation without sending it. Fails when a
+    /// required variable is missing.
+    Render {
+        /// Name of the template.
+        name: String,
+        /// The user message prompt. `stdin` is also read when it isn't a terminal.
+        prompt: Option<String>,
+        /// Variables in JSON format.
+        #[clap(long, value_parser = parse_json)]
+        vars: Option<Value>,
+        /// File with variables, in JSON, TOML, or YAML format.
+        #[clap(long)]
+        vars_file: Option<String>,
+        /// Suffix prompt.
+        #[clap(long)]
+        suffix: Option<String>,
+    },
+    /// Creates a template file in the templates directory. Asks for its values when the name
+    /// isn't given.
+    New(Box<NewTemplate>),
+    /// Installs the templates of a git repository, or a single template file from an https URL.
+    Install {
+        /// URL of a git repository or of a template file.
+        source: String,
+        /// Overwrite templates with the same name.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Installs the templates again from the sources they were installed from.
+    Update {
+        /// Names of the templates to update. Updates every installed template when empty.
+        names: Vec<String>,
+    },
+}
+
+/// Values of the template created by `templates new`.
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct NewTemplate {
+    /// Name of the template.
+    pub name: Option<String>,
+    /// Description printed by `templates list`.
+    #[clap(long)]
+    pub description: Option<String>,
+    /// System prompt template.
+    #[clap(long)]
+    pub system: Option<String>,
+    /// Prompt template. Defaults to `{{ prompt }}`.
+    #[clap(long)]
+    pub template: Option<String>,
+    /// Variable with its default value, like `language=rust`. Can be repeated.
+    #[clap(long = "var", value_name = "NAME=DEFAULT")]
+    pub vars: Vec<String>,
+    /// Overwrite a template with the same name.
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum PresetsCommand {
+    /// Prints the presets defined in the configuration file.
+    List,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum AliasCommand {
+    /// Saves the api, model, preset, template, vars, and system given after the name as an alias,
+    /// replacing the one with the same name.
+    Save {
+        /// Alias name.
+        name: String,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Prints the aliases defined in the configuration file.
+    List,
+    /// Deletes an alias.
+    Delete {
+        /// Alias name.
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ConversationsCommand {
+    /// Prints the cached conversations.
+    List {
+        /// Print the conversations as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Prints a cached conversation.
+    Show {
+        /// Conversation id. Defaults to the last conversation.
+        id: Option<String>,
+        /// Output format.
+        #[clap(long, value_enum, default_value = "markdown")]
+        format: ConversationFormat,
+    },
+    /// Deletes a cached conversation.
+    Delete {
+        /// Conversation id.
+        id: String,
+    },
+    /// Deletes the cached conversations that haven't been updated in the given time.
+    Prune {
+        /// Maximum age of the conversations to keep (e.g. `30d`, `12h`, `2w`).
+        #[clap(long, value_parser = parse_duration)]
+        older_than: std::time::Duration,
+    },
+    /// Exports a cached conversation as Markdown, as HTML, or as JSON with the `--conversation`
+    /// schema.
+    Export {
+        /// Conversation id. Defaults to the last conversation.
+        id: Option<String>,
+        /// Export format.
+        #[clap(long, value_enum, default_value = "md")]
+        format: ExportFormat,
+        /// File where the exported conversation will be written. Defaults to stdout.
+        #[clap(long)]
+        out: Option<String>,
+    },
+    /// Removes the last user and assistant messages from a cached conversation.
+    Pop {
+        /// Conversation id. Defaults to the last conversation.
+        id: Option<String>,
+    },
+    /// Imports a JSON file with the `--conversation` schema as a new cached conversation, or every
+    /// conversation of a ChatGPT or Claude data export.
+    Import {
+        /// JSON file to import. If `-` is provided, `stdin` will be read instead.
+        file: String,
+        /// Format of the file.
+        #[clap(long, value_enum, default_value = "json")]
+        format: ImportFormat,
+        /// Conversation title. Defaults to the title of the exported conversation.
+        #[clap(long)]
+        title: Option<String>,
+    },
+    /// Changes the title of a cached conversation.
+    Rename {
+        /// Conversation id.
+        id: String,
+        /// New conversation title.
+        #[clap(long)]
+        title: String,
+    },
+}
+
+/// Command line of `llm-stream`. Without a subcommand, the arguments of `ask` are used.
+#[derive(Clone, Debug, Parser)]
+#[command(name = "llm-stream", version)]
 #[command(about = "Interact with LLMs through the terminal")]
 #[command(
     long_about = "This Rust-based CLI enables users to interact with various Large Language Models
@@ -76,17 +610,201 @@ highlight the model's responses using syntax highlighting, making it easier to r
 output in the terminal. It also includes functionality to handle streaming responses
 efficiently, ensuring a smooth user experience when interacting with the LLMs."
 )]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: Args,
+
+    /// Format of the diagnostics enabled with `RUST_LOG`.
+    #[clap(long, value_enum, global = true, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Append the diagnostics to a file instead of printing them to stderr. Logs up to `info`
+    /// unless `RUST_LOG` is set.
+    #[clap(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+
+    /// Print the man page, or with a directory, write the pages of every subcommand in it.
+    #[clap(long, hide = true, value_name = "DIR", num_args = 0..=1)]
+    pub generate_man: Option<Option<String>>,
+}
+
+impl Cli {
+    /// Returns the command to run along with its arguments. The commands that send a prompt take
+    /// the options given before them.
+    pub fn into_command(self) -> (Command, Args) {
+        let Cli { command, args, .. } = self;
+
+        match command {
+            None => (Command::Ask(Box::default()), args),
+            Some(Command::Ask(inner)) => (Command::Ask(Box::default()), inner.inherit(args)),
+            Some(Command::Chat(inner)) => (Command::Chat(Box::default()), inner.inherit(args)),
+            Some(Command::Fim(inner)) => (Command::Fim(Box::default()), inner.inherit(args)),
+            Some(Command::Commit {
+                commit,
+                args: inner,
+            }) => (
+                Command::Commit {
+                    commit,
+                    args: Box::default(),
+                },
+                inner.inherit(args),
+            ),
+            Some(Command::Review { diff, args: inner }) => (
+                Command::Review {
+                    diff,
+                    args: Box::default(),
+                },
+                inner.inherit(args),
+            ),
+            Some(Command::Pr { diff, args: inner }) => (
+                Command::Pr {
+                    diff,
+                    args: Box::default(),
+                },
+                inner.inherit(args),
+            ),
+            Some(Command::Agent { name, args: inner }) => (
+                Command::Agent {
+                    name,
+                    args: Box::default(),
+                },
+                inner.inherit(args),
+            ),
+            Some(Command::Alias(AliasCommand::Save { name, args: inner })) => (
+                Command::Alias(AliasCommand::Save {
+                    name,
+                    args: Box::default(),
+                }),
+                inner.inherit(args),
+            ),
+            Some(command) => (command, args),
+        }
+    }
+}
+
+/// Arguments used to send a prompt.
+#[derive(Default, Clone, Debug, clap::Args, PartialEq, Serialize, Deserialize)]
 pub struct Args {
     /// The user message prompt. If `-` is provided, `stdin` will be read instead.
     #[serde(skip_serializing)]
     pub prompt: Option<String>,
 
+    /// File with the user message prompt. If `-` is provided, the prompt is read from `stdin`,
+    /// which isn't added to it as context.
+    #[clap(long, value_name = "PATH", conflicts_with = "prompt")]
+    #[serde(skip_serializing)]
+    pub prompt_file: Option<String>,
+
+    /// Pick the prompt from the history of sent prompts with a fuzzy finder. `Enter` sends it
+    /// again, and `Ctrl-E` edits it in `$EDITOR` first.
+    #[clap(long, conflicts_with_all = ["prompt", "prompt_file"])]
+    #[serde(skip_serializing, default)]
+    pub history: bool,
+
     /// Additional file input to add to the prompt. If `-` is provided, `stdin` will be read
     /// instead.
     #[clap(hide = true)]
     #[serde(skip_serializing)]
     pub stdin: Option<String>,
 
+    /// Files of `--file` and `--files`, and chunks of `--rag`, added before the prompt. They are
+    /// kept apart from `stdin`, so `--stdin-placement none` doesn't leave them out.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub attachments: Option<String>,
+
+    /// Where the text read from stdin goes in the prompt. A `{stdin}` placeholder in the prompt
+    /// takes precedence over it. Defaults to `before`.
+    #[clap(long, value_enum)]
+    #[serde(skip_serializing)]
+    pub stdin_placement: Option<StdinPlacement>,
+
+    /// File with the whole request, in JSON or YAML: api, model, parameters, conversation,
+    /// template, and vars. If `-` is provided, `stdin` will be read instead. Options given on the
+    /// command line take precedence over it.
+    #[clap(long, value_name = "PATH")]
+    #[serde(skip_serializing)]
+    pub spec: Option<String>,
+
+    /// File to add to the prompt as a fenced code block. Can be repeated.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub file: Vec<String>,
+
+    /// File to edit in place: the model answers with a unified diff, which is previewed and
+    /// written once confirmed. Can be repeated.
+    #[clap(long, value_name = "PATH", conflicts_with = "append")]
+    #[serde(skip_serializing, default)]
+    pub apply: Vec<String>,
+
+    /// Write the changes of `--apply` and `--overwrite` without asking.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub yes: bool,
+
+    /// Glob of files to add to the prompt, respecting `.gitignore`. Can be repeated.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub files: Vec<String>,
+
+    /// Maximum amount of tokens of the files added with `--files`.
+    #[clap(long, default_value = "32000")]
+    #[serde(skip_serializing)]
+    pub files_max_tokens: Option<usize>,
+
+    /// Index created with `llm-stream index` whose chunks most similar to the prompt are added to
+    /// the context.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub rag: Option<String>,
+
+    /// Amount of chunks added with `--rag`.
+    #[clap(long, default_value = "5")]
+    #[serde(skip_serializing)]
+    pub rag_top_k: Option<usize>,
+
+    /// Sends the conversation to several apis and models at the same time, like
+    /// `openai:gpt-4o,anthropic:claude-3-5-sonnet-latest`, and compares their answers. Targets can
+    /// also be an api, with its default model, or a preset.
+    #[clap(long, value_name = "TARGETS")]
+    #[serde(skip_serializing)]
+    pub compare: Option<String>,
+
+    /// JSON Lines file of prompts to send, each a string or an object with the fields of `--spec`
+    /// and an optional `id`. The results are written as JSON Lines to `--out`, or stdout.
+    #[clap(long, value_name = "PATH")]
+    #[serde(skip_serializing)]
+    pub batch: Option<String>,
+
+    /// Amount of `--batch` prompts sent at the same time. Defaults to 4.
+    #[clap(long, requires = "batch")]
+    #[serde(skip_serializing)]
+    pub concurrency: Option<usize>,
+
+    /// Most `--batch` prompts sent per minute.
+    #[clap(long, requires = "batch")]
+    #[serde(skip_serializing)]
+    pub rpm: Option<u32>,
+
+    /// File where the `--batch` results are written.
+    #[clap(long, value_name = "PATH", requires = "batch")]
+    #[serde(skip_serializing)]
+    pub out: Option<String>,
+
+    /// Tool defined under `[[tools]]` that the model can call. Can be repeated.
+    #[clap(long = "tool", value_name = "NAME")]
+    #[serde(skip_serializing, default)]
+    pub tools: Vec<String>,
+
+    /// Most requests sent while the model keeps calling tools, set by the `max_iterations` of an
+    /// agent.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub max_iterations: Option<usize>,
+
     /// Suffix prompt
     #[clap(long)]
     #[serde(skip_serializing)]
@@ -97,7 +815,7 @@ pub struct Args {
     pub api: Option<Api>,
 
     /// The LLM Model to use
-    #[clap(short, long)]
+    #[clap(short, long, add = ArgValueCompleter::new(crate::completion::models))]
     pub model: Option<String>,
 
     /// The maximum amount of tokens to return.
@@ -108,6 +826,17 @@ pub struct Args {
     #[clap(long)]
     pub min_tokens: Option<u32>,
 
+    /// Maximum amount of tokens of the conversation sent to the model. The oldest messages are
+    /// dropped, or trimmed, to fit.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub max_context_tokens: Option<usize>,
+
+    /// Print the tokens of every message of the conversation without sending it.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub count_tokens: bool,
+
     /// The environment variable to use to get the access token for the api.
     #[clap(long)]
     pub api_env: Option<String>,
@@ -118,25 +847,90 @@ pub struct Args {
 
     /// The api key to use (will override the value of the environment variable.)
     #[clap(long)]
+    #[serde(skip_serializing)]
     pub api_key: Option<String>,
 
+    /// Command that prints the api key, like `op read op://vault/openai/key`. It only runs when a
+    /// request is sent.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub key_cmd: Option<String>,
+
+    /// Where to read the api key from when `--api-key` isn't given.
+    #[clap(long, value_enum)]
+    #[serde(skip_serializing)]
+    pub key_source: Option<KeySource>,
+
     /// The api base url.
     #[clap(long)]
     pub api_base_url: Option<String>,
 
-    /// Don't run the spinner
+    /// Extra header sent with the request, like `anthropic-beta: prompt-caching-2024-07-31`.
+    #[clap(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    #[serde(skip_serializing, default)]
+    pub headers: Vec<(String, String)>,
+
+    /// JSON object deep merged into the body of the request, for the parameters of the api that
+    /// have no option, like `{"logit_bias": {"50256": -100}}`. `null` values remove a key.
+    #[clap(long, value_name = "JSON", value_parser = parse_json)]
+    #[serde(skip_serializing)]
+    pub extra_body: Option<Value>,
+
+    /// Query parameters added to the url of every request, from the `extra_query` configuration.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub extra_query: Vec<(String, String)>,
+
+    /// Don't show the status line or the notices on stderr. Same as `--no-spinner` and
+    /// `--no-notices` together.
     #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub quiet: bool,
+
+    /// Don't show the status line while the response is on its way.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub no_spinner: bool,
+
+    /// Don't print the notices on stderr, like the path of the cache file or the retries of a
+    /// request.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub no_notices: bool,
+
+    /// Seconds to wait for the first event of the response before giving up on it, and trying the
+    /// `--fallback` presets.
+    #[clap(long, value_name = "SECONDS")]
+    #[serde(skip_serializing)]
+    pub first_token_timeout: Option<u64>,
+
+    /// Seconds to wait for the next event once the response started, before giving up on a stalled
+    /// stream. Events without text, like the deltas of a tool call, count too.
+    #[clap(long, value_name = "SECONDS")]
     #[serde(skip_serializing)]
-    pub quiet: Option<bool>,
+    pub stall_timeout: Option<u64>,
+
+    /// Preset sent the request when the first text doesn't arrive within `--first-token-timeout`.
+    /// Repeat it to try several presets in order.
+    #[clap(long, value_name = "PRESET", add = ArgValueCompleter::new(crate::completion::presets))]
+    #[serde(skip_serializing, default)]
+    pub fallback: Vec<String>,
 
     /// Language to use for syntax highlight
     #[clap(long, default_value = "markdown")]
     pub language: Option<String>,
 
-    /// Add a system message to the request.
+    /// Add to the system message of the preset or template. Can be given more than once, and is
+    /// added after the files of --system-file.
     #[clap(long)]
-    #[serde(skip_serializing)]
-    pub system: Option<String>,
+    #[serde(skip_serializing, default)]
+    pub system: Vec<String>,
+
+    /// Add the contents of a file to the system message of the preset or template. Can be given
+    /// more than once.
+    #[clap(long, value_name = "PATH")]
+    #[serde(skip_serializing, default)]
+    pub system_file: Vec<String>,
 
     /// Temperature value.
     #[clap(long)]
@@ -151,7 +945,7 @@ pub struct Args {
     pub top_k: Option<u32>,
 
     /// Prompt template to use
-    #[clap(short, long)]
+    #[clap(short, long, add = ArgValueCompleter::new(crate::completion::templates))]
     #[serde(skip_serializing)]
     pub template: Option<String>,
 
@@ -160,57 +954,134 @@ pub struct Args {
     #[serde(skip_serializing)]
     pub vars: Option<Value>,
 
+    /// File with additional variables, in JSON, TOML, or YAML format. `--vars` takes precedence
+    /// over it.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub vars_file: Option<String>,
+
     /// Conversation to append to the model.
     #[clap(long, default_value="[]", value_parser = parse_conversation)]
     pub conversation: Conversation,
 
-    /// Language to use for syntax highlight
-    #[clap(long, default_value = "ansi")]
+    /// Whole conversation, cached in place of `conversation` once it was compacted to fit in the
+    /// request, so the older messages aren't lost.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub full_conversation: Option<Conversation>,
+
+    /// File with the conversation to append to the model. JSON and YAML files hold a list of
+    /// messages, and TOML files a `conversation` array of tables.
+    #[clap(long, value_name = "PATH", conflicts_with = "conversation")]
+    #[serde(skip_serializing)]
+    pub conversation_file: Option<String>,
+
+    /// Theme used to highlight the output. Use `auto` to pick the `--dark-theme` or the
+    /// `--light-theme` based on the terminal background.
+    #[clap(long)]
     #[serde(skip_serializing)]
     pub theme: Option<String>,
 
-    /// Config dir where the configuration and conversation history will be stored.
-    #[clap(long, default_value = "~/.config/llm-stream")]
+    /// Theme used by `--theme auto` on terminals with a dark background.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub dark_theme: Option<String>,
+
+    /// Theme used by `--theme auto` on terminals with a light background.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub light_theme: Option<String>,
+
+    /// Wrap prose lines at the given amount of columns, or at the terminal width with `auto`. Use
+    /// `off` to disable it.
+    #[clap(long, value_parser = parse_wrap)]
+    #[serde(skip_serializing)]
+    pub wrap: Option<String>,
+
+    /// Renderer used to print the response on the terminal.
+    #[clap(long, value_enum)]
+    #[serde(skip_serializing)]
+    pub renderer: Option<Renderer>,
+
+    /// Profile whose configuration, templates, conversations, and usage are kept apart from the
+    /// others, inside `config_dir/profiles/<name>`.
+    #[clap(long, env = "LLM_STREAM_PROFILE")]
+    #[serde(skip_serializing)]
+    pub profile: Option<String>,
+
+    /// Config dir where the configuration and templates are stored. Defaults to
+    /// `$XDG_CONFIG_HOME/llm-stream`, or `%APPDATA%\llm-stream` on Windows.
+    #[clap(long)]
     #[serde(skip_serializing)]
     pub config_dir: Option<String>,
 
+    /// Data dir where the conversation history is stored. Defaults to
+    /// `$XDG_DATA_HOME/llm-stream`, or `%LOCALAPPDATA%\llm-stream\data` on Windows.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub data_dir: Option<String>,
+
+    /// State dir. Defaults to `$XDG_STATE_HOME/llm-stream`, or `%LOCALAPPDATA%\llm-stream\state`
+    /// on Windows.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub state_dir: Option<String>,
+
     /// Config file. If undefined, it will be set as `config_dir/config.toml`.
     #[clap(long)]
     #[serde(skip_serializing)]
     pub config_file: Option<String>,
 
     /// Preset configuration
-    #[clap(short, long)]
+    #[clap(short, long, add = ArgValueCompleter::new(crate::completion::presets))]
     #[serde(skip_serializing)]
     pub preset: Option<String>,
 
-    /// Prints the configuration directories
-    #[clap(long, default_value = "false")]
-    #[serde(skip_serializing, default)]
-    pub dir: bool,
-
-    /// Prints the configuration file in use.
-    #[clap(long, default_value = "false")]
-    #[serde(skip_serializing, default)]
-    pub config: bool,
+    /// Alias saved with `alias save`. A first argument like `@<name>` is the same as
+    /// `--alias <name>`.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub alias: Option<String>,
 
     /// Prints the conversation to be sent to the LLM.
     #[clap(long, default_value = "false")]
     #[serde(skip_serializing, default)]
     pub print_conversation: bool,
 
-    /// Don't call the LLM.
+    /// Print what would be redacted from the prompt, stdin, and files, without sending them. Uses
+    /// the built-in patterns when the `redaction` table isn't configured.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub show_redactions: bool,
+
+    /// Don't call the LLM, print the request that would be sent instead, with its credentials
+    /// redacted.
     #[clap(long, default_value = "false")]
     #[serde(skip_serializing, default)]
     pub dry_run: bool,
 
+    /// Commands of the `hooks` table of the configuration.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub hooks: crate::config::Hooks,
+
+    /// Settings of the `audit` table of the configuration, when the audit log is enabled.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub audit: Option<crate::config::Audit>,
+
+    /// Send the prompt even when the monthly budget is spent.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub force: bool,
+
     /// Don't cache the conversation details.
     #[clap(long, default_value = "false")]
     #[serde(skip_serializing, default)]
     pub no_cache: bool,
 
     /// Continue the conversation identified by its id.
-    #[clap(long)]
+    #[clap(long, add = ArgValueCompleter::new(crate::completion::conversations))]
     #[serde(skip_serializing)]
     pub from: Option<String>,
 
@@ -219,15 +1090,74 @@ pub struct Args {
     #[serde(skip_serializing, default)]
     pub from_last: bool,
 
+    /// Export the conversation of --from, or the last one, instead of sending a prompt. It's
+    /// written to --output, or stdout.
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    #[serde(skip_serializing)]
+    pub export: Option<ExportFormat>,
+
     /// Fork the conversation into a new one when using --from or --from-last options.
     #[clap(long)]
     #[serde(skip_serializing, default)]
     pub fork: bool,
 
+    /// Send the last user message of the conversation defined in --from or --from-last again,
+    /// replacing its last answer. Use with --fork to keep the original answer.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub regenerate: bool,
+
+    /// Continue the last answer of the conversation defined in --from or --from-last, when it was
+    /// cut by the token limit, appending the rest to it.
+    #[clap(long, conflicts_with = "regenerate")]
+    #[serde(skip_serializing, default)]
+    pub continue_generation: bool,
+
+    /// Continue the answers cut by the token limit automatically, up to 5 times.
+    #[clap(long, conflicts_with = "json")]
+    #[serde(skip_serializing, default)]
+    pub auto_continue: bool,
+
+    /// Check that the answer is JSON, matching the JSON schema of the file when it's given like
+    /// `--validate-json=schema.json`, and ask again with the errors when it isn't.
+    #[clap(
+        long,
+        value_name = "SCHEMA",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        conflicts_with = "auto_continue"
+    )]
+    #[serde(skip_serializing)]
+    pub validate_json: Option<String>,
+
+    /// Times an answer that isn't valid for --validate-json is asked again. Defaults to 2.
+    #[clap(long, requires = "validate_json")]
+    #[serde(skip_serializing)]
+    pub validate_retries: Option<u32>,
+
+    /// Edit the conversation defined in --from or --from-last in `$EDITOR` before sending the
+    /// new prompt. The format is given with `=`, like `--edit-conversation=json`, so the prompt
+    /// after the flag isn't taken for it.
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "markdown"
+    )]
+    #[serde(skip_serializing)]
+    pub edit_conversation: Option<ConversationFormat>,
+
     /// Conversation parent.
     #[clap(hide = true)]
     pub parent: Option<String>,
 
+    /// Version of the format of a cache file. Files cached before it was versioned don't have it.
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+
     /// Conversation description.
     #[clap(long)]
     pub description: Option<String>,
@@ -236,18 +1166,632 @@ pub struct Args {
     #[clap(long)]
     pub title: Option<String>,
 
-    /// Print the conversation defined in --from or --from-last to stdout
+    /// File where the raw response is written while it streams.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub output: Option<String>,
+
+    /// Print only the contents of the fenced code blocks of the response. Set `--language` to keep
+    /// only the blocks of that language.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub extract_code: bool,
+
+    /// Append the response to the `--output` file instead of overwriting it.
+    #[clap(long, requires = "output")]
+    #[serde(skip_serializing, default)]
+    pub append: bool,
+
+    /// Show the changes to the `--output` file, when it already exists, and ask before replacing
+    /// it once the response ends.
+    #[clap(long, requires = "output", conflicts_with_all = ["append", "apply"])]
+    #[serde(skip_serializing, default)]
+    pub overwrite: bool,
+
+    /// Parts of an answer continued by `--auto-continue`, held until it ends, so `--overwrite`
+    /// previews and asks about the whole answer once.
+    #[clap(skip)]
+    #[serde(skip)]
+    pub overwrite_parts: Option<String>,
+
+    /// Tag recorded with the usage of this request, to group it in the `usage` report.
+    #[clap(long)]
+    #[serde(skip_serializing)]
+    pub tag: Option<String>,
+
+    /// Print the elapsed time, time to first token, token usage, and estimated cost to stderr.
+    #[clap(long)]
+    #[serde(skip_serializing, default)]
+    pub stats: bool,
+
+    /// Print every streamed event as a JSON line: `delta`, `usage`, `stop`, and `error`.
+    #[clap(long, conflicts_with = "json")]
+    #[serde(skip_serializing, default)]
+    pub ndjson: bool,
+
+    /// Print the output as JSON. When sending a prompt, the response is printed once it's
+    /// complete, along with its metadata.
     #[clap(long)]
     #[serde(skip_serializing, default)]
-    pub show: bool,
+    pub json: bool,
 
-    /// Print the list of existing conversations.
+    /// Reads the text from stdin, and writes only the transformed text to stdout, without the
+    /// status line, highlighting, or cache notice. Used to filter text through `llm-stream` from an
+    /// editor, like `:%!llm-stream --filter "Fix the typos"` in Vim.
     #[clap(long)]
     #[serde(skip_serializing, default)]
-    pub list: bool,
+    pub filter: bool,
+
+    /// Shell command the response is piped through once it's complete, like `--pipe 'jq .'`. Its
+    /// output is printed instead of the response.
+    #[clap(
+        long,
+        visible_alias = "post-cmd",
+        value_name = "COMMAND",
+        conflicts_with_all = ["json", "ndjson"]
+    )]
+    #[serde(skip_serializing)]
+    pub pipe: Option<String>,
+
+    /// Sends the prompt to the `llm-stream daemon` instead of the api, which skips reading the
+    /// configuration on every call. The response is printed raw, or as events with `--ndjson`.
+    /// The options the daemon can't honor, like the ones of cached conversations, are refused.
+    #[clap(long, conflicts_with_all = [
+        "history", "apply", "rag", "compare", "batch", "tools", "count_tokens",
+        "print_conversation", "show_redactions", "dry_run", "from", "from_last", "export", "fork",
+        "regenerate", "continue_generation", "auto_continue", "validate_json",
+        "edit_conversation", "output", "stats", "json", "filter", "pipe",
+    ])]
+    #[serde(skip_serializing, default)]
+    pub daemon: bool,
+
+    /// Socket of the daemon. Defaults to `daemon.sock` in the state dir.
+    #[clap(long, value_name = "PATH")]
+    #[serde(skip_serializing)]
+    pub socket: Option<String>,
 
     /// Don't use colors to print the output.
     #[clap(long)]
     #[serde(skip_serializing, default)]
     pub no_color: bool,
 }
+
+impl Args {
+    /// Returns the arguments of an empty command line, with the default values of the options.
+    fn parsed_defaults() -> Args {
+        let command = <Args as clap::Args>::augment_args(clap::Command::new("llm-stream"));
+
+        command
+            .try_get_matches_from(["llm-stream"])
+            .ok()
+            .and_then(|matches| <Args as clap::FromArgMatches>::from_arg_matches(&matches).ok())
+            .unwrap_or_default()
+    }
+
+    /// Fills the options not given to a subcommand with the ones given before it, so
+    /// `llm-stream --model gpt-4o commit` uses `gpt-4o`. Options given after the subcommand win.
+    fn inherit(self: Box<Self>, parent: Args) -> Args {
+        /// Takes the value of the parent when the subcommand left the option as it defaults to.
+        fn inherit<T: PartialEq>(value: &mut T, parent: T, default: &T) {
+            if value == default {
+                *value = parent;
+            }
+        }
+
+        let mut args = *self;
+        let defaults = Args::parsed_defaults();
+
+        // Every field is listed, so a new option can't be forgotten.
+        let Args {
+            prompt,
+            prompt_file,
+            history,
+            stdin,
+            attachments,
+            stdin_placement,
+            spec,
+            file,
+            apply,
+            yes,
+            files,
+            files_max_tokens,
+            rag,
+            rag_top_k,
+            compare,
+            batch,
+            concurrency,
+            rpm,
+            out,
+            tools,
+            max_iterations,
+            suffix,
+            api,
+            model,
+            max_tokens,
+            min_tokens,
+            max_context_tokens,
+            count_tokens,
+            api_env,
+            api_version,
+            api_key,
+            key_cmd,
+            key_source,
+            api_base_url,
+            headers,
+            extra_body,
+            extra_query,
+            quiet,
+            no_spinner,
+            no_notices,
+            first_token_timeout,
+            stall_timeout,
+            fallback,
+            language,
+            system,
+            system_file,
+            temperature,
+            top_p,
+            top_k,
+            template,
+            vars,
+            vars_file,
+            conversation,
+            full_conversation,
+            conversation_file,
+            theme,
+            dark_theme,
+            light_theme,
+            wrap,
+            renderer,
+            profile,
+            config_dir,
+            data_dir,
+            state_dir,
+            config_file,
+            preset,
+            alias,
+            print_conversation,
+            show_redactions,
+            dry_run,
+            hooks,
+            audit,
+            force,
+            no_cache,
+            from,
+            from_last,
+            export,
+            fork,
+            regenerate,
+            continue_generation,
+            auto_continue,
+            validate_json,
+            validate_retries,
+            edit_conversation,
+            parent,
+            schema_version,
+            description,
+            title,
+            output,
+            extract_code,
+            append,
+            overwrite,
+            overwrite_parts,
+            tag,
+            stats,
+            ndjson,
+            json,
+            filter,
+            pipe,
+            daemon,
+            socket,
+            no_color,
+        } = parent;
+
+        inherit(&mut args.prompt, prompt, &defaults.prompt);
+        inherit(&mut args.prompt_file, prompt_file, &defaults.prompt_file);
+        inherit(&mut args.history, history, &defaults.history);
+        inherit(&mut args.stdin, stdin, &defaults.stdin);
+        inherit(&mut args.attachments, attachments, &defaults.attachments);
+        inherit(
+            &mut args.stdin_placement,
+            stdin_placement,
+            &defaults.stdin_placement,
+        );
+        inherit(&mut args.spec, spec, &defaults.spec);
+        inherit(&mut args.file, file, &defaults.file);
+        inherit(&mut args.apply, apply, &defaults.apply);
+        inherit(&mut args.yes, yes, &defaults.yes);
+        inherit(&mut args.files, files, &defaults.files);
+        inherit(
+            &mut args.files_max_tokens,
+            files_max_tokens,
+            &defaults.files_max_tokens,
+        );
+        inherit(&mut args.rag, rag, &defaults.rag);
+        inherit(&mut args.rag_top_k, rag_top_k, &defaults.rag_top_k);
+        inherit(&mut args.compare, compare, &defaults.compare);
+        inherit(&mut args.batch, batch, &defaults.batch);
+        inherit(&mut args.concurrency, concurrency, &defaults.concurrency);
+        inherit(&mut args.rpm, rpm, &defaults.rpm);
+        inherit(&mut args.out, out, &defaults.out);
+        inherit(&mut args.tools, tools, &defaults.tools);
+        inherit(
+            &mut args.max_iterations,
+            max_iterations,
+            &defaults.max_iterations,
+        );
+        inherit(&mut args.suffix, suffix, &defaults.suffix);
+        inherit(&mut args.api, api, &defaults.api);
+        inherit(&mut args.model, model, &defaults.model);
+        inherit(&mut args.max_tokens, max_tokens, &defaults.max_tokens);
+        inherit(&mut args.min_tokens, min_tokens, &defaults.min_tokens);
+        inherit(
+            &mut args.max_context_tokens,
+            max_context_tokens,
+            &defaults.max_context_tokens,
+        );
+        inherit(&mut args.count_tokens, count_tokens, &defaults.count_tokens);
+        inherit(&mut args.api_env, api_env, &defaults.api_env);
+        inherit(&mut args.api_version, api_version, &defaults.api_version);
+        inherit(&mut args.api_key, api_key, &defaults.api_key);
+        inherit(&mut args.key_cmd, key_cmd, &defaults.key_cmd);
+        inherit(&mut args.key_source, key_source, &defaults.key_source);
+        inherit(&mut args.api_base_url, api_base_url, &defaults.api_base_url);
+        inherit(&mut args.headers, headers, &defaults.headers);
+        inherit(&mut args.extra_body, extra_body, &defaults.extra_body);
+        inherit(&mut args.extra_query, extra_query, &defaults.extra_query);
+        inherit(&mut args.quiet, quiet, &defaults.quiet);
+        inherit(&mut args.no_spinner, no_spinner, &defaults.no_spinner);
+        inherit(&mut args.no_notices, no_notices, &defaults.no_notices);
+        inherit(
+            &mut args.first_token_timeout,
+            first_token_timeout,
+            &defaults.first_token_timeout,
+        );
+        inherit(
+            &mut args.stall_timeout,
+            stall_timeout,
+            &defaults.stall_timeout,
+        );
+        inherit(&mut args.fallback, fallback, &defaults.fallback);
+        inherit(&mut args.language, language, &defaults.language);
+        inherit(&mut args.system, system, &defaults.system);
+        inherit(&mut args.system_file, system_file, &defaults.system_file);
+        inherit(&mut args.temperature, temperature, &defaults.temperature);
+        inherit(&mut args.top_p, top_p, &defaults.top_p);
+        inherit(&mut args.top_k, top_k, &defaults.top_k);
+        inherit(&mut args.template, template, &defaults.template);
+        inherit(&mut args.vars, vars, &defaults.vars);
+        inherit(&mut args.vars_file, vars_file, &defaults.vars_file);
+        inherit(&mut args.conversation, conversation, &defaults.conversation);
+        inherit(
+            &mut args.full_conversation,
+            full_conversation,
+            &defaults.full_conversation,
+        );
+        inherit(
+            &mut args.conversation_file,
+            conversation_file,
+            &defaults.conversation_file,
+        );
+        inherit(&mut args.theme, theme, &defaults.theme);
+        inherit(&mut args.dark_theme, dark_theme, &defaults.dark_theme);
+        inherit(&mut args.light_theme, light_theme, &defaults.light_theme);
+        inherit(&mut args.wrap, wrap, &defaults.wrap);
+        inherit(&mut args.renderer, renderer, &defaults.renderer);
+        inherit(&mut args.profile, profile, &defaults.profile);
+        inherit(&mut args.config_dir, config_dir, &defaults.config_dir);
+        inherit(&mut args.data_dir, data_dir, &defaults.data_dir);
+        inherit(&mut args.state_dir, state_dir, &defaults.state_dir);
+        inherit(&mut args.config_file, config_file, &defaults.config_file);
+        inherit(&mut args.preset, preset, &defaults.preset);
+        inherit(&mut args.alias, alias, &defaults.alias);
+        inherit(
+            &mut args.print_conversation,
+            print_conversation,
+            &defaults.print_conversation,
+        );
+        inherit(
+            &mut args.show_redactions,
+            show_redactions,
+            &defaults.show_redactions,
+        );
+        inherit(&mut args.dry_run, dry_run, &defaults.dry_run);
+        inherit(&mut args.hooks, hooks, &defaults.hooks);
+        inherit(&mut args.audit, audit, &defaults.audit);
+        inherit(&mut args.force, force, &defaults.force);
+        inherit(&mut args.no_cache, no_cache, &defaults.no_cache);
+        inherit(&mut args.from, from, &defaults.from);
+        inherit(&mut args.from_last, from_last, &defaults.from_last);
+        inherit(&mut args.export, export, &defaults.export);
+        inherit(&mut args.fork, fork, &defaults.fork);
+        inherit(&mut args.regenerate, regenerate, &defaults.regenerate);
+        inherit(
+            &mut args.continue_generation,
+            continue_generation,
+            &defaults.continue_generation,
+        );
+        inherit(
+            &mut args.auto_continue,
+            auto_continue,
+            &defaults.auto_continue,
+        );
+        inherit(
+            &mut args.validate_json,
+            validate_json,
+            &defaults.validate_json,
+        );
+        inherit(
+            &mut args.validate_retries,
+            validate_retries,
+            &defaults.validate_retries,
+        );
+        inherit(
+            &mut args.edit_conversation,
+            edit_conversation,
+            &defaults.edit_conversation,
+        );
+        inherit(&mut args.parent, parent, &defaults.parent);
+        inherit(
+            &mut args.schema_version,
+            schema_version,
+            &defaults.schema_version,
+        );
+        inherit(&mut args.description, description, &defaults.description);
+        inherit(&mut args.title, title, &defaults.title);
+        inherit(&mut args.output, output, &defaults.output);
+        inherit(&mut args.extract_code, extract_code, &defaults.extract_code);
+        inherit(&mut args.append, append, &defaults.append);
+        inherit(&mut args.overwrite, overwrite, &defaults.overwrite);
+        inherit(
+            &mut args.overwrite_parts,
+            overwrite_parts,
+            &defaults.overwrite_parts,
+        );
+        inherit(&mut args.tag, tag, &defaults.tag);
+        inherit(&mut args.stats, stats, &defaults.stats);
+        inherit(&mut args.ndjson, ndjson, &defaults.ndjson);
+        inherit(&mut args.json, json, &defaults.json);
+        inherit(&mut args.filter, filter, &defaults.filter);
+        inherit(&mut args.pipe, pipe, &defaults.pipe);
+        inherit(&mut args.daemon, daemon, &defaults.daemon);
+        inherit(&mut args.socket, socket, &defaults.socket);
+        inherit(&mut args.no_color, no_color, &defaults.no_color);
+
+        args
+    }
+
+    /// Keeps the whole conversation to cache it, before `conversation` is changed to fit in the
+    /// request.
+    pub fn keep_full_conversation(&mut self) {
+        if self.full_conversation.is_none() {
+            self.full_conversation = Some(self.conversation.clone());
+        }
+    }
+
+    /// Applies `change` to the conversation, and to the whole one kept for the cache, if any.
+    pub fn update_conversations(&mut self, change: impl Fn(&mut Conversation)) {
+        change(&mut self.conversation);
+
+        if let Some(full_conversation) = self.full_conversation.as_mut() {
+            change(full_conversation);
+        }
+    }
+
+    /// Returns true if the status line is shown, unless `--quiet` or `--no-spinner` hide it.
+    pub fn shows_spinner(&self) -> bool {
+        !(self.quiet || self.no_spinner)
+    }
+
+    /// Returns true if `--overwrite` replaces an existing `--output` file once it's confirmed,
+    /// instead of writing the answer as it arrives.
+    pub fn overwrites(&self) -> bool {
+        self.overwrite
+            && !self.append
+            && self
+                .output
+                .as_deref()
+                .is_some_and(|path| std::path::Path::new(path).exists())
+    }
+
+    /// Returns true if the notices are printed on stderr, unless `--quiet` or `--no-notices` hide
+    /// them.
+    pub fn shows_notices(&self) -> bool {
+        !(self.quiet || self.no_notices)
+    }
+
+    /// Returns a copy of the arguments to log, with the api key and the values of the headers
+    /// hidden, since they can hold credentials.
+    pub fn redacted(&self) -> Args {
+        let hidden = || "<redacted>".to_string();
+
+        Args {
+            api_key: self.api_key.as_ref().map(|_| hidden()),
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, _)| (name.clone(), hidden()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        let day = 60 * 60 * 24;
+
+        assert_eq!(parse_duration("45"), Ok(std::time::Duration::from_secs(45)));
+        assert_eq!(
+            parse_duration("15m"),
+            Ok(std::time::Duration::from_secs(15 * 60))
+        );
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(std::time::Duration::from_secs(30 * day))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(std::time::Duration::from_secs(14 * day))
+        );
+        assert!(parse_duration("30y").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn test_cli_defaults_to_ask() {
+        let (command, args) =
+            Cli::parse_from(["llm-stream", "--data-dir", "/tmp", "hello"]).into_command();
+
+        assert!(matches!(command, Command::Ask(_)));
+        assert_eq!(args.prompt, Some("hello".to_string()));
+        assert_eq!(args.data_dir, Some("/tmp".to_string()));
+    }
+
+    #[test]
+    fn test_quiet_hides_the_spinner_and_the_notices() {
+        let (_, args) = Cli::parse_from(["llm-stream", "--no-spinner", "hello"]).into_command();
+
+        assert!(!args.shows_spinner());
+        assert!(args.shows_notices());
+
+        let (_, args) = Cli::parse_from(["llm-stream", "--no-notices", "hello"]).into_command();
+
+        assert!(args.shows_spinner());
+        assert!(!args.shows_notices());
+
+        let (_, args) = Cli::parse_from(["llm-stream", "--quiet", "hello"]).into_command();
+
+        assert!(!args.shows_spinner());
+        assert!(!args.shows_notices());
+    }
+
+    #[test]
+    fn test_cli_edit_conversation_leaves_the_prompt_alone() {
+        let (_, args) =
+            Cli::parse_from(["llm-stream", "--from-last", "--edit-conversation", "json"])
+                .into_command();
+
+        assert_eq!(args.edit_conversation, Some(ConversationFormat::Markdown));
+        assert_eq!(args.prompt, Some("json".to_string()));
+
+        let (_, args) =
+            Cli::parse_from(["llm-stream", "--edit-conversation=json", "hello"]).into_command();
+
+        assert_eq!(args.edit_conversation, Some(ConversationFormat::Json));
+        assert_eq!(args.prompt, Some("hello".to_string()));
+    }
+
+    #[test]
+    fn test_cli_daemon_refuses_the_options_it_cant_honor() {
+        assert!(Cli::try_parse_from(["llm-stream", "--daemon", "--from-last", "hello"]).is_err());
+        assert!(Cli::try_parse_from(["llm-stream", "--daemon", "--output", "a.md", "hi"]).is_err());
+        assert!(Cli::try_parse_from(["llm-stream", "--daemon", "--model", "gpt-4o", "hi"]).is_ok());
+    }
+
+    #[test]
+    fn test_cli_alias_save_takes_the_flags_after_the_name() {
+        let (command, args) = Cli::parse_from([
+            "llm-stream",
+            "--data-dir",
+            "/tmp",
+            "alias",
+            "save",
+            "review",
+            "--model",
+            "gpt-4o",
+        ])
+        .into_command();
+
+        assert!(matches!(
+            command,
+            Command::Alias(AliasCommand::Save { name, .. }) if name == "review"
+        ));
+        assert_eq!(args.model, Some("gpt-4o".to_string()));
+        assert_eq!(args.data_dir, Some("/tmp".to_string()));
+    }
+
+    #[test]
+    fn test_cli_agent_takes_the_task_after_the_name() {
+        let (command, args) = Cli::parse_from([
+            "llm-stream",
+            "agent",
+            "researcher",
+            "Find the cheapest flight",
+            "--model",
+            "gpt-4o",
+        ])
+        .into_command();
+
+        assert!(matches!(command, Command::Agent { name, .. } if name == "researcher"));
+        assert_eq!(args.prompt, Some("Find the cheapest flight".to_string()));
+        assert_eq!(args.model, Some("gpt-4o".to_string()));
+    }
+
+    #[test]
+    fn test_cli_log_options_are_global() {
+        let cli = Cli::parse_from([
+            "llm-stream",
+            "review",
+            "--log-format",
+            "json",
+            "--log-file",
+            "/tmp/llm-stream.log",
+        ]);
+
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert_eq!(cli.log_file, Some("/tmp/llm-stream.log".to_string()));
+    }
+
+    #[test]
+    fn test_cli_subcommands_inherit_directories() {
+        let (command, args) =
+            Cli::parse_from(["llm-stream", "--data-dir", "/tmp", "fim", "fn main() {"])
+                .into_command();
+
+        assert!(matches!(command, Command::Fim(_)));
+        assert_eq!(args.prompt, Some("fn main() {".to_string()));
+        assert_eq!(args.data_dir, Some("/tmp".to_string()));
+    }
+
+    #[test]
+    fn test_cli_subcommands_inherit_the_options_given_before_them() {
+        let (command, args) = Cli::parse_from([
+            "llm-stream",
+            "--model",
+            "gpt-4o",
+            "--temperature",
+            "0.2",
+            "--no-cache",
+            "--files-max-tokens",
+            "1000",
+            "--api",
+            "anthropic",
+            "commit",
+            "--api",
+            "openai",
+        ])
+        .into_command();
+
+        assert!(matches!(command, Command::Commit { .. }));
+        assert_eq!(args.model, Some("gpt-4o".to_string()));
+        assert_eq!(args.temperature, Some(0.2));
+        assert!(args.no_cache);
+        assert_eq!(args.files_max_tokens, Some(1000));
+        assert_eq!(
+            args.api,
+            Some(Api::OpenAi),
+            "The options given after the subcommand should win"
+        );
+
+        let (_, args) =
+            Cli::parse_from(["llm-stream", "--quiet", "chat", "--tag", "work"]).into_command();
+
+        assert!(args.quiet);
+        assert_eq!(args.tag, Some("work".to_string()));
+    }
+}
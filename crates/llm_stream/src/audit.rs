@@ -0,0 +1,460 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::io::{BufRead, Write};
+
+use crate::args::AuditCommand;
+use crate::config::Audit;
+use crate::prelude::*;
+use crate::report::Usage;
+
+/// Size in megabytes the audit log reaches before it's rotated, when `max_size_mb` isn't set.
+const DEFAULT_MAX_SIZE_MB: u64 = 10;
+
+/// Rotated audit logs kept, when `max_files` isn't set.
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// Hash that the first entry of the audit log chains to.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Bytes of the key generated for the audit log.
+const KEY_SIZE: usize = 32;
+
+/// Returns the path of the audit log.
+pub fn audit_file(data_dir: &str) -> String {
+    format!("{}/audit.jsonl", data_dir)
+}
+
+/// Returns the path of the hash that the oldest entry left chains to, once older logs were
+/// removed by the rotation.
+fn anchor_file(data_dir: &str) -> String {
+    format!("{}/audit.anchor.json", data_dir)
+}
+
+/// Returns the path of the key that signs the entries: `key_file`, or a file of the state
+/// directory, so it isn't kept with the log.
+fn key_file(state_dir: &str, audit: &Audit) -> Result<String> {
+    match &audit.key_file {
+        Some(path) => crate::paths::expand(path),
+        None => Ok(format!("{}/audit.key", state_dir)),
+    }
+}
+
+/// Reads the key of the audit log at `path`, generating it, readable only by the user, the first
+/// time.
+fn key(path: &str) -> Result<Vec<u8>> {
+    match std::fs::read_to_string(path) {
+        Ok(key) if !key.trim().is_empty() => return Ok(key.trim().as_bytes().to_vec()),
+        Ok(_) => return Err(Error::AuditTampered(format!("{}: the key is empty", path))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut bytes = [0u8; KEY_SIZE];
+    getrandom::getrandom(&mut bytes).map_err(std::io::Error::other)?;
+    let key = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    writeln!(options.open(path)?, "{}", key)?;
+
+    Ok(key.into_bytes())
+}
+
+/// Returns the path of the audit log rotated `n` times.
+fn rotated_file(data_dir: &str, n: usize) -> String {
+    format!("{}/audit.{}.jsonl", data_dir, n)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Error,
+}
+
+/// Request and its response, stored as a line of the audit log. Each entry holds the hash of the
+/// one before it, so changing or removing a line breaks the chain from there on. The hashes are
+/// HMACs with the key of the log, so they can't be made again without it.
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    date: chrono::DateTime<chrono::Local>,
+    api: Option<Api>,
+    model: Option<&'a str>,
+    messages: &'a Conversation,
+    response: Option<&'a str>,
+    usage: Usage,
+    status: Status,
+    error: Option<String>,
+    previous: String,
+}
+
+/// Returns the HMAC-SHA256 of an entry, without its `hash`, with `key`.
+fn hash(key: &[u8], entry: &Value) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(entry.to_string().as_bytes());
+
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Returns the line of an entry, with its `hash`.
+fn line(key: &[u8], entry: &Entry) -> Result<String> {
+    let mut value = serde_json::to_value(entry)?;
+    value["hash"] = Value::String(hash(key, &value));
+
+    Ok(value.to_string())
+}
+
+/// Returns the hash of the last entry of the log at `path`, or `None` if it's empty or missing.
+fn last_entry_hash(path: &str) -> Result<Option<String>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let Some(last) = contents.lines().rev().find(|l| !l.trim().is_empty()) else {
+        return Ok(None);
+    };
+
+    let entry = serde_json::from_str::<Value>(last)?;
+
+    entry["hash"]
+        .as_str()
+        .map(|hash| Some(hash.to_string()))
+        .ok_or_else(|| Error::AuditTampered(format!("{}: the last entry has no hash", path)))
+}
+
+/// Returns the hash of the last entry of the audit log, looking in the last rotated log when the
+/// current one is empty, and at the anchor when both are.
+fn last_hash(data_dir: &str, key: &[u8]) -> Result<String> {
+    for path in [audit_file(data_dir), rotated_file(data_dir, 1)] {
+        if let Some(hash) = last_entry_hash(&path)? {
+            return Ok(hash);
+        }
+    }
+
+    anchor(data_dir, key)
+}
+
+/// Returns the hash that the oldest entry of the audit logs chains to: the one kept in the anchor
+/// when older logs were removed, or the genesis hash.
+fn anchor(data_dir: &str, key: &[u8]) -> Result<String> {
+    let path = anchor_file(data_dir);
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(GENESIS.to_string()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let tampered = || Error::AuditTampered(format!("{}: the anchor was changed", path));
+
+    let mut anchor = serde_json::from_str::<Value>(&contents).map_err(|_| tampered())?;
+    let stored = anchor
+        .as_object_mut()
+        .and_then(|anchor| anchor.remove("hash"))
+        .and_then(|hash| hash.as_str().map(String::from))
+        .ok_or_else(tampered)?;
+
+    if hash(key, &anchor) != stored {
+        return Err(tampered());
+    }
+
+    anchor["previous"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(tampered)
+}
+
+/// Removes the log at `path`, keeping the hash of its last entry in the anchor, so the entries
+/// left still chain to a known hash.
+fn remove(data_dir: &str, key: &[u8], path: &str) -> Result<()> {
+    if let Some(previous) = last_entry_hash(path)? {
+        let mut anchor = serde_json::json!({ "previous": previous });
+        anchor["hash"] = Value::String(hash(key, &anchor));
+
+        std::fs::write(anchor_file(data_dir), anchor.to_string())?;
+    }
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+/// Rotates the audit log once it reaches `max_size_mb`, keeping the newest `max_files` logs.
+fn rotate(data_dir: &str, key: &[u8], audit: &Audit) -> Result<()> {
+    let max_size = audit.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) * 1024 * 1024;
+    let max_files = audit.max_files.unwrap_or(DEFAULT_MAX_FILES);
+
+    let Ok(metadata) = std::fs::metadata(audit_file(data_dir)) else {
+        return Ok(());
+    };
+
+    if metadata.len() < max_size {
+        return Ok(());
+    }
+
+    for n in (1..=max_files).rev() {
+        let path = rotated_file(data_dir, n);
+
+        if !std::path::Path::new(&path).exists() {
+            continue;
+        }
+
+        if n == max_files {
+            remove(data_dir, key, &path)?;
+        } else {
+            std::fs::rename(&path, rotated_file(data_dir, n + 1))?;
+        }
+    }
+
+    if max_files == 0 {
+        remove(data_dir, key, &audit_file(data_dir))?;
+    } else {
+        std::fs::rename(audit_file(data_dir), rotated_file(data_dir, 1))?;
+    }
+
+    Ok(())
+}
+
+/// Appends an entry to the audit log, chained to the last one.
+fn append(data_dir: &str, key: &[u8], audit: &Audit, mut entry: Entry) -> Result<()> {
+    entry.previous = last_hash(data_dir, key)?;
+
+    rotate(data_dir, key, audit)?;
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_file(data_dir))?;
+
+    writeln!(file, "{}", line(key, &entry)?)?;
+
+    Ok(())
+}
+
+/// Records a request in the audit log when the `audit` table is configured, with the messages
+/// sent, and the response or the error it failed with.
+pub fn record(args: &Args, response: Option<&str>, usage: Usage, error: Option<&Error>) {
+    let Some(audit) = &args.audit else {
+        return;
+    };
+
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+
+    let entry = Entry {
+        date: chrono::Local::now(),
+        api: args.api,
+        model: args.model.as_deref(),
+        messages: &args.conversation,
+        response,
+        usage,
+        status: if error.is_some() {
+            Status::Error
+        } else {
+            Status::Ok
+        },
+        error: error.map(|e| e.to_string()),
+        previous: String::new(),
+    };
+
+    let appended = key_file(&state_dir, audit)
+        .and_then(|path| key(&path))
+        .and_then(|key| append(&data_dir, &key, audit, entry));
+
+    if let Err(e) = appended {
+        eprintln!("Warning: unable to write the audit log: {}", e);
+    }
+}
+
+/// Checks the hashes of every entry of the audit logs with `key`, oldest first, and that each one
+/// chains to the entry before it, starting from the anchor. Returns the number of entries.
+fn verify(data_dir: &str, key: &[u8]) -> Result<usize> {
+    let mut paths = (1..)
+        .map(|n| rotated_file(data_dir, n))
+        .take_while(|path| std::path::Path::new(path).exists())
+        .collect::<Vec<String>>();
+
+    paths.reverse();
+    paths.push(audit_file(data_dir));
+
+    let mut previous = anchor(data_dir, key)?;
+    let mut count = 0;
+
+    for path in paths
+        .iter()
+        .filter(|path| std::path::Path::new(path).exists())
+    {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+
+        for (index, line) in file.lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let tampered = |problem: &str| {
+                Error::AuditTampered(format!("{}:{}: {}", path, index + 1, problem))
+            };
+
+            let mut entry = serde_json::from_str::<Value>(&line)
+                .map_err(|_| tampered("the entry isn't valid JSON"))?;
+
+            let stored = entry
+                .as_object_mut()
+                .and_then(|entry| entry.remove("hash"))
+                .and_then(|hash| hash.as_str().map(String::from))
+                .ok_or_else(|| tampered("the entry has no hash"))?;
+
+            if hash(key, &entry) != stored {
+                return Err(tampered("the entry doesn't match its hash"));
+            }
+
+            if entry["previous"].as_str() != Some(previous.as_str()) {
+                return Err(tampered("the entry before it was changed or removed"));
+            }
+
+            previous = stored;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Runs the `audit` commands.
+pub fn run(command: AuditCommand, args: Args) -> Result<()> {
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+
+    match command {
+        AuditCommand::Verify => {
+            let audit = args.audit.clone().unwrap_or_default();
+            let count = verify(&data_dir, &key(&key_file(&state_dir, &audit)?)?)?;
+            eprintln!("The audit log is intact: {} entries.", count);
+        }
+        AuditCommand::Path => println!("{}", audit_file(&data_dir)),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(data_dir: &str, audit: Audit) -> Args {
+        Args {
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            data_dir: Some(data_dir.to_string()),
+            state_dir: Some(data_dir.to_string()),
+            audit: Some(audit),
+            conversation: vec![ConversationMessage {
+                role: ConversationRole::User,
+                content: "What's a monad?".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_detects_changes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-audit-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let data_dir = dir.to_string_lossy().to_string();
+
+        let args = args(&data_dir, Audit::default());
+        record(&args, Some("A monoid."), Usage::default(), None);
+        record(&args, None, Usage::default(), Some(&Error::ApiNotSpecified));
+        record(&args, Some("A burrito."), Usage::default(), None);
+
+        let key = key(&key_file(&data_dir, &Audit::default())?)?;
+        let verified = verify(&data_dir, &key);
+        let other_key = verify(&data_dir, b"another key");
+
+        let contents = std::fs::read_to_string(audit_file(&data_dir))?;
+        std::fs::write(
+            audit_file(&data_dir),
+            contents.replacen("A burrito.", "A monoid.", 1),
+        )?;
+        let changed = verify(&data_dir, &key);
+
+        let lines = contents.lines().collect::<Vec<&str>>();
+        std::fs::write(
+            audit_file(&data_dir),
+            format!("{}\n{}\n", lines[0], lines[2]),
+        )?;
+        let removed = verify(&data_dir, &key);
+
+        std::fs::write(
+            audit_file(&data_dir),
+            format!("{}\n{}\n", lines[1], lines[2]),
+        )?;
+        let first_removed = verify(&data_dir, &key);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(verified?, 3);
+        assert!(other_key.is_err());
+        assert!(changed.is_err());
+        assert!(removed.is_err());
+        assert!(first_removed.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation_keeps_the_chain() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-audit-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let data_dir = dir.to_string_lossy().to_string();
+
+        // A size of zero rotates the log before every entry.
+        let args = args(
+            &data_dir,
+            Audit {
+                max_size_mb: Some(0),
+                max_files: Some(2),
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..4 {
+            record(&args, Some("A monoid."), Usage::default(), None);
+        }
+
+        let rotated = (1..=3)
+            .map(|n| std::path::Path::new(&rotated_file(&data_dir, n)).exists())
+            .collect::<Vec<bool>>();
+        let key = key(&key_file(&data_dir, &Audit::default())?)?;
+        let verified = verify(&data_dir, &key);
+
+        let mut anchor =
+            serde_json::from_str::<Value>(&std::fs::read_to_string(anchor_file(&data_dir))?)?;
+        anchor["previous"] = Value::String(GENESIS.to_string());
+        std::fs::write(anchor_file(&data_dir), anchor.to_string())?;
+        let anchor_changed = verify(&data_dir, &key);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(rotated, vec![true, true, false]);
+        assert_eq!(verified?, 3);
+        assert!(anchor_changed.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use futures::stream::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+
+use crate::prelude::*;
+use crate::report::{Stats, Usage};
+use crate::spec::Spec;
+
+/// Prompts sent at the same time when `--concurrency` isn't set.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Prompt of a `--batch` file, with the id its result is written with.
+#[derive(Debug, PartialEq)]
+struct Item {
+    id: Value,
+    spec: Spec,
+}
+
+/// Parses a line of a `--batch` file: a JSON string with the prompt, or an object with the fields
+/// of a `--spec` and an optional `id`. Items without an `id` use their line number.
+fn parse_item(line_number: usize, line: &str) -> Result<Item> {
+    let invalid = |e: serde_json::Error| Error::Batch(format!("line {}: {}", line_number, e));
+
+    match serde_json::from_str::<Value>(line).map_err(invalid)? {
+        Value::String(prompt) => Ok(Item {
+            id: Value::from(line_number),
+            spec: Spec {
+                prompt: Some(prompt),
+                ..Default::default()
+            },
+        }),
+        Value::Object(mut object) => Ok(Item {
+            id: object.remove("id").unwrap_or(Value::from(line_number)),
+            spec: serde_json::from_value(Value::Object(object)).map_err(invalid)?,
+        }),
+        _ => Err(Error::Batch(format!(
+            "line {}: expected a string or an object",
+            line_number
+        ))),
+    }
+}
+
+/// Reads the items of a `--batch` file, skipping empty lines.
+fn read_items(path: &str) -> Result<Vec<Item>> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_item(i + 1, line))
+        .collect()
+}
+
+/// Result of a batch item, written as a line of `--out`.
+#[derive(Debug, Serialize)]
+struct Output {
+    id: Value,
+    api: Option<Api>,
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+    /// The provider didn't report the usage, so the tokens were counted locally.
+    estimated: bool,
+    cost: Option<f64>,
+    elapsed_ms: u128,
+}
+
+/// Answer to a batch item, with the arguments it was sent with.
+struct Answer {
+    args: Args,
+    content: String,
+    finish_reason: Option<String>,
+    stats: Stats,
+}
+
+/// Builds the arguments of an item on top of the ones given to `--batch`. The provider an item
+/// picks with its `api`, `model`, or `preset` takes precedence over the `--preset` of the batch.
+fn item_args(item: Item, base: &Args, config: &Config) -> Result<Args> {
+    let mut args = Args {
+        prompt: None,
+        no_cache: true,
+        quiet: true,
+        ..base.clone()
+    };
+
+    if item.spec.api.is_some() || item.spec.model.is_some() || item.spec.preset.is_some() {
+        args.preset = None;
+    }
+
+    item.spec.apply(&mut args);
+    merge_preset(&mut args, config);
+
+    let args = crate::router::route(args, config)?;
+    let args = merge_args_and_config(args, config.clone())?;
+
+    Ok(crate::tokens::truncate(args))
+}
+
+/// Sends an item with the arguments of `item_args`.
+async fn send(item: Item, base: &Args, config: &Config) -> Result<Answer> {
+    let args = item_args(item, base, config)?;
+
+    let metadata = SharedMetadata::default();
+    let start = std::time::Instant::now();
+    let mut first_token = None;
+
+    let content = complete_with_metadata(args.clone(), metadata.clone(), |_| {
+        first_token.get_or_insert(start.elapsed());
+    })
+    .await?;
+
+    let metadata = metadata.lock().map(|m| m.clone()).unwrap_or_default();
+    let stats = Stats::new(&args, &content, &metadata, start.elapsed(), first_token);
+
+    Ok(Answer {
+        args,
+        content,
+        finish_reason: metadata.finish_reason,
+        stats,
+    })
+}
+
+/// Sends every prompt of the `--batch` file, up to `--concurrency` at a time and at most
+/// `--rpm` per minute, and writes one JSON result per line to `--out`, or stdout, as they finish.
+/// Failed prompts are written with their error instead of their content.
+pub async fn run(args: Args, path: &str, config: &Config) -> Result<()> {
+    let items = read_items(path)?;
+    let total = items.len();
+    let concurrency = args.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+    let interval = args
+        .rpm
+        .filter(|rpm| *rpm > 0)
+        .map(|rpm| std::time::Duration::from_secs(60) / rpm);
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+
+    let mut out: Box<dyn Write> = match &args.out {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    if args.shows_notices() {
+        eprintln!("Sending {} prompts...", total);
+    }
+
+    let start = tokio::time::Instant::now();
+
+    let mut results = futures::stream::iter(items.into_iter().enumerate())
+        .map(|(i, item)| {
+            let args = &args;
+
+            async move {
+                if let Some(interval) = interval {
+                    tokio::time::sleep_until(start + interval * i as u32).await;
+                }
+
+                let id = item.id.clone();
+                let item_start = std::time::Instant::now();
+
+                (id, item_start, send(item, args, config).await)
+            }
+        })
+        .buffer_unordered(concurrency);
+
+    let mut failures = 0;
+
+    while let Some((id, item_start, result)) = results.next().await {
+        let output = match result {
+            Ok(answer) => {
+                if let Some(warning) = crate::report::warning(answer.finish_reason.as_deref()) {
+                    eprintln!("Warning: {}: {}", id, warning);
+                }
+
+                let entry = crate::ledger::Entry::new(&answer.args, &answer.stats);
+
+                if let Err(e) = crate::ledger::record(&state_dir, &entry) {
+                    log::warn!("unable to record the usage: {:?}", e);
+                }
+
+                Output {
+                    id,
+                    api: answer.args.api,
+                    model: answer.args.model,
+                    content: Some(answer.content),
+                    error: None,
+                    finish_reason: answer.finish_reason,
+                    usage: Some(answer.stats.usage),
+                    estimated: answer.stats.estimated,
+                    cost: answer.stats.cost,
+                    elapsed_ms: answer.stats.elapsed.as_millis(),
+                }
+            }
+            Err(e) => {
+                failures += 1;
+
+                Output {
+                    id,
+                    api: None,
+                    model: None,
+                    content: None,
+                    error: Some(e.to_string()),
+                    finish_reason: None,
+                    usage: None,
+                    estimated: false,
+                    cost: None,
+                    elapsed_ms: item_start.elapsed().as_millis(),
+                }
+            }
+        };
+
+        writeln!(out, "{}", serde_json::to_string(&output)?)?;
+        out.flush()?;
+    }
+
+    if args.shows_notices() {
+        eprintln!("Sent {} prompts, {} failed", total, failures);
+    }
+
+    if total > 0 && failures == total {
+        return Err(Error::BatchFailed(total));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_item() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let item = parse_item(1, r#""Hi""#)?;
+
+        assert_eq!(item.id, Value::from(1));
+        assert_eq!(item.spec.prompt, Some("Hi".to_string()));
+
+        let item = parse_item(
+            2,
+            r#"{"id": "a", "template": "translate", "vars": {"text": "Hi"}}"#,
+        )?;
+
+        assert_eq!(item.id, Value::from("a"));
+        assert_eq!(item.spec.template, Some("translate".to_string()));
+        assert_eq!(item.spec.prompt, None);
+
+        assert!(parse_item(3, "[1, 2]").is_err());
+        assert!(parse_item(4, r#"{"promt": "Hi"}"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_item_provider_over_the_batch_preset(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            [[presets]]
+            name = "claude"
+            api = "anthropic"
+            model = "claude-3-5-haiku-latest"
+            base_url = "https://proxy.example.com"
+            "#,
+        )?;
+        let base = Args {
+            preset: Some("claude".to_string()),
+            ..Default::default()
+        };
+
+        let args = item_args(parse_item(1, r#""Hi""#)?, &base, &config)?;
+
+        assert_eq!(args.api, Some(Api::Anthropic));
+        assert_eq!(args.model, Some("claude-3-5-haiku-latest".to_string()));
+
+        let item = parse_item(2, r#"{"api": "openai", "model": "gpt-4o", "prompt": "Hi"}"#)?;
+        let args = item_args(item, &base, &config)?;
+
+        assert_eq!(args.api, Some(Api::OpenAi));
+        assert_eq!(args.model, Some("gpt-4o".to_string()));
+        assert_eq!(args.api_base_url, None);
+
+        Ok(())
+    }
+}
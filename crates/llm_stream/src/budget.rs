@@ -0,0 +1,139 @@
+use chrono::Datelike;
+
+use crate::ledger::Entry;
+use crate::prelude::*;
+
+/// Percentages of the monthly budget that print a warning when `warn_at` isn't set.
+const DEFAULT_WARN_AT: &[f64] = &[80.0];
+
+/// How much of the monthly budget is spent.
+#[derive(Debug, PartialEq)]
+enum Status {
+    Available,
+    /// The highest warning percentage reached.
+    Warning(f64),
+    Exceeded,
+}
+
+/// Returns the cost of the ledger entries of the same calendar month as `now`.
+fn spent(entries: &[Entry], now: chrono::DateTime<chrono::Local>) -> f64 {
+    entries
+        .iter()
+        .filter(|entry| entry.date.year() == now.year() && entry.date.month() == now.month())
+        .filter_map(|entry| entry.cost)
+        .sum()
+}
+
+fn status(spent: f64, limit: f64, warn_at: &[f64]) -> Status {
+    if spent >= limit {
+        return Status::Exceeded;
+    }
+
+    let percent = spent / limit * 100.0;
+
+    warn_at
+        .iter()
+        .copied()
+        .filter(|threshold| percent >= *threshold)
+        .reduce(f64::max)
+        .map_or(Status::Available, Status::Warning)
+}
+
+/// Checks the spending of the current month against the `budget` of the configuration. Once the
+/// budget is spent, requests are refused unless `--force` is given, and a warning is printed once
+/// one of the `warn_at` percentages is reached. Dry runs and token counts are always allowed.
+pub fn check(args: &Args, config: &Config) -> Result<()> {
+    let Some(budget) = &config.budget else {
+        return Ok(());
+    };
+
+    let Some(limit) = budget.monthly_usd else {
+        return Ok(());
+    };
+
+    if args.dry_run || args.count_tokens {
+        return Ok(());
+    }
+
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    let spent = spent(&crate::ledger::read(&state_dir)?, chrono::Local::now());
+    let warn_at = budget.warn_at.as_deref().unwrap_or(DEFAULT_WARN_AT);
+
+    match status(spent, limit, warn_at) {
+        Status::Exceeded if !args.force => Err(Error::BudgetExceeded(format!(
+            "${:.2} of ${:.2} spent this month, use --force to send it anyway",
+            spent, limit
+        ))),
+        Status::Exceeded => {
+            if args.shows_notices() {
+                eprintln!(
+                    "Warning: ${:.2} of the ${:.2} monthly budget is spent, sending anyway.",
+                    spent, limit
+                );
+            }
+            Ok(())
+        }
+        Status::Warning(threshold) => {
+            if args.shows_notices() {
+                eprintln!(
+                    "Warning: ${:.2} of the ${:.2} monthly budget is spent ({}% or more).",
+                    spent, limit, threshold
+                );
+            }
+            Ok(())
+        }
+        Status::Available => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(date: chrono::DateTime<chrono::Local>, cost: Option<f64>) -> Entry {
+        Entry {
+            date,
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            tag: None,
+            input_tokens: 10,
+            output_tokens: 20,
+            cost,
+            estimated: false,
+            elapsed_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_spent_counts_the_current_month() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let date = |year, month, day| {
+            chrono::Local
+                .with_ymd_and_hms(year, month, day, 12, 0, 0)
+                .single()
+                .ok_or("invalid date")
+        };
+
+        let entries = vec![
+            entry(date(2024, 4, 30)?, Some(5.0)),
+            entry(date(2024, 5, 1)?, Some(1.5)),
+            entry(date(2024, 5, 20)?, None),
+            entry(date(2024, 5, 31)?, Some(2.0)),
+            entry(date(2023, 5, 10)?, Some(7.0)),
+        ];
+
+        assert_eq!(spent(&entries, date(2024, 5, 31)?), 3.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(status(5.0, 20.0, &[50.0, 80.0]), Status::Available);
+        assert_eq!(status(12.0, 20.0, &[50.0, 80.0]), Status::Warning(50.0));
+        assert_eq!(status(17.0, 20.0, &[80.0, 50.0]), Status::Warning(80.0));
+        assert_eq!(status(20.0, 20.0, &[50.0, 80.0]), Status::Exceeded);
+        assert_eq!(status(19.0, 20.0, &[]), Status::Available);
+    }
+}
@@ -0,0 +1,799 @@
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+
+use crate::args::{ConversationFormat, ConversationsCommand};
+use crate::prelude::*;
+use crate::report::Usage;
+
+/// Returns the directory where the conversations are cached.
+pub fn cache_dir(data_dir: &str) -> String {
+    format!("{}/cache", data_dir)
+}
+
+/// Returns the path of the cache file for the conversation `id`.
+pub fn cache_file(data_dir: &str, id: &str) -> String {
+    format!("{}/{}.toml", cache_dir(data_dir), id)
+}
+
+/// Checks that `id` names a file of the cache directory, and not a path out of it.
+pub fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.contains(['/', '\\']) {
+        return Err(Error::InvalidCacheId(id.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Version of the format of the cache files. Files without a version are from before messages had
+/// timestamps, and are read as they are.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Parses the cache file at `path`, refusing the ones written in a newer format.
+pub fn parse(path: &str) -> Result<Args> {
+    let args = toml::from_str::<Args>(&std::fs::read_to_string(path)?)?;
+
+    if args
+        .schema_version
+        .is_some_and(|version| version > SCHEMA_VERSION)
+    {
+        return Err(Error::UnsupportedCache(path.to_string()));
+    }
+
+    Ok(args)
+}
+
+/// Reads the cached arguments of the conversation `id`.
+pub fn read(data_dir: &str, id: &str) -> Result<Args> {
+    check_id(id)?;
+    let cache_file = cache_file(data_dir, id);
+
+    if !std::path::Path::new(&cache_file).exists() {
+        return Err(Error::CacheNotFound);
+    }
+
+    parse(&cache_file)
+}
+
+/// Stores the arguments of the conversation `id` in its cache file, in the current format.
+pub fn write(data_dir: &str, id: &str, args: &Args) -> Result<()> {
+    check_id(id)?;
+
+    let args = Args {
+        schema_version: Some(SCHEMA_VERSION),
+        conversation: args
+            .full_conversation
+            .clone()
+            .unwrap_or_else(|| args.conversation.clone()),
+        ..args.clone()
+    };
+
+    std::fs::create_dir_all(cache_dir(data_dir))?;
+    std::fs::write(cache_file(data_dir, id), toml::to_string(&args)?)?;
+
+    Ok(())
+}
+
+/// Prompt sent to continue an answer cut by the token limit.
+pub const CONTINUE_PROMPT: &str = "Continue exactly where you stopped, without repeating anything.";
+
+/// Sets the prompt that asks to continue the last answer of a cached conversation.
+pub fn continue_generation(mut args: Args) -> Result<Args> {
+    if args.from.is_none() {
+        return Err(Error::CacheNotFound);
+    }
+
+    if !args
+        .conversation
+        .last()
+        .is_some_and(|m| m.role == ConversationRole::Assistant)
+    {
+        return Err(Error::NothingToContinue);
+    }
+
+    args.prompt = Some(CONTINUE_PROMPT.to_string());
+    args.stdin = None;
+    args.template = None;
+
+    Ok(args)
+}
+
+/// Adds `answer` to the conversation. Answers that continue the previous one are appended to it,
+/// dropping the prompt that asked to continue, so the cache keeps a single answer.
+pub fn add_answer(conversation: &mut Conversation, answer: &str, continues: bool) {
+    if continues
+        && conversation
+            .last()
+            .is_some_and(|m| m.role == ConversationRole::User && m.content == CONTINUE_PROMPT)
+    {
+        conversation.pop();
+    }
+
+    match conversation.last_mut() {
+        Some(last) if continues && last.role == ConversationRole::Assistant => {
+            last.content.push_str(answer)
+        }
+        _ => conversation.push(ConversationMessage {
+            role: ConversationRole::Assistant,
+            content: answer.to_string(),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Records when the prompt of the answer that ends the conversation was sent, and when, by which
+/// model, and with how many tokens the answer was written. The tokens of continued answers are
+/// added up.
+pub fn stamp_answer(args: &mut Args, sent: chrono::DateTime<chrono::Local>, usage: Usage) {
+    let (api, model) = (args.api, args.model.clone());
+
+    args.update_conversations(|conversation| {
+        stamp_conversation(conversation, api, model.clone(), sent, usage)
+    });
+}
+
+fn stamp_conversation(
+    conversation: &mut Conversation,
+    api: Option<Api>,
+    model: Option<String>,
+    sent: chrono::DateTime<chrono::Local>,
+    usage: Usage,
+) {
+    let Some((answer, previous)) = conversation.split_last_mut() else {
+        return;
+    };
+
+    if answer.role != ConversationRole::Assistant {
+        return;
+    }
+
+    if let Some(prompt) = previous
+        .iter_mut()
+        .rev()
+        .find(|m| m.role == ConversationRole::User)
+    {
+        prompt.created_at.get_or_insert(sent);
+    }
+
+    let usage = answer.usage.unwrap_or_default() + usage;
+
+    answer.created_at = Some(chrono::Local::now());
+    answer.api = api;
+    answer.model = model;
+    answer.usage = Some(usage).filter(|usage| *usage != Usage::default());
+}
+
+/// Drops the last assistant answer of a cached conversation and sets its last user message as the
+/// prompt, so it can be sent again.
+pub fn regenerate(mut args: Args) -> Result<Args> {
+    if args.from.is_none() {
+        return Err(Error::CacheNotFound);
+    }
+
+    if args
+        .conversation
+        .last()
+        .is_some_and(|m| m.role == ConversationRole::Assistant)
+    {
+        args.conversation.pop();
+    }
+
+    match args.conversation.pop() {
+        Some(message) if message.role == ConversationRole::User => {
+            args.prompt = Some(message.content);
+            args.stdin = None;
+            args.template = None;
+
+            Ok(args)
+        }
+        _ => Err(Error::NothingToRegenerate),
+    }
+}
+
+/// Removes the trailing user and assistant pair of messages from the conversation, returning them.
+pub fn pop_exchange(conversation: &mut Conversation) -> Conversation {
+    let mut removed = Vec::new();
+
+    if conversation
+        .last()
+        .is_some_and(|m| m.role == ConversationRole::Assistant)
+    {
+        removed.extend(conversation.pop());
+    }
+
+    if conversation
+        .last()
+        .is_some_and(|m| m.role == ConversationRole::User)
+    {
+        removed.extend(conversation.pop());
+    }
+
+    removed.reverse();
+    removed
+}
+
+/// Returns the given conversation id, or the id of the last conversation.
+pub fn resolve(data_dir: &str, id: Option<String>) -> Result<String> {
+    match id {
+        Some(id) => check_id(&id).map(|_| id),
+        None => get_latest_toml_file(&cache_dir(data_dir))?.ok_or(Error::CacheNotFound),
+    }
+}
+
+/// Removes the last exchange of the conversation `id` from its cache file.
+fn pop(data_dir: &str, id: &str) -> Result<()> {
+    let mut cache = read(data_dir, id)?;
+    let removed = pop_exchange(&mut cache.conversation);
+
+    if removed.is_empty() {
+        eprintln!("Nothing to remove from conversation: {}", id);
+        return Ok(());
+    }
+
+    write(data_dir, id, &cache)?;
+
+    for message in removed {
+        eprintln!(
+            "Removed {} message: {}",
+            message.role,
+            message
+                .content
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .take(80)
+                .collect::<String>()
+        );
+    }
+
+    Ok(())
+}
+
+/// Renders the conversation messages as Markdown, using their roles as headers. Empty messages
+/// are kept, so the conversation parsed back has the same messages.
+pub fn conversation_to_markdown(conversation: &Conversation) -> String {
+    conversation
+        .iter()
+        .map(|m| format!("## {}\n\n{}\n", m.role, m.content.trim()))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Parses the Markdown created by `conversation_to_markdown` back into a conversation.
+pub fn conversation_from_markdown(markdown: &str) -> Result<Conversation> {
+    let mut conversation = Conversation::new();
+    let mut current: Option<ConversationMessage> = None;
+    let mut in_code_block = false;
+
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+
+        let role = match line.trim_end() {
+            _ if in_code_block => None,
+            "## User" => Some(ConversationRole::User),
+            "## Assistant" => Some(ConversationRole::Assistant),
+            "## System" => Some(ConversationRole::System),
+            _ => None,
+        };
+
+        if let Some(role) = role {
+            conversation.extend(current.take());
+            current = Some(ConversationMessage {
+                role,
+                content: String::new(),
+                ..Default::default()
+            });
+        } else if let Some(message) = current.as_mut() {
+            message.content.push_str(line);
+            message.content.push('\n');
+        } else if !line.trim().is_empty() {
+            return Err(Error::InvalidConversation(format!(
+                "text outside of a message: {}",
+                line
+            )));
+        }
+    }
+
+    conversation.extend(current);
+
+    for message in conversation.iter_mut() {
+        message.content = message.content.trim().to_string();
+    }
+
+    Ok(conversation)
+}
+
+/// Gives the messages of an `edited` conversation the time, api, model, and usage of the `original`
+/// messages they match, in order, since the Markdown only keeps their role and content. Messages
+/// whose role or content changed are left without them.
+fn keep_metadata(edited: Conversation, original: &[ConversationMessage]) -> Conversation {
+    let mut next = 0;
+
+    edited
+        .into_iter()
+        .map(|message| {
+            let found = original[next..].iter().position(|original| {
+                original.role == message.role && original.content.trim() == message.content
+            });
+
+            match found {
+                Some(i) => {
+                    let original = &original[next + i];
+                    next += i + 1;
+
+                    ConversationMessage {
+                        content: message.content,
+                        ..original.clone()
+                    }
+                }
+                None => message,
+            }
+        })
+        .collect()
+}
+
+/// Wrapper used to store a conversation as a TOML document.
+#[derive(Serialize, Deserialize)]
+struct ConversationDocument {
+    conversation: Conversation,
+}
+
+/// Reads the conversation of `--conversation-file`, in JSON, YAML, or TOML format depending on the
+/// extension of `path`. Parse errors point to the line and column of the file.
+pub fn read_conversation_file(path: &str) -> Result<Conversation> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?;
+
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    let invalid =
+        |e: &dyn std::fmt::Display| Error::InvalidConversation(format!("{}: {}", path, e));
+
+    match extension {
+        "toml" => toml::from_str::<ConversationDocument>(&contents)
+            .map(|document| document.conversation)
+            .map_err(|e| invalid(&e)),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(|e| invalid(&e)),
+        _ => serde_json::from_str(&contents).map_err(|e| invalid(&e)),
+    }
+}
+
+/// Opens the conversation in `$EDITOR` with the given format and replaces it with the edited
+/// version.
+pub fn edit(mut args: Args, format: ConversationFormat) -> Result<Args> {
+    let conversation = std::mem::take(&mut args.conversation);
+
+    args.conversation = match format {
+        ConversationFormat::Markdown => {
+            let edited = crate::editor::edit(&conversation_to_markdown(&conversation), "md")?;
+            keep_metadata(conversation_from_markdown(&edited)?, &conversation)
+        }
+        ConversationFormat::Json => serde_json::from_str(&crate::editor::edit(
+            &serde_json::to_string_pretty(&conversation)?,
+            "json",
+        )?)?,
+        ConversationFormat::Toml => {
+            toml::from_str::<ConversationDocument>(&crate::editor::edit(
+                &toml::to_string(&ConversationDocument { conversation })?,
+                "toml",
+            )?)?
+            .conversation
+        }
+    };
+
+    Ok(args)
+}
+
+/// Renders a conversation as Markdown, using the message roles as headers.
+pub fn to_markdown(args: &Args) -> String {
+    let mut markdown = String::new();
+
+    if let Some(title) = &args.title {
+        markdown.push_str(&format!("# {}\n\n", title));
+    }
+
+    if let Some(description) = &args.description {
+        markdown.push_str(&format!("{}\n\n", description));
+    }
+
+    markdown.push_str(&conversation_to_markdown(&args.conversation));
+
+    markdown
+}
+
+/// Prints `text` to stdout, highlighting it with `language` when writing to a terminal.
+pub fn print(text: &str, language: &str, args: &Args) -> Result<()> {
+    if args.no_color || !std::io::stdout().is_terminal() {
+        println!("{}", text);
+        return Ok(());
+    }
+
+    let theme = Some(crate::theme::resolve(args));
+
+    let output = crate::printer::CustomPrinter::new(language, theme.as_deref())?
+        .input_from_bytes(text.as_bytes())
+        .print()?;
+
+    println!("{}", output);
+
+    Ok(())
+}
+
+/// Runs the `conversations` commands.
+pub fn run(command: ConversationsCommand, mut args: Args) -> Result<()> {
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+
+    match command {
+        ConversationsCommand::List { json } => {
+            args.json |= json;
+            list(args)
+        }
+        ConversationsCommand::Show { id, format } => {
+            let id = resolve(&data_dir, id)?;
+            let cache = read(&data_dir, &id)?;
+
+            match format {
+                ConversationFormat::Markdown => print(&to_markdown(&cache), "markdown", &args),
+                ConversationFormat::Json => {
+                    print(&serde_json::to_string_pretty(&cache)?, "json", &args)
+                }
+                ConversationFormat::Toml => print(
+                    &std::fs::read_to_string(cache_file(&data_dir, &id))?,
+                    "toml",
+                    &args,
+                ),
+            }
+        }
+        ConversationsCommand::Delete { id } => {
+            check_id(&id)?;
+            let cache_file = cache_file(&data_dir, &id);
+
+            if !std::path::Path::new(&cache_file).exists() {
+                return Err(Error::CacheNotFound);
+            }
+
+            std::fs::remove_file(&cache_file)?;
+            eprintln!("Deleted conversation: {}", id);
+
+            Ok(())
+        }
+        ConversationsCommand::Prune { older_than } => {
+            let cache_dir = cache_dir(&data_dir);
+
+            if !std::path::Path::new(&cache_dir).exists() {
+                return Ok(());
+            }
+
+            let now = std::time::SystemTime::now();
+
+            for entry in std::fs::read_dir(&cache_dir)? {
+                let path = entry?.path();
+
+                if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                    continue;
+                }
+
+                let modified = std::fs::metadata(&path)?.modified()?;
+                let age = now.duration_since(modified).unwrap_or_default();
+
+                if age > older_than {
+                    std::fs::remove_file(&path)?;
+                    eprintln!(
+                        "Deleted conversation: {}",
+                        path.file_stem().unwrap_or_default().to_string_lossy()
+                    );
+                }
+            }
+
+            Ok(())
+        }
+        ConversationsCommand::Export { id, format, out } => {
+            crate::export::run(&resolve(&data_dir, id)?, format, out, &args)
+        }
+        ConversationsCommand::Pop { id } => pop(&data_dir, &resolve(&data_dir, id)?),
+        ConversationsCommand::Import {
+            file,
+            format,
+            title,
+        } => {
+            let contents = if file == "-" {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(&file)?
+            };
+
+            for mut cache in crate::import::parse(&contents, format)? {
+                if title.is_some() {
+                    cache.title = title.clone();
+                }
+
+                let id = xid::new().to_string();
+                write(&data_dir, &id, &cache)?;
+                println!("{}", id);
+            }
+
+            Ok(())
+        }
+        ConversationsCommand::Rename { id, title } => {
+            let mut cache = read(&data_dir, &id)?;
+            cache.title = Some(title);
+            write(&data_dir, &id, &cache)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_answer_appends_continuations() {
+        let mut conversation = vec![
+            ConversationMessage::new(ConversationRole::User, "Count to six"),
+            ConversationMessage::new(ConversationRole::Assistant, "1, 2, 3,"),
+            ConversationMessage::new(ConversationRole::User, CONTINUE_PROMPT),
+        ];
+
+        add_answer(&mut conversation, " 4, 5, 6", true);
+
+        assert_eq!(
+            conversation,
+            vec![
+                ConversationMessage::new(ConversationRole::User, "Count to six"),
+                ConversationMessage::new(ConversationRole::Assistant, "1, 2, 3, 4, 5, 6"),
+            ]
+        );
+
+        add_answer(&mut conversation, "Done.", false);
+
+        assert_eq!(conversation.len(), 3);
+    }
+
+    #[test]
+    fn test_stamp_answer_adds_up_continuations() {
+        let sent = chrono::Local::now();
+        let usage = |input, output| Usage {
+            input_tokens: Some(input),
+            output_tokens: Some(output),
+        };
+
+        let mut args = Args {
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            conversation: vec![
+                ConversationMessage::new(ConversationRole::User, "Count to six"),
+                ConversationMessage::new(ConversationRole::Assistant, "1, 2, 3,"),
+            ],
+            ..Default::default()
+        };
+        stamp_answer(&mut args, sent, usage(10, 5));
+
+        args.conversation.push(ConversationMessage::new(
+            ConversationRole::User,
+            CONTINUE_PROMPT,
+        ));
+        add_answer(&mut args.conversation, " 4, 5, 6", true);
+        stamp_answer(&mut args, chrono::Local::now(), usage(20, 5));
+
+        let conversation = args.conversation;
+        assert_eq!(conversation[0].created_at, Some(sent));
+        assert!(conversation[1].created_at.is_some_and(|date| date >= sent));
+        assert_eq!(conversation[1].api, Some(Api::OpenAi));
+        assert_eq!(conversation[1].model.as_deref(), Some("gpt-4o"));
+        assert_eq!(conversation[1].usage, Some(usage(30, 10)));
+    }
+
+    #[test]
+    fn test_check_id() {
+        assert!(check_id("cs0t1k2l3m4n5o6p7q8r").is_ok());
+
+        for id in ["", "../config", "a/b", "..\\config"] {
+            assert!(check_id(id).is_err(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_parse_reads_unversioned_files() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-cache-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+
+        let old = dir.join("old.toml").to_string_lossy().to_string();
+        std::fs::write(
+            &old,
+            "model = \"gpt-4o\"\n\n[[conversation]]\nrole = \"user\"\ncontent = \"Hi\"\n",
+        )?;
+        let newer = dir.join("newer.toml").to_string_lossy().to_string();
+        std::fs::write(&newer, format!("schema_version = {}\n", SCHEMA_VERSION + 1))?;
+
+        let parsed = parse(&old);
+        let unsupported = parse(&newer);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        let parsed = parsed?;
+        assert_eq!(parsed.schema_version, None);
+        assert_eq!(
+            parsed.conversation,
+            vec![ConversationMessage::new(ConversationRole::User, "Hi")]
+        );
+        assert!(unsupported.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_conversation_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-conversation-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+
+        let files = [
+            (
+                "conversation.json",
+                r#"[{ "role": "user", "content": "Hi" }, { "role": "assistant", "content": "Hello" }]"#,
+            ),
+            (
+                "conversation.yaml",
+                "- role: user\n  content: Hi\n- role: assistant\n  content: Hello\n",
+            ),
+            (
+                "conversation.toml",
+                "[[conversation]]\nrole = \"user\"\ncontent = \"Hi\"\n\n[[conversation]]\nrole = \"assistant\"\ncontent = \"Hello\"\n",
+            ),
+        ];
+
+        for (name, contents) in files {
+            let path = dir.join(name);
+            std::fs::write(&path, contents)?;
+
+            assert_eq!(
+                read_conversation_file(&path.to_string_lossy())?,
+                vec![
+                    ConversationMessage::new(ConversationRole::User, "Hi"),
+                    ConversationMessage::new(ConversationRole::Assistant, "Hello"),
+                ]
+            );
+        }
+
+        let path = dir.join("invalid.yaml");
+        std::fs::write(
+            &path,
+            "- role: user\n  content: Hi\n- role: robot\n  content: Hello\n",
+        )?;
+
+        let error = read_conversation_file(&path.to_string_lossy())
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert!(error.contains("line 3 column"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pop_exchange_removes_last_user_and_assistant_messages() {
+        let mut conversation = vec![
+            ConversationMessage::new(ConversationRole::System, "system"),
+            ConversationMessage::new(ConversationRole::User, "first"),
+            ConversationMessage::new(ConversationRole::Assistant, "first answer"),
+            ConversationMessage::new(ConversationRole::User, "second"),
+            ConversationMessage::new(ConversationRole::Assistant, "second answer"),
+        ];
+
+        let removed = pop_exchange(&mut conversation);
+
+        assert_eq!(
+            removed,
+            vec![
+                ConversationMessage::new(ConversationRole::User, "second"),
+                ConversationMessage::new(ConversationRole::Assistant, "second answer"),
+            ]
+        );
+        assert_eq!(conversation.len(), 3, "The first exchange should be kept");
+    }
+
+    #[test]
+    fn test_pop_exchange_keeps_system_message() {
+        let mut conversation = vec![ConversationMessage::new(ConversationRole::System, "system")];
+
+        let removed = pop_exchange(&mut conversation);
+
+        assert!(removed.is_empty(), "Nothing should be removed");
+        assert_eq!(conversation.len(), 1, "The system message should be kept");
+    }
+
+    #[test]
+    fn test_conversation_markdown_round_trip() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let conversation = vec![
+            ConversationMessage::new(ConversationRole::System, "system"),
+            ConversationMessage::new(ConversationRole::User, "Write a heading"),
+            ConversationMessage::new(
+                ConversationRole::Assistant,
+                "Sure:\n\n```markdown\n## User\n```\n\nDone.",
+            ),
+            ConversationMessage::new(ConversationRole::User, "Thanks"),
+            ConversationMessage::new(ConversationRole::Assistant, ""),
+        ];
+
+        let actual = conversation_from_markdown(&conversation_to_markdown(&conversation))?;
+
+        assert_eq!(
+            actual, conversation,
+            "Headers inside code blocks shouldn't start new messages, and empty ones are kept"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_markdown_edit_keeps_the_metadata_of_the_untouched_messages(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let answer = |content: &str, output_tokens: u64| ConversationMessage {
+            created_at: Some(chrono::Local::now()),
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            usage: Some(Usage {
+                input_tokens: Some(10),
+                output_tokens: Some(output_tokens),
+            }),
+            ..message(ConversationRole::Assistant, content)
+        };
+        let question = |content: &str| ConversationMessage {
+            created_at: Some(chrono::Local::now()),
+            ..message(ConversationRole::User, content)
+        };
+        let conversation = vec![
+            question("First"),
+            answer("First answer", 3),
+            question("Second"),
+            answer("Second answer", 4),
+        ];
+
+        let markdown = conversation_to_markdown(&conversation);
+        let unchanged = keep_metadata(conversation_from_markdown(&markdown)?, &conversation);
+        let edited = keep_metadata(
+            conversation_from_markdown(&markdown.replace("Second answer", "Edited answer"))?,
+            &conversation,
+        );
+
+        assert_eq!(unchanged, conversation);
+        assert_eq!(edited[..3], conversation[..3]);
+        assert_eq!(
+            edited[3],
+            ConversationMessage::new(ConversationRole::Assistant, "Edited answer")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regenerate_uses_last_user_message_as_prompt(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut args = Args::default();
+        args.from = Some("id".to_string());
+        args.conversation = vec![
+            ConversationMessage::new(ConversationRole::System, "system"),
+            ConversationMessage::new(ConversationRole::User, "question"),
+            ConversationMessage::new(ConversationRole::Assistant, "answer"),
+        ];
+
+        let actual = regenerate(args)?;
+
+        assert_eq!(actual.prompt, Some("question".to_string()));
+        assert_eq!(
+            actual.conversation,
+            vec![ConversationMessage::new(ConversationRole::System, "system")],
+            "The last exchange should be removed from the conversation"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::config::Template;
+use crate::prelude::*;
+
+/// Name of the template used by `commit`. Define a template with the same name to override it.
+pub const TEMPLATE: &str = "commit";
+
+const SYSTEM: &str = "You write git commit messages following the Conventional Commits \
+specification. The subject line has the form `<type>(<optional scope>): <description>`, uses the \
+imperative mood, and is at most 72 characters long. Add a body, separated by a blank line and \
+wrapped at 72 characters, only when the change needs an explanation. Return only the commit \
+message, without code fences.";
+
+const PROMPT: &str = "Write the commit message for this diff:
+
+```diff
+{{ diff }}
+```
+{% if prompt %}
+{{ prompt }}
+{% endif %}";
+
+/// Message file of a `commit --commit` run.
+pub struct Message {
+    path: String,
+    /// Whether the file was created by `commit`, and has to be removed.
+    temporary: bool,
+}
+
+/// Returns the template used when there isn't a `commit` template in the configuration.
+pub fn template() -> Template {
+    Template {
+        name: TEMPLATE.to_string(),
+        description: Some(
+            "Writes a Conventional Commits message for the staged changes".to_string(),
+        ),
+        system: Some(SYSTEM.to_string()),
+        template: Some(PROMPT.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Runs a git command, returning its output.
+pub fn git(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git").args(args).output()?;
+
+    if !output.status.success() {
+        return Err(Error::Git(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Selects the `commit` template, unless another one was given, and passes it the staged diff as
+/// the `diff` variable, unless `--vars` sets it. When the message has to be committed, it's also written to a file.
+pub fn prepare(args: &mut Args, commit: bool) -> Result<Option<Message>> {
+    let diff = git(&["diff", "--staged"])?;
+
+    if diff.trim().is_empty() {
+        return Err(Error::Git("there are no staged changes".to_string()));
+    }
+
+    args.template.get_or_insert(TEMPLATE.to_string());
+
+    let mut vars = serde_json::json!({ "diff": diff });
+    merge(&mut vars, args.vars.take().unwrap_or_default());
+    args.vars = Some(vars);
+
+    if !commit {
+        return Ok(None);
+    }
+
+    let temporary = args.output.is_none();
+    let path = args
+        .output
+        .get_or_insert_with(|| {
+            std::env::temp_dir()
+                .join(format!("llm-stream-commit-{}.txt", xid::new()))
+                .to_string_lossy()
+                .to_string()
+        })
+        .clone();
+
+    Ok(Some(Message { path, temporary }))
+}
+
+/// Runs `git commit` with the generated message, opening the editor to review it first.
+pub fn commit(message: Message) -> Result<()> {
+    let contents = std::fs::read_to_string(&message.path)?;
+
+    if message.temporary {
+        std::fs::remove_file(&message.path)?;
+    }
+
+    let status = std::process::Command::new("git")
+        .args(["commit", "-e", "-m", contents.trim()])
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::Git("git commit didn't finish".to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_needs_the_diff() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let variables = crate::templates::variables(&template())?;
+
+        assert_eq!(
+            variables.required.into_iter().collect::<Vec<String>>(),
+            vec!["diff".to_string()]
+        );
+
+        Ok(())
+    }
+}
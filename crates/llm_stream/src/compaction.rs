@@ -0,0 +1,194 @@
+use crate::config::{Compaction, Preset};
+use crate::prelude::*;
+
+const DEFAULT_KEEP_MESSAGES: usize = 4;
+
+const SUMMARY_SYSTEM: &str = "You summarize conversations between a user and an AI assistant. \
+Write a concise summary that preserves every fact, decision, name, and piece of code needed to \
+continue the conversation. Return only the summary.";
+
+/// Builds the arguments used to summarize `transcript` with the compaction preset, or with the
+/// current api and model if no preset is configured.
+fn summary_args(
+    args: &Args,
+    compaction: &Compaction,
+    presets: &[Preset],
+    transcript: String,
+) -> Args {
+    let mut summary_args = Args {
+        api: args.api,
+        model: args.model.clone(),
+        api_env: args.api_env.clone(),
+        api_key: args.api_key.clone(),
+        key_cmd: args.key_cmd.clone(),
+        key_source: args.key_source,
+        api_version: args.api_version.clone(),
+        api_base_url: args.api_base_url.clone(),
+        headers: args.headers.clone(),
+        hooks: args.hooks.clone(),
+        audit: args.audit.clone(),
+        conversation: vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: SUMMARY_SYSTEM.to_string(),
+                ..Default::default()
+            },
+            ConversationMessage {
+                role: ConversationRole::User,
+                content: transcript,
+                ..Default::default()
+            },
+        ],
+        quiet: true,
+        no_cache: true,
+        ..Default::default()
+    };
+
+    if let Some(preset) = compaction
+        .preset
+        .as_ref()
+        .and_then(|name| presets.iter().find(|p| &p.name == name))
+    {
+        summary_args.api = Some(preset.api);
+        summary_args.model = preset.model.clone();
+        summary_args.api_env = preset.env.clone();
+        summary_args.api_key = preset.key.clone();
+        summary_args.key_cmd = preset.key_cmd.clone();
+        summary_args.api_version = preset.version.clone();
+        summary_args.api_base_url = preset.base_url.clone();
+        summary_args.headers = Vec::new();
+        summary_args.max_tokens = preset.max_tokens;
+    }
+
+    summary_args
+}
+
+/// Summarizes the older messages of the conversation into the system message when the
+/// conversation exceeds the configured token budget.
+pub async fn compact(
+    mut args: Args,
+    compaction: Option<Compaction>,
+    presets: Vec<Preset>,
+) -> Result<Args> {
+    let Some(compaction) = compaction else {
+        return Ok(args);
+    };
+
+    let tokens = crate::tokens::Tokenizer::new(args.api, args.model.as_deref())
+        .count_conversation(&args.conversation);
+
+    if tokens <= compaction.max_tokens {
+        return Ok(args);
+    }
+
+    let start = usize::from(
+        args.conversation
+            .first()
+            .is_some_and(|m| m.role == ConversationRole::System),
+    );
+    let keep = compaction
+        .keep_messages
+        .unwrap_or(DEFAULT_KEEP_MESSAGES)
+        .max(1);
+    let end = args.conversation.len().saturating_sub(keep);
+
+    if end <= start {
+        return Ok(args);
+    }
+
+    let transcript = args.conversation[start..end]
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    if args.shows_notices() {
+        eprintln!(
+            "Compacting {} messages (~{} tokens over the {} tokens budget)",
+            end - start,
+            tokens - compaction.max_tokens,
+            compaction.max_tokens
+        );
+    }
+
+    let summary = complete(
+        summary_args(&args, &compaction, &presets, transcript),
+        |_| {},
+    )
+    .await?;
+    replace_with_summary(&mut args, start..end, &summary);
+
+    Ok(args)
+}
+
+/// Replaces the `messages` of the conversation sent with the request by their `summary`. The
+/// whole conversation is kept for the cache.
+fn replace_with_summary(args: &mut Args, messages: std::ops::Range<usize>, summary: &str) {
+    let start = messages.start;
+    let context = format!("Summary of the earlier conversation:\n\n{}", summary);
+
+    args.keep_full_conversation();
+    args.conversation.drain(messages);
+
+    if start == 1 {
+        let system = &mut args.conversation[0];
+        system.content = if system.content.trim().is_empty() {
+            context
+        } else {
+            format!("{}\n\n{}", system.content, context)
+        };
+    } else {
+        args.conversation.insert(
+            0,
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: context,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compaction_keeps_the_cached_conversation(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let conversation = vec![
+            ConversationMessage::new(ConversationRole::System, "Be brief."),
+            ConversationMessage::new(ConversationRole::User, "First question"),
+            ConversationMessage::new(ConversationRole::Assistant, "First answer"),
+            ConversationMessage::new(ConversationRole::User, "Second question"),
+        ];
+        let mut args = Args {
+            conversation: conversation.clone(),
+            ..Default::default()
+        };
+
+        replace_with_summary(&mut args, 1..3, "The user asked a first question.");
+
+        assert_eq!(
+            args.conversation,
+            vec![
+                ConversationMessage::new(
+                    ConversationRole::System,
+                    "Be brief.\n\nSummary of the earlier conversation:\n\nThe user asked a first question."
+                ),
+                ConversationMessage::new(ConversationRole::User, "Second question"),
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("llm-stream-compaction-{}", xid::new()));
+        let data_dir = dir.to_string_lossy().to_string();
+        let cached = crate::cache::write(&data_dir, "compacted", &args)
+            .and_then(|_| crate::cache::read(&data_dir, "compacted"));
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(cached?.conversation, conversation);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+use cli_table::{format::Justify, Table, WithTitle};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::prelude::*;
+use crate::report::Stats;
+
+/// Api and model a `--compare` prompt is sent to.
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    label: String,
+    api: Api,
+    model: Option<String>,
+}
+
+/// Parses the comma separated targets of `--compare`. Each one is `<api>:<model>`, an api, which
+/// uses its default model, or the name of a preset, which uses its api and model.
+fn targets(targets: &str, config: &Config) -> Result<Vec<Target>> {
+    targets
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(|target| {
+            let label = target.to_string();
+
+            if let Some(preset) = config.presets.iter().flatten().find(|p| p.name == target) {
+                return Ok(Target {
+                    label,
+                    api: preset.api,
+                    model: preset.model.clone(),
+                });
+            }
+
+            let (api, model) = match target.split_once(':') {
+                Some((api, model)) => (api, Some(model.to_string())),
+                None => (target, None),
+            };
+
+            Ok(Target {
+                label,
+                api: api.parse::<Api>().map_err(|_| Error::InvalidAPI)?,
+                model,
+            })
+        })
+        .collect()
+}
+
+/// Builds the arguments of a target. The api options are only kept for the api of the prompt,
+/// other apis use their `[apis.<api>]` section.
+fn target_args(args: &Args, target: &Target, config: &Config) -> Args {
+    let mut target_args = Args {
+        api: Some(target.api),
+        model: target.model.clone(),
+        no_cache: true,
+        ..args.clone()
+    };
+
+    if args.api == Some(target.api) {
+        target_args.model = target.model.clone().or(args.model.clone());
+    } else {
+        target_args.api_env = None;
+        target_args.api_key = None;
+        target_args.key_cmd = None;
+        target_args.api_base_url = None;
+        target_args.api_version = None;
+        target_args.headers = Vec::new();
+
+        if let Some(section) = config
+            .apis
+            .as_ref()
+            .and_then(|apis| apis.get(&target.api.to_string()))
+        {
+            merge_api_config(&mut target_args, section.clone());
+        }
+    }
+
+    target_args
+}
+
+/// Prints the answers of the targets, each in its own section. The first answer to arrive is
+/// streamed as it's written, while the others are held until it ends; then the finished ones are
+/// printed, and one still running takes its place.
+struct Sections {
+    args: Args,
+    labels: Vec<String>,
+    /// Text received from each target so far.
+    answers: Vec<String>,
+    ended: Vec<bool>,
+    printed: Vec<bool>,
+    /// Target whose answer is streamed, with the printer that renders it on a terminal.
+    live: Option<(usize, Option<crate::printer::StreamPrinter>)>,
+}
+
+impl Sections {
+    fn new(args: &Args, targets: &[Target]) -> Self {
+        Self {
+            args: args.clone(),
+            labels: targets.iter().map(|target| target.label.clone()).collect(),
+            answers: vec![String::new(); targets.len()],
+            ended: vec![false; targets.len()],
+            printed: vec![false; targets.len()],
+            live: None,
+        }
+    }
+
+    /// Adds `text` to the answer of target `i`, printing it if it's the one streamed.
+    fn received(&mut self, i: usize, text: &str) -> Result<()> {
+        self.answers[i].push_str(text);
+
+        match &mut self.live {
+            None => self.stream(i),
+            Some((live, printer)) if *live == i => write(printer.as_mut(), text),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Starts streaming the answer of target `i` with the text received so far.
+    fn stream(&mut self, i: usize) -> Result<()> {
+        crate::cache::print(&format!("## {}\n", self.labels[i]), "markdown", &self.args)?;
+
+        let mut printer = if std::io::stdout().is_terminal() {
+            let renderer = if self.args.no_color {
+                Renderer::Plain
+            } else {
+                self.args.renderer.unwrap_or_default()
+            };
+
+            Some(crate::printer::StreamPrinter::new(crate::render::new(
+                renderer,
+                "markdown",
+                &crate::theme::resolve(&self.args),
+                crate::wrap::width(self.args.wrap.as_deref()),
+            )?))
+        } else {
+            None
+        };
+
+        write(printer.as_mut(), &self.answers[i])?;
+        self.printed[i] = true;
+        self.live = Some((i, printer));
+
+        Ok(())
+    }
+
+    /// Marks target `i` as ended. Once the streamed answer ends, the finished answers are printed
+    /// and the next one received is streamed.
+    fn ended(&mut self, i: usize, result: &Result<String>) -> Result<()> {
+        self.ended[i] = true;
+
+        match (&mut self.live, result) {
+            (Some((live, printer)), _) if *live == i => {
+                match printer.as_mut() {
+                    Some(printer) => printer.finish()?,
+                    None => println!(),
+                }
+
+                if let Err(e) = result {
+                    println!("\nError: {:?}", e);
+                }
+
+                println!();
+                self.live = None;
+            }
+            (_, Ok(content)) => self.answers[i] = content.clone(),
+            (_, Err(e)) => self.answers[i] = format!("Error: {:?}", e),
+        }
+
+        if self.live.is_some() {
+            return Ok(());
+        }
+
+        for j in 0..self.labels.len() {
+            if self.ended[j] && !self.printed[j] {
+                crate::cache::print(
+                    &format!("## {}\n\n{}\n", self.labels[j], self.answers[j]),
+                    "markdown",
+                    &self.args,
+                )?;
+                self.printed[j] = true;
+            }
+        }
+
+        match (0..self.labels.len()).find(|&j| !self.ended[j] && !self.answers[j].is_empty()) {
+            Some(j) => self.stream(j),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the text of the streamed answer, rendered by `printer` on a terminal.
+fn write(printer: Option<&mut crate::printer::StreamPrinter>, text: &str) -> Result<()> {
+    match printer {
+        Some(printer) => printer.print(text),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            write!(stdout, "{}", text)?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// Row of the comparison table.
+#[derive(Debug, Table)]
+struct Row {
+    #[table(title = "Target", justify = "Justify::Left")]
+    target: String,
+    #[table(title = "Elapsed", justify = "Justify::Right")]
+    elapsed: String,
+    #[table(title = "First token", justify = "Justify::Right")]
+    first_token: String,
+    #[table(title = "Input", justify = "Justify::Right")]
+    input_tokens: String,
+    #[table(title = "Output", justify = "Justify::Right")]
+    output_tokens: String,
+    #[table(title = "Cost", justify = "Justify::Right")]
+    cost: String,
+}
+
+impl Row {
+    fn new(target: &Target, stats: Option<&Stats>) -> Self {
+        let Some(stats) = stats else {
+            return Self {
+                target: target.label.clone(),
+                elapsed: "failed".to_string(),
+                first_token: "-".to_string(),
+                input_tokens: "-".to_string(),
+                output_tokens: "-".to_string(),
+                cost: "-".to_string(),
+            };
+        };
+
+        let approximate = if stats.estimated { "~" } else { "" };
+
+        Self {
+            target: target.label.clone(),
+            elapsed: format!("{:.2}s", stats.elapsed.as_secs_f64()),
+            first_token: stats
+                .first_token
+                .map(|first_token| format!("{:.2}s", first_token.as_secs_f64()))
+                .unwrap_or("-".to_string()),
+            input_tokens: format!(
+                "{}{}",
+                approximate,
+                stats.usage.input_tokens.unwrap_or_default()
+            ),
+            output_tokens: format!(
+                "{}{}",
+                approximate,
+                stats.usage.output_tokens.unwrap_or_default()
+            ),
+            cost: stats
+                .cost
+                .map(|cost| format!("{}${:.4}", approximate, cost))
+                .unwrap_or("-".to_string()),
+        }
+    }
+}
+
+/// Sends the conversation to every target of `--compare` at the same time, printing each answer
+/// in its own section as it's streamed, and a table comparing their timing, usage, and cost at the
+/// end.
+pub async fn run(args: Args, compare: &str, config: &Config) -> Result<()> {
+    let targets = targets(compare, config)?;
+
+    if targets.is_empty() {
+        return Err(Error::Compare("no targets given".to_string()));
+    }
+
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+
+    if args.shows_notices() {
+        eprintln!("Waiting for {} answers...\n", targets.len());
+    }
+
+    let sections = Arc::new(Mutex::new(Sections::new(&args, &targets)));
+
+    let mut pending = targets
+        .iter()
+        .enumerate()
+        .map(|(i, target)| {
+            let target_args = target_args(&args, target, config);
+            let sections = sections.clone();
+
+            async move {
+                let metadata = SharedMetadata::default();
+                let start = std::time::Instant::now();
+                let mut first_token = None;
+
+                let result =
+                    complete_with_metadata(target_args.clone(), metadata.clone(), |text| {
+                        first_token.get_or_insert(start.elapsed());
+
+                        let mut sections = sections.lock().unwrap_or_else(|e| e.into_inner());
+                        if let Err(e) = sections.received(i, text) {
+                            log::warn!("unable to print the answer: {:?}", e);
+                        }
+                    })
+                    .await;
+
+                let metadata = metadata.lock().map(|m| m.clone()).unwrap_or_default();
+                let stats = result.as_ref().ok().map(|content| {
+                    Stats::new(
+                        &target_args,
+                        content,
+                        &metadata,
+                        start.elapsed(),
+                        first_token,
+                    )
+                });
+
+                (i, target_args, result, stats, metadata.finish_reason)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut stats = targets.iter().map(|_| None).collect::<Vec<Option<Stats>>>();
+    let mut failures = 0;
+
+    while let Some((i, target_args, result, target_stats, finish_reason)) = pending.next().await {
+        if result.is_err() {
+            failures += 1;
+        }
+
+        sections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .ended(i, &result)?;
+
+        if let Some(warning) = crate::report::warning(finish_reason.as_deref()) {
+            eprintln!("Warning: {}: {}\n", targets[i].label, warning);
+        }
+
+        if let Some(target_stats) = &target_stats {
+            let entry = crate::ledger::Entry::new(&target_args, target_stats);
+
+            if let Err(e) = crate::ledger::record(&state_dir, &entry) {
+                log::warn!("unable to record the usage: {:?}", e);
+            }
+        }
+
+        stats[i] = target_stats;
+    }
+
+    let rows = targets
+        .iter()
+        .zip(&stats)
+        .map(|(target, stats)| Row::new(target, stats.as_ref()))
+        .collect::<Vec<Row>>();
+
+    println!();
+    print_table(rows.with_title(), &args)?;
+
+    if failures == targets.len() {
+        return Err(Error::CompareFailed(failures));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Preset;
+
+    #[test]
+    fn test_targets() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::default();
+        config.presets = Some(vec![Preset {
+            name: "haiku".to_string(),
+            api: Api::Anthropic,
+            model: Some("claude-3-5-haiku-latest".to_string()),
+            ..Default::default()
+        }]);
+
+        assert_eq!(
+            targets("openai:gpt-4o, haiku,mistral", &config)?,
+            vec![
+                Target {
+                    label: "openai:gpt-4o".to_string(),
+                    api: Api::OpenAi,
+                    model: Some("gpt-4o".to_string()),
+                },
+                Target {
+                    label: "haiku".to_string(),
+                    api: Api::Anthropic,
+                    model: Some("claude-3-5-haiku-latest".to_string()),
+                },
+                Target {
+                    label: "mistral".to_string(),
+                    api: Api::Mistral,
+                    model: None,
+                },
+            ]
+        );
+        assert!(targets("nope:model", &config).is_err());
+
+        Ok(())
+    }
+}
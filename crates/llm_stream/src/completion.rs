@@ -0,0 +1,155 @@
+use clap_complete::engine::CompletionCandidate;
+use config_file::FromConfigFile;
+use std::ffi::OsStr;
+
+use crate::prelude::*;
+
+/// Characters of a conversation message shown next to its id.
+const MESSAGE_WIDTH: usize = 60;
+
+/// Directories and configuration read by the completions. They run before the options are
+/// parsed, so they use the default directories, or the ones of `LLM_STREAM_PROFILE`.
+struct Context {
+    config_dir: String,
+    data_dir: String,
+    state_dir: String,
+    config: Config,
+}
+
+impl Context {
+    fn load() -> Option<Self> {
+        let profile = std::env::var("LLM_STREAM_PROFILE").ok();
+        let (config_dir, data_dir, state_dir) =
+            crate::paths::resolve_dirs(None, None, None, profile.as_deref()).ok()?;
+        let config = Config::from_config_file(crate::paths::join(&config_dir, "config.toml"))
+            .unwrap_or_default();
+
+        Some(Self {
+            config_dir,
+            data_dir,
+            state_dir,
+            config,
+        })
+    }
+}
+
+/// Returns the candidates that start with the value typed so far, with their help.
+fn matching(
+    current: &OsStr,
+    candidates: impl IntoIterator<Item = (String, Option<String>)>,
+) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+
+    candidates
+        .into_iter()
+        .filter(|(value, _)| value.starts_with(current.as_ref()))
+        .map(|(value, help)| CompletionCandidate::new(value).help(help.map(Into::into)))
+        .collect()
+}
+
+/// Completes `--model` with the known models, the ones last listed by each api, and the ones of
+/// the presets.
+pub fn models(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(context) = Context::load() else {
+        return Vec::new();
+    };
+
+    let mut ids = crate::models::known_ids(&context.state_dir);
+    ids.extend(
+        context
+            .config
+            .presets
+            .iter()
+            .flatten()
+            .filter_map(|preset| preset.model.clone()),
+    );
+    ids.sort();
+    ids.dedup();
+
+    matching(current, ids.into_iter().map(|id| (id, None)))
+}
+
+/// Completes `--preset` with the presets of the configuration file.
+pub fn presets(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(context) = Context::load() else {
+        return Vec::new();
+    };
+
+    let presets = context.config.presets.unwrap_or_default();
+
+    matching(
+        current,
+        presets
+            .into_iter()
+            .map(|preset| (preset.name, preset.model)),
+    )
+}
+
+/// Completes `--template` with the templates of the configuration file and the templates dir,
+/// and the built-in ones.
+pub fn templates(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(context) = Context::load() else {
+        return Vec::new();
+    };
+
+    let templates =
+        load_templates(&context.config_dir, context.config.templates).unwrap_or_default();
+
+    matching(
+        current,
+        templates
+            .into_iter()
+            .map(|template| (template.name, template.description)),
+    )
+}
+
+/// Completes `--from` with the ids of the cached conversations, newest first, next to their title
+/// or first message.
+pub fn conversations(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(context) = Context::load() else {
+        return Vec::new();
+    };
+
+    let cache_dir = crate::cache::cache_dir(&context.data_dir);
+    let paths = get_sorted_cache_files(&cache_dir).unwrap_or_default();
+
+    let conversations = paths.iter().rev().filter_map(|path| {
+        let summary = ConversationSummary::from_path(path).ok()?;
+        let help = summary.title.or(summary.message).map(|text| {
+            let line = text.lines().next().unwrap_or_default();
+            line.chars().take(MESSAGE_WIDTH).collect::<String>()
+        });
+
+        Some((summary.id, help))
+    });
+
+    matching(current, conversations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let candidates = matching(
+            OsStr::new("gpt"),
+            [
+                ("gpt-4o".to_string(), None),
+                ("claude-3-5-haiku-latest".to_string(), None),
+                ("gpt-4o-mini".to_string(), Some("Small".to_string())),
+            ],
+        );
+
+        let values = candidates
+            .iter()
+            .map(|candidate| candidate.get_value().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(values, vec!["gpt-4o", "gpt-4o-mini"]);
+        assert_eq!(
+            candidates[1].get_help().map(|help| help.to_string()),
+            Some("Small".to_string())
+        );
+    }
+}
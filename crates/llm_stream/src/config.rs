@@ -1,16 +1,24 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use toml_edit::{DocumentMut, Item, TableLike};
 
-use crate::conversation::ConversationMessage;
+use crate::args::ConfigCommand;
+use crate::conversation::ConversationRole;
+use crate::prelude::*;
+
+/// Prefix of the environment variables that override the configuration keys.
+const ENV_PREFIX: &str = "LLM_STREAM_";
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Preset {
     pub name: String,
 
     // Api
+    #[serde(default)]
     pub api: crate::args::Api,
     pub env: Option<String>,
     pub key: Option<String>,
+    pub key_cmd: Option<String>,
     pub base_url: Option<String>,
 
     // Model
@@ -24,6 +32,180 @@ pub struct Preset {
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
     pub top_k: Option<u32>,
+
+    // Router
+    /// Presets picked from for each prompt, which makes this preset a router.
+    pub routes: Option<Vec<String>>,
+    /// Most a routed request can cost, in dollars.
+    pub budget: Option<f64>,
+    /// What the router optimizes for among the presets that fit.
+    pub prefer: Option<RoutePreference>,
+}
+
+/// Value logged instead of a credential.
+const REDACTED: &str = "<redacted>";
+
+impl Preset {
+    /// Returns a copy of the preset to log, with its key hidden.
+    pub fn redacted(&self) -> Preset {
+        Preset {
+            key: self.key.as_ref().map(|_| REDACTED.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+/// What a router preset optimizes for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutePreference {
+    /// The cheapest preset.
+    #[default]
+    Cost,
+    /// The preset with the lowest average latency in the usage ledger.
+    Latency,
+}
+
+/// Defaults used for a single provider, set under `[apis.<api>]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiConfig {
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    pub env: Option<String>,
+    pub key: Option<String>,
+    pub key_cmd: Option<String>,
+    pub version: Option<String>,
+    /// Extra headers sent with every request to the provider.
+    pub headers: Option<std::collections::BTreeMap<String, String>>,
+    /// JSON object deep merged into the body of every request to the provider.
+    pub extra_body: Option<Value>,
+    /// Query parameters added to the url of every request to the provider, like `api-version`.
+    pub extra_query: Option<std::collections::BTreeMap<String, String>>,
+}
+
+impl ApiConfig {
+    /// Returns a copy of the section to log, with the key and the values of the headers hidden.
+    pub fn redacted(&self) -> ApiConfig {
+        ApiConfig {
+            key: self.key.as_ref().map(|_| REDACTED.to_string()),
+            headers: self.headers.as_ref().map(|headers| {
+                headers
+                    .keys()
+                    .map(|name| (name.clone(), REDACTED.to_string()))
+                    .collect()
+            }),
+            ..self.clone()
+        }
+    }
+}
+
+/// Tera functions that templates are allowed to call, set under `[template_functions]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TemplateFunctions {
+    /// Allows `read_file(path="...")`.
+    #[serde(default)]
+    pub read_file: bool,
+    /// Environment variables that `env(name="...")` can read.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Commands, or globs like `git log *`, that `shell(command="...")` can run.
+    #[serde(default)]
+    pub shell: Vec<String>,
+}
+
+/// Command the model can call with `--tool`, defined under `[[tools]]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments.
+    #[serde(default = "default_tool_parameters")]
+    pub parameters: Value,
+    /// Command run with the shell. It reads the arguments as JSON from its standard input.
+    pub command: String,
+    /// Asks before running the command.
+    #[serde(default = "default_true")]
+    pub confirm: bool,
+}
+
+fn default_tool_parameters() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// Task runner used with `agent <name>`, defined under `[agents.<name>]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Agent {
+    pub description: Option<String>,
+    /// Added to the system message before the ones of `--system`.
+    pub system: Option<String>,
+    /// Tools of `[[tools]]` the agent can call.
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// Most requests sent while the model keeps calling tools. Defaults to 10.
+    pub max_iterations: Option<usize>,
+    pub api: Option<crate::args::Api>,
+    pub model: Option<String>,
+    pub preset: Option<String>,
+}
+
+/// Settings used to summarize the older messages of long conversations.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Compaction {
+    /// Estimated amount of tokens above which the conversation gets compacted.
+    pub max_tokens: usize,
+    /// Amount of trailing messages that are always sent verbatim.
+    pub keep_messages: Option<usize>,
+    /// Preset used to summarize the older messages. Usually a cheap model.
+    pub preset: Option<String>,
+}
+
+/// Pattern whose matches are redacted before a prompt is sent.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RedactionPattern {
+    /// Name shown in place of the redacted text, like `[REDACTED:name]`.
+    pub name: String,
+    /// Regular expression, with the syntax of the `regex` crate.
+    pub regex: String,
+}
+
+/// Settings of the redaction of secrets and personal data from the prompts.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Redaction {
+    /// Use the built-in patterns for API keys, AWS keys, private keys, and emails.
+    #[serde(default = "default_true")]
+    pub builtin: bool,
+    #[serde(default)]
+    pub patterns: Vec<RedactionPattern>,
+}
+
+/// Spending limit enforced with the usage ledger.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Budget {
+    /// Most that can be spent in a calendar month, in dollars.
+    pub monthly_usd: Option<f64>,
+    /// Percentages of the monthly budget that print a warning once they're reached. Defaults to
+    /// `[80]`.
+    pub warn_at: Option<Vec<f64>>,
+}
+
+/// Settings of the audit log of the requests and responses.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Audit {
+    /// Size in megabytes the log reaches before it's rotated. Defaults to 10.
+    pub max_size_mb: Option<u64>,
+    /// Rotated logs kept besides the current one. Defaults to 5.
+    pub max_files: Option<usize>,
+    /// File with the key that signs the entries. Defaults to `audit.key` in the state directory.
+    pub key_file: Option<String>,
+}
+
+/// Commands run before every request is sent and after every response is received.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Hooks {
+    /// Receives the body of the request on stdin, and can print a new one to send instead.
+    pub pre_request: Option<String>,
+    /// Receives the response as JSON on stdin, and can print it with a new `message`.
+    pub post_response: Option<String>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -36,18 +218,75 @@ pub enum Role {
     System,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+/// Message of a template conversation. Its content is a Tera template, rendered with the same
+/// variables as the prompt.
+///
+/// System messages are joined into one, and only used when the conversation doesn't have a system
+/// message already.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct TemplateMessage {
+    pub role: ConversationRole,
+    pub content: String,
+}
+
+/// Step of a template pipeline, sent before the prompt of the template. Its output is available
+/// to the next steps, and to the template, as `steps.<name>` and as `previous`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TemplateStep {
+    pub name: String,
+    /// Prompt of the step, rendered like the prompt of the template.
+    pub template: String,
+    pub system: Option<String>,
+    // Used instead of the ones of the template or the command line.
+    pub api: Option<crate::args::Api>,
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Template {
     pub name: String,
     pub description: Option<String>,
     pub template: Option<String>,
     pub default_vars: Option<Value>,
     pub system: Option<String>,
-    pub conversation: Option<Vec<ConversationMessage>>,
+    /// Messages added before the prompt, like examples of questions and answers.
+    pub conversation: Option<Vec<TemplateMessage>>,
+    /// Requests sent in order before the prompt, whose outputs are available as variables.
+    pub steps: Option<Vec<TemplateStep>>,
+
+    // Used when the template is selected, unless given on the command line or with a preset.
+    pub api: Option<crate::args::Api>,
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+/// Flags saved with `alias save` and used with `@<name>`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Alias {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api: Option<crate::args::Api>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vars: Option<Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Config {
+    /// Version of the format of the file, stamped by the `migrate` command.
+    pub schema_version: Option<u32>,
+
     // Api
     #[serde(default = "default_api")]
     pub api: Option<crate::args::Api>,
@@ -56,23 +295,64 @@ pub struct Config {
     #[serde(default = "default_env")]
     pub env: Option<String>,
     pub key: Option<String>,
+    pub key_source: Option<crate::args::KeySource>,
+    pub key_cmd: Option<String>,
+
+    // Providers
+    pub apis: Option<std::collections::BTreeMap<String, ApiConfig>>,
 
     // Presets
     pub presets: Option<Vec<Preset>>,
 
+    // Aliases
+    pub alias: Option<std::collections::BTreeMap<String, Alias>>,
+
     // Templates
     pub templates: Option<Vec<Template>>,
+    pub template_functions: Option<TemplateFunctions>,
+
+    // Tools
+    pub tools: Option<Vec<Tool>>,
+
+    // Agents
+    pub agents: Option<std::collections::BTreeMap<String, Agent>>,
+
+    // Compaction
+    pub compaction: Option<Compaction>,
+
+    // Budget
+    pub budget: Option<Budget>,
+
+    // Redaction
+    pub redaction: Option<Redaction>,
+
+    // Hooks
+    pub hooks: Option<Hooks>,
+
+    // Audit
+    pub audit: Option<Audit>,
 
     // Global
     #[serde(default = "default_false")]
     pub quiet: Option<bool>,
+    pub no_spinner: Option<bool>,
+    pub no_notices: Option<bool>,
+    pub first_token_timeout: Option<u64>,
+    pub stall_timeout: Option<u64>,
+    pub fallback: Option<Vec<String>>,
     #[serde(default = "default_language")]
     pub language: Option<String>,
     #[serde(default = "default_theme")]
     pub theme: Option<String>,
+    pub dark_theme: Option<String>,
+    pub light_theme: Option<String>,
+    pub wrap: Option<String>,
+    pub renderer: Option<crate::args::Renderer>,
+    pub history: Option<bool>,
 
     // Model
     pub model: Option<String>,
+    pub validate_model: Option<bool>,
 
     // Model Configuration
     pub system: Option<String>,
@@ -82,13 +362,183 @@ pub struct Config {
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
     pub top_k: Option<u32>,
+    pub extra_body: Option<Value>,
+    pub extra_query: Option<std::collections::BTreeMap<String, String>>,
 }
 
 impl Config {
     pub fn new() -> Self {
-        let config: Config = serde_json::from_str("{}").unwrap();
+        let mut config: Config = serde_json::from_str("{}").unwrap();
+        config.schema_version = Some(crate::schema::CONFIG_SCHEMA_VERSION);
         config
     }
+
+    /// Returns a copy of the configuration to log, with the keys of the top level, of the
+    /// presets, and of the apis, and the values of the headers hidden.
+    pub fn redacted(&self) -> Config {
+        Config {
+            key: self.key.as_ref().map(|_| REDACTED.to_string()),
+            apis: self.apis.as_ref().map(|apis| {
+                apis.iter()
+                    .map(|(name, api)| (name.clone(), api.redacted()))
+                    .collect()
+            }),
+            presets: self
+                .presets
+                .as_ref()
+                .map(|presets| presets.iter().map(Preset::redacted).collect()),
+            ..self.clone()
+        }
+    }
+
+    /// Overrides the configuration keys with the `LLM_STREAM_<KEY>` environment variables, like
+    /// `LLM_STREAM_MODEL`. Command line arguments still take precedence over them.
+    pub fn merge_env(self) -> Result<Self> {
+        self.merge_vars(&std::env::vars().collect())
+    }
+
+    fn merge_vars(self, vars: &std::collections::HashMap<String, String>) -> Result<Self> {
+        let mut config = self;
+        let keys = match serde_json::to_value(&config)? {
+            Value::Object(map) => map.keys().cloned().collect::<Vec<String>>(),
+            _ => Vec::new(),
+        };
+
+        for key in keys {
+            let Some(raw) = vars.get(&format!("{}{}", ENV_PREFIX, key.to_uppercase())) else {
+                continue;
+            };
+
+            // Values are read as JSON, so numbers and booleans keep their type, and as strings
+            // when that doesn't fit the key.
+            let mut value = serde_json::to_value(&config)?;
+            value[&key] = serde_json::from_str(raw).unwrap_or(Value::String(raw.clone()));
+
+            config = match serde_json::from_value(value.clone()) {
+                Ok(config) => config,
+                Err(_) => {
+                    value[&key] = Value::String(raw.clone());
+                    serde_json::from_value(value)?
+                }
+            };
+        }
+
+        Ok(config)
+    }
+}
+
+/// Returns the table that holds the last segment of a dotted key, creating the missing tables
+/// when `create` is set. Arrays of tables, like `presets`, are indexed by the `name` of their
+/// tables.
+fn parent<'a>(
+    mut table: &'a mut dyn TableLike,
+    key: &str,
+    segments: &[&str],
+    create: bool,
+) -> Result<&'a mut dyn TableLike> {
+    let mut segments = segments.iter();
+
+    while let Some(segment) = segments.next() {
+        if create && !table.contains_key(segment) {
+            table.insert(segment, toml_edit::table());
+        }
+
+        table = match table.get_mut(segment) {
+            Some(Item::Table(child)) => child as &mut dyn TableLike,
+            Some(Item::Value(toml_edit::Value::InlineTable(child))) => child as &mut dyn TableLike,
+            Some(Item::ArrayOfTables(array)) => {
+                let name = segments
+                    .next()
+                    .ok_or_else(|| Error::ConfigKey(key.to_string()))?;
+
+                array
+                    .iter_mut()
+                    .find(|child| child.get("name").and_then(|n| n.as_str()) == Some(*name))
+                    .ok_or_else(|| Error::ConfigKey(key.to_string()))?
+                    as &mut dyn TableLike
+            }
+            _ => return Err(Error::ConfigKey(key.to_string())),
+        };
+    }
+
+    Ok(table)
+}
+
+/// Returns the value of a dotted key of the configuration document.
+pub fn get(document: &mut DocumentMut, key: &str) -> Result<String> {
+    let segments = key.split('.').collect::<Vec<&str>>();
+    let (last, segments) = segments
+        .split_last()
+        .ok_or_else(|| Error::ConfigKey(key.to_string()))?;
+
+    let item = parent(document.as_table_mut(), key, segments, false)?
+        .get(last)
+        .ok_or_else(|| Error::ConfigKey(key.to_string()))?;
+
+    Ok(match item.as_value() {
+        Some(value) => match value.as_str() {
+            Some(text) => text.to_string(),
+            None => value.to_string().trim().to_string(),
+        },
+        None => item.to_string().trim().to_string(),
+    })
+}
+
+/// Sets a dotted key of the configuration document, keeping its formatting and comments.
+pub fn set(document: &mut DocumentMut, key: &str, value: &str) -> Result<()> {
+    let segments = key.split('.').collect::<Vec<&str>>();
+    let (last, segments) = segments
+        .split_last()
+        .ok_or_else(|| Error::ConfigKey(key.to_string()))?;
+
+    let value = value
+        .parse::<toml_edit::Value>()
+        .unwrap_or_else(|_| toml_edit::Value::from(value));
+
+    parent(document.as_table_mut(), key, segments, true)?.insert(last, Item::Value(value));
+
+    Ok(())
+}
+
+/// Runs the `config` commands.
+pub fn run(command: ConfigCommand, args: Args) -> Result<()> {
+    let config_file = args.config_file.clone().expect("can't find config file");
+
+    match command {
+        ConfigCommand::Edit => {
+            crate::editor::open(std::path::Path::new(&config_file))?;
+            toml::from_str::<Config>(&std::fs::read_to_string(&config_file)?)?;
+            Ok(())
+        }
+        ConfigCommand::Get { key } => {
+            let mut document = std::fs::read_to_string(&config_file)?.parse::<DocumentMut>()?;
+            println!("{}", get(&mut document, &key)?);
+            Ok(())
+        }
+        ConfigCommand::Set { key, value } => {
+            let mut document = std::fs::read_to_string(&config_file)
+                .unwrap_or_default()
+                .parse::<DocumentMut>()?;
+
+            set(&mut document, &key, &value)?;
+
+            let contents = document.to_string();
+            toml::from_str::<Config>(&contents)?;
+            std::fs::write(&config_file, contents)?;
+
+            Ok(())
+        }
+        ConfigCommand::Path => {
+            println!("{}", config_file);
+            Ok(())
+        }
+        ConfigCommand::Dir => {
+            println!("{}", args.config_dir.expect("can't find config directory"));
+            Ok(())
+        }
+        ConfigCommand::Themes => crate::printer::list_themes(),
+        ConfigCommand::Languages => crate::printer::list_languages(),
+    }
 }
 
 fn default_api() -> Option<crate::args::Api> {
@@ -107,6 +557,10 @@ fn default_false() -> Option<bool> {
     Some(false)
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_language() -> Option<String> {
     Some("markdown".to_string())
 }
@@ -114,3 +568,91 @@ fn default_language() -> Option<String> {
 fn default_theme() -> Option<String> {
     Some("ansi".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_keeps_comments_and_selects_presets_by_name(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut document = r#"# Default model
+model = "gpt-4o"
+
+[[presets]]
+name = "haiku"
+api = "anthropic"
+
+[[presets]]
+name = "sonnet"
+api = "anthropic"
+"#
+        .parse::<DocumentMut>()?;
+
+        set(&mut document, "model", "gpt-4o-mini")?;
+        set(&mut document, "presets.sonnet.temperature", "0.3")?;
+
+        assert!(document.to_string().starts_with("# Default model\n"));
+        assert_eq!(get(&mut document, "model")?, "gpt-4o-mini");
+        assert_eq!(get(&mut document, "presets.sonnet.temperature")?, "0.3");
+        assert!(
+            get(&mut document, "presets.haiku.temperature").is_err(),
+            "Other presets shouldn't change"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redacted_hides_the_keys() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"key = "sk-top"
+
+[apis.openai]
+key = "sk-api"
+headers = { "OpenAI-Organization" = "org-secret" }
+
+[[presets]]
+name = "haiku"
+api = "anthropic"
+key = "sk-preset"
+"#,
+        )?;
+
+        let logged = format!("{:?}", config.redacted());
+
+        assert!(!logged.contains("sk-"), "{}", logged);
+        assert!(!logged.contains("org-secret"), "{}", logged);
+        assert!(logged.contains("OpenAI-Organization"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_vars_overrides_keys() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let vars = [
+            ("LLM_STREAM_MODEL", "gpt-4o"),
+            ("LLM_STREAM_API", "anthropic"),
+            ("LLM_STREAM_TEMPERATURE", "0.5"),
+            ("LLM_STREAM_THEME", "1337"),
+            ("OTHER_MODEL", "ignored"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let config = Config::new().merge_vars(&vars)?;
+
+        assert_eq!(config.model, Some("gpt-4o".to_string()));
+        assert_eq!(config.api, Some(crate::args::Api::Anthropic));
+        assert_eq!(config.temperature, Some(0.5));
+        assert_eq!(
+            config.theme,
+            Some("1337".to_string()),
+            "Numbers should be kept as strings for string keys"
+        );
+        assert_eq!(config.language, Some("markdown".to_string()));
+
+        Ok(())
+    }
+}
@@ -12,6 +12,18 @@ pub enum ConversationRole {
     System,
 }
 
+impl std::fmt::Display for ConversationRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConversationRole::User => "User",
+            ConversationRole::Assistant => "Assistant",
+            ConversationRole::System => "System",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
 /// LLM-Stream Convversation message.
 ///
 /// THis struct should be converted to the appropriate API struct for each implementation.
@@ -19,6 +31,30 @@ pub enum ConversationRole {
 pub struct ConversationMessage {
     pub role: ConversationRole,
     pub content: String,
+    /// When the message was sent or answered. Messages cached by older versions don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Local>>,
+    /// Api that wrote an answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api: Option<crate::args::Api>,
+    /// Model that wrote an answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Tokens of the requests that wrote an answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<crate::report::Usage>,
+}
+
+#[cfg(test)]
+impl ConversationMessage {
+    /// Returns a message without any metadata.
+    pub fn new(role: ConversationRole, content: &str) -> Self {
+        Self {
+            role,
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
 }
 
 /// Simplified type that identifies a conversation as a vector of Conversation Messages.
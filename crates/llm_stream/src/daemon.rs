@@ -0,0 +1,308 @@
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+
+use crate::prelude::*;
+use crate::report::{Stats, StreamEvent};
+use crate::spec::Spec;
+
+/// Returns the path of the socket of the daemon, `--socket` or `daemon.sock` in the state dir.
+fn socket_path(args: &Args, state_dir: &str) -> String {
+    args.socket
+        .clone()
+        .unwrap_or_else(|| format!("{}/daemon.sock", state_dir))
+}
+
+/// Builds the arguments of a forwarded request. Only the directories and configuration file of
+/// the daemon are kept, the rest comes from the client.
+fn request_args(spec: Spec, base: &Args, config: &Config) -> Result<Args> {
+    let mut args = Args {
+        config_dir: base.config_dir.clone(),
+        data_dir: base.data_dir.clone(),
+        state_dir: base.state_dir.clone(),
+        config_file: base.config_file.clone(),
+        no_cache: true,
+        quiet: true,
+        ..Default::default()
+    };
+
+    spec.apply(&mut args);
+
+    let mut args = crate::redact::apply(args, config)?;
+
+    crate::alias::apply(&mut args, config)?;
+    merge_preset(&mut args, config);
+    crate::budget::check(&args, config)?;
+
+    let args = crate::router::route(args, config)?;
+    let args = merge_args_and_config(args, config.clone())?;
+
+    Ok(crate::tokens::truncate(args))
+}
+
+/// Answers a forwarded request on `runtime`, writing the response as `--ndjson` events.
+#[cfg(unix)]
+fn handle(
+    stream: std::os::unix::net::UnixStream,
+    base: &Args,
+    config: &Config,
+    runtime: &tokio::runtime::Handle,
+) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let mut writer = &stream;
+    let mut write = |event: StreamEvent| {
+        if let Err(e) = event.write(&mut writer) {
+            log::warn!("unable to write to the client: {:?}", e);
+        }
+    };
+
+    let args = match serde_json::from_str::<Spec>(&line)
+        .map_err(Error::from)
+        .and_then(|spec| request_args(spec, base, config))
+    {
+        Ok(args) => args,
+        Err(e) => {
+            write(StreamEvent::Error {
+                message: e.to_string(),
+            });
+            return Ok(());
+        }
+    };
+
+    let metadata = SharedMetadata::default();
+    let start = std::time::Instant::now();
+    let mut first_token = None;
+
+    let result = runtime.block_on(complete_with_metadata(
+        args.clone(),
+        metadata.clone(),
+        |text| {
+            first_token.get_or_insert(start.elapsed());
+            write(StreamEvent::Delta { text });
+        },
+    ));
+
+    let content = match result {
+        Ok(content) => content,
+        Err(e) => {
+            write(StreamEvent::Error {
+                message: e.to_string(),
+            });
+            return Ok(());
+        }
+    };
+
+    let metadata = metadata.lock().map(|m| m.clone()).unwrap_or_default();
+    let stats = Stats::new(&args, &content, &metadata, start.elapsed(), first_token);
+
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    if let Err(e) = crate::ledger::record(&state_dir, &crate::ledger::Entry::new(&args, &stats)) {
+        log::warn!("unable to record the usage: {:?}", e);
+    }
+
+    write(StreamEvent::Usage(stats.usage));
+    write(StreamEvent::Stop {
+        id: None,
+        finish_reason: metadata.finish_reason,
+    });
+
+    Ok(())
+}
+
+/// Listens on a Unix socket for the requests forwarded with `--daemon`, keeping the configuration,
+/// templates, the keys read with `key_cmd`, and the connections to the apis open between them.
+#[cfg(unix)]
+pub fn run(args: Args) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let (args, config) = build_config(args)?;
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    let path = socket_path(&args, &state_dir);
+
+    if std::path::Path::new(&path).exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(Error::Daemon(format!("already listening on {}", path)));
+        }
+
+        // The socket of a daemon that didn't exit cleanly.
+        std::fs::remove_file(&path)?;
+    }
+
+    // Requests are sent with the keys of the user, so nobody else can connect. The umask keeps
+    // the socket private from the moment it's created, before its permissions are set.
+    // SAFETY: `umask` can't fail, and it's restored right after the socket is created.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+
+    let listener = listener?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    eprintln!("Listening on {}", path);
+
+    let args = std::sync::Arc::new(args);
+    let config = std::sync::Arc::new(config);
+    // Requests run on the runtime of the daemon, which outlives them, so the connections they
+    // open are reused by the next ones.
+    let runtime = tokio::runtime::Handle::current();
+
+    // Accepting blocks, so the worker of the runtime is handed over to the other tasks first, or
+    // with a single worker, nothing would drive the requests of the connections.
+    tokio::task::block_in_place(|| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("unable to accept the connection: {}", e);
+                    continue;
+                }
+            };
+
+            let args = std::sync::Arc::clone(&args);
+            let config = std::sync::Arc::clone(&config);
+            let runtime = runtime.clone();
+
+            std::thread::spawn(move || {
+                if let Err(e) = handle(stream, &args, &config, &runtime) {
+                    log::error!("unable to handle the request: {:?}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn run(_args: Args) -> Result<()> {
+    Err(Error::Daemon(
+        "Unix sockets aren't supported on this platform".to_string(),
+    ))
+}
+
+/// Sends the prompt to the daemon and prints its response as it streams, raw, or as events with
+/// `--ndjson`. The configuration isn't read, so the prompt is sent as soon as the arguments are
+/// parsed.
+#[cfg(unix)]
+pub fn forward(mut args: Args, state_dir: &str) -> Result<()> {
+    let path = socket_path(&args, state_dir);
+    let mut stream = std::os::unix::net::UnixStream::connect(&path)
+        .map_err(|e| Error::Daemon(format!("unable to connect to {}: {}", path, e)))?;
+
+    let stdin = std::io::stdin();
+
+    if !stdin.is_terminal() && args.prompt_file.as_deref() != Some("-") {
+        let mut text = String::new();
+        stdin.lock().read_to_string(&mut text)?;
+        args.stdin = Some(text.trim().to_string());
+    }
+
+    if let Some(path) = args.prompt_file.take() {
+        args.prompt = Some(read_prompt_file(&path)?);
+    }
+
+    if let Some(path) = args.conversation_file.take() {
+        args.conversation = crate::cache::read_conversation_file(&path)?;
+    }
+
+    let mut args = crate::files::attach(args)?;
+
+    if args.prompt.is_none() {
+        args.prompt = args.stdin.take();
+    }
+
+    args.vars = Some(template_vars(&mut args)?);
+
+    writeln!(stream, "{}", serde_json::to_string(&Spec::from(&args))?)?;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut warning = None;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+
+        if args.ndjson {
+            writeln!(stdout, "{}", line)?;
+            continue;
+        }
+
+        let event = serde_json::from_str::<serde_json::Value>(&line)?;
+
+        match event["type"].as_str() {
+            Some("delta") => write!(stdout, "{}", event["text"].as_str().unwrap_or_default())?,
+            Some("stop") => warning = crate::report::warning(event["finish_reason"].as_str()),
+            Some("error") => {
+                return Err(Error::Daemon(
+                    event["message"].as_str().unwrap_or_default().to_string(),
+                ))
+            }
+            _ => {}
+        }
+
+        stdout.flush()?;
+    }
+
+    writeln!(stdout)?;
+
+    if let Some(warning) = warning {
+        eprintln!("Warning: {}.", warning);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn forward(_args: Args, _state_dir: &str) -> Result<()> {
+    Err(Error::Daemon(
+        "Unix sockets aren't supported on this platform".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_args_only_keep_the_directories_of_the_daemon(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let base = Args {
+            data_dir: Some("/tmp/data".to_string()),
+            model: Some("gpt-4o".to_string()),
+            ..Default::default()
+        };
+        let spec = Spec {
+            api: Some(Api::Anthropic),
+            prompt: Some("Hi".to_string()),
+            ..Default::default()
+        };
+
+        let args = request_args(spec, &base, &Config::default())?;
+
+        assert_eq!(args.api, Some(Api::Anthropic));
+        assert_eq!(args.model, None);
+        assert_eq!(args.data_dir, Some("/tmp/data".to_string()));
+        assert!(args.no_cache);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_args_keep_the_attached_files(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let client = Args {
+            prompt: Some("Explain it".to_string()),
+            attachments: Some("Files:\n\n- src/main.rs".to_string()),
+            ..Default::default()
+        };
+
+        let args = request_args(Spec::from(&client), &Args::default(), &Config::default())?;
+
+        assert_eq!(
+            args.conversation.last().map(|m| m.content.as_str()),
+            Some("Files:\n\n- src/main.rs\nExplain it")
+        );
+
+        Ok(())
+    }
+}
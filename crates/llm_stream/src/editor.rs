@@ -0,0 +1,37 @@
+use crate::prelude::*;
+
+const DEFAULT_EDITOR: &str = if cfg!(windows) { "notepad" } else { "vi" };
+
+/// Opens the file at `path` in `$EDITOR` and waits for the editor to exit.
+pub fn open(path: &std::path::Path) -> Result<()> {
+    let editor = std::env::var("EDITOR").unwrap_or(DEFAULT_EDITOR.to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or(DEFAULT_EDITOR);
+
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::Editor(editor));
+    }
+
+    Ok(())
+}
+
+/// Opens `text` in `$EDITOR` inside a temporary file with the given `extension` and returns its
+/// contents once the editor exits.
+pub fn edit(text: &str, extension: &str) -> Result<String> {
+    let path = std::env::temp_dir().join(format!("llm-stream-{}.{}", xid::new(), extension));
+
+    std::fs::write(&path, text)?;
+
+    let status = open(&path);
+    let contents = std::fs::read_to_string(&path);
+    std::fs::remove_file(&path)?;
+
+    status?;
+
+    Ok(contents?)
+}
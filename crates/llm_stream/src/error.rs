@@ -14,24 +14,129 @@ pub enum Error {
     TryFrom(#[from] std::num::TryFromIntError),
     #[error("api not specified")]
     ApiNotSpecified,
+    #[error("model not specified")]
+    ModelNotSpecified,
     #[error("cache not found")]
     CacheNotFound,
+    #[error("invalid conversation id: {0}")]
+    InvalidCacheId(String),
+    #[error("the cache file was written by a newer version of llm-stream: {0}")]
+    UnsupportedCache(String),
+    #[error("the config file was written by a newer version of llm-stream: {0}")]
+    UnsupportedConfig(String),
+    #[error("invalid conversation: {0}")]
+    InvalidConversation(String),
+    #[error("editor exited with an error: {0}")]
+    Editor(String),
+    #[error("the conversation doesn't end with a user message to regenerate")]
+    NothingToRegenerate,
+    #[error("the conversation doesn't end with an answer to continue")]
+    NothingToContinue,
+    #[error("the prompt history is empty")]
+    EmptyHistory,
     #[error("config file error")]
     ConfigFile(#[from] config_file::ConfigFileError),
     #[error("infallible error")]
     Infallible(#[from] std::convert::Infallible),
     #[error("template not found")]
     TemplateNotFound,
+    #[error("agent not found: {0}")]
+    AgentNotFound(String),
+    #[error("alias not found: {0}")]
+    AliasNotFound(String),
+    #[error("invalid alias: {0}")]
+    InvalidAlias(String),
+    #[error("can't find the home directory")]
+    HomeNotFound,
+    #[error("invalid profile name: {0}")]
+    InvalidProfile(String),
+    #[error("template already exists: {0}")]
+    TemplateExists(String),
+    #[error("invalid template: {0}")]
+    InvalidTemplate(String),
+    #[error("missing template variables: {0}")]
+    MissingVariables(String),
+    #[error("unable to install templates: {0}")]
+    TemplateInstall(String),
+    #[error("unable to update llm-stream: {0}")]
+    SelfUpdate(String),
+    #[error("the --pipe command failed: {0}")]
+    Pipe(String),
+    #[error("git error: {0}")]
+    Git(String),
+    #[error("unable to route the prompt: {0}")]
+    Route(String),
+    #[error("no text arrived within {0}s")]
+    FirstTokenTimeout(u64),
+    #[error("daemon error: {0}")]
+    Daemon(String),
+    #[error("invalid --batch: {0}")]
+    Batch(String),
+    #[error("every prompt of the batch failed, {0} in all")]
+    BatchFailed(usize),
+    #[error("invalid --compare: {0}")]
+    Compare(String),
+    #[error("every target of the comparison failed, {0} in all")]
+    CompareFailed(usize),
+    #[error("tool error: {0}")]
+    Tool(String),
+    #[error("unknown model: {0}")]
+    UnknownModel(String),
+    #[error("monthly budget exceeded: {0}")]
+    BudgetExceeded(String),
+    #[error("invalid redaction pattern: {0}")]
+    InvalidRedaction(String),
+    #[error("hook error: {0}")]
+    Hook(String),
+    #[error("the audit log was tampered with: {0}")]
+    AuditTampered(String),
+    #[error("unable to set up logging: {0}")]
+    Logging(String),
+    #[error("the answer isn't valid: {0}")]
+    InvalidJson(String),
+    #[error("invalid JSON schema: {0}")]
+    InvalidSchema(String),
+    #[error("unable to apply the diff: {0}")]
+    Patch(String),
+    #[cfg(feature = "otel")]
+    #[error("unable to set up OpenTelemetry: {0}")]
+    Telemetry(String),
+    #[error("unable to list the models of {0}")]
+    Models(String),
+    #[error("embeddings error: {0}")]
+    Embeddings(String),
+    #[error("index not found: {0}")]
+    IndexNotFound(String),
+    #[error("invalid index name: {0}")]
+    InvalidIndex(String),
+    #[error("sqlite error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("unable to read file: {0}")]
+    ReadFile(String),
+    #[error("invalid glob")]
+    Glob(#[from] globset::Error),
     #[error("tera error")]
     Tera(#[from] tera::Error),
     #[error("toml deserialization error")]
     TomlDe(#[from] toml::de::Error),
     #[error("toml serialization error")]
     TomlSer(#[from] toml::ser::Error),
+    #[error("toml edit error")]
+    TomlEdit(#[from] toml_edit::TomlError),
+    #[error("configuration key not found: {0}")]
+    ConfigKey(String),
+    #[error("key command failed: {0}")]
+    KeyCommand(String),
+    #[error("keyring error")]
+    Keyring(#[from] keyring::Error),
     #[error("json error")]
     Json(#[from] serde_json::Error),
+    #[error("yaml error")]
+    Yaml(#[from] serde_yaml::Error),
     #[error("file or stdin error")]
     Stdin(#[from] clap_stdin::StdinError),
+    #[error("syntax highlighting error")]
+    Syntect(#[from] syntect::Error),
 }
 
 pub(crate) fn format_error(
@@ -0,0 +1,308 @@
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+
+use crate::args::ExportFormat;
+use crate::prelude::*;
+
+const HTML_THEME: &str = "InspiredGitHub";
+
+/// Url schemes that can run scripts when a link or an image is opened.
+const UNSAFE_SCHEMES: &[&str] = &["javascript:", "vbscript:", "data:"];
+
+/// Returns `url`, or `#` if it could run a script.
+fn safe_url(url: pulldown_cmark::CowStr) -> pulldown_cmark::CowStr {
+    // Browsers ignore the whitespace and control characters of a url.
+    let scheme = url
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect::<String>()
+        .to_lowercase();
+
+    if UNSAFE_SCHEMES
+        .iter()
+        .any(|unsafe_scheme| scheme.starts_with(unsafe_scheme))
+    {
+        "#".into()
+    } else {
+        url
+    }
+}
+
+/// Renders Markdown as HTML, highlighting the contents of fenced code blocks. Raw HTML is escaped,
+/// and links that could run scripts are dropped, since the messages can't be trusted.
+pub fn markdown_to_html(markdown: &str) -> Result<String> {
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let theme_set = ThemeSet::load_defaults();
+    let theme = &theme_set.themes[HTML_THEME];
+
+    let mut events: Vec<Event> = Vec::new();
+    let mut code_block: Option<(String, String)> = None;
+
+    for event in Parser::new_ext(markdown, Options::all()) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(language) => language.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((language, String::new()));
+            }
+            Event::Text(text) if code_block.is_some() => {
+                if let Some((_, code)) = code_block.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((language, code)) = code_block.take() {
+                    let syntax = syntax_set
+                        .find_syntax_by_token(&language)
+                        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+                    let highlighted = syntect::html::highlighted_html_for_string(
+                        &code,
+                        &syntax_set,
+                        syntax,
+                        theme,
+                    )?;
+                    events.push(Event::Html(highlighted.into()));
+                }
+            }
+            Event::Html(text) | Event::InlineHtml(text) => events.push(Event::Text(text)),
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => events.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            })),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => events.push(Event::Start(Tag::Image {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            })),
+            event => events.push(event),
+        }
+    }
+
+    let mut output = String::new();
+    html::push_html(&mut output, events.into_iter());
+
+    Ok(output)
+}
+
+/// Escapes the HTML special characters of `text`.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Wraps an HTML fragment into a standalone HTML document.
+fn html_document(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ max-width: 50rem; margin: 2rem auto; padding: 0 1rem; font-family: sans-serif; line-height: 1.5; }}
+pre {{ padding: 1rem; overflow-x: auto; border-radius: 4px; }}
+details {{ margin: 1rem 0; padding: 0 1rem; border-left: 4px solid #ddd; }}
+details.user {{ border-left-color: #0969da; }}
+details.assistant {{ border-left-color: #1a7f37; }}
+summary {{ cursor: pointer; font-weight: bold; padding: 0.5rem 0; }}
+summary small {{ font-weight: normal; color: #666; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#
+    )
+}
+
+/// Renders a cached conversation as HTML, with each message in a section that can be collapsed.
+/// System messages start collapsed.
+fn conversation_to_html(cache: &Args) -> Result<String> {
+    let mut body = String::new();
+
+    if let Some(title) = &cache.title {
+        body.push_str(&format!("<h1>{}</h1>\n", escape_html(title)));
+    }
+
+    if let Some(description) = &cache.description {
+        body.push_str(&format!("<p>{}</p>\n", escape_html(description)));
+    }
+
+    for message in cache
+        .conversation
+        .iter()
+        .filter(|m| !m.content.trim().is_empty())
+    {
+        let open = if message.role == ConversationRole::System {
+            ""
+        } else {
+            " open"
+        };
+
+        let details = [
+            message.model.clone(),
+            message
+                .created_at
+                .map(|date| date.format("%Y-%m-%d %H:%M").to_string()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>();
+
+        let summary = if details.is_empty() {
+            message.role.to_string()
+        } else {
+            format!(
+                "{} <small>{}</small>",
+                message.role,
+                escape_html(&details.join(", "))
+            )
+        };
+
+        body.push_str(&format!(
+            "<details class=\"{}\"{}>\n<summary>{}</summary>\n{}</details>\n",
+            message.role.to_string().to_lowercase(),
+            open,
+            summary,
+            markdown_to_html(message.content.trim())?
+        ));
+    }
+
+    Ok(body)
+}
+
+/// Exports the cached conversation `id` as Markdown, HTML, or JSON, writing it to `out` or to
+/// stdout.
+pub fn run(id: &str, format: ExportFormat, out: Option<String>, args: &Args) -> Result<()> {
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let cache = crate::cache::read(&data_dir, id)?;
+
+    let output = match format {
+        ExportFormat::Md => crate::cache::to_markdown(&cache),
+        ExportFormat::Html => {
+            let title = cache.title.clone().unwrap_or(id.to_string());
+            html_document(&escape_html(&title), &conversation_to_html(&cache)?)
+        }
+        ExportFormat::Json => serde_json::to_string_pretty(&cache.conversation)?,
+    };
+
+    match out {
+        Some(out) => {
+            std::fs::write(&out, output)?;
+            eprintln!("Exported conversation: {}", out);
+        }
+        None => println!("{}", output),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_to_html_escapes_raw_html(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let html = markdown_to_html(
+            "<script>alert(1)</script>\n\nSee <img src=x onerror=alert(1)> and \
+             [this](javascript:alert(1)) or [that](https://example.com).",
+        )?;
+
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("javascript:"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+        assert!(html.contains("<a href=\"#\">this</a>"), "{}", html);
+        assert!(
+            html.contains("<a href=\"https://example.com\">that</a>"),
+            "{}",
+            html
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversation_to_html() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let cache = Args {
+            title: Some("Rust <lifetimes>".to_string()),
+            conversation: vec![
+                ConversationMessage {
+                    role: ConversationRole::System,
+                    content: "Be brief.".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::User,
+                    content: "What's `'static`?".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::Assistant,
+                    model: Some("gpt-4o".to_string()),
+                    content: "A lifetime:\n\n```rust\nlet s: &'static str = \"hi\";\n```"
+                        .to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let html = conversation_to_html(&cache)?;
+
+        assert!(html.starts_with("<h1>Rust &lt;lifetimes&gt;</h1>\n"));
+        assert!(html.contains("<details class=\"system\">\n<summary>System</summary>"));
+        assert!(html.contains("<details class=\"user\" open>\n<summary>User</summary>"));
+        assert!(html.contains(
+            "<details class=\"assistant\" open>\n<summary>Assistant <small>gpt-4o</small></summary>"
+        ));
+        assert!(html.contains("<pre style="));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversation_to_html_keeps_messages_in_their_section(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let cache = Args {
+            description: Some("<b onclick='alert(1)'>bold</b>".to_string()),
+            conversation: vec![ConversationMessage {
+                role: ConversationRole::Assistant,
+                model: Some("<script>alert(1)</script>".to_string()),
+                content: "</details><script>alert(1)</script><details>".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let html = conversation_to_html(&cache)?;
+
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(!html.contains("<b "), "{}", html);
+        assert_eq!(html.matches("<details").count(), 1, "{}", html);
+        assert_eq!(html.matches("</details>").count(), 1, "{}", html);
+
+        Ok(())
+    }
+}
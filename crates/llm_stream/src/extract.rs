@@ -0,0 +1,77 @@
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, TagEnd};
+
+/// Returns the contents of the fenced code blocks of `markdown`. When `language` is set, only the
+/// blocks annotated with that language are returned.
+pub fn code_blocks(markdown: &str, language: Option<&str>) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let block_language = info.split_whitespace().next().unwrap_or_default();
+
+                if language.map_or(true, |l| l.eq_ignore_ascii_case(block_language)) {
+                    current = Some(String::new());
+                }
+            }
+            Event::Text(text) => {
+                if let Some(code) = current.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+
+    blocks
+}
+
+/// Returns the contents of the code block when the whole `text` is a single fenced code block,
+/// and `text` otherwise.
+pub fn unwrap_fence(text: &str) -> String {
+    let trimmed = text.trim();
+
+    if trimmed.starts_with("```") && trimmed.ends_with("```") {
+        if let [block] = code_blocks(trimmed, None).as_slice() {
+            return block.clone();
+        }
+    }
+
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str =
+        "Run this:\n\n```bash\nls -la\n```\n\nThen:\n\n```python\nprint(1)\n```\n";
+
+    #[test]
+    fn test_code_blocks_strips_prose() {
+        assert_eq!(
+            code_blocks(RESPONSE, None),
+            vec!["ls -la\n".to_string(), "print(1)\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_code_blocks_filters_by_language() {
+        assert_eq!(
+            code_blocks(RESPONSE, Some("python")),
+            vec!["print(1)\n".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_unwrap_fence() {
+        assert_eq!(
+            unwrap_fence("```rust\nfn main() {}\n```\n"),
+            "fn main() {}\n"
+        );
+        assert_eq!(unwrap_fence(RESPONSE), RESPONSE);
+        assert_eq!(unwrap_fence("  indented\n"), "  indented\n");
+    }
+}
@@ -0,0 +1,279 @@
+use crate::prelude::*;
+
+/// Returns the language used to annotate the fenced block of the file at `path`.
+pub fn language(path: &str) -> String {
+    let path = std::path::Path::new(path);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    match name {
+        "Dockerfile" => return "dockerfile".to_string(),
+        "Makefile" | "makefile" => return "make".to_string(),
+        _ => {}
+    }
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" | "mts" => "typescript",
+        "rb" => "ruby",
+        "h" => "c",
+        "cc" | "cxx" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "kt" | "kts" => "kotlin",
+        "sh" => "bash",
+        "yml" => "yaml",
+        "md" => "markdown",
+        "htm" => "html",
+        "ex" | "exs" => "elixir",
+        "hs" => "haskell",
+        "tf" => "hcl",
+        extension => extension,
+    }
+    .to_string()
+}
+
+/// Returns a fence longer than any backtick run inside `content`.
+pub fn fence_marker(content: &str) -> String {
+    let longest = content
+        .lines()
+        .map(|line| line.trim_start().chars().take_while(|c| *c == '`').count())
+        .max()
+        .unwrap_or_default();
+
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Wraps `content` in a fenced code block annotated with its path and language.
+///
+/// The fence is made longer than any backtick run inside `content` so the block can't be closed
+/// early.
+pub fn fence(path: &str, content: &str) -> String {
+    let fence = fence_marker(content);
+
+    format!(
+        "{path}:\n\n{fence}{}\n{}\n{fence}",
+        language(path),
+        content.trim_end()
+    )
+}
+
+/// Reads the file at `path` and wraps it in a fenced code block.
+pub fn read(path: &str) -> Result<String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?;
+
+    Ok(fence(path, &content))
+}
+
+/// Files bigger than this are skipped by `--files`.
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+/// Returns the part of `pattern` before its first glob component.
+fn base_dir(pattern: &str) -> String {
+    let base = pattern
+        .split('/')
+        .take_while(|component| !component.contains(['*', '?', '[', '{']))
+        .collect::<Vec<&str>>()
+        .join("/");
+
+    if base.is_empty() && pattern.starts_with('/') {
+        "/".to_string()
+    } else if base.is_empty() {
+        ".".to_string()
+    } else {
+        base
+    }
+}
+
+/// Expands `pattern` into the sorted list of files it matches, skipping the ones ignored by
+/// `.gitignore` and hidden files. A directory matches every file inside it.
+pub fn expand(pattern: &str) -> Result<Vec<String>> {
+    let pattern = pattern.trim_start_matches("./");
+    let base = base_dir(pattern);
+    let matcher = if std::path::Path::new(pattern).is_dir() {
+        None
+    } else {
+        Some(
+            globset::GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher(),
+        )
+    };
+
+    let mut paths = ignore::WalkBuilder::new(&base)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("unable to read entry: {}", e);
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| {
+            let path = entry.path().to_string_lossy().to_string();
+            path.strip_prefix("./").map(String::from).unwrap_or(path)
+        })
+        .filter(|path| matcher.as_ref().map_or(true, |m| m.is_match(path)))
+        .collect::<Vec<String>>();
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Expands the `--files` globs and renders the matching files as a context section, stopping once
+/// `max_tokens` is reached.
+fn context(patterns: &[String], max_tokens: Option<usize>, args: &Args) -> Result<String> {
+    let mut paths = Vec::new();
+
+    for pattern in patterns {
+        let matches = expand(pattern)?;
+
+        if matches.is_empty() && args.shows_notices() {
+            eprintln!("No files match: {}", pattern);
+        }
+
+        paths.extend(matches);
+    }
+
+    paths.sort();
+    paths.dedup();
+
+    let tokenizer = crate::tokens::Tokenizer::new(args.api, args.model.as_deref());
+    let mut total = 0;
+    let mut index = Vec::new();
+    let mut blocks = Vec::new();
+
+    for path in paths {
+        if std::fs::metadata(&path)?.len() > MAX_FILE_SIZE {
+            if args.shows_notices() {
+                eprintln!("Skipped file over {} bytes: {}", MAX_FILE_SIZE, path);
+            }
+            continue;
+        }
+
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            if args.shows_notices() {
+                eprintln!("Skipped binary file: {}", path);
+            }
+            continue;
+        };
+
+        let block = fence(&path, &content);
+        let tokens = tokenizer.count(&block);
+
+        if max_tokens.is_some_and(|max_tokens| total + tokens > max_tokens) {
+            if args.shows_notices() {
+                eprintln!(
+                    "Skipped file over the token budget ({} tokens): {}",
+                    tokens, path
+                );
+            }
+            continue;
+        }
+
+        total += tokens;
+        index.push(format!("- {}", path));
+        blocks.push(block);
+    }
+
+    if blocks.is_empty() {
+        return Ok(String::new());
+    }
+
+    Ok(format!(
+        "Files:\n\n{}\n\n{}",
+        index.join("\n"),
+        blocks.join("\n\n")
+    ))
+}
+
+/// Adds the files given with `--file` and `--files` to the context sent before the prompt, and the
+/// ones of `--system-file` before the values of `--system`.
+pub fn attach(mut args: Args) -> Result<Args> {
+    let system = args
+        .system_file
+        .drain(..)
+        .map(|path| {
+            std::fs::read_to_string(crate::paths::expand(&path)?)
+                .map(|content| content.trim().to_string())
+                .map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))
+        })
+        .collect::<Result<Vec<String>>>()?;
+    args.system.splice(0..0, system);
+
+    if args.file.is_empty() && args.files.is_empty() {
+        return Ok(args);
+    }
+
+    let mut sections = args
+        .file
+        .iter()
+        .map(|path| read(path))
+        .collect::<Result<Vec<String>>>()?;
+
+    if !args.files.is_empty() {
+        sections.push(context(&args.files, args.files_max_tokens, &args)?);
+    }
+
+    sections.retain(|section| !section.is_empty());
+
+    args.attachments = Some(sections.join("\n\n"));
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fence_annotates_path_and_language() {
+        let actual = fence("src/main.rs", "fn main() {}\n");
+
+        assert_eq!(actual, "src/main.rs:\n\n```rust\nfn main() {}\n```");
+    }
+
+    #[test]
+    fn test_fence_is_longer_than_inner_fences() {
+        let actual = fence("README.md", "```bash\nls\n```");
+
+        assert!(
+            actual.starts_with("README.md:\n\n````markdown\n"),
+            "The outer fence should be longer than the inner one"
+        );
+        assert!(actual.ends_with("\n````"));
+    }
+
+    #[test]
+    fn test_expand_respects_gitignore() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-{}", xid::new()));
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::write(dir.join(".gitignore"), "ignored.rs\n")?;
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}")?;
+        std::fs::write(dir.join("src/ignored.rs"), "")?;
+        std::fs::write(dir.join("src/notes.txt"), "")?;
+
+        let base = dir.to_string_lossy();
+        let actual = expand(&format!("{}/**/*.rs", base))?;
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(actual, vec![format!("{}/src/main.rs", base)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use tera::Value;
+
+use crate::config::TemplateFunctions;
+
+/// Returns the string argument `name` of a function call.
+fn string_arg(function: &str, args: &HashMap<String, Value>, name: &str) -> tera::Result<String> {
+    match args.get(name) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(_) => Err(format!("`{function}`: `{name}` must be a string").into()),
+        None => Err(format!("`{function}`: missing `{name}` argument").into()),
+    }
+}
+
+/// Returns true if `command` matches one of the allowed commands, which can be globs like
+/// `git log *`.
+fn allowed(patterns: &[String], command: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        pattern == command
+            || globset::Glob::new(pattern)
+                .map(|glob| glob.compile_matcher().is_match(command))
+                .unwrap_or(false)
+    })
+}
+
+/// Splits `command` into the program and its arguments, on whitespace outside of single or double
+/// quotes. A backslash escapes the next character outside of single quotes. Nothing else is
+/// interpreted, so `;`, `|`, or `$(...)` are passed as they are instead of running anything.
+fn split(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// `read_file(path="...")`: returns the contents of a file.
+fn read_file(enabled: bool) -> impl tera::Function {
+    move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+        if !enabled {
+            return Err(
+                "`read_file` is disabled, set `template_functions.read_file = true`".into(),
+            );
+        }
+
+        let path = string_arg("read_file", args, "path")?;
+        let path = crate::paths::expand(&path).map_err(|e| e.to_string())?;
+
+        std::fs::read_to_string(&path)
+            .map(Value::String)
+            .map_err(|e| format!("`read_file`: unable to read {path}: {e}").into())
+    }
+}
+
+/// `env(name="...", default="...")`: returns the value of an allowed environment variable.
+fn env(names: Vec<String>) -> impl tera::Function {
+    move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+        let name = string_arg("env", args, "name")?;
+
+        if !names.contains(&name) {
+            return Err(format!("`env`: {name} isn't in `template_functions.env`").into());
+        }
+
+        match (std::env::var(&name), args.get("default")) {
+            (Ok(value), _) => Ok(Value::String(value)),
+            (Err(_), Some(default)) => Ok(default.clone()),
+            (Err(_), None) => Err(format!("`env`: {name} isn't set").into()),
+        }
+    }
+}
+
+/// `shell(command="...")`: returns the output of an allowed command.
+fn shell(patterns: Vec<String>) -> impl tera::Function {
+    move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+        let command = string_arg("shell", args, "command")?;
+
+        if !allowed(&patterns, &command) {
+            return Err(format!("`shell`: `{command}` isn't in `template_functions.shell`").into());
+        }
+
+        // The command runs without a shell, so an allowed glob can't chain other commands.
+        let words = split(&command).map_err(|e| format!("`shell`: `{command}`: {e}"))?;
+        let (program, arguments) = words
+            .split_first()
+            .ok_or_else(|| format!("`shell`: `{command}` is empty"))?;
+
+        let output = std::process::Command::new(program)
+            .args(arguments)
+            .output()
+            .map_err(|e| format!("`shell`: unable to run `{command}`: {e}"))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "`shell`: `{command}` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(Value::String(
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+        ))
+    }
+}
+
+/// Registers the `read_file`, `env`, and `shell` functions. They fail unless they are allowed in
+/// the `template_functions` section of the configuration.
+pub fn register(tera: &mut tera::Tera, functions: Option<TemplateFunctions>) {
+    let functions = functions.unwrap_or_default();
+
+    tera.register_function("read_file", read_file(functions.read_file));
+    tera.register_function("env", env(functions.env));
+    tera.register_function("shell", shell(functions.shell));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, functions: TemplateFunctions) -> tera::Result<String> {
+        let mut tera = tera::Tera::default();
+        register(&mut tera, Some(functions));
+
+        tera.render_str(template, &tera::Context::new())
+    }
+
+    #[test]
+    fn test_allowed() {
+        let patterns = vec!["git diff --staged".to_string(), "git log *".to_string()];
+
+        assert!(allowed(&patterns, "git diff --staged"));
+        assert!(allowed(&patterns, "git log -n 5"));
+        assert!(!allowed(&patterns, "git diff"));
+        assert!(!allowed(&patterns, "rm -rf ~"));
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split(r#"git log --format="%h %s" -n 5"#),
+            Ok(vec![
+                "git".to_string(),
+                "log".to_string(),
+                "--format=%h %s".to_string(),
+                "-n".to_string(),
+                "5".to_string()
+            ])
+        );
+        assert_eq!(
+            split(r#"echo '' a\ b 'c\d'"#),
+            Ok(vec![
+                "echo".to_string(),
+                "".to_string(),
+                "a b".to_string(),
+                "c\\d".to_string()
+            ])
+        );
+        assert_eq!(
+            split("git log; rm -rf ~"),
+            Ok(vec![
+                "git".to_string(),
+                "log;".to_string(),
+                "rm".to_string(),
+                "-rf".to_string(),
+                "~".to_string()
+            ])
+        );
+        assert!(split("echo 'hi").is_err());
+    }
+
+    #[test]
+    fn test_functions_are_opt_in() {
+        assert!(render(r#"{{ read_file(path="Cargo.toml") }}"#, Default::default()).is_err());
+        assert!(render(r#"{{ env(name="HOME") }}"#, Default::default()).is_err());
+        assert!(render(r#"{{ shell(command="echo hi") }}"#, Default::default()).is_err());
+    }
+
+    // `echo` is a program on Unix, and a builtin of `cmd` on Windows.
+    #[cfg(unix)]
+    #[test]
+    fn test_shell() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let functions = TemplateFunctions {
+            shell: vec!["echo *".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render(r#"{{ shell(command="echo hi") }}"#, functions.clone())?,
+            "hi"
+        );
+        assert_eq!(
+            render(r#"{{ shell(command="echo hi; echo $HOME") }}"#, functions)?,
+            "hi; echo $HOME",
+            "The command shouldn't run with a shell"
+        );
+
+        Ok(())
+    }
+}
@@ -2,9 +2,9 @@ use llm_stream::google;
 
 use crate::prelude::*;
 
-const DEFAULT_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+pub const DEFAULT_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
 const DEFAULT_MODEL: &str = "gemini-1.5-pro";
-const DEFAULT_ENV: &str = "GOOGLE_API_KEY";
+pub const DEFAULT_ENV: &str = "GOOGLE_API_KEY";
 
 // From ConversationRole to google::Role
 impl From<ConversationRole> for google::Role {
@@ -17,18 +17,12 @@ impl From<ConversationRole> for google::Role {
     }
 }
 
-pub async fn run(mut args: Args) -> Result<()> {
-    let key = match args.api_key.take() {
-        Some(key) => key,
-        None => {
-            let environment_variable = match args.api_env.take() {
-                Some(env) => env,
-                None => DEFAULT_ENV.to_string(),
-            };
-            std::env::var(environment_variable)?
-        }
-    };
-    log::info!("key: {}", key);
+/// Builds the `google` client and request body from the arguments.
+fn prepare(
+    mut args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<(google::Client, google::MessageBody, Args)> {
+    let key = crate::keys::resolve(&mut args, Api::Google, DEFAULT_ENV)?;
 
     let url = match args.api_base_url.take() {
         Some(url) => url,
@@ -37,11 +31,16 @@ pub async fn run(mut args: Args) -> Result<()> {
     log::info!("url: {}", url);
 
     let auth = google::Auth::new(key);
-    log::info!("auth: {:#?}", auth);
-
-    let client = google::Client::new(auth, url);
-    log::info!("client: {:#?}", client);
-
+    let client = args
+        .headers
+        .iter()
+        .fold(google::Client::new(auth, url), |client, (name, value)| {
+            client.header(name, value)
+        });
+    let client = args
+        .extra_query
+        .iter()
+        .fold(client, |client, (name, value)| client.query(name, value));
     let mut contents: Vec<google::Content> = Default::default();
 
     for message in &args.conversation {
@@ -68,21 +67,11 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let mut body = google::MessageBody::new(
         args.model
-            .take()
-            .unwrap_or(DEFAULT_MODEL.to_string())
-            .as_ref(),
+            .get_or_insert_with(|| DEFAULT_MODEL.to_string())
+            .as_str(),
         contents,
     );
 
-    if let Some(system) = args.system.take() {
-        let system_message = google::Content {
-            parts: vec![google::Part { text: system }],
-            role: google::Role::User,
-        };
-
-        body.contents.insert(0, system_message);
-    }
-
     body.generation_config = Some(google::GenerationConfig {
         max_output_tokens: Some(args.max_tokens.unwrap_or(4096)),
         temperature: args.temperature,
@@ -93,7 +82,69 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     log::info!("body: {:#?}", body);
 
-    let stream = client.delta(&body)?;
+    let client = request_hooks(&args, &body, tools)?
+        .into_iter()
+        .fold(client, |client, hook| client.on_request(hook));
+
+    Ok((client, body, args))
+}
+
+/// Streams the LLM response to the terminal, returning the answer when `--auto-continue` has to
+/// continue it.
+pub async fn run(args: Args) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::Google, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::Google, metadata.clone()));
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Returns the LLM response without printing it, calling `on_delta` with every chunk of text and
+/// filling `metadata` with the finish reason and usage of the response.
+pub async fn complete(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let client = client
+        .on_event(crate::report::hook(Api::Google, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::Google, metadata));
+    let stream = open_stream(|| client.delta(&body), true, Timeouts::new(&args)).await?;
+
+    collect_stream(stream, on_delta).await
+}
+
+/// Returns the tokens of the conversation, counted by the `countTokens` endpoint of the api.
+pub fn count_tokens(args: Args) -> Result<u32> {
+    let (client, body, _) = prepare(args, None)?;
+
+    Ok(client.count_tokens(&body)?.total_tokens)
+}
+
+/// Returns the information the api has about the model, like its context window.
+pub fn model(args: Args) -> Result<google::Model> {
+    let (client, body, _) = prepare(args, None)?;
+
+    Ok(client.get_model(&body.model)?)
+}
+
+/// Returns the request `run` sends, without sending it.
+pub fn request(
+    args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<llm_stream::request::Request> {
+    let (client, body, _) = prepare(args, tools)?;
 
-    handle_stream(stream, args).await
+    Ok(client.request(&body)?)
 }
@@ -0,0 +1,384 @@
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+use crate::prelude::*;
+
+/// Prompts kept in the history.
+const MAX_ENTRIES: usize = 1000;
+
+/// Returns the path of the prompt history.
+pub fn history_file(state_dir: &str) -> String {
+    format!("{}/history.jsonl", state_dir)
+}
+
+/// Prompt sent by the user, stored as a line of the history.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Entry {
+    pub date: chrono::DateTime<chrono::Local>,
+    pub prompt: String,
+}
+
+/// Reads every entry of the prompt history, oldest first.
+pub fn read(state_dir: &str) -> Result<Vec<Entry>> {
+    let path = history_file(state_dir);
+
+    if !std::path::Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+
+    for line in std::io::BufReader::new(std::fs::File::open(&path)?).lines() {
+        match serde_json::from_str::<Entry>(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!("unable to read history entry: {:?}", e),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Adds a prompt to the history, unless it's the same as the last one. Once the history is full,
+/// it's written again without its oldest prompts.
+fn add(state_dir: &str, prompt: &str) -> Result<()> {
+    let mut entries = read(state_dir)?;
+
+    if prompt.trim().is_empty() || entries.last().is_some_and(|e| e.prompt == prompt) {
+        return Ok(());
+    }
+
+    let entry = Entry {
+        date: chrono::Local::now(),
+        prompt: prompt.to_string(),
+    };
+
+    if entries.len() < MAX_ENTRIES {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(history_file(state_dir))?;
+
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+        return Ok(());
+    }
+
+    entries.push(entry);
+    entries.drain(..entries.len() - MAX_ENTRIES);
+
+    let mut contents = String::new();
+
+    for entry in &entries {
+        contents.push_str(&serde_json::to_string(entry)?);
+        contents.push('\n');
+    }
+
+    std::fs::write(history_file(state_dir), contents)?;
+
+    Ok(())
+}
+
+/// Records the prompt of the arguments, unless `history = false` is set in the configuration. It's
+/// called once the prompt was redacted, so secrets don't end up in the history.
+pub fn record(args: &Args, config: &Config) {
+    if config.history == Some(false) {
+        return;
+    }
+
+    let Some(prompt) = args.prompt.as_deref() else {
+        return;
+    };
+
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+
+    if let Err(e) = add(&state_dir, prompt) {
+        log::warn!("unable to record the prompt: {:?}", e);
+    }
+}
+
+/// Scores how well `text` matches `query`, like `skim` does: every character of the query has to
+/// appear in order, and matches that are consecutive or start a word score higher. Returns `None`
+/// if the text doesn't match.
+fn score(query: &str, text: &str) -> Option<i64> {
+    let text = text.to_lowercase().chars().collect::<Vec<char>>();
+    let mut position = 0;
+    let mut previous: Option<usize> = None;
+    let mut score = 0;
+
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let index = position + text[position..].iter().position(|&t| t == c)?;
+
+        score += 1;
+
+        if previous.is_some_and(|p| p + 1 == index) {
+            score += 5;
+        }
+
+        if index == 0 || !text[index - 1].is_alphanumeric() {
+            score += 3;
+        }
+
+        // Gaps lower the score, up to a point, so long prompts aren't pushed to the bottom.
+        score -= i64::try_from(index - position).unwrap_or(i64::MAX).min(5);
+
+        previous = Some(index);
+        position = index + 1;
+    }
+
+    Some(score)
+}
+
+/// Returns the entries that match the query, best first. Entries with the same score keep their
+/// order, newest first.
+fn matches<'a>(query: &str, entries: &'a [Entry]) -> Vec<&'a Entry> {
+    let mut scored = entries
+        .iter()
+        .filter_map(|entry| score(query, &entry.prompt).map(|score| (score, entry)))
+        .collect::<Vec<(i64, &Entry)>>();
+
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+    scored.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// What to do with the prompt picked from the history.
+enum Choice {
+    Send(String),
+    Edit(String),
+}
+
+/// State of the history picker.
+struct Picker {
+    /// Unique prompts of the history, newest first.
+    entries: Vec<Entry>,
+    query: String,
+    list: ListState,
+    choice: Option<Choice>,
+    quit: bool,
+}
+
+impl Picker {
+    fn new(history: Vec<Entry>) -> Self {
+        let mut entries: Vec<Entry> = Vec::new();
+
+        for entry in history.into_iter().rev() {
+            if !entries.iter().any(|e| e.prompt == entry.prompt) {
+                entries.push(entry);
+            }
+        }
+
+        let mut list = ListState::default();
+        list.select(Some(0));
+
+        Self {
+            entries,
+            query: String::new(),
+            list,
+            choice: None,
+            quit: false,
+        }
+    }
+
+    /// Returns the prompt of the selected entry.
+    fn selected(&self) -> Option<String> {
+        let index = self.list.selected()?;
+
+        matches(&self.query, &self.entries)
+            .get(index)
+            .map(|entry| entry.prompt.clone())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Enter => {
+                self.choice = self.selected().map(Choice::Send);
+                self.quit = self.choice.is_some();
+            }
+            KeyCode::Char('e') if ctrl => {
+                self.choice = self.selected().map(Choice::Edit);
+                self.quit = self.choice.is_some();
+            }
+            KeyCode::Up => self.list.select_previous(),
+            KeyCode::Char('p') if ctrl => self.list.select_previous(),
+            KeyCode::Down => self.list.select_next(),
+            KeyCode::Char('n') if ctrl => self.list.select_next(),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.list.select(Some(0));
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.list.select(Some(0));
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [results, query, help] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let matches = matches(&self.query, &self.entries);
+        let total = matches.len();
+
+        let items = matches
+            .into_iter()
+            .map(|entry| {
+                ListItem::new(Line::from(vec![
+                    Span::styled(
+                        entry.date.format("%Y-%m-%d %H:%M  ").to_string(),
+                        Style::default().add_modifier(Modifier::DIM),
+                    ),
+                    Span::raw(entry.prompt.lines().next().unwrap_or_default().to_string()),
+                ]))
+            })
+            .collect::<Vec<_>>();
+
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("History"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        frame.render_stateful_widget(list, results, &mut self.list);
+
+        let title = format!("{}/{}", total, self.entries.len());
+        let offset = u16::try_from(self.query.chars().count()).unwrap_or(u16::MAX);
+
+        frame.render_widget(
+            Paragraph::new(self.query.as_str())
+                .block(Block::default().borders(Borders::ALL).title(title)),
+            query,
+        );
+        frame.set_cursor_position((query.x + 1 + offset, query.y + 1));
+
+        frame.render_widget(
+            Paragraph::new(Line::styled(
+                "Enter: send  Ctrl-E: edit and send  Up/Down: select  Esc: cancel",
+                Style::default().add_modifier(Modifier::DIM),
+            )),
+            help,
+        );
+    }
+
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<std::io::Stderr>>) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens a fuzzy picker with the prompts of the history and returns the picked one, edited in
+/// `$EDITOR` first when it's picked with `Ctrl-E`. Returns `None` if nothing was picked. The picker
+/// is drawn on stderr, so the answer can still be redirected.
+pub fn pick(args: &Args) -> Result<Option<String>> {
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    let entries = read(&state_dir)?;
+
+    if entries.is_empty() {
+        return Err(Error::EmptyHistory);
+    }
+
+    let mut picker = Picker::new(entries);
+
+    enable_raw_mode()?;
+    execute!(std::io::stderr(), EnterAlternateScreen)?;
+
+    let result = Terminal::new(CrosstermBackend::new(std::io::stderr()))
+        .map_err(Error::from)
+        .and_then(|mut terminal| picker.run(&mut terminal));
+
+    disable_raw_mode()?;
+    execute!(std::io::stderr(), LeaveAlternateScreen)?;
+
+    result?;
+
+    match picker.choice {
+        Some(Choice::Send(prompt)) => Ok(Some(prompt)),
+        Some(Choice::Edit(prompt)) => {
+            let prompt = crate::editor::edit(&prompt, "md")?.trim().to_string();
+            Ok((!prompt.is_empty()).then_some(prompt))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(prompt: &str) -> Entry {
+        Entry {
+            date: chrono::Local::now(),
+            prompt: prompt.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_matches_prefers_consecutive_characters() {
+        let entries = vec![
+            entry("Explain the borrow checker"),
+            entry("Write a commit message"),
+            entry("Compare merge and rebase"),
+        ];
+
+        let actual = matches("comm", &entries)
+            .into_iter()
+            .map(|entry| entry.prompt.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(
+            actual,
+            vec!["Write a commit message", "Compare merge and rebase"]
+        );
+        assert_eq!(matches("", &entries).len(), 3);
+        assert!(matches("xyz", &entries).is_empty());
+    }
+
+    #[test]
+    fn test_add_skips_repeated_prompts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-history-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let state_dir = dir.to_string_lossy().to_string();
+
+        add(&state_dir, "What's a monad?")?;
+        add(&state_dir, "What's a monad?")?;
+        add(&state_dir, "")?;
+        add(&state_dir, "What's a functor?")?;
+
+        let actual = read(&state_dir)?
+            .into_iter()
+            .map(|entry| entry.prompt)
+            .collect::<Vec<String>>();
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(actual, vec!["What's a monad?", "What's a functor?"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::prelude::*;
+use crate::report::{Metadata, Usage};
+
+/// Response given to the `post_response` hook.
+#[derive(Debug, Serialize)]
+struct Response<'a> {
+    api: Option<Api>,
+    model: Option<&'a str>,
+    message: &'a str,
+    finish_reason: Option<&'a str>,
+    usage: Usage,
+}
+
+/// Runs a hook with `input` on its standard input. Hooks reject the request or response by
+/// exiting with an error, and their standard error goes to the terminal, so they can say why.
+/// Returns `None` when the hook prints nothing, leaving the input as it was.
+fn run(name: &str, command: &str, input: &str) -> Result<Option<Value>> {
+    let output = crate::pipe::run(command, input).map_err(|e| match e {
+        Error::Pipe(message) => Error::Hook(format!("{} rejected it: {}", name, message)),
+        e => e,
+    })?;
+
+    if output.trim().is_empty() {
+        return Ok(None);
+    }
+
+    serde_json::from_str(&output)
+        .map(Some)
+        .map_err(|e| Error::Hook(format!("{} printed invalid JSON: {}", name, e)))
+}
+
+/// Runs the `pre_request` hook with the body of the request, returning the body printed by the
+/// hook to send instead, if any.
+pub fn pre_request(args: &Args, body: &impl Serialize) -> Result<Option<Value>> {
+    let Some(command) = &args.hooks.pre_request else {
+        return Ok(None);
+    };
+
+    run("pre_request", command, &serde_json::to_string(body)?)
+}
+
+/// Runs the `post_response` hook with the answer, returning the `message` printed by the hook to
+/// use instead, if any.
+pub fn post_response(args: &Args, message: &str, metadata: &Metadata) -> Result<Option<String>> {
+    let Some(command) = &args.hooks.post_response else {
+        return Ok(None);
+    };
+
+    let response = Response {
+        api: args.api,
+        model: args.model.as_deref(),
+        message,
+        finish_reason: metadata.finish_reason.as_deref(),
+        usage: metadata.usage,
+    };
+
+    let Some(output) = run("post_response", command, &serde_json::to_string(&response)?)? else {
+        return Ok(None);
+    };
+
+    output["message"]
+        .as_str()
+        .map(|message| Some(message.to_string()))
+        .ok_or_else(|| Error::Hook("post_response printed no `message`".to_string()))
+}
+
+// The hooks run with `sh`.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::Hooks;
+
+    fn args(pre_request: Option<&str>, post_response: Option<&str>) -> Args {
+        Args {
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            hooks: Hooks {
+                pre_request: pre_request.map(String::from),
+                post_response: post_response.map(String::from),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pre_request() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let body = serde_json::json!({ "model": "gpt-4o", "messages": [] });
+
+        assert_eq!(pre_request(&args(None, None), &body)?, None);
+        assert_eq!(
+            pre_request(&args(Some("cat > /dev/null"), None), &body)?,
+            None
+        );
+        assert_eq!(
+            pre_request(&args(Some("sed s/gpt-4o/gpt-4o-mini/"), None), &body)?,
+            Some(serde_json::json!({ "model": "gpt-4o-mini", "messages": [] }))
+        );
+        assert!(pre_request(&args(Some("exit 1"), None), &body).is_err());
+        assert!(pre_request(&args(Some("echo nope"), None), &body).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_response() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let metadata = Metadata::default();
+
+        assert_eq!(
+            post_response(&args(None, Some("cat")), "The answer", &metadata)?,
+            Some("The answer".to_string())
+        );
+        assert_eq!(
+            post_response(
+                &args(None, Some("sed s/answer/reply/")),
+                "The answer",
+                &metadata
+            )?,
+            Some("The reply".to_string())
+        );
+        assert!(post_response(&args(None, Some("exit 1")), "The answer", &metadata).is_err());
+        assert!(post_response(&args(None, Some("echo {}")), "The answer", &metadata).is_err());
+
+        Ok(())
+    }
+}
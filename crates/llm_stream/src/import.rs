@@ -0,0 +1,305 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::args::ImportFormat;
+use crate::prelude::*;
+
+/// Conversation of a ChatGPT export. Its messages are a tree, because edited prompts and
+/// regenerated answers start new branches, and `current_node` is the last message of the branch
+/// shown in ChatGPT.
+#[derive(Debug, Deserialize)]
+struct ChatGptConversation {
+    title: Option<String>,
+    #[serde(default)]
+    mapping: HashMap<String, ChatGptNode>,
+    current_node: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptNode {
+    message: Option<ChatGptMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptMessage {
+    author: ChatGptAuthor,
+    content: ChatGptContent,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptAuthor {
+    role: String,
+}
+
+/// Content of a ChatGPT message. Images and other attachments are parts that aren't strings, and
+/// code, reasoning, and browsing results have no parts.
+#[derive(Debug, Deserialize)]
+struct ChatGptContent {
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+/// Conversation of a Claude export.
+#[derive(Debug, Deserialize)]
+struct ClaudeConversation {
+    name: Option<String>,
+    #[serde(default)]
+    chat_messages: Vec<ClaudeMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClaudeMessage {
+    sender: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    content: Vec<ClaudeContent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClaudeContent {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+/// Adds a message to the conversation, merging it with the previous one when they have the same
+/// role, like the answers ChatGPT splits around tool calls.
+fn push(conversation: &mut Conversation, role: ConversationRole, content: &str) {
+    let content = content.trim();
+
+    if content.is_empty() {
+        return;
+    }
+
+    match conversation.last_mut() {
+        Some(last) if last.role == role => {
+            last.content.push_str("\n\n");
+            last.content.push_str(content);
+        }
+        _ => conversation.push(ConversationMessage {
+            role,
+            content: content.to_string(),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Returns the messages of the current branch of a ChatGPT conversation. Tool messages are skipped.
+fn chatgpt_conversation(conversation: &ChatGptConversation) -> Conversation {
+    let mut messages = Vec::new();
+    let mut node = conversation.current_node.as_ref();
+
+    // The length of the mapping bounds the walk, in case the export has a cycle.
+    for _ in 0..conversation.mapping.len() {
+        let Some(current) = node.and_then(|id| conversation.mapping.get(id)) else {
+            break;
+        };
+
+        messages.extend(current.message.as_ref());
+        node = current.parent.as_ref();
+    }
+
+    let mut result = Conversation::new();
+
+    for message in messages.into_iter().rev() {
+        let role = match message.author.role.as_str() {
+            "user" => ConversationRole::User,
+            "assistant" => ConversationRole::Assistant,
+            "system" => ConversationRole::System,
+            _ => continue,
+        };
+
+        let text = message
+            .content
+            .parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        push(&mut result, role, &text);
+    }
+
+    result
+}
+
+/// Returns the messages of a Claude conversation.
+fn claude_conversation(conversation: &ClaudeConversation) -> Conversation {
+    let mut result = Conversation::new();
+
+    for message in &conversation.chat_messages {
+        let role = match message.sender.as_str() {
+            "human" => ConversationRole::User,
+            "assistant" => ConversationRole::Assistant,
+            _ => continue,
+        };
+
+        let text = message
+            .content
+            .iter()
+            .filter(|content| content.kind == "text")
+            .filter_map(|content| content.text.as_deref())
+            .collect::<Vec<&str>>()
+            .join("\n\n");
+
+        push(
+            &mut result,
+            role,
+            if text.trim().is_empty() {
+                &message.text
+            } else {
+                &text
+            },
+        );
+    }
+
+    result
+}
+
+/// Builds the cache entry of an imported conversation, or `None` if it has no messages.
+fn cache(api: Api, title: Option<String>, conversation: Conversation) -> Option<Args> {
+    if conversation.is_empty() {
+        return None;
+    }
+
+    Some(Args {
+        api: Some(api),
+        title: title.filter(|t| !t.trim().is_empty()),
+        conversation,
+        ..Default::default()
+    })
+}
+
+/// Parses a conversation file into cache entries. Conversations of ChatGPT and Claude exports are
+/// continued with the api they come from and its default model.
+pub fn parse(contents: &str, format: ImportFormat) -> Result<Vec<Args>> {
+    let invalid = |e: serde_json::Error| Error::InvalidConversation(e.to_string());
+
+    let caches = match format {
+        ImportFormat::Json => vec![Args {
+            conversation: serde_json::from_str(contents).map_err(invalid)?,
+            ..Default::default()
+        }],
+        ImportFormat::Chatgpt => serde_json::from_str::<Vec<ChatGptConversation>>(contents)
+            .map_err(invalid)?
+            .into_iter()
+            .filter_map(|c| {
+                let conversation = chatgpt_conversation(&c);
+                cache(Api::OpenAi, c.title, conversation)
+            })
+            .collect(),
+        ImportFormat::Claude => serde_json::from_str::<Vec<ClaudeConversation>>(contents)
+            .map_err(invalid)?
+            .into_iter()
+            .filter_map(|c| {
+                let conversation = claude_conversation(&c);
+                cache(Api::Anthropic, c.name, conversation)
+            })
+            .collect(),
+    };
+
+    Ok(caches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chatgpt_follows_the_current_branch(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::json!([{
+            "title": "Rust lifetimes",
+            "current_node": "c",
+            "mapping": {
+                "root": { "message": null, "parent": null },
+                "s": {
+                    "message": {
+                        "author": { "role": "system" },
+                        "content": { "content_type": "text", "parts": [""] },
+                    },
+                    "parent": "root",
+                },
+                "a": {
+                    "message": {
+                        "author": { "role": "user" },
+                        "content": { "content_type": "text", "parts": ["What's a lifetime?"] },
+                    },
+                    "parent": "s",
+                },
+                "old": {
+                    "message": {
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["A regenerated answer"] },
+                    },
+                    "parent": "a",
+                },
+                "b": {
+                    "message": {
+                        "author": { "role": "tool" },
+                        "content": { "content_type": "text", "parts": ["search results"] },
+                    },
+                    "parent": "a",
+                },
+                "c": {
+                    "message": {
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["The scope of a borrow."] },
+                    },
+                    "parent": "b",
+                },
+            },
+        }, {
+            "title": "Empty",
+            "current_node": null,
+            "mapping": {},
+        }])
+        .to_string();
+
+        let caches = parse(&contents, ImportFormat::Chatgpt)?;
+
+        assert_eq!(caches.len(), 1);
+        assert_eq!(caches[0].api, Some(Api::OpenAi));
+        assert_eq!(caches[0].title, Some("Rust lifetimes".to_string()));
+        assert_eq!(
+            caches[0].conversation,
+            vec![
+                ConversationMessage::new(ConversationRole::User, "What's a lifetime?"),
+                ConversationMessage::new(ConversationRole::Assistant, "The scope of a borrow."),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_claude() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::json!([{
+            "uuid": "1",
+            "name": "",
+            "chat_messages": [
+                { "sender": "human", "text": "Hi", "content": [{ "type": "text", "text": "Hi" }] },
+                { "sender": "assistant", "text": "Hello!", "content": [] },
+            ],
+        }])
+        .to_string();
+
+        let caches = parse(&contents, ImportFormat::Claude)?;
+
+        assert_eq!(caches.len(), 1);
+        assert_eq!(caches[0].api, Some(Api::Anthropic));
+        assert_eq!(caches[0].title, None);
+        assert_eq!(
+            caches[0].conversation,
+            vec![
+                ConversationMessage::new(ConversationRole::User, "Hi"),
+                ConversationMessage::new(ConversationRole::Assistant, "Hello!"),
+            ]
+        );
+
+        Ok(())
+    }
+}
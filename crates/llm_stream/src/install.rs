@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config::Template;
+use crate::prelude::*;
+use crate::templates::{from_file, parse, templates_dir, validate_name};
+
+/// Templates installed from each source, used by `templates update`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Installed {
+    #[serde(default)]
+    sources: BTreeMap<String, Vec<String>>,
+}
+
+/// Returns the path of the file that records where the templates were installed from.
+fn installed_file(data_dir: &str) -> String {
+    format!("{}/installed-templates.toml", data_dir)
+}
+
+impl Installed {
+    fn read(data_dir: &str) -> Result<Self> {
+        let path = installed_file(data_dir);
+
+        if !std::path::Path::new(&path).exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn write(&self, data_dir: &str) -> Result<()> {
+        std::fs::write(installed_file(data_dir), toml::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    /// Returns the source the template `name` was installed from.
+    fn source_of(&self, name: &str) -> Option<&String> {
+        self.sources
+            .iter()
+            .find(|(_, names)| names.iter().any(|n| n == name))
+            .map(|(source, _)| source)
+    }
+}
+
+/// Downloads a single template file. `path` is the URL without its query.
+fn fetch_url(url: &str, path: &str) -> Result<Vec<Template>> {
+    let contents = ureq::get(url)
+        .call()
+        .map_err(|e| Error::TemplateInstall(format!("{}: {}", url, e)))?
+        .into_string()?;
+
+    Ok(vec![parse(std::path::Path::new(path), &contents)?])
+}
+
+/// Clones a git repository and reads every template file in it. Other TOML and Markdown files,
+/// like `Cargo.toml` or a `README.md` without frontmatter, are skipped.
+fn fetch_git(url: &str) -> Result<Vec<Template>> {
+    let dir = std::env::temp_dir().join(format!("llm-stream-{}", xid::new()));
+
+    // `--` keeps a URL that starts with `-` from being read as an option of `git clone`.
+    let output = std::process::Command::new("git")
+        .args(["clone", "--depth", "1", "--quiet", "--", url])
+        .arg(&dir)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::TemplateInstall(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let mut templates = ignore::WalkBuilder::new(&dir)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| match from_file(entry.path()) {
+            Ok(template) => template,
+            Err(e) => {
+                log::warn!("skipping {}: {:?}", entry.path().display(), e);
+                None
+            }
+        })
+        .collect::<Vec<Template>>();
+
+    std::fs::remove_dir_all(&dir)?;
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(templates)
+}
+
+/// Fetches the templates of `source`, a URL or path of a template file, or a git repository.
+fn fetch(source: &str) -> Result<Vec<Template>> {
+    let path = source.split(['?', '#']).next().unwrap_or_default();
+    let is_file = path.ends_with(".toml") || path.ends_with(".md");
+
+    let templates = if is_file && source.starts_with("http") {
+        fetch_url(source, path)?
+    } else if is_file {
+        from_file(std::path::Path::new(source))?
+            .into_iter()
+            .collect()
+    } else {
+        fetch_git(source)?
+    };
+
+    if templates.is_empty() {
+        return Err(Error::TemplateInstall(format!(
+            "no templates found in {}",
+            source
+        )));
+    }
+
+    Ok(templates)
+}
+
+/// Installs the templates of `source` into the templates directory. Templates whose name is
+/// already taken fail the whole install, unless they came from the same source or `force` is set.
+pub fn install(source: &str, force: bool, existing: &[Template], args: &Args) -> Result<()> {
+    let config_dir = args.config_dir.clone().unwrap_or_default();
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let mut installed = Installed::read(&data_dir)?;
+    let templates = fetch(source)?;
+
+    for template in &templates {
+        validate_name(&template.name)?;
+
+        let taken = existing.iter().any(|t| t.name == template.name);
+        let ours = installed.source_of(&template.name) == Some(&source.to_string());
+
+        if taken && !ours && !force {
+            return Err(Error::TemplateExists(template.name.clone()));
+        }
+    }
+
+    for template in &templates {
+        let path = format!("{}/{}.toml", templates_dir(&config_dir), template.name);
+
+        std::fs::write(&path, toml::to_string(template)?)?;
+        eprintln!("Installed template: {}", template.name);
+    }
+
+    installed.sources.insert(
+        source.to_string(),
+        templates.into_iter().map(|t| t.name).collect(),
+    );
+    installed.write(&data_dir)
+}
+
+/// Installs the templates of every recorded source again, or only of the sources of `names`.
+pub fn update(names: &[String], existing: &[Template], args: &Args) -> Result<()> {
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let installed = Installed::read(&data_dir)?;
+
+    let sources = if names.is_empty() {
+        installed.sources.keys().cloned().collect::<Vec<String>>()
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                installed
+                    .source_of(name)
+                    .cloned()
+                    .ok_or_else(|| Error::TemplateInstall(format!("{} wasn't installed", name)))
+            })
+            .collect::<Result<Vec<String>>>()?
+    };
+
+    for source in sources
+        .iter()
+        .collect::<std::collections::BTreeSet<&String>>()
+    {
+        install(source, false, existing, args)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_of() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let installed: Installed = toml::from_str(
+            r#"
+            [sources]
+            "https://github.com/acme/prompts.git" = ["review", "commit"]
+            "https://example.com/translate.toml" = ["translate"]
+            "#,
+        )?;
+
+        assert_eq!(
+            installed.source_of("commit").map(String::as_str),
+            Some("https://github.com/acme/prompts.git")
+        );
+        assert_eq!(installed.source_of("summarize"), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+
+use crate::args::{KeySource, KeysCommand};
+use crate::prelude::*;
+
+/// Service name of the keys stored in the platform secret store.
+const SERVICE: &str = "llm-stream";
+
+/// Output of the key commands already run by this process.
+static COMMAND_KEYS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Runs `command` with the shell and returns the key it prints, reusing the key of a previous run.
+fn run_key_cmd(command: &str) -> Result<String> {
+    let keys = COMMAND_KEYS.get_or_init(Default::default);
+
+    if let Some(key) = keys.lock().ok().and_then(|keys| keys.get(command).cloned()) {
+        return Ok(key);
+    }
+
+    let output = crate::pipe::shell(command).output()?;
+
+    if !output.status.success() {
+        return Err(Error::KeyCommand(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if let Ok(mut keys) = keys.lock() {
+        keys.insert(command.to_string(), key.clone());
+    }
+
+    Ok(key)
+}
+
+/// Returns the secret store entry of the `api` key, apart from the other profiles' keys when
+/// `profile` is set.
+fn entry(api: Api, profile: Option<&str>) -> Result<keyring::Entry> {
+    let user = match profile {
+        Some(profile) => format!("{}/{}", profile, api),
+        None => api.to_string(),
+    };
+
+    Ok(keyring::Entry::new(SERVICE, &user)?)
+}
+
+/// Returns the key of `api`: the one given with `--api-key`, the output of `--key-cmd`, the one
+/// stored in the keyring when `key_source = "keyring"`, or the value of the `--api-env`
+/// environment variable, in that order.
+pub fn resolve(args: &mut Args, api: Api, default_env: &str) -> Result<String> {
+    if let Some(key) = args.api_key.take() {
+        return Ok(key);
+    }
+
+    if let Some(command) = args.key_cmd.take() {
+        return run_key_cmd(&command);
+    }
+
+    if args.key_source == Some(KeySource::Keyring) {
+        match entry(api, args.profile.as_deref())?.get_password() {
+            Ok(key) => return Ok(key),
+            Err(keyring::Error::NoEntry) => {
+                log::warn!("no {} key in the keyring, using the environment", api)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let environment_variable = args.api_env.take().unwrap_or(default_env.to_string());
+
+    Ok(std::env::var(environment_variable)?)
+}
+
+/// Runs the `keys` commands.
+pub fn run(command: KeysCommand, profile: Option<&str>) -> Result<()> {
+    match command {
+        KeysCommand::Set { api } => {
+            let key = if std::io::stdin().is_terminal() {
+                rpassword::prompt_password(format!("{} key: ", api))?
+            } else {
+                std::io::read_to_string(std::io::stdin())?
+            };
+
+            entry(api, profile)?.set_password(key.trim())?;
+            eprintln!("Stored the {} key in the keyring", api);
+        }
+        KeysCommand::Delete { api } => {
+            entry(api, profile)?.delete_credential()?;
+            eprintln!("Deleted the {} key from the keyring", api);
+        }
+    }
+
+    Ok(())
+}
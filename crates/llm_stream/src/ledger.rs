@@ -0,0 +1,211 @@
+use cli_table::{format::Justify, Table, WithTitle};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+use crate::args::UsageGroup;
+use crate::prelude::*;
+use crate::report::Stats;
+
+/// Returns the path of the usage ledger.
+pub fn ledger_file(state_dir: &str) -> String {
+    format!("{}/usage.jsonl", state_dir)
+}
+
+/// Usage of a single request, stored as a line of the ledger.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Entry {
+    pub date: chrono::DateTime<chrono::Local>,
+    pub api: Option<Api>,
+    pub model: Option<String>,
+    pub tag: Option<String>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: Option<f64>,
+    /// The tokens were counted locally because the provider didn't report them.
+    #[serde(default)]
+    pub estimated: bool,
+    /// Time it took to receive the whole response.
+    #[serde(default)]
+    pub elapsed_ms: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(args: &Args, stats: &Stats) -> Self {
+        Self {
+            date: chrono::Local::now(),
+            api: args.api,
+            model: args.model.clone(),
+            tag: args.tag.clone(),
+            input_tokens: stats.usage.input_tokens.unwrap_or_default(),
+            output_tokens: stats.usage.output_tokens.unwrap_or_default(),
+            cost: stats.cost,
+            estimated: stats.estimated,
+            elapsed_ms: Some(stats.elapsed.as_millis() as u64),
+        }
+    }
+
+    /// Returns the value of the field used to group the report.
+    fn group(&self, by: UsageGroup) -> String {
+        match by {
+            UsageGroup::Day => self.date.format("%Y-%m-%d").to_string(),
+            UsageGroup::Model => self.model.clone().unwrap_or_default(),
+            UsageGroup::Api => self.api.map(|api| api.to_string()).unwrap_or_default(),
+            UsageGroup::Tag => self.tag.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Appends an entry to the usage ledger, and exports it with OpenTelemetry when built with the
+/// `otel` feature.
+pub fn record(state_dir: &str, entry: &Entry) -> Result<()> {
+    #[cfg(feature = "otel")]
+    crate::telemetry::record(entry);
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ledger_file(state_dir))?;
+
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+    Ok(())
+}
+
+/// Reads every entry of the usage ledger.
+pub fn read(state_dir: &str) -> Result<Vec<Entry>> {
+    let path = ledger_file(state_dir);
+
+    if !std::path::Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+
+    for line in std::io::BufReader::new(std::fs::File::open(&path)?).lines() {
+        match serde_json::from_str::<Entry>(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!("unable to read usage entry: {:?}", e),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Usage of a group of requests.
+#[derive(Debug, Default, Serialize, Table, PartialEq)]
+pub struct Summary {
+    #[table(title = "Group", justify = "Justify::Left")]
+    pub group: String,
+    #[table(title = "Requests", justify = "Justify::Right")]
+    pub requests: usize,
+    #[table(title = "Input", justify = "Justify::Right")]
+    pub input_tokens: u64,
+    #[table(title = "Output", justify = "Justify::Right")]
+    pub output_tokens: u64,
+    #[table(
+        title = "Cost",
+        justify = "Justify::Right",
+        display_fn = "display_cost"
+    )]
+    pub cost: f64,
+}
+
+fn display_cost(cost: &f64) -> String {
+    format!("${:.4}", cost)
+}
+
+/// Groups the entries by the given field, keeping the groups sorted.
+pub fn summarize(entries: &[Entry], by: UsageGroup) -> Vec<Summary> {
+    let mut groups: std::collections::BTreeMap<String, Summary> = Default::default();
+
+    for entry in entries {
+        let group = entry.group(by);
+        let summary = groups.entry(group.clone()).or_insert_with(|| Summary {
+            group,
+            ..Default::default()
+        });
+
+        summary.requests += 1;
+        summary.input_tokens += entry.input_tokens;
+        summary.output_tokens += entry.output_tokens;
+        summary.cost += entry.cost.unwrap_or_default();
+    }
+
+    groups.into_values().collect()
+}
+
+/// Prints the usage recorded in the ledger grouped by `by`.
+pub fn run(by: UsageGroup, since: Option<std::time::Duration>, args: Args) -> Result<()> {
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    let mut entries = read(&state_dir)?;
+
+    if let Some(since) = since {
+        let start = chrono::Local::now() - chrono::Duration::from_std(since).unwrap_or_default();
+        entries.retain(|entry| entry.date >= start);
+    }
+
+    let summaries = summarize(&entries, by);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    let table = if atty::is(atty::Stream::Stdout) {
+        summaries.with_title()
+    } else {
+        summaries.table()
+    };
+
+    print_table(table, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(model: &str, tag: Option<&str>, cost: f64) -> Entry {
+        Entry {
+            date: chrono::Local::now(),
+            api: Some(Api::Anthropic),
+            model: Some(model.to_string()),
+            tag: tag.map(String::from),
+            input_tokens: 10,
+            output_tokens: 20,
+            cost: Some(cost),
+            estimated: false,
+            elapsed_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_summarize_groups_by_model() {
+        let entries = vec![
+            entry("claude-3-5-sonnet", None, 0.5),
+            entry("claude-3-haiku", Some("scripts"), 0.25),
+            entry("claude-3-5-sonnet", Some("scripts"), 0.5),
+        ];
+
+        let actual = summarize(&entries, UsageGroup::Model);
+
+        assert_eq!(
+            actual,
+            vec![
+                Summary {
+                    group: "claude-3-5-sonnet".to_string(),
+                    requests: 2,
+                    input_tokens: 20,
+                    output_tokens: 40,
+                    cost: 1.0,
+                },
+                Summary {
+                    group: "claude-3-haiku".to_string(),
+                    requests: 1,
+                    input_tokens: 10,
+                    output_tokens: 20,
+                    cost: 0.25,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::io::IsTerminal;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::args::LogFormat;
+use crate::prelude::*;
+
+/// Sets up the diagnostics, including the ones of the `log` macros, filtered by `RUST_LOG`. They
+/// go to stderr, or are appended to `file`, as text or as a JSON object per line. Only errors are
+/// logged when `RUST_LOG` isn't set, or up to `info` when writing to a file.
+pub fn init(format: LogFormat, file: Option<&str>) -> Result<()> {
+    let writer = match file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(crate::paths::expand(path)?)?;
+
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+
+    let level = if file.is_some() {
+        LevelFilter::INFO
+    } else {
+        LevelFilter::ERROR
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(level.into())
+                .from_env_lossy(),
+        )
+        .with_writer(writer)
+        .with_ansi(file.is_none() && std::io::stderr().is_terminal());
+
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+
+    result.map_err(|e| Error::Logging(e.to_string()))
+}
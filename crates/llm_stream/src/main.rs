@@ -1,84 +1,277 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 
+mod agent;
+mod alias;
 mod anthropic;
 mod args;
+mod audit;
+mod batch;
+mod budget;
+mod cache;
+mod commit;
+mod compaction;
+mod compare;
+mod completion;
 mod config;
 mod conversation;
+mod daemon;
+mod editor;
 mod error;
+mod export;
+mod extract;
+mod files;
+mod functions;
 mod google;
+mod history;
+mod hooks;
+mod import;
+mod install;
+mod keys;
+mod ledger;
+mod logging;
+mod man;
 mod mistral;
 mod mistral_fim;
+mod models;
+mod ollama_fim;
 mod openai;
+mod patch;
+mod paths;
+mod pipe;
+mod pipeline;
 mod prelude;
+mod presets;
 mod printer;
+mod rag;
+mod redact;
+mod render;
+mod report;
+mod review;
+mod router;
+mod schema;
+mod self_update;
+mod serve;
+mod spec;
+mod status;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod templates;
+mod theme;
+mod tokens;
+mod tools;
+mod tui;
+mod validate;
+mod wrap;
 
 use crate::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    // Answers the completion scripts printed by `COMPLETE=<shell> llm-stream`.
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
 
-    let mut args = Args::parse();
+    let cli = Cli::parse_from(alias::expand(std::env::args_os()));
 
-    log::info!("args: {:#?}", args);
+    logging::init(cli.log_format, cli.log_file.as_deref())?;
 
-    let home = std::env::var("HOME")?;
+    #[cfg(feature = "otel")]
+    let _telemetry = telemetry::init()?;
 
-    let config_dir = args
-        .config_dir
-        .clone()
-        .unwrap_or("~/.config/llm-stream".to_string())
-        .replace('~', &home);
+    if let Some(dir) = &cli.generate_man {
+        return man::generate(dir.as_deref());
+    }
 
-    args.config_dir = Some(config_dir.clone());
+    let (command, mut args) = cli.into_command();
+
+    // Windows consoles without virtual terminal support print the escape sequences as text.
+    #[cfg(windows)]
+    if !crossterm::ansi_support::supports_ansi() {
+        args.no_color = true;
+    }
+
+    log::info!("command: {:#?}", command);
+    log::info!("args: {:#?}", args.redacted());
+
+    let (config_dir, data_dir, state_dir) = paths::resolve_dirs(
+        args.config_dir.take(),
+        args.data_dir.take(),
+        args.state_dir.take(),
+        args.profile.as_deref(),
+    )?;
+
+    for dir in [&config_dir, &data_dir, &state_dir] {
+        if !std::path::Path::new(dir).exists() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
 
-    if !std::path::Path::new(&config_dir).exists() {
-        std::fs::create_dir_all(&config_dir)?;
+    // Forwarded prompts skip loading the assets and configuration, the daemon already has them.
+    if args.daemon && matches!(command, Command::Ask(_)) {
+        return daemon::forward(args, &state_dir);
     }
 
+    paths::migrate(&config_dir, &data_dir, args.shows_notices())?;
+
+    if let Err(e) = printer::init_assets(&config_dir, &state_dir) {
+        eprintln!("Unable to build the user themes and syntaxes: {:?}", e);
+    }
+
+    args.config_dir = Some(config_dir.clone());
+    args.data_dir = Some(data_dir);
+    args.state_dir = Some(state_dir);
+
     args.config_file = if let Some(config_file) = args.config_file {
-        Some(config_file.clone().replace('~', &home))
+        Some(paths::expand(&config_file)?)
     } else {
-        Some(config_dir.to_string() + "/config.toml")
+        Some(paths::join(&config_dir, "config.toml"))
     };
 
-    if args.config {
-        if let Some(config_file) = args.config_file {
-            println!("{}", config_file);
-        } else {
-            println!("{}/config.toml", args.config_dir.unwrap());
+    let mut message = None;
+    let mut diff = None;
+    let mut agent = None;
+
+    let chat = match command {
+        Command::Ask(_) => false,
+        Command::Chat(_) => true,
+        Command::Fim(_) => {
+            args.api.get_or_insert(Api::MistralFim);
+            false
         }
-        return Ok(());
+        Command::Commit { commit, .. } => {
+            message = commit::prepare(&mut args, commit)?;
+            false
+        }
+        Command::Review { diff: options, .. } => {
+            diff = Some(review::prepare(&mut args, review::Mode::Review, options)?);
+            false
+        }
+        Command::Pr { diff: options, .. } => {
+            diff = Some(review::prepare(&mut args, review::Mode::Pr, options)?);
+            false
+        }
+        Command::Agent { name, .. } => {
+            agent = Some(name);
+            false
+        }
+        Command::Config(command) => return config::run(command, args),
+        Command::Templates(command) => return templates::run(command, args),
+        Command::Presets(command) => return presets::run(command, args),
+        Command::Alias(command) => return alias::run(command, args),
+        Command::Audit(command) => return audit::run(command, args),
+        Command::Conversations(command) => return cache::run(command, args),
+        Command::Models {
+            available,
+            capability,
+            info,
+        } => return models::run(available, capability, info, args),
+        Command::Keys(command) => return keys::run(command, args.profile.as_deref()),
+        Command::Usage { by, since } => return ledger::run(by, since, args),
+        Command::Migrate { dry_run } => return schema::run(dry_run, args),
+        Command::SelfUpdate { check, yes } => return self_update::run(check, yes),
+        Command::Serve { host, port, token } => return serve::run(&host, port, token, args),
+        Command::Daemon => return daemon::run(args),
+        Command::Index {
+            paths,
+            name,
+            embedding_model,
+            chunk_tokens,
+        } => return rag::index(&paths, &name, embedding_model, chunk_tokens, args),
+    };
+
+    if let Some(format) = args.export {
+        let data_dir = args.data_dir.clone().expect("can't find data directory");
+        let id = cache::resolve(&data_dir, args.from.clone())?;
+
+        return export::run(&id, format, args.output.clone(), &args);
     }
 
-    if args.dir {
-        println!("{}", args.config_dir.unwrap());
-        return Ok(());
+    let patch = patch::prepare(&mut args)?;
+
+    if args.history {
+        match history::pick(&args)? {
+            Some(prompt) => args.prompt = Some(prompt),
+            None => return Ok(()),
+        }
     }
 
     let (args, config) = build_config(args)?;
 
-    log::info!("config: {:#?}", config);
+    log::info!("config: {:#?}", config.redacted());
+
+    let args = match &agent {
+        Some(name) => agent::apply(args, &config, name)?,
+        None => args,
+    };
 
     let (args, config) = parse_args(args, config)?;
 
-    log::info!("parsed args: {:#?}", args);
+    // Prompts read from stdin aren't recorded, they are usually piped text rather than something
+    // typed.
+    let typed = args.stdin.is_some();
 
-    let args = merge_args_and_cache(args)?;
+    budget::check(&args, &config)?;
 
-    log::info!("merged args and cache: {:#?}", args);
+    let args = files::attach(args)?;
 
-    if args.list {
-        return list(args);
+    let (args, sources) = rag::attach(args, &config)?;
+
+    let args = redact::apply(args, &config)?;
+
+    if args.show_redactions {
+        return Ok(());
     }
 
-    if args.show {
-        return show(args);
+    if typed {
+        history::record(&args, &config);
     }
 
-    let args = merge_args_and_config(args, config)?;
+    log::info!("parsed args: {:#?}", args.redacted());
 
-    log::info!("merged args and config: {:#?}", args);
+    let args = merge_args_and_cache(args)?;
+
+    if let Some(path) = args.batch.clone() {
+        return batch::run(args, &path, &config).await;
+    }
+
+    let args = router::route(args, &config)?;
+
+    log::info!("merged args and cache: {:#?}", args.redacted());
+
+    if chat {
+        return tui::run(args, config);
+    }
+
+    let args = if args.regenerate {
+        cache::regenerate(args)?
+    } else if args.continue_generation {
+        cache::continue_generation(args)?
+    } else {
+        args
+    };
+
+    let args = match args.edit_conversation {
+        Some(format) => cache::edit(args, format)?,
+        None => args,
+    };
+
+    let args = pipeline::run(args, &config).await?;
+
+    let args = match diff {
+        Some(diff) => diff.run(args, &config).await?,
+        None => args,
+    };
+
+    let tools = config.tools.clone().unwrap_or_default();
+
+    let args = merge_args_and_config(args, config.clone())?;
+
+    log::info!("merged args and config: {:#?}", args.redacted());
+
+    if args.count_tokens {
+        return tokens::count(args);
+    }
+
+    let args = prepare(args, &config).await?;
 
     if args.print_conversation {
         let json = serde_json::to_string_pretty(&args.conversation)?;
@@ -87,15 +280,36 @@ async fn main() -> Result<()> {
     }
 
     if args.dry_run {
-        return Ok(());
+        return print_request(args, &tools);
+    }
+
+    if let Some(compare) = args.compare.clone() {
+        return compare::run(args, &compare, &config).await;
+    }
+
+    let filter = args.filter;
+    let notices = args.shows_notices();
+    let theme = match &patch {
+        Some(_) if !args.no_color => Some(theme::resolve(&args)),
+        _ => None,
+    };
+
+    send(args, &tools, &config).await?;
+
+    if !sources.is_empty() && !filter && notices {
+        eprintln!("\nSources:");
+
+        for source in &sources {
+            eprintln!("  {}", source);
+        }
+    }
+
+    if let Some(patch) = patch {
+        patch::apply(patch, theme.as_deref())?;
     }
 
-    match args.api {
-        Some(Api::OpenAi) => openai::run(args).await,
-        Some(Api::Anthropic) => anthropic::run(args).await,
-        Some(Api::Google) => google::run(args).await,
-        Some(Api::Mistral) => mistral::run(args).await,
-        Some(Api::MistralFim) => mistral_fim::run(args).await,
-        None => Err(Error::ApiNotSpecified),
+    match message {
+        Some(message) => commit::commit(message),
+        None => Ok(()),
     }
 }
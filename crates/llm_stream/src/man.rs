@@ -0,0 +1,210 @@
+use clap::CommandFactory;
+use serde_json::Value;
+use std::io::Write;
+
+use crate::prelude::*;
+
+/// Descriptions of the configuration keys that don't have a matching flag.
+const TABLES: &[(&str, &str)] = &[
+    (
+        "apis",
+        "Tables named after each api, like [apis.anthropic], with the model, base_url, env, key, key_cmd, version, and headers used with that api.",
+    ),
+    (
+        "presets",
+        "List of named sets of api, model, and model options, selected with --preset.",
+    ),
+    (
+        "templates",
+        "List of prompt templates, selected with --template. Templates are also read from the templates directory.",
+    ),
+    (
+        "template_functions",
+        "Table with read_file, env, and shell, which allow the templates to read files, the listed environment variables, and the output of the listed commands.",
+    ),
+    (
+        "tools",
+        "List of commands the model can call when enabled with --tool, with their name, description, JSON schema parameters, command, and whether to confirm before running them.",
+    ),
+    (
+        "compaction",
+        "Table with the max_tokens, keep_messages, and preset used to summarize long conversations.",
+    ),
+];
+
+/// Section describing the template syntax.
+const TEMPLATES: &str = r#".SH TEMPLATES
+Templates are rendered with Tera (https://keats.github.io/tera/docs/). The prompt is rendered
+from the \fBtemplate\fR field and the system message from the \fBsystem\fR field.
+.PP
+These variables are available inside every template:
+.TP
+\fBprompt\fR
+The prompt given as an argument.
+.TP
+\fBstdin\fR
+The text read from stdin.
+.TP
+\fBsuffix\fR
+The value of \-\-suffix.
+.TP
+\fBlanguage\fR
+The value of \-\-language.
+.PP
+Other variables come from the \fBdefault_vars\fR table of the template, overridden by the JSON
+object given to \-\-vars.
+"#;
+
+/// Escapes text to be used inside a roff paragraph.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+
+    if text.starts_with(['.', '\'']) {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+/// Renders the configuration keys, documented with the help of the flag they set.
+fn configuration(command: &clap::Command) -> Result<String> {
+    let keys = match serde_json::to_value(Config::default())? {
+        Value::Object(map) => map.keys().cloned().collect::<Vec<String>>(),
+        _ => Vec::new(),
+    };
+
+    let mut section = String::from(
+        ".SH CONFIGURATION\nThe configuration is read from \\fIconfig.toml\\fR inside the config directory. Flags override its values.\n",
+    );
+
+    for key in keys {
+        let flag = key.replace('_', "-");
+        let help = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(flag.as_str())
+                    || arg.get_long() == Some(format!("api-{}", flag).as_str())
+            })
+            .and_then(|arg| arg.get_help())
+            .map(|help| help.to_string())
+            .or_else(|| {
+                TABLES
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, help)| help.to_string())
+            })
+            .unwrap_or_default();
+
+        section.push_str(&format!(
+            ".TP\n\\fB{}\\fR\n{}\n",
+            escape(&key),
+            escape(&help)
+        ));
+    }
+
+    Ok(section)
+}
+
+/// Renders the man page of `command` with `clap_mangen`.
+fn render_command(command: &clap::Command) -> Result<String> {
+    let mut buffer = Vec::new();
+
+    clap_mangen::Man::new(command.clone()).render(&mut buffer)?;
+
+    Ok(String::from_utf8_lossy(&buffer).to_string())
+}
+
+/// Renders the man page of `llm-stream`.
+fn render() -> Result<String> {
+    let command = Cli::command();
+    let mut page = render_command(&command)?;
+
+    page.push_str(&configuration(&command)?);
+    page.push_str(TEMPLATES);
+
+    Ok(page)
+}
+
+/// Renders the pages of the subcommands of `command`, and of theirs, each named after its parents
+/// like `llm-stream-conversations-list`, along with their file names.
+fn subcommand_pages(command: &clap::Command) -> Result<Vec<(String, String)>> {
+    let mut pages = Vec::new();
+
+    for subcommand in command
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+    {
+        let name = format!("{}-{}", command.get_name(), subcommand.get_name());
+        let subcommand = subcommand.clone().name(name.clone());
+
+        pages.push((format!("{}.1", name), render_command(&subcommand)?));
+        pages.extend(subcommand_pages(&subcommand)?);
+    }
+
+    Ok(pages)
+}
+
+/// Renders the page of `llm-stream` and of every subcommand, along with their file names.
+fn pages() -> Result<Vec<(String, String)>> {
+    let mut command = Cli::command();
+    // Propagates the global options to the subcommands.
+    command.build();
+
+    let mut pages = vec![(format!("{}.1", command.get_name()), render()?)];
+    pages.extend(subcommand_pages(&command)?);
+
+    Ok(pages)
+}
+
+/// Prints the man page of `llm-stream`, generated from its arguments, to stdout. With `dir`, the
+/// pages of `llm-stream` and of every subcommand are written in it instead.
+pub fn generate(dir: Option<&str>) -> Result<()> {
+    let Some(dir) = dir else {
+        std::io::stdout().write_all(render()?.as_bytes())?;
+        return Ok(());
+    };
+
+    let dir = std::path::Path::new(dir);
+    std::fs::create_dir_all(dir)?;
+
+    for (file, page) in pages()? {
+        std::fs::write(dir.join(file), page)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_documents_configuration_keys(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let page = render()?;
+
+        assert!(page.contains(".SH CONFIGURATION"));
+        assert!(page.contains("\\fBmodel\\fR\nThe LLM Model to use"));
+        assert!(page.contains(".SH TEMPLATES"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pages_cover_the_subcommands() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let pages = pages()?;
+        let page = |file: &str| {
+            pages
+                .iter()
+                .find(|(name, _)| name == file)
+                .map(|(_, page)| page.as_str())
+        };
+
+        assert!(page("llm-stream.1").is_some_and(|page| page.contains(".SH CONFIGURATION")));
+        assert!(page("llm-stream-chat.1").is_some_and(|page| page.contains(".SH NAME")));
+        assert!(page("llm-stream-conversations-list.1").is_some());
+        assert!(page("llm-stream-help.1").is_none());
+
+        Ok(())
+    }
+}
@@ -6,18 +6,12 @@ const DEFAULT_URL: &str = "https://api.mistral.ai/v1";
 const DEFAULT_MODEL: &str = "codestral-2405";
 const DEFAULT_ENV: &str = "MISTRAL_API_KEY";
 
-pub async fn run(mut args: Args) -> Result<()> {
-    let key = match args.api_key.take() {
-        Some(key) => key,
-        None => {
-            let environment_variable = match args.api_env.take() {
-                Some(env) => env,
-                None => DEFAULT_ENV.to_string(),
-            };
-            std::env::var(environment_variable)?
-        }
-    };
-    log::info!("key: {}", key);
+/// Builds the `mistral_fim` client and request body from the arguments.
+fn prepare(
+    mut args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<(mistral_fim::Client, mistral_fim::MessageBody, Args)> {
+    let key = crate::keys::resolve(&mut args, Api::MistralFim, DEFAULT_ENV)?;
 
     let url = match args.api_base_url.take() {
         Some(url) => url,
@@ -28,11 +22,14 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let auth = mistral_fim::Auth::new(key);
 
-    log::info!("auth: {:#?}", auth);
-
-    let client = mistral_fim::Client::new(auth, url);
-
-    log::info!("client: {:#?}", client);
+    let client = args.headers.iter().fold(
+        mistral_fim::Client::new(auth, url),
+        |client, (name, value)| client.header(name, value),
+    );
+    let client = args
+        .extra_query
+        .iter()
+        .fold(client, |client, (name, value)| client.query(name, value));
 
     let prompt = args
         .conversation
@@ -44,9 +41,8 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let mut body = mistral_fim::MessageBody::new(
         args.model
-            .take()
-            .unwrap_or(DEFAULT_MODEL.to_string())
-            .as_ref(),
+            .get_or_insert_with(|| DEFAULT_MODEL.to_string())
+            .as_str(),
         prompt,
         args.suffix.take(),
     );
@@ -62,7 +58,58 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     log::info!("body: {:#?}", body);
 
-    let stream = client.delta(&body)?;
+    let client = request_hooks(&args, &body, tools)?
+        .into_iter()
+        .fold(client, |client, hook| client.on_request(hook));
+
+    Ok((client, body, args))
+}
+
+/// Streams the LLM response to the terminal, returning the answer when `--auto-continue` has to
+/// continue it.
+pub async fn run(args: Args) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::MistralFim, metadata.clone()))
+        .on_headers(crate::report::headers_hook(
+            Api::MistralFim,
+            metadata.clone(),
+        ));
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Returns the LLM response without printing it, calling `on_delta` with every chunk of text and
+/// filling `metadata` with the finish reason and usage of the response.
+pub async fn complete(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let client = client
+        .on_event(crate::report::hook(Api::MistralFim, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::MistralFim, metadata));
+    let stream = open_stream(|| client.delta(&body), true, Timeouts::new(&args)).await?;
+
+    collect_stream(stream, on_delta).await
+}
+
+/// Returns the request `run` sends, without sending it.
+pub fn request(
+    args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<llm_stream::request::Request> {
+    let (client, body, _) = prepare(args, tools)?;
 
-    handle_stream(stream, args).await
+    Ok(client.request(&body)?)
 }
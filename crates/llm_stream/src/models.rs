@@ -0,0 +1,814 @@
+use clap::ValueEnum;
+use cli_table::{format::Justify, Color, Table, WithTitle};
+use serde::{Deserialize, Serialize};
+
+use crate::args::Capability;
+use crate::prelude::*;
+use crate::report::PRICES;
+
+/// Version sent to the Anthropic models endpoint.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// How long the models listed by an api are trusted by `validate_model` before asking again.
+const LIST_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Models known to each api when this version was released. `validate_model` checks against them
+/// when the api can't be asked for its models.
+const REGISTRY: &[(Api, &str)] = &[
+    (Api::Anthropic, "claude-3-5-sonnet-latest"),
+    (Api::Anthropic, "claude-3-5-sonnet-20241022"),
+    (Api::Anthropic, "claude-3-5-sonnet-20240620"),
+    (Api::Anthropic, "claude-3-5-haiku-latest"),
+    (Api::Anthropic, "claude-3-5-haiku-20241022"),
+    (Api::Anthropic, "claude-3-opus-latest"),
+    (Api::Anthropic, "claude-3-opus-20240229"),
+    (Api::Anthropic, "claude-3-sonnet-20240229"),
+    (Api::Anthropic, "claude-3-haiku-20240307"),
+    (Api::OpenAi, "gpt-4o"),
+    (Api::OpenAi, "gpt-4o-2024-08-06"),
+    (Api::OpenAi, "gpt-4o-2024-05-13"),
+    (Api::OpenAi, "gpt-4o-mini"),
+    (Api::OpenAi, "gpt-4o-mini-2024-07-18"),
+    (Api::OpenAi, "gpt-4-turbo"),
+    (Api::OpenAi, "gpt-4"),
+    (Api::OpenAi, "gpt-3.5-turbo"),
+    (Api::OpenAi, "o1"),
+    (Api::OpenAi, "o1-preview"),
+    (Api::OpenAi, "o1-mini"),
+    (Api::Google, "gemini-1.5-pro"),
+    (Api::Google, "gemini-1.5-pro-latest"),
+    (Api::Google, "gemini-1.5-flash"),
+    (Api::Google, "gemini-1.5-flash-latest"),
+    (Api::Google, "gemini-1.5-flash-8b"),
+    (Api::Google, "gemini-2.0-flash-exp"),
+    (Api::Mistral, "mistral-large-latest"),
+    (Api::Mistral, "mistral-small-latest"),
+    (Api::Mistral, "open-mistral-nemo"),
+    (Api::Mistral, "codestral-latest"),
+    (Api::Mistral, "pixtral-large-latest"),
+    (Api::Mistral, "ministral-8b-latest"),
+    (Api::Mistral, "ministral-3b-latest"),
+    (Api::MistralFim, "codestral-latest"),
+    (Api::MistralFim, "codestral-2405"),
+];
+
+/// Model as printed by the `models` command.
+#[derive(Debug, Serialize, Table)]
+struct ModelLine {
+    #[table(title = "Model", justify = "Justify::Left", color = "Color::Cyan")]
+    model: String,
+    #[table(
+        title = "Input ($/M)",
+        justify = "Justify::Right",
+        display_fn = "display_price"
+    )]
+    input: f64,
+    #[table(
+        title = "Output ($/M)",
+        justify = "Justify::Right",
+        display_fn = "display_price"
+    )]
+    output: f64,
+}
+
+fn display_price(price: &f64) -> String {
+    format!("${:.3}", price)
+}
+
+/// Model offered by an api, as printed by `models --available`.
+#[derive(Debug, Serialize, Table)]
+struct AvailableLine {
+    #[table(title = "Api", justify = "Justify::Left")]
+    api: String,
+    #[table(title = "Model", justify = "Justify::Left", color = "Color::Cyan")]
+    model: String,
+    #[table(
+        title = "Capabilities",
+        justify = "Justify::Left",
+        display_fn = "display_capabilities"
+    )]
+    capabilities: Vec<Capability>,
+}
+
+fn display_capabilities(capabilities: &[Capability]) -> String {
+    capabilities
+        .iter()
+        .map(Capability::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Details of a model, as printed by `models --info`.
+#[derive(Debug, Default, Serialize)]
+struct ModelInfo {
+    api: String,
+    model: String,
+    name: Option<String>,
+    description: Option<String>,
+    context_window: Option<usize>,
+    output_limit: Option<usize>,
+    methods: Vec<String>,
+    input_price: Option<f64>,
+    output_price: Option<f64>,
+}
+
+impl std::fmt::Display for ModelInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tokens = |tokens: Option<usize>| {
+            tokens.map_or("unknown".to_string(), |tokens| format!("{} tokens", tokens))
+        };
+        let price = |price: Option<f64>| price.map_or("unknown".to_string(), |p| display_price(&p));
+
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(f, "Api: {}", self.api)?;
+        if let Some(name) = &self.name {
+            writeln!(f, "Name: {}", name)?;
+        }
+        if let Some(description) = &self.description {
+            writeln!(f, "Description: {}", description)?;
+        }
+        writeln!(f, "Context window: {}", tokens(self.context_window))?;
+        writeln!(f, "Output limit: {}", tokens(self.output_limit))?;
+        if !self.methods.is_empty() {
+            writeln!(f, "Methods: {}", self.methods.join(", "))?;
+        }
+        writeln!(f, "Input ($/M): {}", price(self.input_price))?;
+        write!(f, "Output ($/M): {}", price(self.output_price))
+    }
+}
+
+/// Model of the OpenAI, Anthropic, and Mistral `/models` responses.
+#[derive(Debug, Deserialize)]
+struct ListedModel {
+    id: String,
+    #[serde(default)]
+    capabilities: Option<MistralCapabilities>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MistralCapabilities {
+    #[serde(default)]
+    completion_chat: bool,
+    #[serde(default)]
+    completion_fim: bool,
+    #[serde(default)]
+    function_calling: bool,
+    #[serde(default)]
+    vision: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListResponse {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleModel {
+    name: String,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleResponse {
+    #[serde(default)]
+    models: Vec<GoogleModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    #[serde(default)]
+    models: Vec<OllamaModel>,
+}
+
+/// Parts of the ids of the OpenAI models that start like chat models but aren't.
+const NOT_CHAT: &[&str] = &[
+    "audio",
+    "realtime",
+    "tts",
+    "transcribe",
+    "instruct",
+    "image",
+    "search",
+    "moderation",
+];
+
+/// OpenAI doesn't list what its models support, so it's guessed from their ids.
+fn openai_capabilities(id: &str) -> Vec<Capability> {
+    if id.contains("embedding") {
+        return vec![Capability::Embeddings];
+    }
+
+    let is_chat = (id.starts_with("gpt-") || id.starts_with('o') || id.starts_with("chatgpt-"))
+        && !NOT_CHAT.iter().any(|kind| id.contains(kind));
+
+    if !is_chat {
+        return Vec::new();
+    }
+
+    let mut capabilities = vec![Capability::Chat, Capability::Tools];
+
+    if !id.starts_with("gpt-3.5") && id != "gpt-4" && !id.starts_with("gpt-4-0") {
+        capabilities.push(Capability::Vision);
+    }
+
+    capabilities
+}
+
+fn mistral_capabilities(model: &ListedModel) -> Vec<Capability> {
+    if model.id.contains("embed") {
+        return vec![Capability::Embeddings];
+    }
+
+    let Some(listed) = &model.capabilities else {
+        return vec![Capability::Chat];
+    };
+
+    [
+        (listed.completion_chat, Capability::Chat),
+        (listed.completion_fim, Capability::Fim),
+        (listed.function_calling, Capability::Tools),
+        (listed.vision, Capability::Vision),
+    ]
+    .into_iter()
+    .filter_map(|(supported, capability)| supported.then_some(capability))
+    .collect()
+}
+
+fn google_capabilities(model: &GoogleModel) -> Vec<Capability> {
+    let supports = |method: &str| {
+        model
+            .supported_generation_methods
+            .iter()
+            .any(|m| m == method)
+    };
+    let mut capabilities = Vec::new();
+
+    if supports("generateContent") {
+        capabilities.push(Capability::Chat);
+
+        if model.name.contains("gemini") {
+            capabilities.extend([Capability::Tools, Capability::Vision]);
+        }
+    }
+
+    if supports("embedContent") {
+        capabilities.push(Capability::Embeddings);
+    }
+
+    capabilities
+}
+
+/// Parses the models listing of `api` into the ids and capabilities of its models.
+fn parse(api: Api, body: &str) -> Result<Vec<(String, Vec<Capability>)>> {
+    let models = match api {
+        Api::Google => serde_json::from_str::<GoogleResponse>(body)?
+            .models
+            .into_iter()
+            .map(|model| {
+                let capabilities = google_capabilities(&model);
+                let id = model.name.trim_start_matches("models/").to_string();
+
+                (id, capabilities)
+            })
+            .collect(),
+        api => serde_json::from_str::<ListResponse>(body)?
+            .data
+            .into_iter()
+            .map(|model| {
+                let capabilities = match api {
+                    Api::Anthropic => {
+                        vec![Capability::Chat, Capability::Tools, Capability::Vision]
+                    }
+                    Api::Mistral | Api::MistralFim => mistral_capabilities(&model),
+                    _ => openai_capabilities(&model.id),
+                };
+
+                (model.id, capabilities)
+            })
+            .collect(),
+    };
+
+    Ok(models)
+}
+
+fn parse_ollama(body: &str) -> Result<Vec<(String, Vec<Capability>)>> {
+    Ok(serde_json::from_str::<OllamaResponse>(body)?
+        .models
+        .into_iter()
+        .map(|model| {
+            let capability = if model.name.contains("embed") {
+                Capability::Embeddings
+            } else {
+                Capability::Chat
+            };
+
+            (model.name, vec![capability])
+        })
+        .collect())
+}
+
+/// Returns the default url and key environment variable of `api`, or `None` for Ollama, which
+/// runs locally without a key.
+fn defaults(api: Api) -> Option<(&'static str, &'static str)> {
+    match api {
+        Api::OpenAi => Some((crate::openai::DEFAULT_URL, crate::openai::DEFAULT_ENV)),
+        Api::Anthropic => Some((crate::anthropic::DEFAULT_URL, crate::anthropic::DEFAULT_ENV)),
+        Api::Google => Some((crate::google::DEFAULT_URL, crate::google::DEFAULT_ENV)),
+        Api::Mistral | Api::MistralFim => {
+            Some((crate::mistral::DEFAULT_URL, crate::mistral::DEFAULT_ENV))
+        }
+        Api::OllamaFim => None,
+    }
+}
+
+/// Returns the arguments that `api` is asked with. The key and url come from the `[apis.<api>]`
+/// section, or from the options given before `models` when it's the api of `--api`.
+fn list_args(api: Api, cli: &Args, config: &Config) -> Args {
+    let mut args = api_args(api, config);
+
+    if cli.api == Some(api) {
+        args.api_base_url = cli.api_base_url.clone().or(args.api_base_url);
+        args.api_env = cli.api_env.clone().or(args.api_env);
+        args.api_key = cli.api_key.clone().or(args.api_key);
+        args.key_cmd = cli.key_cmd.clone().or(args.key_cmd);
+        args.headers.extend(cli.headers.iter().cloned());
+    }
+
+    args
+}
+
+/// Asks `api` for the models it offers.
+fn list(api: Api, cli: &Args, config: &Config) -> Result<Vec<(String, Vec<Capability>)>> {
+    let Some((url, env)) = defaults(api) else {
+        return list_ollama().ok_or_else(|| Error::Models(api.to_string()));
+    };
+    let mut args = list_args(api, cli, config);
+
+    let key = crate::keys::resolve(&mut args, api, env)?;
+    let url = format!("{}/models", args.api_base_url.unwrap_or(url.to_string()));
+
+    let request = match api {
+        Api::Google => ureq::get(&url)
+            .query("pageSize", "1000")
+            .set("x-goog-api-key", &key),
+        Api::Anthropic => ureq::get(&url)
+            .query("limit", "1000")
+            .set("x-api-key", &key)
+            .set("anthropic-version", ANTHROPIC_VERSION),
+        _ => ureq::get(&url).set("Authorization", &format!("Bearer {}", key)),
+    };
+
+    let request = args
+        .extra_query
+        .iter()
+        .fold(request, |request, (name, value)| request.query(name, value));
+
+    let body = args
+        .headers
+        .iter()
+        .fold(request, |request, (name, value)| request.set(name, value))
+        .call()
+        .map_err(|e| Error::Models(format!("{}: {}", api, e)))?
+        .into_string()?;
+
+    parse(api, &body)
+}
+
+/// Asks the local Ollama server for its models, or returns `None` if it isn't running.
+fn list_ollama() -> Option<Vec<(String, Vec<Capability>)>> {
+    let body = ureq::AgentBuilder::new()
+        .timeout_connect(std::time::Duration::from_secs(1))
+        .build()
+        .get(&format!("{}/api/tags", crate::ollama_fim::host()))
+        .call()
+        .ok()?
+        .into_string()
+        .ok()?;
+
+    match parse_ollama(&body) {
+        Ok(models) => Some(models),
+        Err(e) => {
+            log::warn!("unable to read the Ollama models: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Prints the models each api offers. With `--api`, only that api is asked and its errors are
+/// returned; otherwise the apis without a key, or that fail, are skipped with a warning.
+fn available(capability: Option<Capability>, args: Args) -> Result<()> {
+    let (args, config) = build_config(args)?;
+
+    let mut models = Vec::new();
+
+    if let Some(api) = args.api {
+        models.extend(
+            list(api, &args, &config)?
+                .into_iter()
+                .map(|m| (api.to_string(), m)),
+        );
+    } else {
+        for api in [Api::OpenAi, Api::Anthropic, Api::Google, Api::Mistral] {
+            match list(api, &args, &config) {
+                Ok(listed) => models.extend(listed.into_iter().map(|m| (api.to_string(), m))),
+                Err(Error::EnvVar(_)) => log::info!("skipping {}: no key", api),
+                Err(e) => eprintln!("Skipping {}: {}", api, e),
+            }
+        }
+
+        if let Some(listed) = list_ollama() {
+            models.extend(listed.into_iter().map(|m| ("ollama".to_string(), m)));
+        }
+    }
+
+    let mut lines = models
+        .into_iter()
+        .map(|(api, (model, mut capabilities))| {
+            capabilities.sort();
+            capabilities.dedup();
+
+            AvailableLine {
+                api,
+                model,
+                capabilities,
+            }
+        })
+        .filter(|line| capability.map_or(true, |c| line.capabilities.contains(&c)))
+        .collect::<Vec<AvailableLine>>();
+
+    lines.sort_by(|a, b| (&a.api, &a.model).cmp(&(&b.api, &b.model)));
+    lines.dedup_by(|a, b| a.api == b.api && a.model == b.model);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&lines)?);
+        return Ok(());
+    }
+
+    let table = if atty::is(atty::Stream::Stdout) {
+        lines.with_title()
+    } else {
+        lines.table()
+    };
+
+    print_table(table, &args)
+}
+
+/// Prints the details of the model of `--model`, or of the configuration. Google is asked for its
+/// context window, output limit, and supported methods; the other apis use the known ones.
+fn info(args: Args) -> Result<()> {
+    let (args, config) = build_config(args)?;
+
+    let api = args.api.or(config.api).unwrap_or_default();
+    let mut api_args = list_args(api, &args, &config);
+    let model = args
+        .model
+        .clone()
+        .or(api_args.model.take())
+        .or(config.model.clone())
+        .ok_or(Error::ModelNotSpecified)?;
+
+    let price = PRICES.iter().find(|(name, _, _)| model.starts_with(name));
+    let mut info = ModelInfo {
+        api: api.to_string(),
+        model: model.clone(),
+        context_window: crate::report::context_window(&model),
+        input_price: price.map(|(_, input, _)| *input),
+        output_price: price.map(|(_, _, output)| *output),
+        ..Default::default()
+    };
+
+    if api == Api::Google {
+        let details = crate::google::model(Args {
+            model: Some(model),
+            ..api_args
+        })?;
+
+        info.name = Some(details.display_name).filter(|name| !name.is_empty());
+        info.description = Some(details.description).filter(|d| !d.is_empty());
+        info.context_window = Some(details.input_token_limit as usize);
+        info.output_limit = Some(details.output_token_limit as usize);
+        info.methods = details.supported_generation_methods;
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("{}", info);
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the file with the models last listed by `api`.
+fn list_file(state_dir: &str, api: Api) -> String {
+    format!("{}/models-{}.json", state_dir, api)
+}
+
+/// Returns the models of the registry and of the lists last saved for each api, sorted and
+/// without repeats. They complete `--model` without asking the apis.
+pub fn known_ids(state_dir: &str) -> Vec<String> {
+    let listed = Api::value_variants()
+        .iter()
+        .filter_map(|api| std::fs::read_to_string(list_file(state_dir, *api)).ok())
+        .filter_map(|contents| serde_json::from_str::<Vec<String>>(&contents).ok())
+        .flatten();
+
+    let mut ids = REGISTRY
+        .iter()
+        .map(|(_, id)| id.to_string())
+        .chain(listed)
+        .collect::<Vec<String>>();
+
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Returns the ids of the models of `api`, listed at most a day ago, or `None` when it can't be
+/// asked for them.
+fn listed_ids(api: Api, args: &Args, config: &Config) -> Option<Vec<String>> {
+    let path = list_file(&args.state_dir.clone()?, api);
+
+    let fresh = std::fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < LIST_TTL);
+
+    if fresh {
+        if let Some(ids) = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+        {
+            return Some(ids);
+        }
+    }
+
+    let ids = match list(api, args, config) {
+        Ok(models) => models
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<String>>(),
+        Err(e) => {
+            log::warn!("unable to list the {} models: {:?}", api, e);
+            return None;
+        }
+    };
+
+    if let Err(e) = serde_json::to_string(&ids)
+        .map_err(Error::from)
+        .and_then(|contents| Ok(std::fs::write(&path, contents)?))
+    {
+        log::warn!("unable to save the {} models: {:?}", api, e);
+    }
+
+    Some(ids)
+}
+
+/// Returns the amount of single character edits that turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, cb) in b.iter().enumerate() {
+            current.push(
+                (previous[j] + usize::from(ca != *cb))
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1),
+            );
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Returns up to three of `ids` that look like `model`, closest first.
+fn suggestions<'a>(model: &str, ids: &'a [String]) -> Vec<&'a str> {
+    let mut close = ids
+        .iter()
+        .map(|id| (distance(model, id), id.as_str()))
+        .filter(|(distance, id)| *distance <= (model.len() / 6).max(2) || id.contains(model))
+        .collect::<Vec<(usize, &str)>>();
+
+    close.sort();
+    close.into_iter().take(3).map(|(_, id)| id).collect()
+}
+
+/// Returns true if `model` is one of `ids`. Untagged Ollama models, like `qwen2.5-coder`, are
+/// listed with their `:latest` tag.
+fn known(model: &str, ids: &[String]) -> bool {
+    ids.iter()
+        .any(|id| id == model || id.strip_suffix(":latest") == Some(model))
+}
+
+/// Checks `--model` against the models its api lists, or the bundled registry when the api can't
+/// be asked, when `validate_model` is set. Models missing from the registry only fail when they
+/// look like one of its models, since it doesn't know the ones released after this version. The
+/// aliases of the registry, like `claude-3-5-sonnet-latest`, are accepted even if the api doesn't
+/// list them.
+pub fn validate(args: &Args, config: &Config) -> Result<()> {
+    if !config.validate_model.unwrap_or(false) {
+        return Ok(());
+    }
+
+    let (Some(api), Some(model)) = (args.api, args.model.as_deref()) else {
+        return Ok(());
+    };
+
+    let registry = REGISTRY
+        .iter()
+        .filter(|(registry_api, _)| *registry_api == api)
+        .map(|(_, id)| id.to_string());
+
+    let (ids, listed) = match listed_ids(api, args, config) {
+        Some(ids) => (
+            ids.into_iter().chain(registry).collect::<Vec<String>>(),
+            true,
+        ),
+        None => (registry.collect::<Vec<String>>(), false),
+    };
+
+    if known(model, &ids) {
+        return Ok(());
+    }
+
+    let suggestions = suggestions(model, &ids);
+
+    match (suggestions.is_empty(), listed) {
+        (true, false) => {
+            log::warn!("{} isn't in the {} models registry", model, api);
+            Ok(())
+        }
+        (true, true) => Err(Error::UnknownModel(format!(
+            "{} has no {} model, see `llm-stream --api {} models --available`",
+            api, model, api
+        ))),
+        (false, _) => Err(Error::UnknownModel(format!(
+            "{} has no {} model, did you mean {}?",
+            api,
+            model,
+            suggestions.join(", ")
+        ))),
+    }
+}
+
+/// Prints the models whose prices are known, used to estimate the cost of each request, or with
+/// `available`, the models each api offers, or with `info`, the details of a model.
+pub fn run(available: bool, capability: Option<Capability>, info: bool, args: Args) -> Result<()> {
+    if available {
+        return self::available(capability, args);
+    }
+
+    if info {
+        return self::info(args);
+    }
+
+    let lines = PRICES
+        .iter()
+        .map(|(model, input, output)| ModelLine {
+            model: model.to_string(),
+            input: *input,
+            output: *output,
+        })
+        .collect::<Vec<ModelLine>>();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&lines)?);
+        return Ok(());
+    }
+
+    let table = if atty::is(atty::Stream::Stdout) {
+        lines.with_title()
+    } else {
+        lines.table()
+    };
+
+    print_table(table, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions() {
+        let ids = REGISTRY
+            .iter()
+            .filter(|(api, _)| *api == Api::Anthropic)
+            .map(|(_, id)| id.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            suggestions("claude-3-5-sonet-latest", &ids),
+            vec!["claude-3-5-sonnet-latest"]
+        );
+        assert_eq!(
+            suggestions("haiku", &ids),
+            vec![
+                "claude-3-5-haiku-latest",
+                "claude-3-haiku-20240307",
+                "claude-3-5-haiku-20241022"
+            ]
+        );
+        assert!(suggestions("gpt-4o", &ids).is_empty());
+    }
+
+    #[test]
+    fn test_known() {
+        let ids = vec![
+            "claude-3-5-sonnet-latest".to_string(),
+            "qwen2.5-coder:latest".to_string(),
+            "qwen2.5-coder:7b".to_string(),
+        ];
+
+        assert!(known("claude-3-5-sonnet-latest", &ids));
+        assert!(known("qwen2.5-coder", &ids));
+        assert!(known("qwen2.5-coder:7b", &ids));
+        assert!(!known("qwen2.5-coder:14b", &ids));
+        assert!(!known("claude-3-5-sonnet", &ids));
+    }
+
+    #[test]
+    fn test_parse() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mistral = r#"{"data": [
+            {"id": "codestral-latest", "capabilities": {"completion_chat": true, "completion_fim": true, "function_calling": true, "vision": false}},
+            {"id": "mistral-embed", "capabilities": {"completion_chat": false}}
+        ]}"#;
+
+        assert_eq!(
+            parse(Api::Mistral, mistral)?,
+            vec![
+                (
+                    "codestral-latest".to_string(),
+                    vec![Capability::Chat, Capability::Fim, Capability::Tools]
+                ),
+                ("mistral-embed".to_string(), vec![Capability::Embeddings]),
+            ]
+        );
+
+        let google = r#"{"models": [
+            {"name": "models/gemini-1.5-pro", "supportedGenerationMethods": ["generateContent", "countTokens"]},
+            {"name": "models/text-embedding-004", "supportedGenerationMethods": ["embedContent"]}
+        ]}"#;
+
+        assert_eq!(
+            parse(Api::Google, google)?,
+            vec![
+                (
+                    "gemini-1.5-pro".to_string(),
+                    vec![Capability::Chat, Capability::Tools, Capability::Vision]
+                ),
+                (
+                    "text-embedding-004".to_string(),
+                    vec![Capability::Embeddings]
+                ),
+            ]
+        );
+
+        let openai = r#"{"data": [{"id": "gpt-4o-mini"}, {"id": "whisper-1"}]}"#;
+
+        assert_eq!(
+            parse(Api::OpenAi, openai)?,
+            vec![
+                (
+                    "gpt-4o-mini".to_string(),
+                    vec![Capability::Chat, Capability::Tools, Capability::Vision]
+                ),
+                ("whisper-1".to_string(), vec![]),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_info() {
+        let info = ModelInfo {
+            api: "google".to_string(),
+            model: "gemini-1.5-flash".to_string(),
+            context_window: Some(1_000_000),
+            methods: vec!["generateContent".to_string(), "countTokens".to_string()],
+            input_price: Some(0.075),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            info.to_string(),
+            "Model: gemini-1.5-flash\nApi: google\nContext window: 1000000 tokens\n\
+             Output limit: unknown\nMethods: generateContent, countTokens\n\
+             Input ($/M): $0.075\nOutput ($/M): unknown"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use llm_stream::ollama_fim;
+
+use crate::prelude::*;
+
+/// Ollama address used when `OLLAMA_HOST` isn't set.
+const DEFAULT_HOST: &str = "http://localhost:11434";
+const DEFAULT_MODEL: &str = "qwen2.5-coder";
+
+/// Returns the address of the local Ollama server, from `OLLAMA_HOST`.
+pub fn host() -> String {
+    let host = std::env::var("OLLAMA_HOST").unwrap_or(DEFAULT_HOST.to_string());
+    let host = if host.starts_with("http") {
+        host
+    } else {
+        format!("http://{}", host)
+    };
+
+    host.trim_end_matches('/').to_string()
+}
+
+/// Builds the `ollama_fim` client and request body from the arguments. Ollama runs locally, so
+/// it doesn't need a key.
+fn prepare(
+    mut args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<(ollama_fim::Client, ollama_fim::MessageBody, Args)> {
+    let url = match args.api_base_url.take() {
+        Some(url) => url,
+        None => host(),
+    };
+
+    log::info!("url: {}", url);
+
+    let client = args
+        .headers
+        .iter()
+        .fold(ollama_fim::Client::new(url), |client, (name, value)| {
+            client.header(name, value)
+        });
+    let client = args
+        .extra_query
+        .iter()
+        .fold(client, |client, (name, value)| client.query(name, value));
+
+    let prompt = args
+        .conversation
+        .iter()
+        .filter(|m| m.role == ConversationRole::User)
+        .map(|m| m.content.clone())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let mut body = ollama_fim::MessageBody::new(
+        args.model
+            .get_or_insert_with(|| DEFAULT_MODEL.to_string())
+            .as_str(),
+        prompt,
+        args.suffix.take(),
+    );
+
+    body.options = Some(ollama_fim::MessageBodyOptions {
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        num_predict: args.max_tokens.map(|tokens| tokens as i32),
+        ..Default::default()
+    });
+
+    log::info!("body: {:#?}", body);
+
+    let client = request_hooks(&args, &body, tools)?
+        .into_iter()
+        .fold(client, |client, hook| client.on_request(hook));
+
+    Ok((client, body, args))
+}
+
+/// Streams the LLM response to the terminal, returning the answer when `--auto-continue` has to
+/// continue it.
+pub async fn run(args: Args) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::OllamaFim, metadata.clone()))
+        .on_headers(crate::report::headers_hook(
+            Api::OllamaFim,
+            metadata.clone(),
+        ));
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Returns the LLM response without printing it, calling `on_delta` with every chunk of text and
+/// filling `metadata` with the finish reason and usage of the response.
+pub async fn complete(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let client = client
+        .on_event(crate::report::hook(Api::OllamaFim, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::OllamaFim, metadata));
+    let stream = open_stream(|| client.delta(&body), true, Timeouts::new(&args)).await?;
+
+    collect_stream(stream, on_delta).await
+}
+
+/// Returns the request `run` sends, without sending it.
+pub fn request(
+    args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<llm_stream::request::Request> {
+    let (client, body, _) = prepare(args, tools)?;
+
+    Ok(client.request(&body)?)
+}
@@ -2,9 +2,9 @@ use llm_stream::openai;
 
 use crate::prelude::*;
 
-const DEFAULT_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_URL: &str = "https://api.openai.com/v1";
 const DEFAULT_MODEL: &str = "gpt-4o";
-const DEFAULT_ENV: &str = "OPENAI_API_KEY";
+pub const DEFAULT_ENV: &str = "OPENAI_API_KEY";
 
 // From ConversationRole to openai::Role
 impl From<ConversationRole> for openai::Role {
@@ -17,18 +17,12 @@ impl From<ConversationRole> for openai::Role {
     }
 }
 
-pub async fn run(mut args: Args) -> Result<()> {
-    let key = match args.api_key.take() {
-        Some(key) => key,
-        None => {
-            let environment_variable = match args.api_env.take() {
-                Some(env) => env,
-                None => DEFAULT_ENV.to_string(),
-            };
-            std::env::var(environment_variable)?
-        }
-    };
-    log::info!("key: {}", key);
+/// Builds the `openai` client and request body from the arguments.
+fn prepare(
+    mut args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<(openai::Client, openai::MessageBody, Args)> {
+    let key = crate::keys::resolve(&mut args, Api::OpenAi, DEFAULT_ENV)?;
 
     let url = match args.api_base_url.take() {
         Some(url) => url,
@@ -37,13 +31,21 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     log::info!("url: {}", url);
 
-    let auth = openai::Auth::new(key);
-
-    log::info!("auth: {:#?}", auth);
+    // OpenAI compatible APIs may reject `stream_options`, so the usage is only asked to OpenAI.
+    let include_usage = url == DEFAULT_URL;
 
-    let client = openai::Client::new(auth, url);
+    let auth = openai::Auth::new(key);
 
-    log::info!("client: {:#?}", client);
+    let client = args
+        .headers
+        .iter()
+        .fold(openai::Client::new(auth, url), |client, (name, value)| {
+            client.header(name, value)
+        });
+    let client = args
+        .extra_query
+        .iter()
+        .fold(client, |client, (name, value)| client.query(name, value));
 
     let mut messages: Vec<openai::Message> = Default::default();
 
@@ -56,30 +58,91 @@ pub async fn run(mut args: Args) -> Result<()> {
 
     let mut body = openai::MessageBody::new(
         args.model
-            .take()
-            .unwrap_or(DEFAULT_MODEL.to_string())
-            .as_ref(),
+            .get_or_insert_with(|| DEFAULT_MODEL.to_string())
+            .as_str(),
         messages,
     );
 
-    if let Some(system) = args.system.take() {
-        let system_message = openai::Message {
-            role: openai::Role::System,
-            content: system,
-        };
-
-        body.messages.insert(0, system_message);
-    }
-
     body.temperature = args.temperature;
     body.top_p = args.top_p;
+    if include_usage {
+        body.stream_options = Some(openai::StreamOptions { include_usage });
+    }
     if let Some(max_tokens) = args.max_tokens {
         body.max_tokens = Some(max_tokens);
     };
 
     log::info!("body: {:#?}", body);
 
-    let stream = client.delta(&body)?;
+    let client = request_hooks(&args, &body, tools)?
+        .into_iter()
+        .fold(client, |client, hook| client.on_request(hook));
+
+    Ok((client, body, args))
+}
+
+/// Streams the LLM response to the terminal, returning the answer when `--auto-continue` has to
+/// continue it.
+pub async fn run(args: Args) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::OpenAi, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::OpenAi, metadata.clone()));
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Streams the LLM response to the terminal, advertising the tools of the session and collecting
+/// the calls of the response.
+pub async fn run_with_tools(args: Args, tools: &crate::tools::Session) -> Result<Option<Followup>> {
+    let (client, body, args) = prepare(args, Some(tools))?;
+
+    let metadata = SharedMetadata::default();
+    let client = client
+        .on_event(crate::report::hook(Api::OpenAi, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::OpenAi, metadata.clone()))
+        .on_event(tools.event_hook());
+
+    let stream = open_stream(
+        || client.delta(&body),
+        !args.shows_notices(),
+        Timeouts::new(&args),
+    );
+
+    handle_stream(stream, args, metadata).await
+}
+
+/// Returns the LLM response without printing it, calling `on_delta` with every chunk of text and
+/// filling `metadata` with the finish reason and usage of the response.
+pub async fn complete(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let (client, body, args) = prepare(args, None)?;
+
+    let client = client
+        .on_event(crate::report::hook(Api::OpenAi, metadata.clone()))
+        .on_headers(crate::report::headers_hook(Api::OpenAi, metadata));
+    let stream = open_stream(|| client.delta(&body), true, Timeouts::new(&args)).await?;
+
+    collect_stream(stream, on_delta).await
+}
+
+/// Returns the request `run` sends, without sending it.
+pub fn request(
+    args: Args,
+    tools: Option<&crate::tools::Session>,
+) -> Result<llm_stream::request::Request> {
+    let (client, body, _) = prepare(args, tools)?;
 
-    handle_stream(stream, args).await
+    Ok(client.request(&body)?)
 }
@@ -0,0 +1,435 @@
+use std::io::{IsTerminal, Write};
+
+use crate::prelude::*;
+
+/// Added to the system message with `--apply`.
+const SYSTEM: &str = "Answer only with a unified diff of the changes to the given files, in a \
+single ```diff code block. Start the changes of each file with `--- a/<path>` and `+++ b/<path>` \
+lines, using the path the file was given with, and give each hunk an `@@ -<line>,<count> \
++<line>,<count> @@` header and three lines of context around the changed lines.";
+
+/// Answer of an `--apply` run, and the files it can change.
+pub struct Patch {
+    path: String,
+    /// Whether the file was created by `--apply`, and has to be removed.
+    temporary: bool,
+    files: Vec<String>,
+    yes: bool,
+}
+
+/// Lines replaced by a hunk of a unified diff.
+#[derive(Debug, Default, PartialEq)]
+struct Hunk {
+    /// Line where the hunk starts in the original file, counting from 1.
+    start: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+/// Changes of a file in a unified diff.
+#[derive(Debug, PartialEq)]
+struct FileDiff {
+    path: String,
+    hunks: Vec<Hunk>,
+}
+
+/// Sets up `--apply`: adds the files to the prompt, asks for a unified diff of the changes, and
+/// writes the answer to a file, so it can be applied once it ends.
+pub fn prepare(args: &mut Args) -> Result<Option<Patch>> {
+    if args.apply.is_empty() {
+        return Ok(None);
+    }
+
+    for path in &args.apply {
+        if !std::path::Path::new(&crate::paths::expand(path)?).is_file() {
+            return Err(Error::Patch(format!("{} isn't a file", path)));
+        }
+    }
+
+    args.file.extend(args.apply.iter().cloned());
+    args.system.push(SYSTEM.to_string());
+
+    let temporary = args.output.is_none();
+    let path = args
+        .output
+        .get_or_insert_with(|| {
+            std::env::temp_dir()
+                .join(format!("llm-stream-apply-{}.diff", xid::new()))
+                .to_string_lossy()
+                .to_string()
+        })
+        .clone();
+
+    Ok(Some(Patch {
+        path,
+        temporary,
+        files: args.apply.clone(),
+        yes: args.yes,
+    }))
+}
+
+/// Returns the path of a `---` or `+++` line, without its timestamp.
+fn header_path(line: &str) -> &str {
+    line[4..].split('\t').next().unwrap_or_default().trim()
+}
+
+/// Returns the first line of the original file in a `@@ -<line>,<count> +<line>,<count> @@`
+/// header.
+fn hunk_start(line: &str) -> Option<usize> {
+    let old = line.strip_prefix("@@ -")?.split_whitespace().next()?;
+
+    old.split(',').next()?.parse().ok()
+}
+
+/// Parses the files and hunks of a unified diff. Lines outside of the hunks, like the `diff --git`
+/// and `index` lines, are skipped.
+fn parse(diff: &str) -> Result<Vec<FileDiff>> {
+    let lines = diff.lines().collect::<Vec<&str>>();
+    let mut files: Vec<FileDiff> = Vec::new();
+    let mut in_hunk = false;
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+
+        if line.starts_with("--- ") && lines.get(index).is_some_and(|l| l.starts_with("+++ ")) {
+            let path = header_path(lines[index]);
+            index += 1;
+
+            if path == "/dev/null" {
+                return Err(Error::Patch(format!(
+                    "the diff removes {}, which isn't supported",
+                    header_path(line)
+                )));
+            }
+
+            files.push(FileDiff {
+                path: path.to_string(),
+                hunks: Vec::new(),
+            });
+            in_hunk = false;
+            continue;
+        }
+
+        if line.starts_with("@@ ") {
+            let Some(file) = files.last_mut() else {
+                return Err(Error::Patch(
+                    "a hunk comes before the file names".to_string(),
+                ));
+            };
+            let start = hunk_start(line)
+                .ok_or_else(|| Error::Patch(format!("invalid hunk header: {}", line)))?;
+
+            file.hunks.push(Hunk {
+                start,
+                ..Default::default()
+            });
+            in_hunk = true;
+            continue;
+        }
+
+        let Some(hunk) = files.last_mut().and_then(|f| f.hunks.last_mut()) else {
+            continue;
+        };
+
+        if !in_hunk {
+            continue;
+        }
+
+        // Blank lines are taken as context, since the trailing space of context lines is often
+        // trimmed.
+        match line.chars().next() {
+            None => {
+                hunk.old.push(String::new());
+                hunk.new.push(String::new());
+            }
+            Some(' ') => {
+                hunk.old.push(line[1..].to_string());
+                hunk.new.push(line[1..].to_string());
+            }
+            Some('-') => hunk.old.push(line[1..].to_string()),
+            Some('+') => hunk.new.push(line[1..].to_string()),
+            Some('\\') => {}
+            Some(_) => in_hunk = false,
+        }
+    }
+
+    Ok(files)
+}
+
+/// Returns the lines of the original file where the hunk applies, the nearest to `hint` when
+/// there's more than one. Lines are compared without their trailing whitespace.
+fn find(lines: &[String], old: &[String], from: usize, hint: usize) -> Option<usize> {
+    if old.is_empty() {
+        return Some(hint.clamp(from, lines.len()));
+    }
+
+    (from..=lines.len().checked_sub(old.len())?)
+        .filter(|start| {
+            lines[*start..*start + old.len()]
+                .iter()
+                .zip(old)
+                .all(|(line, old)| line.trim_end() == old.trim_end())
+        })
+        .min_by_key(|start| start.abs_diff(hint))
+}
+
+/// Applies the hunks of a file to its contents, in order.
+fn apply_hunks(path: &str, contents: &str, hunks: &[Hunk]) -> Result<String> {
+    let mut lines = contents.lines().map(String::from).collect::<Vec<String>>();
+    let mut from = 0;
+    let mut offset: isize = 0;
+
+    for (n, hunk) in hunks.iter().enumerate() {
+        let hint = (hunk.start.saturating_sub(1) as isize + offset).max(0) as usize;
+        let start = find(&lines, &hunk.old, from, hint).ok_or_else(|| {
+            Error::Patch(format!(
+                "hunk {} of {} doesn't match the lines of the file",
+                n + 1,
+                path
+            ))
+        })?;
+
+        lines.splice(start..start + hunk.old.len(), hunk.new.iter().cloned());
+
+        from = start + hunk.new.len();
+        offset += hunk.new.len() as isize - hunk.old.len() as isize;
+    }
+
+    let mut patched = lines.join("\n");
+
+    if !lines.is_empty() && (contents.is_empty() || contents.ends_with('\n')) {
+        patched.push('\n');
+    }
+
+    Ok(patched)
+}
+
+/// Returns a path without its leading `./`.
+fn normalize(path: &str) -> &str {
+    path.strip_prefix("./").unwrap_or(path)
+}
+
+/// Returns the file given to `--apply` that a path of the diff refers to, which can have the `a/`
+/// or `b/` prefix of git.
+fn resolve<'a>(files: &'a [String], path: &str) -> Result<&'a String> {
+    let candidates = [
+        Some(normalize(path)),
+        path.strip_prefix("a/").map(normalize),
+        path.strip_prefix("b/").map(normalize),
+    ];
+
+    files
+        .iter()
+        .find(|file| candidates.contains(&Some(normalize(file))))
+        .ok_or_else(|| {
+            Error::Patch(format!(
+                "the diff changes {}, which wasn't given to --apply",
+                path
+            ))
+        })
+}
+
+/// Returns the diff of the answer: its `diff` code blocks, or the whole answer without a fence.
+fn diff_of(answer: &str) -> String {
+    let blocks = crate::extract::code_blocks(answer, Some("diff"));
+
+    if blocks.is_empty() {
+        crate::extract::unwrap_fence(answer)
+    } else {
+        blocks.join("\n")
+    }
+}
+
+/// Returns the changes of each file given to `--apply`, as its path, contents, and patched
+/// contents, checking that every hunk of the answer applies.
+fn changes(answer: &str, files: &[String]) -> Result<Vec<(String, String, String)>> {
+    let diffs = parse(&diff_of(answer))?;
+
+    if diffs.iter().all(|diff| diff.hunks.is_empty()) {
+        return Err(Error::Patch("the answer has no changes".to_string()));
+    }
+
+    let mut changes: Vec<(String, String, String)> = Vec::new();
+
+    for diff in diffs {
+        let path = resolve(files, &diff.path)?;
+
+        match changes.iter_mut().find(|(p, _, _)| *p == *path) {
+            Some((_, _, patched)) => *patched = apply_hunks(path, patched, &diff.hunks)?,
+            None => {
+                let contents = std::fs::read_to_string(crate::paths::expand(path)?)?;
+                let patched = apply_hunks(path, &contents, &diff.hunks)?;
+                changes.push((path.clone(), contents, patched));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Prints the unified diff between the contents of a file and its new ones, highlighted when
+/// `theme` is given.
+fn preview(path: &str, contents: &str, patched: &str, theme: Option<&str>) -> Result<()> {
+    let diff = similar::TextDiff::from_lines(contents, patched)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string();
+
+    match theme {
+        Some(theme) if std::io::stderr().is_terminal() => {
+            let output = crate::printer::CustomPrinter::new("diff", Some(theme))?
+                .input_from_bytes(diff.as_bytes())
+                .print()?;
+            eprint!("{}", output);
+        }
+        _ => eprint!("{}", diff),
+    }
+
+    std::io::stderr().flush()?;
+
+    Ok(())
+}
+
+/// Applies the diff of the answer, after previewing the changes and asking to write them, unless
+/// `--yes` was given.
+pub fn apply(patch: Patch, theme: Option<&str>) -> Result<()> {
+    let answer = std::fs::read_to_string(&patch.path)?;
+
+    if patch.temporary {
+        std::fs::remove_file(&patch.path)?;
+    }
+
+    let changes = changes(&answer, &patch.files)?;
+
+    eprintln!();
+    for (path, contents, patched) in &changes {
+        preview(path, contents, patched, theme)?;
+    }
+
+    if !patch.yes && !confirm("Apply the changes?")? {
+        eprintln!("The files weren't changed.");
+        return Ok(());
+    }
+
+    for (path, _, patched) in &changes {
+        std::fs::write(crate::paths::expand(path)?, patched)?;
+        eprintln!("Changed {}", path);
+    }
+
+    Ok(())
+}
+
+/// Replaces the `--overwrite` file at `path` with the answer, after previewing the changes and
+/// asking to write them, unless `yes` is set.
+pub fn overwrite(path: &str, answer: &str, theme: Option<&str>, yes: bool) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+
+    if contents == answer {
+        eprintln!("\n{} is unchanged.", path);
+        return Ok(());
+    }
+
+    eprintln!();
+    preview(path, &contents, answer, theme)?;
+
+    if !yes && !confirm(&format!("Overwrite {}?", path))? {
+        eprintln!("{} wasn't changed.", path);
+        return Ok(());
+    }
+
+    std::fs::write(path, answer)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &str = "fn main() {\n    println!(\"Hello\");\n}\n\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n";
+
+    #[test]
+    fn test_parse() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let diff = "diff --git a/src/main.rs b/src/main.rs\n--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,3 +1,3 @@\n fn main() {\n-    println!(\"Hello\");\n+    println!(\"Hello, world!\");\n }\n";
+
+        assert_eq!(
+            parse(diff)?,
+            vec![FileDiff {
+                path: "b/src/main.rs".to_string(),
+                hunks: vec![Hunk {
+                    start: 1,
+                    old: vec![
+                        "fn main() {".to_string(),
+                        "    println!(\"Hello\");".to_string(),
+                        "}".to_string(),
+                    ],
+                    new: vec![
+                        "fn main() {".to_string(),
+                        "    println!(\"Hello, world!\");".to_string(),
+                        "}".to_string(),
+                    ],
+                }],
+            }]
+        );
+        assert!(parse("--- a/src/main.rs\n+++ /dev/null\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_apply_with_wrong_line_numbers(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-apply-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("main.rs").to_string_lossy().to_string();
+        std::fs::write(&path, CONTENTS)?;
+
+        let files = vec![path.clone()];
+        let answer = format!(
+            "```diff\n--- a/{0}\n+++ b/{0}\n@@ -1,3 +1,3 @@\n fn main() {{\n-    println!(\"Hello\");\n+    println!(\"Hello, world!\");\n }}\n@@ -2,3 +2,3 @@\n fn add(a: i32, b: i32) -> i32 {{\n-    a + b\n+    a.saturating_add(b)\n }}\n```",
+            path
+        );
+        let applied = changes(&answer, &files);
+        let mismatched = changes(&answer.replace("-    a + b", "-    a - b"), &files);
+        let unknown = changes(&answer.replace("main.rs", "lib.rs"), &files);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(
+            applied?,
+            vec![(
+                path.clone(),
+                CONTENTS.to_string(),
+                CONTENTS
+                    .replace("\"Hello\"", "\"Hello, world!\"")
+                    .replace("a + b", "a.saturating_add(b)")
+            )]
+        );
+        assert!(mismatched.is_err());
+        assert!(unknown.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-overwrite-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("main.rs").to_string_lossy().to_string();
+        std::fs::write(&path, CONTENTS)?;
+
+        let answer = CONTENTS.replace("a + b", "a.saturating_add(b)");
+        let overwritten = overwrite(&path, &answer, None, true);
+        let written = std::fs::read_to_string(&path);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        overwritten?;
+        assert_eq!(written?, answer);
+
+        Ok(())
+    }
+}
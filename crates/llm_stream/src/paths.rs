@@ -0,0 +1,360 @@
+use std::path::{Path, PathBuf};
+
+use crate::prelude::*;
+
+const APP_NAME: &str = "llm-stream";
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Config,
+    Data,
+    State,
+}
+
+impl Kind {
+    /// XDG variable that sets the base directory, on every platform.
+    fn var(self) -> &'static str {
+        match self {
+            Kind::Config => "XDG_CONFIG_HOME",
+            Kind::Data => "XDG_DATA_HOME",
+            Kind::State => "XDG_STATE_HOME",
+        }
+    }
+
+    /// Base directory inside the home directory when the XDG variable is not set.
+    fn fallback(self) -> &'static [&'static str] {
+        match self {
+            Kind::Config => &[".config"],
+            Kind::Data => &[".local", "share"],
+            Kind::State => &[".local", "state"],
+        }
+    }
+}
+
+/// Directories of the user that the `llm-stream` directories are resolved from.
+#[derive(Debug, Default)]
+struct Base {
+    home: Option<PathBuf>,
+    /// `%APPDATA%`, used on Windows.
+    roaming: Option<PathBuf>,
+    /// `%LOCALAPPDATA%`, used on Windows.
+    local: Option<PathBuf>,
+    windows: bool,
+}
+
+impl Base {
+    fn current() -> Self {
+        Self {
+            home: dirs::home_dir(),
+            roaming: dirs::config_dir(),
+            local: dirs::data_local_dir(),
+            windows: cfg!(windows),
+        }
+    }
+}
+
+/// Returns the `llm-stream` directory of `kind` inside the `xdg` base directory when it's set.
+/// Otherwise, it's inside `%APPDATA%` for the configuration and `%LOCALAPPDATA%` for the rest on
+/// Windows, or inside the fallback of the home directory, like `~/.config`, everywhere else.
+fn app_dir(kind: Kind, xdg: Option<String>, base: &Base) -> Result<PathBuf> {
+    if let Some(dir) = xdg.filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join(APP_NAME));
+    }
+
+    if base.windows {
+        let dir = match kind {
+            Kind::Config => base.roaming.as_ref().map(|dir| dir.join(APP_NAME)),
+            Kind::Data => base
+                .local
+                .as_ref()
+                .map(|dir| dir.join(APP_NAME).join("data")),
+            Kind::State => base
+                .local
+                .as_ref()
+                .map(|dir| dir.join(APP_NAME).join("state")),
+        };
+
+        if let Some(dir) = dir {
+            return Ok(dir);
+        }
+    }
+
+    let home = base.home.clone().ok_or(Error::HomeNotFound)?;
+
+    Ok(kind
+        .fallback()
+        .iter()
+        .fold(home, |dir, name| dir.join(name))
+        .join(APP_NAME))
+}
+
+fn resolve(kind: Kind) -> Result<String> {
+    let dir = app_dir(kind, std::env::var(kind.var()).ok(), &Base::current())?;
+
+    Ok(dir.to_string_lossy().into_owned())
+}
+
+/// Directory for the configuration file and templates: `$XDG_CONFIG_HOME/llm-stream`, or
+/// `%APPDATA%\llm-stream` on Windows.
+pub fn config_dir() -> Result<String> {
+    resolve(Kind::Config)
+}
+
+/// Directory for the cached conversations: `$XDG_DATA_HOME/llm-stream`, or
+/// `%LOCALAPPDATA%\llm-stream\data` on Windows.
+pub fn data_dir() -> Result<String> {
+    resolve(Kind::Data)
+}
+
+/// Directory for state that can be lost without much harm: `$XDG_STATE_HOME/llm-stream`, or
+/// `%LOCALAPPDATA%\llm-stream\state` on Windows.
+pub fn state_dir() -> Result<String> {
+    resolve(Kind::State)
+}
+
+/// Returns the config, data, and state dirs: the ones given, or the default ones. A profile keeps
+/// its configuration, templates, cache, and ledgers inside its own directory of the config dir.
+pub fn resolve_dirs(
+    config_dir: Option<String>,
+    data_dir: Option<String>,
+    state_dir: Option<String>,
+    profile: Option<&str>,
+) -> Result<(String, String, String)> {
+    let config_dir = match config_dir {
+        Some(dir) => expand(&dir)?,
+        None => self::config_dir()?,
+    };
+
+    let profile_dir = match profile {
+        Some(profile) => Some(profile_dir(&config_dir, profile)?),
+        None => None,
+    };
+
+    let data_dir = match (data_dir, &profile_dir) {
+        (Some(dir), _) => expand(&dir)?,
+        (None, Some(profile_dir)) => join(profile_dir, "data"),
+        (None, None) => self::data_dir()?,
+    };
+    let state_dir = match (state_dir, &profile_dir) {
+        (Some(dir), _) => expand(&dir)?,
+        (None, Some(profile_dir)) => join(profile_dir, "state"),
+        (None, None) => self::state_dir()?,
+    };
+
+    Ok((profile_dir.unwrap_or(config_dir), data_dir, state_dir))
+}
+
+/// Returns the path of `name` inside `dir`, with the separator of the platform.
+pub fn join(dir: &str, name: &str) -> String {
+    Path::new(dir).join(name).to_string_lossy().into_owned()
+}
+
+/// Returns the directory of `profile` inside the config dir: `config_dir/profiles/<profile>`.
+/// Names are limited to letters, digits, `-`, and `_`, so they can't point outside of it.
+pub fn profile_dir(config_dir: &str, profile: &str) -> Result<String> {
+    let valid = profile
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if profile.is_empty() || !valid {
+        return Err(Error::InvalidProfile(profile.to_string()));
+    }
+
+    Ok(join(&join(config_dir, "profiles"), profile))
+}
+
+/// Expands a leading `~` to the user home directory, in `~` and in paths that start with `~/`, or
+/// `~\` on Windows.
+pub fn expand(path: &str) -> Result<String> {
+    expand_with(path, dirs::home_dir)
+}
+
+fn expand_with(path: &str, home: impl FnOnce() -> Option<PathBuf>) -> Result<String> {
+    let Some(rest) = path.strip_prefix('~') else {
+        return Ok(path.to_string());
+    };
+
+    if !rest.is_empty() && !rest.starts_with(std::path::is_separator) {
+        return Ok(path.to_string());
+    }
+
+    let expanded = rest
+        .split(std::path::is_separator)
+        .filter(|name| !name.is_empty())
+        .fold(home().ok_or(Error::HomeNotFound)?, |dir, name| {
+            dir.join(name)
+        });
+
+    Ok(expanded.to_string_lossy().into_owned())
+}
+
+/// Moves a file, falling back to copy and remove when both paths are on different devices.
+fn move_file(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+/// Moves the conversations cached by previous versions inside the config dir to the data dir,
+/// printing where they went when `notices` is true and some were moved. Returns the number of
+/// conversations moved.
+pub fn migrate(config_dir: &str, data_dir: &str, notices: bool) -> Result<usize> {
+    let old_cache_dir = std::path::Path::new(config_dir).join("cache");
+    let new_cache_dir = std::path::Path::new(data_dir).join("cache");
+
+    if !old_cache_dir.is_dir() || old_cache_dir == new_cache_dir {
+        return Ok(0);
+    }
+
+    std::fs::create_dir_all(&new_cache_dir)?;
+
+    let mut moved = 0;
+
+    for entry in std::fs::read_dir(&old_cache_dir)? {
+        let entry = entry?;
+        let target = new_cache_dir.join(entry.file_name());
+
+        if !target.exists() {
+            move_file(&entry.path(), &target)?;
+            moved += 1;
+        }
+    }
+
+    if std::fs::remove_dir(&old_cache_dir).is_err() {
+        log::warn!(
+            "unable to remove the old cache directory: {}",
+            old_cache_dir.display()
+        );
+    }
+
+    if notices && moved > 0 {
+        eprintln!(
+            "Moved {} cached conversations from {} to {}",
+            moved,
+            old_cache_dir.display(),
+            new_cache_dir.display()
+        );
+    }
+
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(names: &[&str]) -> PathBuf {
+        names.iter().collect()
+    }
+
+    #[test]
+    fn test_app_dir() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let unix = Base {
+            home: Some(path(&["/home", "user"])),
+            ..Default::default()
+        };
+        let windows = Base {
+            home: Some(path(&["C:\\", "Users", "user"])),
+            roaming: Some(path(&["C:\\", "Users", "user", "AppData", "Roaming"])),
+            local: Some(path(&["C:\\", "Users", "user", "AppData", "Local"])),
+            windows: true,
+        };
+
+        assert_eq!(
+            app_dir(Kind::Config, None, &unix)?,
+            path(&["/home", "user", ".config", "llm-stream"])
+        );
+        assert_eq!(
+            app_dir(Kind::Data, Some(String::new()), &unix)?,
+            path(&["/home", "user", ".local", "share", "llm-stream"])
+        );
+        assert_eq!(
+            app_dir(Kind::State, Some("/var/state".to_string()), &unix)?,
+            path(&["/var/state", "llm-stream"])
+        );
+        assert_eq!(
+            app_dir(Kind::Config, None, &windows)?,
+            path(&["C:\\", "Users", "user", "AppData", "Roaming", "llm-stream"])
+        );
+        assert_eq!(
+            app_dir(Kind::State, None, &windows)?,
+            path(&[
+                "C:\\",
+                "Users",
+                "user",
+                "AppData",
+                "Local",
+                "llm-stream",
+                "state"
+            ])
+        );
+        assert_eq!(
+            app_dir(Kind::Data, Some("D:\\data".to_string()), &windows)?,
+            path(&["D:\\data", "llm-stream"])
+        );
+        assert!(app_dir(Kind::Config, None, &Base::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let home = || Some(path(&["/home", "user"]));
+        let expanded = |names: &[&str]| path(names).to_string_lossy().into_owned();
+
+        assert_eq!(expand_with("~", home)?, expanded(&["/home", "user"]));
+        assert_eq!(
+            expand_with("~/notes/todo.md", home)?,
+            expanded(&["/home", "user", "notes", "todo.md"])
+        );
+        assert_eq!(expand_with("~user/notes", home)?, "~user/notes");
+        assert_eq!(expand_with("notes/~", home)?, "notes/~");
+        assert!(expand_with("~/notes", || None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_dir() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            profile_dir("/home/user/.config/llm-stream", "work")?,
+            join(&join("/home/user/.config/llm-stream", "profiles"), "work")
+        );
+        assert!(profile_dir("/tmp", "").is_err());
+        assert!(profile_dir("/tmp", "../personal").is_err());
+        assert!(profile_dir("/tmp", "work/old").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_only_moves_once() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-migrate-{}", xid::new()));
+        let config_dir = dir.join("config");
+        let data_dir = dir.join("data");
+
+        std::fs::create_dir_all(config_dir.join("cache"))?;
+        std::fs::create_dir_all(data_dir.join("cache"))?;
+        std::fs::write(config_dir.join("cache").join("old.toml"), "")?;
+        std::fs::write(config_dir.join("cache").join("kept.toml"), "old")?;
+        std::fs::write(data_dir.join("cache").join("kept.toml"), "new")?;
+
+        let config_dir = config_dir.to_string_lossy().to_string();
+        let data_dir = data_dir.to_string_lossy().to_string();
+
+        let first = migrate(&config_dir, &data_dir, false);
+        let second = migrate(&config_dir, &data_dir, false);
+        let kept = std::fs::read_to_string(format!("{}/cache/kept.toml", data_dir));
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(first?, 1);
+        assert_eq!(second?, 0, "Nothing is left to move");
+        assert_eq!(kept?, "new");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::Write;
+
+use crate::prelude::*;
+
+/// Returns the process that runs `command` with the shell: `sh -c`, or `cmd /C` on Windows.
+pub fn shell(command: &str) -> std::process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let mut process = std::process::Command::new(shell);
+    process.args([flag, command]);
+    process
+}
+
+/// Runs `command` with the shell, writing `input` to its standard input, and returns its output.
+/// Its standard error goes to the terminal, so errors like the ones of `jq` are shown as they are.
+pub fn run(command: &str, input: &str) -> Result<String> {
+    let mut child = shell(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    // The input is written from another thread, so commands that print before reading all of it
+    // don't block on a full pipe.
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = format!("{}\n", input);
+        // Commands that don't read their input close it early, so the error is ignored.
+        std::thread::spawn(move || stdin.write_all(input.as_bytes()).ok())
+    });
+
+    let output = child.wait_with_output()?;
+
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+
+    if !output.status.success() {
+        return Err(Error::Pipe(format!("`{}` {}", command, output.status)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// The commands run with `sh`.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(run("tr a-z A-Z", "hello")?, "HELLO\n");
+        assert!(run("exit 3", "hello").is_err());
+
+        Ok(())
+    }
+}
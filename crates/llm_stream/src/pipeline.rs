@@ -0,0 +1,240 @@
+use serde_json::Value;
+use std::hash::{Hash, Hasher};
+
+use crate::config::{Template, TemplateStep};
+use crate::prelude::*;
+
+/// Variables set with the outputs of the steps.
+pub const VARIABLES: &[&str] = &["steps", "previous"];
+
+/// Returns the directory where the outputs of the steps are cached.
+fn steps_dir(data_dir: &str) -> String {
+    format!("{}/steps", data_dir)
+}
+
+/// Returns the variables of the steps with placeholders instead of their outputs, used when the
+/// steps aren't sent.
+pub fn placeholders(template: &Template) -> Value {
+    let placeholder = |step: &TemplateStep| format!("<output of the {} step>", step.name);
+    let steps = template.steps.as_deref().unwrap_or_default();
+
+    let outputs = steps
+        .iter()
+        .map(|step| (step.name.clone(), Value::String(placeholder(step))))
+        .collect::<serde_json::Map<String, Value>>();
+    let previous = steps.last().map(placeholder).unwrap_or_default();
+
+    serde_json::json!({ "steps": outputs, "previous": previous })
+}
+
+/// Builds the arguments of a step. The api options of the command line are only kept when the
+/// step uses the same api.
+fn step_args(args: &Args, template: &Template, step: &TemplateStep) -> Args {
+    let api = step.api.or(args.api).or(template.api);
+    let same_api = step.api.is_none() || step.api == args.api;
+
+    let mut step_args = Args {
+        api,
+        model: step.model.clone(),
+        max_tokens: step.max_tokens,
+        temperature: step.temperature,
+        key_source: args.key_source,
+        quiet: true,
+        no_cache: true,
+        ..Default::default()
+    };
+
+    if same_api {
+        if step_args.model.is_none() {
+            step_args.model = args.model.clone().or(template.model.clone());
+        }
+        step_args.api_env = args.api_env.clone();
+        step_args.api_key = args.api_key.clone();
+        step_args.key_cmd = args.key_cmd.clone();
+        step_args.api_version = args.api_version.clone();
+        step_args.api_base_url = args.api_base_url.clone();
+        step_args.headers = args.headers.clone();
+    }
+
+    step_args
+}
+
+/// Returns the cache key of a step request, built from everything that changes its output.
+fn cache_key(args: &Args) -> Result<String> {
+    let request = serde_json::to_string(&(
+        args.api,
+        &args.model,
+        args.max_tokens,
+        args.temperature,
+        &args.conversation,
+    ))?;
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    request.hash(&mut hasher);
+
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Sends the step request, or returns its cached output when the same request was sent before.
+async fn send(args: Args, data_dir: &str, use_cache: bool) -> Result<String> {
+    let path = format!("{}/{}.md", steps_dir(data_dir), cache_key(&args)?);
+
+    if use_cache && std::path::Path::new(&path).exists() {
+        if args.shows_notices() {
+            eprintln!("  using the cached output");
+        }
+        return Ok(std::fs::read_to_string(&path)?);
+    }
+
+    let output = complete(args, |_| {}).await?;
+
+    std::fs::create_dir_all(steps_dir(data_dir))?;
+    std::fs::write(&path, &output)?;
+
+    Ok(output)
+}
+
+/// Runs the steps of the selected template, if it has any, and adds their outputs to the
+/// variables of the template.
+///
+/// Outputs are cached, so running the pipeline again, like after a failed step, only sends the
+/// steps whose inputs changed. `--no-cache` sends every step again. With `--dry-run` or
+/// `--count-tokens` the steps aren't sent, and placeholders are used instead.
+pub async fn run(mut args: Args, config: &Config) -> Result<Args> {
+    let Some(template) = args.template.as_ref().and_then(|name| {
+        config
+            .templates
+            .iter()
+            .flatten()
+            .find(|t| &t.name == name)
+            .cloned()
+    }) else {
+        return Ok(args);
+    };
+
+    let Some(steps) = template.steps.clone() else {
+        return Ok(args);
+    };
+
+    if args.dry_run || args.count_tokens {
+        merge(
+            args.vars.get_or_insert(serde_json::json!({})),
+            placeholders(&template),
+        );
+        return Ok(args);
+    }
+
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+
+    let mut tera = tera::Tera::default();
+    crate::functions::register(&mut tera, config.template_functions.clone());
+
+    let mut context = template_context(&mut args.clone(), &template)?;
+    let mut outputs = serde_json::json!({ "steps": {}, "previous": "" });
+
+    for (i, step) in steps.iter().enumerate() {
+        if args.shows_notices() {
+            eprintln!("Running step {}/{}: {}", i + 1, steps.len(), step.name);
+        }
+
+        let variables = tera::Context::from_value(context.clone())?;
+
+        let mut request = step_args(&args, &template, step);
+        request.prompt = Some(tera.render_str(&step.template, &variables)?);
+        request.system = step
+            .system
+            .as_ref()
+            .map(|system| tera.render_str(system, &variables))
+            .transpose()?
+            .into_iter()
+            .collect();
+
+        let request = merge_args_and_config(request, config.clone())?;
+        let output = send(request, &data_dir, !args.no_cache).await?;
+
+        outputs["steps"][&step.name] = Value::String(output.clone());
+        outputs["previous"] = Value::String(output);
+
+        merge(&mut context, outputs.clone());
+    }
+
+    merge(args.vars.get_or_insert(serde_json::json!({})), outputs);
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_args_only_keep_the_options_of_the_same_api() {
+        let args = Args {
+            api: Some(Api::OpenAi),
+            model: Some("gpt-4o".to_string()),
+            api_base_url: Some("https://example.com/v1".to_string()),
+            ..Default::default()
+        };
+        let template = Template::default();
+
+        let same = step_args(
+            &args,
+            &template,
+            &TemplateStep {
+                name: "draft".to_string(),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(same.model, Some("gpt-4o".to_string()));
+        assert_eq!(
+            same.api_base_url,
+            Some("https://example.com/v1".to_string())
+        );
+
+        let other = step_args(
+            &args,
+            &template,
+            &TemplateStep {
+                name: "critique".to_string(),
+                api: Some(Api::Anthropic),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(other.api, Some(Api::Anthropic));
+        assert_eq!(
+            other.model, None,
+            "The model of another api shouldn't be used"
+        );
+        assert_eq!(other.api_base_url, None);
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let template = Template {
+            steps: Some(vec![
+                TemplateStep {
+                    name: "facts".to_string(),
+                    ..Default::default()
+                },
+                TemplateStep {
+                    name: "draft".to_string(),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            placeholders(&template),
+            serde_json::json!({
+                "steps": {
+                    "facts": "<output of the facts step>",
+                    "draft": "<output of the draft step>",
+                },
+                "previous": "<output of the draft step>",
+            })
+        );
+    }
+}
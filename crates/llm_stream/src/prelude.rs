@@ -1,928 +1,2778 @@
 use cli_table::{format::Justify, print_stdout, Color, ColorChoice, Table, WithTitle};
 use config_file::FromConfigFile;
-use futures::stream::{Stream, TryStreamExt};
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
 use serde_json::Value;
 use std::io::{BufRead, IsTerminal, Write};
 
-pub use crate::args::{Api, Args};
+pub use crate::args::{Api, Args, Cli, Command, Renderer, StdinPlacement};
 pub use crate::config::Config;
 pub use crate::conversation::*;
 pub use crate::error::Error;
+pub use crate::report::SharedMetadata;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 const SYSTEM_TEMPLATE: &str = "system";
 const PROMPT_TEMPLATE: &str = "prompt";
-const CONTENT_TEMPLATE: &str = "template";
 
-/// Handles the stream of text from the LLM and prints it to the terminal.
-pub async fn handle_stream(
-    mut stream: impl Stream<Item = std::result::Result<String, llm_stream::error::Error>>
-        + std::marker::Unpin,
+/// Placeholder of the prompt replaced with the text read from stdin.
+const STDIN_PLACEHOLDER: &str = "{stdin}";
+
+/// Answer that `send` follows up on with another request.
+#[derive(Debug)]
+pub enum Followup {
+    /// Cut by the token limit, and continued with `--auto-continue`.
+    Truncated {
+        /// Id of the cached conversation, written again with the rest of the answer.
+        id: Option<String>,
+        /// Conversation that ends with the answer so far.
+        conversation: Conversation,
+        /// Whole conversation kept for the cache, when `conversation` was compacted.
+        full_conversation: Option<Conversation>,
+        /// Text of the answer so far.
+        answer: String,
+    },
+    /// Not valid for `--validate-json`, and asked again with its errors.
+    Invalid {
+        id: Option<String>,
+        answer: String,
+        errors: String,
+    },
+}
+
+/// Handles the stream of text from the LLM, once `stream` opens it, and prints it to the terminal.
+/// Returns the answer when it was cut by the token limit and `--auto-continue` is set, or when it
+/// isn't valid for `--validate-json`, so `send` follows up on it.
+pub async fn handle_stream<S>(
+    stream: impl std::future::Future<Output = Result<S>>,
     mut args: Args,
-) -> Result<()> {
-    let mut previous_output = String::new();
+    metadata: SharedMetadata,
+) -> Result<Option<Followup>>
+where
+    S: Stream<Item = std::result::Result<String, llm_stream::error::Error>> + std::marker::Unpin,
+{
+    let start = std::time::Instant::now();
+    let sent = chrono::Local::now();
+    let mut first_token: Option<std::time::Duration> = None;
     let mut accumulated_content_bytes: Vec<u8> = Vec::new();
 
     let is_terminal = atty::is(atty::Stream::Stdout);
+    let theme = crate::theme::resolve(&args);
 
-    let mut sp = if args.quiet.is_none() || (args.quiet == Some(false) && is_terminal) {
-        Some(spinners::Spinner::new(
-            spinners::Spinners::OrangeBluePulse,
-            "Loading...".into(),
+    let mut status = if args.shows_spinner() && std::io::stderr().is_terminal() {
+        Some(crate::status::Status::start(
+            args.api,
+            args.model.as_deref(),
         ))
     } else {
         None
     };
 
+    // The status line shows while the first text is awaited, and dropping it on errors clears it.
+    let mut stream = stream.await?;
+
     let language = args.language.clone().unwrap_or("markdown".to_string());
-    let theme = Some(args.theme.clone().unwrap_or("ansi".to_string()));
+    let wrap = crate::wrap::width(args.wrap.as_deref());
+    let mut printer = if is_terminal {
+        let renderer = if args.no_color {
+            Renderer::Plain
+        } else {
+            args.renderer.unwrap_or_default()
+        };
+
+        Some(crate::printer::StreamPrinter::new(crate::render::new(
+            renderer, &language, &theme, wrap,
+        )?))
+    } else {
+        None
+    };
+
+    // Answers checked by a `post_response` hook, or by `--validate-json`, are printed once they
+    // are accepted.
+    let held = args.hooks.post_response.is_some() || args.validate_json.is_some();
+
+    // With `--overwrite`, an existing file is replaced once the changes are confirmed.
+    let overwrites = args.overwrites();
+
+    let mut output_file = match &args.output {
+        Some(_) if overwrites => None,
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(args.append)
+                .truncate(!args.append)
+                .open(path)?,
+        ),
+        None => None,
+    };
 
     loop {
         let result = stream.try_next().await;
 
         match result {
             Ok(Some(text)) => {
-                if is_terminal && sp.is_some() {
-                    // TODO: Find a better way to clean the spinner from the terminal.
-                    sp.take().unwrap().stop();
-                    std::io::stdout().flush()?;
-                    crossterm::execute!(std::io::stdout(), crossterm::cursor::MoveToColumn(0))?;
-                    print!("                      ");
-                    crossterm::execute!(std::io::stdout(), crossterm::cursor::MoveToColumn(0))?;
+                // The role chunks that open some streams carry no text yet.
+                if first_token.is_none() && !text.is_empty() {
+                    first_token = Some(start.elapsed());
                 }
 
-                if !is_terminal {
-                    // If not a terminal, print each instance of `text` directly to `stdout`
-                    print!("{}", text);
-                    std::io::stdout().flush()?;
-                    continue;
+                if let Some(status) = &status {
+                    status.received(&text);
                 }
 
                 accumulated_content_bytes.extend_from_slice(text.as_bytes());
 
-                let output = crate::printer::CustomPrinter::new(&language, theme.as_deref())?
-                    .input_from_bytes(&accumulated_content_bytes)
-                    .print()?;
+                if held {
+                    continue;
+                }
 
-                let unprinted_lines = output
-                    .lines()
-                    .skip(if previous_output.lines().count() == 0 {
-                        0
-                    } else {
-                        previous_output.lines().count() - 1
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n");
+                if let Some(file) = output_file.as_mut() {
+                    file.write_all(text.as_bytes())?;
+                    file.flush()?;
+                }
 
-                crossterm::execute!(std::io::stdout(), crossterm::cursor::MoveToColumn(0))?;
-                print!("{unprinted_lines}");
-                std::io::stdout().flush()?;
+                if args.ndjson {
+                    clear_status(&mut status);
+                    crate::report::StreamEvent::Delta { text: &text }.print()?;
+                    continue;
+                }
+
+                if args.extract_code
+                    || args.json
+                    || args.filter
+                    || args.pipe.is_some()
+                    || !args.apply.is_empty()
+                {
+                    continue;
+                }
+
+                if is_terminal {
+                    clear_status(&mut status);
+                }
+
+                if !is_terminal {
+                    // If not a terminal, print each instance of `text` directly to `stdout`
+                    print!("{}", text);
+                    std::io::stdout().flush()?;
+                    continue;
+                }
 
-                // Update the previous output
-                previous_output = output;
+                if let Some(printer) = printer.as_mut() {
+                    printer.print(&text)?;
+                }
             }
             Ok(None) => break,
             Err(llm_stream::error::Error::EventsourceClient(
                 llm_stream::error::EventsourceError::Eof,
             )) => break,
             Err(e) => {
-                if is_terminal && sp.is_some() {
-                    // TODO: Find a better way to clean the spinner from the terminal.
-                    sp.take().unwrap().stop();
-                    std::io::stdout().flush()?;
-                    crossterm::execute!(std::io::stdout(), crossterm::cursor::MoveToColumn(0))?;
-                    print!("                      ");
-                    crossterm::execute!(std::io::stdout(), crossterm::cursor::MoveToColumn(0))?;
+                clear_status(&mut status);
+                if args.ndjson {
+                    crate::report::StreamEvent::Error {
+                        message: e.to_string(),
+                    }
+                    .print()?;
                 }
                 return Err(Error::from(e));
             }
         };
     }
 
-    if !args.no_cache {
-        let id = if args.fork {
-            if args.from.is_some() {
-                args.parent = args.from.clone();
+    let elapsed = start.elapsed();
+    let metadata = metadata.lock().map(|m| m.clone()).unwrap_or_default();
+
+    let stats = crate::report::Stats::new(
+        &args,
+        String::from_utf8_lossy(&accumulated_content_bytes).trim(),
+        &metadata,
+        elapsed,
+        first_token,
+    );
+
+    // The usage is recorded before the `post_response` hook, since it's spent even if the hook
+    // rejects the answer.
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    if let Err(e) = crate::ledger::record(&state_dir, &crate::ledger::Entry::new(&args, &stats)) {
+        log::warn!("unable to record the usage: {:?}", e);
+    }
+
+    let truncated = crate::report::is_truncated(metadata.finish_reason.as_deref())
+        && !matches!(args.api, Some(Api::MistralFim | Api::OllamaFim));
+    let continues = truncated && args.auto_continue;
+
+    // Responses that call tools are continued by `tools::run`, which caches the last one.
+    let calls_tools = matches!(
+        metadata.finish_reason.as_deref(),
+        Some("tool_calls" | "tool_use")
+    );
+
+    let mut errors = None;
+
+    if held {
+        let text = String::from_utf8_lossy(&accumulated_content_bytes).to_string();
+        let text = match crate::hooks::post_response(&args, &text, &metadata) {
+            Ok(message) => message.unwrap_or(text),
+            Err(e) => {
+                clear_status(&mut status);
+                return Err(e);
             }
-            xid::new().to_string()
-        } else {
-            args.from.clone().unwrap_or(xid::new().to_string())
         };
 
-        args.conversation.push(ConversationMessage {
-            role: ConversationRole::Assistant,
-            content: String::from_utf8_lossy(&accumulated_content_bytes)
-                .trim()
-                .to_string(),
-        });
-
-        let cache_file = format!(
-            "{}/cache/{}.toml",
-            args.config_dir
-                .clone()
-                .unwrap_or("~/.config/llm-stream".to_string()),
-            id
-        );
+        // Answers that call tools or are continued aren't complete yet. Invalid answers are asked
+        // again by `send`, without printing them.
+        errors = match &args.validate_json {
+            Some(schema) if !continues && !calls_tools => {
+                crate::validate::check(text.trim(), schema)?
+            }
+            _ => None,
+        };
+
+        if errors.is_none() {
+            if let Some(file) = output_file.as_mut() {
+                file.write_all(text.as_bytes())?;
+                file.flush()?;
+            }
+
+            if args.ndjson {
+                clear_status(&mut status);
+                crate::report::StreamEvent::Delta { text: &text }.print()?;
+            } else if !(args.extract_code
+                || args.json
+                || args.filter
+                || args.pipe.is_some()
+                || !args.apply.is_empty())
+            {
+                clear_status(&mut status);
+
+                match printer.as_mut() {
+                    Some(printer) => printer.print(&text)?,
+                    None => {
+                        print!("{}", text);
+                        std::io::stdout().flush()?;
+                    }
+                }
+            }
+        }
 
-        let cache_toml = toml::to_string(&args)?;
+        accumulated_content_bytes = text.into_bytes();
+    }
 
-        std::fs::write(&cache_file, cache_toml)?;
+    let valid = errors.is_none();
 
-        eprintln!("\n\nCache file: {}", &cache_file);
+    if let Some(printer) = printer.as_mut() {
+        printer.finish()?;
     }
-    Ok(())
-}
 
-/// Merges two JSON objects defined as `serde_json::Value`.
-pub fn merge(a: &mut Value, b: Value) {
-    if let Value::Object(a) = a {
-        if let Value::Object(b) = b {
-            for (k, v) in b {
-                if v.is_null() {
-                    a.remove(&k);
-                } else {
-                    merge(a.entry(k).or_insert(Value::Null), v);
-                }
+    let raw = String::from_utf8_lossy(&accumulated_content_bytes).to_string();
+    let content = raw.trim().to_string();
+
+    // An answer that continues is held by `send`, and the whole of it overwrites the file.
+    if let Some(path) = args
+        .output
+        .as_deref()
+        .filter(|_| overwrites && valid && !continues)
+    {
+        clear_status(&mut status);
+
+        let theme = (!args.no_color).then_some(theme.as_str());
+        let whole = format!(
+            "{}{}",
+            args.overwrite_parts.as_deref().unwrap_or_default(),
+            raw
+        );
+        crate::patch::overwrite(path, &whole, theme, args.yes)?;
+    }
+
+    crate::audit::record(&args, Some(&content), metadata.usage, None);
+
+    // Continuations keep their leading whitespace, since they can start in the middle of a line.
+    let answer = if args.continue_generation {
+        raw.trim_end()
+    } else {
+        content.as_str()
+    };
+    // Errors of `--pipe` are returned once the response is cached, so it isn't lost.
+    let mut pipe_error = None;
+
+    if !valid {
+        // Invalid answers are asked again, their text is only cached.
+    } else if let Some(command) = &args.pipe {
+        clear_status(&mut status);
+
+        let input = if args.filter {
+            let text = String::from_utf8_lossy(&accumulated_content_bytes);
+            crate::extract::unwrap_fence(text.trim_matches('\n'))
+                .trim_end_matches('\n')
+                .to_string()
+        } else if args.extract_code {
+            let filter = args
+                .language
+                .as_deref()
+                .filter(|language| *language != "markdown");
+            crate::extract::code_blocks(&content, filter).join("\n")
+        } else {
+            content.clone()
+        };
+
+        match crate::pipe::run(command, &input) {
+            Ok(output) => {
+                print!("{}", output);
+                std::io::stdout().flush()?;
             }
+            Err(e) => pipe_error = Some(e),
+        }
+    } else if args.filter {
+        let text = String::from_utf8_lossy(&accumulated_content_bytes);
 
-            return;
+        println!(
+            "{}",
+            crate::extract::unwrap_fence(text.trim_matches('\n')).trim_end_matches('\n')
+        );
+        std::io::stdout().flush()?;
+    } else if args.extract_code && !args.json && !args.ndjson {
+        clear_status(&mut status);
+
+        let filter = args
+            .language
+            .as_deref()
+            .filter(|language| *language != "markdown");
+        let code = crate::extract::code_blocks(&content, filter).join("\n");
+
+        match filter {
+            Some(language) if is_terminal && !args.no_color => {
+                let output = crate::printer::CustomPrinter::new(language, Some(&theme))?
+                    .input_from_bytes(code.as_bytes())
+                    .print()?;
+                print!("{}", output);
+            }
+            _ => print!("{}", code),
         }
-        return;
+
+        std::io::stdout().flush()?;
+    } else if !args.apply.is_empty() {
+        // The diff is previewed once it's parsed.
+        clear_status(&mut status);
     }
 
-    *a = b;
-}
+    let id = if args.no_cache || calls_tools {
+        None
+    } else if args.fork {
+        if args.from.is_some() {
+            args.parent = args.from.clone();
+        }
+        Some(xid::new().to_string())
+    } else {
+        Some(args.from.clone().unwrap_or(xid::new().to_string()))
+    };
 
-/// Reads the configuration file. If it or the config directory doesn't exist, they'll be created.
-pub fn build_config(args: Args) -> Result<(Args, Config)> {
-    let config_dir = args
-        .config_dir
-        .clone()
-        .unwrap_or("~/.config/llm-stream".to_string());
-    let config_file = args.config_file.clone().unwrap();
+    let continue_generation = args.continue_generation;
+    args.update_conversations(|conversation| {
+        crate::cache::add_answer(conversation, answer, continue_generation)
+    });
+    crate::cache::stamp_answer(&mut args, sent, metadata.usage);
 
-    log::info!("config_dir: {}", &config_dir);
-    log::info!("config_file: {}", &config_file);
+    // The notices below go to stderr, under the status line otherwise.
+    clear_status(&mut status);
 
-    let mut config = if !std::path::Path::new(&config_file).exists() {
-        let config = Config::new();
-        let config_toml = toml::to_string(&config)?;
-        // Store `config_toml` in the `&config_file` path.
-        std::fs::write(&config_file, config_toml)?;
+    if let Some(id) = &id {
+        let data_dir = args.data_dir.clone().expect("can't find data directory");
+        let cache_file = crate::cache::cache_file(&data_dir, id);
 
-        config
+        crate::cache::write(&data_dir, id, &args)?;
+
+        if !args.filter && !continues && args.shows_notices() {
+            eprintln!("\n\nCache file: {}", &cache_file);
+        }
+    }
+
+    // Answers cut by the token limit or a content filter would look complete otherwise.
+    if let Some(warning) = crate::report::warning(metadata.finish_reason.as_deref()) {
+        match &id {
+            _ if continues => {}
+            Some(id) if truncated => eprintln!(
+                "\nWarning: {}, continue it with `llm-stream --from {} --continue-generation`.",
+                warning, id
+            ),
+            _ => eprintln!("\nWarning: {}.", warning),
+        }
+    }
+
+    let followup = if continues {
+        Some(Followup::Truncated {
+            id: id.clone(),
+            conversation: args.conversation.clone(),
+            full_conversation: args.full_conversation.clone(),
+            answer: raw.clone(),
+        })
     } else {
-        Config::from_config_file(&config_file)?
+        errors.map(|errors| Followup::Invalid {
+            id: id.clone(),
+            answer: answer.to_string(),
+            errors,
+        })
     };
 
-    let templates_dir = format!("{}/templates", &config_dir);
-    if !std::path::Path::new(&templates_dir).exists() {
-        std::fs::create_dir_all(&templates_dir)?;
+    if args.ndjson && !continues && valid {
+        clear_status(&mut status);
+
+        if metadata.usage != crate::report::Usage::default() {
+            crate::report::StreamEvent::Usage(metadata.usage).print()?;
+        }
+
+        crate::report::StreamEvent::Stop {
+            id: id.clone(),
+            finish_reason: metadata.finish_reason.clone(),
+        }
+        .print()?;
     }
 
-    let cache_dir = format!("{}/cache", &config_dir);
-    if !std::path::Path::new(&cache_dir).exists() {
-        std::fs::create_dir_all(&cache_dir)?;
+    if args.json && valid {
+        clear_status(&mut status);
+
+        let response = crate::report::Response {
+            id,
+            message: content,
+            model: args.model.clone(),
+            api: args.api,
+            finish_reason: metadata.finish_reason,
+            usage: metadata.usage,
+            timing: crate::report::Timing {
+                elapsed_ms: elapsed.as_millis(),
+                first_token_ms: first_token.map(|d| d.as_millis()),
+            },
+        };
+
+        println!("{}", serde_json::to_string_pretty(&response)?);
     }
 
-    let templates = std::fs::read_dir(&templates_dir)?
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            let path = entry.path();
-            if path.extension()?.to_str()? == "toml" {
-                let contents = std::fs::read_to_string(&path).ok()?;
-                let template: crate::config::Template = toml::from_str(&contents).ok()?;
+    if args.stats {
+        eprintln!("\n{}", stats);
+    }
 
-                Some(template)
-            } else {
-                None
+    match pipe_error {
+        Some(e) => Err(e),
+        None => Ok(followup),
+    }
+}
+
+/// Stops the status line, if any, and clears it from the terminal.
+fn clear_status(status: &mut Option<crate::status::Status>) {
+    // Dropping the status line clears it.
+    status.take();
+}
+
+/// Collects the stream of text from the LLM into a single `String` without printing it, calling
+/// `on_delta` with every chunk of text.
+pub async fn collect_stream(
+    mut stream: impl Stream<Item = std::result::Result<String, llm_stream::error::Error>>
+        + std::marker::Unpin,
+    mut on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let mut content = String::new();
+
+    loop {
+        match stream.try_next().await {
+            Ok(Some(text)) => {
+                on_delta(&text);
+                content.push_str(&text);
             }
-        })
-        .collect::<Vec<crate::config::Template>>();
+            Ok(None) => break,
+            Err(llm_stream::error::Error::EventsourceClient(
+                llm_stream::error::EventsourceError::Eof,
+            )) => break,
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
 
-    config.templates = Some(if let Some(config_templates) = config.templates {
-        config_templates.into_iter().chain(templates).collect()
-    } else {
-        templates
-    });
+    Ok(content.trim().to_string())
+}
 
-    Ok((args, config))
+/// Returns the request as it's printed by `--dry-run`: the method and url, the headers, and the
+/// JSON body.
+fn format_request(request: &llm_stream::request::Request) -> Result<String> {
+    let mut text = format!("{} {}\n", request.method, request.url);
+
+    for (name, value) in &request.headers {
+        text.push_str(&format!("{}: {}\n", name, value));
+    }
+
+    text.push('\n');
+    text.push_str(&serde_json::to_string_pretty(&request.body)?);
+
+    Ok(text)
 }
 
-/// Handles the command prompt, adding support for reading from `stdin`, an argument, a file, or
-/// a tuple of those three.
-///
-/// This function handles the following situations:
-///
-/// 1. Calling the binary with no arguments.
-///
-/// ```bash
-/// llm-stream
-/// ```
-///
-/// This will render in an empty value for both `prompt` and `stdin`.
-///
-/// 2. Calling the binary with `stdin` input.
-///
-/// ```bash
-/// echo -n "Something" | llm-stream
-/// ```
-///
-/// This will render in `prompt` to `Something` and `stdin` to be empty.
-///
-/// 3. Calling the binary with an argument.
-///
-/// ```bash
-/// llm-stream "Something"
-/// ```
-///
-/// This will render in `prompt` to `Something` and `stdin` to be empty.
-///
-/// 4. Calling the binary with an argument and `stdin` input.
-///
-/// ```bash
-/// echo -n "Awesome" | llm-stream "Something"
-/// ```
-///
-/// This will render `prompt` to be `Something, and `stdin` to be `Awesome`.
-pub fn parse_args(mut args: Args, config: Config) -> Result<(Args, Config)> {
-    let stdin = std::io::stdin();
+/// Prints the request `send` would make, for `--dry-run`, with its credentials redacted. The
+/// key isn't resolved, since it's redacted anyway.
+pub fn print_request(mut args: Args, tools: &[crate::config::Tool]) -> Result<()> {
+    args.api_key = Some(String::new());
 
-    args.stdin = Some(if stdin.is_terminal() {
-        "".to_string()
+    let session = if args.tools.is_empty() {
+        None
     } else {
-        std::io::stdin()
-            .lock()
-            .lines()
-            .collect::<std::result::Result<Vec<String>, std::io::Error>>()?
-            .join("\n")
-            .trim()
-            .to_string()
-    });
+        Some(crate::tools::session(&args, tools)?)
+    };
+    let json = args.json;
+
+    let request = match args.api {
+        Some(Api::OpenAi) => crate::openai::request(args, session.as_ref())?,
+        Some(Api::Anthropic) => crate::anthropic::request(args, session.as_ref())?,
+        Some(Api::Google) => crate::google::request(args, session.as_ref())?,
+        Some(Api::Mistral) => crate::mistral::request(args, session.as_ref())?,
+        Some(Api::MistralFim) => crate::mistral_fim::request(args, session.as_ref())?,
+        Some(Api::OllamaFim) => crate::ollama_fim::request(args, session.as_ref())?,
+        None => return Err(Error::ApiNotSpecified),
+    };
 
-    if args.prompt.is_none() {
-        args.prompt = Some(args.stdin.clone().unwrap_or_default().trim().to_string());
-        args.stdin = None;
+    let request = request.redacted();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&request)?);
+    } else {
+        println!("{}", format_request(&request)?);
     }
 
-    if let Some(preset) = args.preset.clone() {
-        let p = config
-            .presets
-            .clone()
-            .unwrap_or(vec![])
-            .into_iter()
-            .find(|p| p.name == preset);
+    Ok(())
+}
 
-        if let Some(p) = p {
-            if args.api.is_none() {
-                args.api = Some(p.api);
-            }
+/// Times an answer cut by the token limit is continued with `--auto-continue`.
+const MAX_CONTINUATIONS: u32 = 5;
+
+/// Streams the response of the api selected by `args.api` to the terminal, or runs the tools of
+/// `--tool`. Conversations the api rejects for being longer than the context window of the model
+/// are shrunk, dropping their oldest messages, and sent again. With `--auto-continue`, answers
+/// cut by the token limit are continued in the same cached conversation, and with
+/// `--validate-json`, invalid answers are asked again with their errors. Responses that send no
+/// text within `--first-token-timeout` are sent to the `--fallback` presets, in order.
+pub async fn send(mut args: Args, tools: &[crate::config::Tool], config: &Config) -> Result<()> {
+    let mut shrinks = 0;
+    let mut continuations = 0;
+    let mut retries = 0;
+    let mut fallbacks = args.fallback.clone().into_iter();
+    // Decided once, since the file is only replaced when the whole answer arrived.
+    let overwrites = args.overwrites();
 
-            if args.top_p.is_none() {
-                args.top_p = p.top_p;
-            }
-            if args.top_k.is_none() {
-                args.top_k = p.top_k;
-            }
-            if args.temperature.is_none() {
-                args.temperature = p.temperature;
-            }
-            if args.conversation.len() == 0
-                || args.conversation.first().unwrap().role != ConversationRole::System
-            {
-                args.conversation.insert(
-                    0,
-                    ConversationMessage {
-                        role: ConversationRole::System,
-                        content: p.system.clone().unwrap_or_default(),
-                    },
-                );
-            }
-            if args.max_tokens.is_none() {
-                args.max_tokens = p.max_tokens;
-            }
-            if args.api_version.is_none() {
-                args.api_version = p.version;
-            }
-            if args.api_env.is_none() {
-                args.api_env = p.env;
-            }
-            if args.api_key.is_none() {
-                args.api_key = p.key;
-            }
-            if args.api_base_url.is_none() {
-                args.api_base_url = p.base_url;
-            }
-            if args.model.is_none() {
-                args.model = p.model;
+    loop {
+        crate::report::wait_for_quota(args.api, args.shows_notices()).await;
+
+        let result = match args.api {
+            _ if !args.tools.is_empty() => {
+                crate::tools::run(args.clone(), tools).await.map(|_| None)
             }
+            Some(Api::OpenAi) => crate::openai::run(args.clone()).await,
+            Some(Api::Anthropic) => crate::anthropic::run(args.clone()).await,
+            Some(Api::Google) => crate::google::run(args.clone()).await,
+            Some(Api::Mistral) => crate::mistral::run(args.clone()).await,
+            Some(Api::MistralFim) => crate::mistral_fim::run(args.clone()).await,
+            Some(Api::OllamaFim) => crate::ollama_fim::run(args.clone()).await,
+            None => Err(Error::ApiNotSpecified),
+        };
+
+        if let Err(e) = &result {
+            crate::audit::record(&args, None, Default::default(), Some(e));
         }
-    };
 
-    Ok((args, config))
-}
+        match result {
+            Ok(Some(Followup::Truncated {
+                id,
+                conversation,
+                full_conversation,
+                answer,
+            })) => {
+                args.conversation = conversation;
+                args.full_conversation = full_conversation;
+                args.update_conversations(|conversation| {
+                    conversation.push(ConversationMessage {
+                        role: ConversationRole::User,
+                        content: crate::cache::CONTINUE_PROMPT.to_string(),
+                        ..Default::default()
+                    })
+                });
 
-fn get_latest_toml_file(cache_dir: &str) -> Result<Option<String>> {
-    let cache_files = std::fs::read_dir(cache_dir)?
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            let path = entry.path();
-            if path.extension()?.to_str()? == "toml" {
-                Some(path)
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<std::path::PathBuf>>();
+                if args.fork && args.from.is_some() {
+                    args.parent = args.from.clone();
+                }
 
-    let latest_file = cache_files.iter().max_by_key(|path| {
-        std::fs::metadata(path)
-            .and_then(|meta| meta.modified())
-            .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
-    });
+                args.from = id;
+                args.fork = false;
+                if overwrites {
+                    args.overwrite_parts =
+                        Some(args.overwrite_parts.take().unwrap_or_default() + &answer);
+                } else {
+                    args.append = args.output.is_some();
+                }
+                args.continue_generation = true;
 
-    Ok(latest_file.and_then(|path| {
-        path.file_stem()
-            .and_then(|stem| stem.to_str().map(String::from))
-    }))
-}
+                continuations += 1;
+                args.auto_continue = continuations < MAX_CONTINUATIONS;
+            }
+            Ok(Some(Followup::Invalid { id, answer, errors })) => {
+                let max_retries = args
+                    .validate_retries
+                    .unwrap_or(crate::validate::DEFAULT_RETRIES);
 
-/// Combines the existing arguments with the ones found on the cache file.
-pub fn merge_args_and_cache(mut args: Args) -> Result<Args> {
-    if args.from.is_none() && !args.from_last {
-        return Ok(args);
-    }
+                if retries >= max_retries {
+                    return Err(Error::InvalidJson(errors));
+                }
 
-    let cache_dir = format!(
-        "{}/cache",
-        args.config_dir
-            .clone()
-            .unwrap_or("~/.config/llm-stream".to_string()),
-    );
+                retries += 1;
+                if args.shows_notices() {
+                    eprintln!(
+                        "\nThe answer isn't valid: {}. Asking again, {} of {}.",
+                        errors, retries, max_retries
+                    );
+                }
 
-    if args.from_last {
-        args.from = get_latest_toml_file(&cache_dir)?
-    }
+                args.update_conversations(|conversation| {
+                    crate::cache::add_answer(conversation, &answer, false);
+                    conversation.push(ConversationMessage {
+                        role: ConversationRole::User,
+                        content: crate::validate::retry_prompt(&errors),
+                        ..Default::default()
+                    });
+                });
 
-    let id = args.from.clone().expect("No cache file found");
+                if args.fork && args.from.is_some() {
+                    args.parent = args.from.clone();
+                }
 
-    let cache_file = format!("{}/{}.toml", cache_dir, id);
+                args.from = id;
+                args.fork = false;
+            }
+            Ok(None) => return Ok(()),
+            Err(Error::EsStream(e))
+                if e.is_context_length_exceeded() && shrinks < crate::tokens::MAX_SHRINKS =>
+            {
+                args = crate::tokens::shrink(&args).ok_or(Error::EsStream(e))?;
+                shrinks += 1;
+            }
+            Err(e @ Error::FirstTokenTimeout(_)) => {
+                let Some(preset) = fallbacks.next() else {
+                    return Err(e);
+                };
+
+                if args.shows_notices() {
+                    crate::status::notice(&format!("{}, falling back to the {} preset", e, preset));
+                }
 
-    if !std::path::Path::new(&cache_file).exists() {
-        return Err(Error::CacheNotFound);
+                args = fallback_args(&args, &preset, config);
+            }
+            Err(e) => return Err(e),
+        }
     }
+}
 
-    let cache_args = toml::from_str::<Args>(&std::fs::read_to_string(&cache_file)?)?;
+/// Waits for the first text of `stream`, skipping the events without text. Errors sent before it,
+/// like an overloaded api, can be retried without printing anything twice. Returns `None` if the
+/// stream ends first.
+pub async fn first_text(
+    stream: &mut (impl Stream<Item = std::result::Result<String, llm_stream::error::Error>>
+              + std::marker::Unpin),
+) -> std::result::Result<Option<String>, llm_stream::error::Error> {
+    loop {
+        match stream.try_next().await {
+            Ok(Some(text)) if text.is_empty() => continue,
+            Ok(Some(text)) => return Ok(Some(text)),
+            Ok(None) => return Ok(None),
+            Err(llm_stream::error::Error::EventsourceClient(
+                llm_stream::error::EventsourceError::Eof,
+            )) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-    args.conversation = cache_args.conversation;
+/// Times a request is sent again when the api is overloaded or rate limited.
+const MAX_RETRIES: u32 = 3;
 
-    if args.api.is_none() {
-        args.api = cache_args.api;
-    }
-    if args.model.is_none() {
-        args.model = cache_args.model;
-    }
-    if args.api_version.is_none() {
-        args.api_version = cache_args.api_version;
-    }
-    if args.api_env.is_none() {
-        args.api_env = cache_args.api_env;
-    }
-    if args.api_key.is_none() {
-        args.api_key = cache_args.api_key;
-    }
-    if args.temperature.is_none() {
-        args.temperature = cache_args.temperature;
-    }
-    if args.max_tokens.is_none() {
-        args.max_tokens = cache_args.max_tokens;
-    }
-    if args.quiet.is_none() {
-        args.quiet = cache_args.quiet;
-    }
-    if args.language.is_none() {
-        args.language = cache_args.language;
-    }
-    if args.theme.is_none() {
-        args.theme = cache_args.theme;
-    }
-    if args.top_p.is_none() {
-        args.top_p = cache_args.top_p;
-    }
-    if args.top_k.is_none() {
-        args.top_k = cache_args.top_k;
-    }
+/// Longest time waited before sending a request again.
+pub(crate) const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
 
-    Ok(args)
+/// Times a response is given to send its events, from `--first-token-timeout` and
+/// `--stall-timeout`. Without them, a hung connection waits forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Time waited for the first event of the response.
+    pub first_token: Option<std::time::Duration>,
+    /// Time waited for the next event once the response started.
+    pub stall: Option<std::time::Duration>,
 }
 
-/// Builds the arguments struct based on a combination of the following inputs,
-/// in this order.
-///
-/// 1. CLI options/Environment variables.
-/// 2. Environment variable.
-/// 3. Config preset and/or template options.
-/// 4. Config file default options.
-pub fn merge_args_and_config(mut args: Args, config: Config) -> Result<Args> {
-    if let Some(ref template) = args.template {
-        let t = config
-            .templates
-            .unwrap_or_default()
-            .into_iter()
-            .find(|t| t.name == *template);
-
-        if t.is_none() {
-            return Err(Error::TemplateNotFound);
+impl Timeouts {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            first_token: args.first_token_timeout.map(std::time::Duration::from_secs),
+            stall: args.stall_timeout.map(std::time::Duration::from_secs),
         }
+    }
+}
 
-        let t = t.unwrap();
-
-        let mut default_vars =
-            if t.default_vars.is_none() || t.default_vars.as_ref().unwrap().is_null() {
-                serde_json::json!("{}")
-            } else {
-                t.default_vars.unwrap()
-            };
+/// Ends `stream` with an error when it sends no event for longer than `timeout`. Events without
+/// text count as progress too, since the deltas of a tool call have none.
+fn stall_timeout<S>(
+    stream: S,
+    timeout: Option<std::time::Duration>,
+) -> impl Stream<Item = std::result::Result<String, llm_stream::error::Error>>
+where
+    S: Stream<Item = std::result::Result<String, llm_stream::error::Error>> + std::marker::Unpin,
+{
+    futures::stream::unfold((stream, false), move |(mut stream, stalled)| async move {
+        if stalled {
+            return None;
+        }
 
-        let vars = if args.vars.is_none() || args.vars.as_ref().unwrap().is_null() {
-            serde_json::json!("{}")
-        } else {
-            args.vars.take().unwrap()
+        let next = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let e = llm_stream::error::Error::RequestError(format!(
+                        "the stream stalled, nothing arrived for {}s",
+                        timeout.as_secs()
+                    ));
+                    return Some((Err(e), (stream, true)));
+                }
+            },
+            None => stream.next().await,
         };
 
-        merge(&mut default_vars, vars);
+        next.map(|item| (item, (stream, false)))
+    })
+}
 
-        let mut value = serde_json::json!({
-            "prompt": args.prompt.clone().unwrap_or_default(),
-            "stdin": args.stdin.clone().unwrap_or_default(),
-            "suffix": args.suffix.clone().unwrap_or_default().to_string(),
-            "language": args.language.clone(),
-        });
+/// Opens the stream of a response with `open`. Errors sent before any text, like an overloaded or
+/// rate limited api, are retried after the time given by the `retry-after` header, or with an
+/// exponential backoff. Other errors fail with the message of the api, and so does a response
+/// that sends no event within the `timeouts`.
+pub async fn open_stream<S>(
+    open: impl Fn() -> std::result::Result<S, llm_stream::error::Error>,
+    quiet: bool,
+    timeouts: Timeouts,
+) -> Result<
+    impl Stream<Item = std::result::Result<String, llm_stream::error::Error>> + std::marker::Unpin,
+>
+where
+    S: Stream<Item = std::result::Result<String, llm_stream::error::Error>>,
+{
+    let mut attempt = 0;
 
-        merge(&mut value, default_vars);
+    loop {
+        let mut opened = Box::pin(open()?);
 
-        let context = tera::Context::from_value(value)?;
+        let first_event = match timeouts.first_token {
+            Some(timeout) => tokio::time::timeout(timeout, opened.next())
+                .await
+                .map_err(|_| Error::FirstTokenTimeout(timeout.as_secs()))?,
+            None => opened.next().await,
+        };
 
-        log::info!("context: {:#?}", &context);
+        // Once an event arrived, the ones without text, like the deltas of a tool call, keep the
+        // stream alive until its first text.
+        let opened = if first_event.is_some() {
+            opened.take(usize::MAX)
+        } else {
+            opened.take(0)
+        };
+        let mut stream = Box::pin(
+            futures::stream::iter(first_event).chain(stall_timeout(opened, timeouts.stall)),
+        );
+        let first = first_text(&mut stream).await;
+
+        match first {
+            Err(e) if e.is_retryable() && attempt < MAX_RETRIES => {
+                let delay = e
+                    .retry_after()
+                    .unwrap_or(std::time::Duration::from_secs(2u64.pow(attempt)))
+                    .min(MAX_RETRY_DELAY);
+                attempt += 1;
+
+                log::warn!("{}, retry {} of {}", e, attempt, MAX_RETRIES);
+                if !quiet {
+                    crate::status::notice(&format!(
+                        "{}, retrying in {:.0}s...",
+                        e,
+                        delay.as_secs_f64()
+                    ));
+                }
 
-        let mut tera = tera::Tera::default();
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(Error::from(e)),
+            // The rest of the stream is dropped if it already ended, so it isn't polled again.
+            Ok(first) => {
+                let rest = if first.is_some() {
+                    stream.take(usize::MAX)
+                } else {
+                    stream.take(0)
+                };
 
-        if args.system.is_none() {
-            if let Some(system) = t.system {
-                tera.add_raw_template(SYSTEM_TEMPLATE, &system)?;
-                args.system = Some(tera.render(SYSTEM_TEMPLATE, &context)?);
+                return Ok(futures::stream::iter(first.map(Ok))
+                    .chain(rest.try_filter(|text| futures::future::ready(!text.is_empty()))));
             }
         }
+    }
+}
 
-        if let Some(template) = t.template {
-            tera.add_raw_template(PROMPT_TEMPLATE, &template)?;
-
-            args.prompt = Some(tera.render(PROMPT_TEMPLATE, &context)?);
-        }
+/// Sends the conversation to the LLM selected by `args.api` and returns its response without
+/// printing it, calling `on_delta` with every chunk of text.
+pub async fn complete(args: Args, on_delta: impl FnMut(&str)) -> Result<String> {
+    complete_with_metadata(args, SharedMetadata::default(), on_delta).await
+}
 
-        if let Some(conversation) = t.conversation {
-            for message in conversation {
-                tera.add_raw_template(CONTENT_TEMPLATE, &message.content)?;
-
-                if message.role == ConversationRole::System && args.conversation.len() > 0 {
-                    if args.conversation.first().unwrap().role != ConversationRole::System {
-                        args.conversation.insert(
-                            0,
-                            ConversationMessage {
-                                role: ConversationRole::System,
-                                content: tera.render(CONTENT_TEMPLATE, &context)?,
-                            },
-                        );
-                    } else {
-                        continue;
-                    }
-                } else {
-                    args.conversation.push(ConversationMessage {
-                        role: message.role.clone(),
-                        content: tera.render(CONTENT_TEMPLATE, &context)?,
-                    });
-                }
-            }
+/// Same as `complete`, filling `metadata` with the finish reason and usage of the response. The
+/// answer goes through the `post_response` hook after `on_delta` sees its text.
+pub async fn complete_with_metadata(
+    args: Args,
+    metadata: SharedMetadata,
+    on_delta: impl FnMut(&str),
+) -> Result<String> {
+    let request = args.clone();
+
+    crate::report::wait_for_quota(args.api, args.shows_notices()).await;
+
+    let result = match args.api {
+        Some(Api::OpenAi) => crate::openai::complete(args, metadata.clone(), on_delta).await,
+        Some(Api::Anthropic) => crate::anthropic::complete(args, metadata.clone(), on_delta).await,
+        Some(Api::Google) => crate::google::complete(args, metadata.clone(), on_delta).await,
+        Some(Api::Mistral) => crate::mistral::complete(args, metadata.clone(), on_delta).await,
+        Some(Api::MistralFim) => {
+            crate::mistral_fim::complete(args, metadata.clone(), on_delta).await
         }
-    } else if args.stdin.is_some() {
-        args.prompt = Some(
-            format!(
-                "{}\n{}",
-                args.stdin.clone().unwrap(),
-                args.prompt.clone().unwrap_or_default()
-            )
-            .trim()
-            .to_string(),
-        );
+        Some(Api::OllamaFim) => crate::ollama_fim::complete(args, metadata.clone(), on_delta).await,
+        None => Err(Error::ApiNotSpecified),
     };
 
-    if args.top_p.is_none() {
-        args.top_p = config.top_p;
-    }
-    if args.top_k.is_none() {
-        args.top_k = config.top_k;
-    }
-    if args.temperature.is_none() {
-        args.temperature = config.temperature;
-    }
-    if args.conversation.len() == 0
-        || args.conversation.first().unwrap().role != ConversationRole::System
-    {
-        args.conversation.insert(
-            0,
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: config.system.clone().unwrap_or_default(),
-            },
-        );
-    }
-    if args.max_tokens.is_none() {
-        args.max_tokens = config.max_tokens;
-    }
-    if args.api_version.is_none() {
-        args.api_version = config.version;
-    }
-    if args.api_env.is_none() {
-        args.api_env = config.env;
-    }
-    if args.api_key.is_none() {
-        args.api_key = config.key;
-    }
-    if args.api_base_url.is_none() {
-        args.api_base_url = config.base_url;
-    }
-    if args.model.is_none() {
-        args.model = config.model;
-    }
-    if args.quiet.is_none() {
-        args.quiet = config.quiet;
-    }
-    if args.language.is_none() {
-        args.language = config.language;
-    }
-    if args.theme.is_none() {
-        args.theme = config.theme;
-    }
-    if args.api.is_none() {
-        args.api = config.api;
-    }
+    let snapshot = metadata.lock().map(|m| m.clone()).unwrap_or_default();
 
-    args.conversation.push(ConversationMessage {
-        role: ConversationRole::User,
-        content: args.prompt.clone().unwrap_or_default(),
+    let result = result.and_then(|content| {
+        match crate::hooks::post_response(&request, &content, &snapshot)? {
+            Some(message) => Ok(message.trim().to_string()),
+            None => Ok(content),
+        }
     });
 
-    if args.system.is_some() {
-        if args.conversation.len() > 1
-            && args.conversation.first().unwrap().role == ConversationRole::System
-        {
-            args.conversation[0].content = args.system.clone().unwrap();
-        } else {
-            args.conversation.insert(
-                0,
-                ConversationMessage {
-                    role: ConversationRole::System,
-                    content: args.system.clone().unwrap(),
-                },
-            );
-        }
-    };
+    crate::audit::record(
+        &request,
+        result.as_deref().ok(),
+        snapshot.usage,
+        result.as_ref().err(),
+    );
 
-    Ok(args)
+    result
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config::{Preset, Template};
-
-    #[test]
-    fn test_args_dont_change_on_empty_config() -> std::result::Result<(), Box<dyn std::error::Error>>
-    {
-        let args = Args::default();
-        let config: Config = Config::default();
+/// Asks a yes or no question on the terminal. It's answered no when there isn't a terminal to
+/// ask on.
+pub fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        eprintln!("Declined, there's no terminal to confirm it");
+        return Ok(false);
+    }
 
-        let mut expected = args.clone();
-        expected.conversation = vec![ConversationMessage::default()];
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
 
-        let actual = merge_args_and_config(args, config)?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
 
-        assert_eq!(
-            expected, actual,
-            "merge_args_and_config changed the default values"
-        );
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Merges two JSON objects defined as `serde_json::Value`.
+pub fn merge(a: &mut Value, b: Value) {
+    if let Value::Object(a) = a {
+        if let Value::Object(b) = b {
+            for (k, v) in b {
+                if v.is_null() {
+                    a.remove(&k);
+                } else {
+                    merge(a.entry(k).or_insert(Value::Null), v);
+                }
+            }
+
+            return;
+        }
+        return;
+    }
+
+    *a = b;
+}
+
+/// Reads a file of template variables, in JSON, TOML, or YAML format depending on its extension.
+fn read_vars_file(path: &str) -> Result<Value> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?;
+
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    Ok(match extension {
+        "toml" => toml::from_str(&contents)?,
+        "yaml" | "yml" => serde_yaml::from_str(&contents)?,
+        _ => serde_json::from_str(&contents)?,
+    })
+}
+
+/// Returns the template variables of `--vars-file` merged with the ones of `--vars`, which take
+/// precedence.
+pub fn template_vars(args: &mut Args) -> Result<Value> {
+    let mut vars = match args.vars_file.take() {
+        Some(path) => read_vars_file(&path)?,
+        None => serde_json::json!({}),
+    };
+
+    if let Some(overrides) = args.vars.take().filter(|v| !v.is_null()) {
+        merge(&mut vars, overrides);
+    }
+
+    Ok(vars)
+}
+
+/// Returns the variables used to render a template: the built-in ones, its `default_vars`, and the
+/// ones given with `--vars-file` and `--vars`, in increasing order of precedence.
+pub fn template_context(args: &mut Args, template: &crate::config::Template) -> Result<Value> {
+    let mut default_vars = match template.default_vars.clone() {
+        Some(vars) if !vars.is_null() => vars,
+        _ => serde_json::json!({}),
+    };
+
+    let vars = template_vars(args)?;
+
+    merge(&mut default_vars, vars);
+
+    let mut value = serde_json::json!({
+        "prompt": args.prompt.clone().unwrap_or_default(),
+        "stdin": args.stdin.clone().unwrap_or_default(),
+        "suffix": args.suffix.clone().unwrap_or_default().to_string(),
+        "language": args.language.clone(),
+    });
+
+    merge(&mut value, default_vars);
+
+    Ok(value)
+}
+
+/// Reads the configuration file. If it or the config directory doesn't exist, they'll be created.
+pub fn build_config(args: Args) -> Result<(Args, Config)> {
+    let config_dir = args
+        .config_dir
+        .clone()
+        .unwrap_or("~/.config/llm-stream".to_string());
+    let config_file = args.config_file.clone().unwrap();
+
+    log::info!("config_dir: {}", &config_dir);
+    log::info!("config_file: {}", &config_file);
+
+    let mut config = if !std::path::Path::new(&config_file).exists() {
+        let config = Config::new();
+        let config_toml = toml::to_string(&config)?;
+        // Store `config_toml` in the `&config_file` path.
+        std::fs::write(&config_file, config_toml)?;
+
+        config
+    } else {
+        Config::from_config_file(&config_file)?
+    }
+    .merge_env()?;
+
+    if config
+        .schema_version
+        .is_some_and(|version| version > crate::schema::CONFIG_SCHEMA_VERSION)
+    {
+        return Err(Error::UnsupportedConfig(config_file));
+    }
+
+    let templates_dir = crate::templates::templates_dir(&config_dir);
+    if !std::path::Path::new(&templates_dir).exists() {
+        std::fs::create_dir_all(&templates_dir)?;
+    }
+
+    let cache_dir =
+        crate::cache::cache_dir(&args.data_dir.clone().expect("can't find data directory"));
+    if !std::path::Path::new(&cache_dir).exists() {
+        std::fs::create_dir_all(&cache_dir)?;
+    }
+
+    config.templates = Some(load_templates(&config_dir, config.templates.take())?);
+
+    Ok((args, config))
+}
+
+/// Returns the templates of the configuration file, followed by the ones in the templates dir and
+/// the built-in ones that weren't redefined.
+pub fn load_templates(
+    config_dir: &str,
+    config_templates: Option<Vec<crate::config::Template>>,
+) -> Result<Vec<crate::config::Template>> {
+    let templates_dir = crate::templates::templates_dir(config_dir);
+    let templates = std::fs::read_dir(&templates_dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+
+            match crate::templates::from_file(&path) {
+                Ok(template) => template,
+                Err(e) => {
+                    log::warn!("skipping template {}: {:?}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect::<Vec<crate::config::Template>>();
+
+    let mut templates: Vec<crate::config::Template> =
+        if let Some(config_templates) = config_templates {
+            config_templates.into_iter().chain(templates).collect()
+        } else {
+            templates
+        };
+
+    // Built-in templates, used unless a template with the same name is defined.
+    for template in [
+        crate::commit::template(),
+        crate::review::Mode::Review.template(),
+        crate::review::Mode::Pr.template(),
+    ] {
+        if !templates.iter().any(|t| t.name == template.name) {
+            templates.push(template);
+        }
+    }
+
+    Ok(templates)
+}
+
+/// Handles the command prompt, adding support for reading from `stdin`, an argument, a file, or
+/// a tuple of those three.
+///
+/// This function handles the following situations:
+///
+/// 1. Calling the binary with no arguments.
+///
+/// ```bash
+/// llm-stream
+/// ```
+///
+/// This will render in an empty value for both `prompt` and `stdin`.
+///
+/// 2. Calling the binary with `stdin` input.
+///
+/// ```bash
+/// echo -n "Something" | llm-stream
+/// ```
+///
+/// This will render in `prompt` to `Something` and `stdin` to be empty.
+///
+/// 3. Calling the binary with an argument.
+///
+/// ```bash
+/// llm-stream "Something"
+/// ```
+///
+/// This will render in `prompt` to `Something` and `stdin` to be empty.
+///
+/// 4. Calling the binary with an argument and `stdin` input.
+///
+/// ```bash
+/// echo -n "Awesome" | llm-stream "Something"
+/// ```
+///
+/// This will render `prompt` to be `Something, and `stdin` to be `Awesome`.
+pub fn parse_args(mut args: Args, config: Config) -> Result<(Args, Config)> {
+    let stdin = std::io::stdin();
+
+    // `--spec -` and `--prompt-file -` read the request or the prompt from stdin instead.
+    let stdin_is_input =
+        args.spec.as_deref() == Some("-") || args.prompt_file.as_deref() == Some("-");
+
+    args.stdin = Some(if stdin.is_terminal() || stdin_is_input {
+        "".to_string()
+    } else {
+        let text = std::io::stdin()
+            .lock()
+            .lines()
+            .collect::<std::result::Result<Vec<String>, std::io::Error>>()?
+            .join("\n");
+
+        // The text of `--filter` keeps its indentation.
+        if args.filter {
+            text.trim_matches('\n').to_string()
+        } else {
+            text.trim().to_string()
+        }
+    });
+
+    if let Some(path) = args.prompt_file.take() {
+        args.prompt = Some(read_prompt_file(&path)?);
+    }
+
+    if let Some(path) = args.spec.take() {
+        crate::spec::read(&path)?.apply(&mut args);
+    }
+
+    if let Some(path) = args.conversation_file.take() {
+        args.conversation = crate::cache::read_conversation_file(&path)?;
+    }
+
+    if args.prompt.is_none() {
+        args.prompt = Some(args.stdin.clone().unwrap_or_default());
+        args.stdin = None;
+    }
+
+    if args.filter {
+        filter(&mut args);
+    }
+
+    crate::alias::apply(&mut args, &config)?;
+    merge_preset(&mut args, &config);
+
+    Ok((args, config))
+}
+
+/// Reads the prompt of `--prompt-file`, or of stdin if `path` is `-`.
+pub fn read_prompt_file(path: &str) -> Result<String> {
+    let prompt = if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?
+    };
+
+    Ok(prompt.trim().to_string())
+}
+
+/// Sets up `--filter`: turns off the status line and colors, and, unless a template is used, asks
+/// for only the transformed text, with the text fenced after the instructions.
+fn filter(args: &mut Args) {
+    args.quiet = true;
+    args.no_color = true;
+
+    if args.template.is_some() {
+        return;
+    }
+
+    if let (Some(instructions), Some(text)) = (&args.prompt, args.stdin.take()) {
+        let fence = crate::files::fence_marker(&text);
+
+        args.prompt = Some(format!(
+            "{}\n\nAnswer only with the transformed text, without explanations or code fences.\n\n{fence}\n{}\n{fence}",
+            instructions, text
+        ));
+    }
+}
+
+/// Combines the prompt with the text read from stdin. A `{stdin}` placeholder in the prompt is
+/// replaced with it, otherwise `placement` decides where it goes.
+fn place_stdin(prompt: &str, stdin: &str, placement: StdinPlacement) -> String {
+    let prompt = if prompt.contains(STDIN_PLACEHOLDER) {
+        prompt.replace(STDIN_PLACEHOLDER, stdin)
+    } else {
+        match placement {
+            StdinPlacement::Before => format!("{}\n{}", stdin, prompt),
+            StdinPlacement::After => format!("{}\n{}", prompt, stdin),
+            StdinPlacement::None => prompt.to_string(),
+        }
+    };
+
+    prompt.trim().to_string()
+}
+
+/// Fills the arguments that weren't set with the values of the preset selected with `--preset`.
+pub fn merge_preset(args: &mut Args, config: &Config) {
+    if let Some(preset) = args.preset.clone() {
+        let p = config
+            .presets
+            .clone()
+            .unwrap_or(vec![])
+            .into_iter()
+            .find(|p| p.name == preset);
+
+        // Routers are resolved to one of their presets by `router::route`.
+        if let Some(p) = p.filter(|p| p.routes.is_none()) {
+            if args.api.is_none() {
+                args.api = Some(p.api);
+            }
+
+            if args.top_p.is_none() {
+                args.top_p = p.top_p;
+            }
+            if args.top_k.is_none() {
+                args.top_k = p.top_k;
+            }
+            if args.temperature.is_none() {
+                args.temperature = p.temperature;
+            }
+            if args.conversation.len() == 0
+                || args.conversation.first().unwrap().role != ConversationRole::System
+            {
+                args.conversation.insert(
+                    0,
+                    ConversationMessage {
+                        role: ConversationRole::System,
+                        content: p.system.clone().unwrap_or_default(),
+                        ..Default::default()
+                    },
+                );
+            }
+            if args.max_tokens.is_none() {
+                args.max_tokens = p.max_tokens;
+            }
+            if args.api_version.is_none() {
+                args.api_version = p.version;
+            }
+            if args.api_env.is_none() {
+                args.api_env = p.env;
+            }
+            if args.api_key.is_none() {
+                args.api_key = p.key;
+            }
+            if args.key_cmd.is_none() {
+                args.key_cmd = p.key_cmd;
+            }
+            if args.api_base_url.is_none() {
+                args.api_base_url = p.base_url;
+            }
+            if args.model.is_none() {
+                args.model = p.model;
+            }
+        }
+    }
+}
+
+/// Returns the arguments that send the request to the provider of `preset` instead, for
+/// `--fallback`. The options of the provider, like its key, url, and headers, come from the preset
+/// and its `[apis.<api>]` section, while the conversation and the rest of the options are kept.
+pub fn fallback_args(args: &Args, preset: &str, config: &Config) -> Args {
+    let mut fallback = Args {
+        api: None,
+        model: None,
+        api_base_url: None,
+        api_env: None,
+        api_key: None,
+        key_cmd: None,
+        api_version: None,
+        headers: Vec::new(),
+        extra_body: None,
+        extra_query: Vec::new(),
+        preset: Some(preset.to_string()),
+        ..args.clone()
+    };
+
+    merge_preset(&mut fallback, config);
+
+    if let Some(section) = fallback
+        .api
+        .and_then(|api| config.apis.as_ref()?.get(&api.to_string()).cloned())
+    {
+        merge_api_config(&mut fallback, section);
+    }
+
+    fallback
+}
+
+pub fn get_latest_toml_file(cache_dir: &str) -> Result<Option<String>> {
+    let cache_files = std::fs::read_dir(cache_dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()?.to_str()? == "toml" {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<std::path::PathBuf>>();
+
+    let latest_file = cache_files.iter().max_by_key(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
+    });
+
+    Ok(latest_file.and_then(|path| {
+        path.file_stem()
+            .and_then(|stem| stem.to_str().map(String::from))
+    }))
+}
+
+/// Combines the existing arguments with the ones found on the cache file.
+pub fn merge_args_and_cache(mut args: Args) -> Result<Args> {
+    if args.from.is_none() && !args.from_last {
+        return Ok(args);
+    }
+
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+
+    if args.from_last {
+        args.from = get_latest_toml_file(&crate::cache::cache_dir(&data_dir))?
+    }
+
+    let id = args.from.clone().expect("No cache file found");
+
+    // Read through the cache, which refuses ids outside of it before anything is sent.
+    let cache_args = crate::cache::read(&data_dir, &id)?;
+
+    args.conversation = cache_args.conversation;
+
+    if args.api.is_none() {
+        args.api = cache_args.api;
+    }
+    if args.model.is_none() {
+        args.model = cache_args.model;
+    }
+    if args.api_version.is_none() {
+        args.api_version = cache_args.api_version;
+    }
+    if args.api_env.is_none() {
+        args.api_env = cache_args.api_env;
+    }
+    if args.api_key.is_none() {
+        args.api_key = cache_args.api_key;
+    }
+    if args.temperature.is_none() {
+        args.temperature = cache_args.temperature;
+    }
+    if args.max_tokens.is_none() {
+        args.max_tokens = cache_args.max_tokens;
+    }
+    if args.language.is_none() {
+        args.language = cache_args.language;
+    }
+    if args.theme.is_none() {
+        args.theme = cache_args.theme;
+    }
+    if args.top_p.is_none() {
+        args.top_p = cache_args.top_p;
+    }
+    if args.top_k.is_none() {
+        args.top_k = cache_args.top_k;
+    }
+
+    Ok(args)
+}
+
+/// Gets `args`, merged with the configuration, ready to be sent: checks the model, compacts the
+/// conversation unless it's a dry run, and truncates it to the context window. Shared by the
+/// command line and the terminal interface.
+pub async fn prepare(args: Args, config: &Config) -> Result<Args> {
+    crate::models::validate(&args, config)?;
+
+    let args = if args.dry_run {
+        args
+    } else {
+        crate::compaction::compact(
+            args,
+            config.compaction.clone(),
+            config.presets.clone().unwrap_or_default(),
+        )
+        .await?
+    };
+
+    Ok(crate::tokens::truncate(args))
+}
+
+/// Builds the arguments struct based on a combination of the following inputs,
+/// in this order.
+///
+/// 1. CLI options/Environment variables.
+/// 2. Environment variable.
+/// 3. Config preset and/or template options.
+/// 4. Config file default options.
+pub fn merge_args_and_config(mut args: Args, config: Config) -> Result<Args> {
+    let mut template_system = None;
+    // Continued conversations keep the system message they were cached with.
+    let cached_system = args
+        .conversation
+        .first()
+        .is_some_and(|m| m.role == ConversationRole::System);
+
+    if let Some(ref template) = args.template {
+        let t = config
+            .templates
+            .unwrap_or_default()
+            .into_iter()
+            .find(|t| t.name == *template);
+
+        if t.is_none() {
+            return Err(Error::TemplateNotFound);
+        }
+
+        let t = t.unwrap();
+
+        if args.api.is_none() {
+            args.api = t.api;
+        }
+        if args.model.is_none() {
+            args.model = t.model.clone();
+        }
+        if args.max_tokens.is_none() {
+            args.max_tokens = t.max_tokens;
+        }
+        if args.temperature.is_none() {
+            args.temperature = t.temperature;
+        }
+        if args.top_p.is_none() {
+            args.top_p = t.top_p;
+        }
+        if args.top_k.is_none() {
+            args.top_k = t.top_k;
+        }
+
+        let value = template_context(&mut args, &t)?;
+
+        let context = tera::Context::from_value(value)?;
+
+        log::info!("context: {:#?}", &context);
+
+        let mut tera = tera::Tera::default();
+        crate::functions::register(&mut tera, config.template_functions.clone());
+
+        if let Some(system) = t.system {
+            tera.add_raw_template(SYSTEM_TEMPLATE, &system)?;
+            template_system = Some(tera.render(SYSTEM_TEMPLATE, &context)?);
+        }
+
+        if let Some(template) = t.template {
+            tera.add_raw_template(PROMPT_TEMPLATE, &template)?;
+
+            args.prompt = Some(tera.render(PROMPT_TEMPLATE, &context)?);
+        }
+
+        if let Some(conversation) = t.conversation {
+            render_conversation(&mut tera, &context, conversation, &mut args.conversation)?;
+        }
+    } else if let Some(stdin) = &args.stdin {
+        args.prompt = Some(place_stdin(
+            args.prompt.as_deref().unwrap_or_default(),
+            stdin,
+            args.stdin_placement.unwrap_or_default(),
+        ));
+    };
+
+    // The attached files go before the prompt, wherever stdin is placed.
+    if let Some(attachments) = args.attachments.take() {
+        let prompt = args.prompt.take().unwrap_or_default();
+        args.prompt = Some(format!("{}\n{}", attachments, prompt).trim().to_string());
+    }
+
+    if args.api.is_none() {
+        args.api = config.api;
+    }
+
+    // The section of the selected api takes precedence over the top-level defaults.
+    if let Some(api) = args
+        .api
+        .and_then(|api| config.apis.as_ref()?.get(&api.to_string()).cloned())
+    {
+        merge_api_config(&mut args, api);
+    }
+
+    if args.top_p.is_none() {
+        args.top_p = config.top_p;
+    }
+    if args.top_k.is_none() {
+        args.top_k = config.top_k;
+    }
+    if args.temperature.is_none() {
+        args.temperature = config.temperature;
+    }
+    if args.conversation.len() == 0
+        || args.conversation.first().unwrap().role != ConversationRole::System
+    {
+        args.conversation.insert(
+            0,
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: config.system.clone().unwrap_or_default(),
+                ..Default::default()
+            },
+        );
+    }
+    if args.max_tokens.is_none() {
+        args.max_tokens = config.max_tokens;
+    }
+    if args.api_version.is_none() {
+        args.api_version = config.version;
+    }
+    if args.api_env.is_none() {
+        args.api_env = config.env;
+    }
+    if args.api_key.is_none() {
+        args.api_key = config.key;
+    }
+    if args.key_source.is_none() {
+        args.key_source = config.key_source;
+    }
+    if args.key_cmd.is_none() {
+        args.key_cmd = config.key_cmd;
+    }
+    if args.api_base_url.is_none() {
+        args.api_base_url = config.base_url;
+    }
+    merge_extra_body(&mut args, config.extra_body);
+    merge_extra_query(&mut args, config.extra_query);
+    if args.model.is_none() {
+        args.model = config.model;
+    }
+    args.quiet |= config.quiet.unwrap_or_default();
+    args.no_spinner |= config.no_spinner.unwrap_or_default();
+    args.no_notices |= config.no_notices.unwrap_or_default();
+    if args.first_token_timeout.is_none() {
+        args.first_token_timeout = config.first_token_timeout;
+    }
+    if args.stall_timeout.is_none() {
+        args.stall_timeout = config.stall_timeout;
+    }
+    if args.fallback.is_empty() {
+        args.fallback = config.fallback.unwrap_or_default();
+    }
+    if args.language.is_none() {
+        args.language = config.language;
+    }
+    if args.theme.is_none() {
+        args.theme = config.theme;
+    }
+    if args.dark_theme.is_none() {
+        args.dark_theme = config.dark_theme;
+    }
+    if args.light_theme.is_none() {
+        args.light_theme = config.light_theme;
+    }
+    if args.wrap.is_none() {
+        args.wrap = config.wrap;
+    }
+    if args.renderer.is_none() {
+        args.renderer = config.renderer;
+    }
+    args.hooks = config.hooks.unwrap_or_default();
+    args.audit = config.audit;
+    args.conversation.push(ConversationMessage {
+        role: ConversationRole::User,
+        content: args.prompt.clone().unwrap_or_default(),
+        ..Default::default()
+    });
+
+    // The system message of the template replaces the one of the preset, and `--system-file` and
+    // `--system` are added after it, in that order. Continued conversations keep their system
+    // message, and don't get the parts they already have twice.
+    if let Some(system) = template_system.filter(|_| !cached_system) {
+        args.conversation[0].content = system;
+    }
+
+    let system = &mut args.conversation[0].content;
+
+    for part in &args.system {
+        if has_entry(system, part) {
+            continue;
+        }
+
+        if !system.trim().is_empty() {
+            system.push_str("\n\n");
+        }
+
+        system.push_str(part);
+    }
+
+    Ok(args)
+}
+
+/// Returns true if `part` is one of the entries joined into `system`, and not only the text of
+/// some of them.
+fn has_entry(system: &str, part: &str) -> bool {
+    system == part
+        || system.starts_with(&format!("{}\n\n", part))
+        || system.ends_with(&format!("\n\n{}", part))
+        || system.contains(&format!("\n\n{}\n\n", part))
+}
+
+/// Renders the messages of a template conversation into `conversation`.
+///
+/// The system messages of the template are joined into one, which is only used when the
+/// conversation doesn't start with a system message already, like when continuing a cached
+/// conversation. The other messages are added in order.
+fn render_conversation(
+    tera: &mut tera::Tera,
+    context: &tera::Context,
+    messages: Vec<crate::config::TemplateMessage>,
+    conversation: &mut Conversation,
+) -> Result<()> {
+    let mut system = Vec::new();
+
+    for message in messages {
+        let content = tera.render_str(&message.content, context)?;
+
+        match message.role {
+            ConversationRole::System => system.push(content),
+            role => conversation.push(ConversationMessage {
+                role,
+                content,
+                ..Default::default()
+            }),
+        }
+    }
+
+    let has_system = conversation
+        .first()
+        .is_some_and(|m| m.role == ConversationRole::System);
+
+    if !system.is_empty() && !has_system {
+        conversation.insert(
+            0,
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: system.join("\n\n"),
+                ..Default::default()
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the arguments used to reach `api` outside of a prompt, like to list its models: its
+/// `[apis.<api>]` section, and the top-level key options when it's the default api.
+pub fn api_args(api: Api, config: &Config) -> Args {
+    let mut args = Args {
+        api: Some(api),
+        key_source: config.key_source,
+        ..Default::default()
+    };
+
+    if let Some(section) = config
+        .apis
+        .as_ref()
+        .and_then(|apis| apis.get(&api.to_string()))
+    {
+        merge_api_config(&mut args, section.clone());
+    }
+
+    if config.api == Some(api) {
+        args.api_base_url = args.api_base_url.or(config.base_url.clone());
+        args.api_env = args.api_env.or(config.env.clone());
+        args.api_key = args.api_key.or(config.key.clone());
+        args.key_cmd = args.key_cmd.or(config.key_cmd.clone());
+        merge_extra_query(&mut args, config.extra_query.clone());
+    }
+
+    args
+}
+
+/// Fills the arguments that weren't set with the values of an `[apis.<api>]` section.
+pub fn merge_api_config(args: &mut Args, api: crate::config::ApiConfig) {
+    if args.model.is_none() {
+        args.model = api.model;
+    }
+    if args.api_base_url.is_none() {
+        args.api_base_url = api.base_url;
+    }
+    if args.api_env.is_none() {
+        args.api_env = api.env;
+    }
+    if args.api_key.is_none() {
+        args.api_key = api.key;
+    }
+    if args.key_cmd.is_none() {
+        args.key_cmd = api.key_cmd;
+    }
+    if args.api_version.is_none() {
+        args.api_version = api.version;
+    }
+
+    for (name, value) in api.headers.unwrap_or_default() {
+        if !args
+            .headers
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(&name))
+        {
+            args.headers.push((name, value));
+        }
+    }
+
+    merge_extra_body(args, api.extra_body);
+    merge_extra_query(args, api.extra_query);
+}
+
+/// Adds the query parameters of the configuration that the arguments don't have yet.
+fn merge_extra_query(
+    args: &mut Args,
+    extra_query: Option<std::collections::BTreeMap<String, String>>,
+) {
+    for (name, value) in extra_query.unwrap_or_default() {
+        if !args.extra_query.iter().any(|(n, _)| *n == name) {
+            args.extra_query.push((name, value));
+        }
+    }
+}
+
+/// Deep merges `b` into `a`, like `merge`, but keeps the `null` values, so they still remove their
+/// keys once merged into the request.
+fn layer(a: &mut Value, b: Value) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in b {
+                layer(a.entry(k).or_insert(Value::Null), v);
+            }
+        }
+        (a, b) => *a = b,
+    }
+}
+
+/// Merges an `extra_body` of the configuration under the one of the arguments, so the keys given
+/// on the command line win.
+fn merge_extra_body(args: &mut Args, extra_body: Option<Value>) {
+    let Some(mut extra_body) = extra_body else {
+        return;
+    };
+
+    if let Some(overrides) = args.extra_body.take() {
+        layer(&mut extra_body, overrides);
+    }
+
+    args.extra_body = Some(extra_body);
+}
+
+/// Returns the `on_request` hook that deep merges `--extra-body` into the body of the request.
+pub fn extra_body_hook(args: &Args) -> Option<impl Fn(&mut Value) + Send + Sync + 'static> {
+    let extra_body = args.extra_body.clone()?;
+
+    Some(move |body: &mut Value| merge(body, extra_body.clone()))
+}
+
+/// Hook that changes the body of a request before it's sent.
+pub type RequestHook = Box<dyn Fn(&mut Value) + Send + Sync>;
+
+/// Returns the `on_request` hooks of a request, in order: `--extra-body`, the tools of the
+/// session, and last the `pre_request` hook, which gets the body the other hooks left, so it sees
+/// exactly what's sent.
+pub fn request_hooks(
+    args: &Args,
+    body: &impl Serialize,
+    tools: Option<&crate::tools::Session>,
+) -> Result<Vec<RequestHook>> {
+    let mut hooks: Vec<RequestHook> = Vec::new();
+
+    if let Some(hook) = extra_body_hook(args) {
+        hooks.push(Box::new(hook));
+    }
+
+    if let Some(tools) = tools {
+        hooks.push(Box::new(tools.request_hook()));
+    }
+
+    if args.hooks.pre_request.is_some() {
+        let mut request = serde_json::to_value(body)?;
+
+        for hook in &hooks {
+            hook(&mut request);
+        }
+
+        if let Some(request) = crate::hooks::pre_request(args, &request)? {
+            hooks.push(Box::new(move |body: &mut Value| *body = request.clone()));
+        }
+    }
+
+    Ok(hooks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Preset, Template, TemplateMessage};
+
+    #[test]
+    fn test_merge_args_and_cache_refuses_ids_outside_the_cache() {
+        let args = Args {
+            from: Some("../../config".to_string()),
+            data_dir: Some(std::env::temp_dir().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            merge_args_and_cache(args),
+            Err(Error::InvalidCacheId(_))
+        ));
+    }
+
+    #[test]
+    fn test_args_dont_change_on_empty_config() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let args = Args::default();
+        let config: Config = Config::default();
+
+        let mut expected = args.clone();
+        expected.conversation = vec![ConversationMessage::default()];
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected, actual,
+            "merge_args_and_config changed the default values"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_override_config() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut args = Args::default();
+        args.api = Some(Api::OpenAi);
+        args.model = Some("gpt-4o".to_string());
+        args.max_tokens = Some(100);
+        args.min_tokens = Some(10);
+        args.api_env = Some("OPENAI_API_KEY".to_string());
+        args.api_version = Some("0.1.0".to_string());
+        args.api_key = Some("123".to_string());
+        args.api_base_url = Some("https://api.openai.com/v1".to_string());
+        args.quiet = true;
+        args.language = Some("markdown".to_string());
+        args.system = vec!["Something Awesome".to_string()];
+        args.temperature = Some(0.5);
+        args.top_p = Some(0.5);
+        args.top_k = Some(50);
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: "Something Awesome".to_string(),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        let mut config: Config = Config::default();
+        config.api = Some(Api::Anthropic);
+        config.model = Some("gpt-3".to_string());
+        config.max_tokens = Some(200);
+        config.min_tokens = Some(20);
+        config.env = Some("ANTHROPIC_API_KEY".to_string());
+        config.version = Some("0.2.0".to_string());
+        config.key = Some("456".to_string());
+        config.base_url = Some("https://api.anthropic.com/v1".to_string());
+        config.quiet = Some(false);
+        config.language = Some("html".to_string());
+        config.system = Some("Something Awesome".to_string());
+        config.temperature = Some(0.7);
+        config.top_p = Some(0.7);
+        config.top_k = Some(70);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected, actual,
+            "merge_args_and_config changed the default values"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_args_are_added_to_the_preset_system(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let preset_name = "preset_name";
+
+        let mut args = Args::default();
+        args.system = vec!["first system".to_string(), "second system".to_string()];
+        args.preset = Some(preset_name.to_string());
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: "preset system\n\nfirst system\n\nsecond system".to_string(),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        let mut config: Config = Config::default();
+        config.presets = Some(vec![Preset {
+            name: preset_name.to_string(),
+            system: Some("preset system".to_string()),
+            ..Default::default()
+        }]);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "The system args should be added to the preset system, in order"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prest_system_over_config_system() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        let system = "preset system";
+        let config_system = "config system";
+        let preset_name = "preset_name";
+
+        let mut args = Args::default();
+        args.preset = Some(preset_name.to_string());
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: system.to_string(),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        let mut config: Config = Config::default();
+        config.system = Some(config_system.to_string());
+        config.presets = Some(vec![Preset {
+            name: preset_name.to_string(),
+            system: Some(system.to_string()),
+            ..Default::default()
+        }]);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "The system arg should overwrite the preset system"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_arg_is_added_to_the_template_system(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let template_name = "template_name";
+
+        let mut args = Args::default();
+        args.system = vec!["param system".to_string()];
+        args.template = Some(template_name.to_string());
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: "template system\n\nparam system".to_string(),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        let mut config: Config = Config::default();
+        config.templates = Some(vec![Template {
+            name: template_name.to_string(),
+            description: Some("test".to_string()),
+            system: Some("template system".to_string()),
+            template: Some("".to_string()),
+            ..Default::default()
+        }]);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "The system arg should be added to the template system"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_template_over_system_preset(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let system = "template system";
+        let template_name = "template_name";
+        let preset_name = "preset_name";
+
+        let mut args = Args::default();
+        args.template = Some(template_name.to_string());
+        args.preset = Some(preset_name.to_string());
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: system.to_string(),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        let mut config: Config = Config::default();
+        config.templates = Some(vec![Template {
+            name: template_name.to_string(),
+            description: Some("test".to_string()),
+            system: Some(system.to_string()),
+            template: Some("".to_string()),
+            ..Default::default()
+        }]);
+        config.presets = Some(vec![Preset {
+            name: preset_name.to_string(),
+            system: Some("preset system".to_string()),
+            ..Default::default()
+        }]);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "The template system should overwrite the preset system"
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_args_override_config() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fn test_template_system_should_not_be_duplicated(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let system = "template system";
+        let template_name = "template_name";
+
         let mut args = Args::default();
-        args.api = Some(Api::OpenAi);
-        args.model = Some("gpt-4o".to_string());
-        args.max_tokens = Some(100);
-        args.min_tokens = Some(10);
-        args.api_env = Some("OPENAI_API_KEY".to_string());
-        args.api_version = Some("0.1.0".to_string());
-        args.api_key = Some("123".to_string());
-        args.api_base_url = Some("https://api.openai.com/v1".to_string());
-        args.quiet = Some(true);
-        args.language = Some("markdown".to_string());
-        args.system = Some("Something Awesome".to_string());
-        args.temperature = Some(0.5);
-        args.top_p = Some(0.5);
-        args.top_k = Some(50);
+        args.template = Some(template_name.to_string());
 
         let mut expected = args.clone();
         expected.conversation = vec![
             ConversationMessage {
                 role: ConversationRole::System,
-                content: "Something Awesome".to_string(),
+                content: system.to_string(),
+                ..Default::default()
             },
             ConversationMessage::default(),
         ];
 
         let mut config: Config = Config::default();
-        config.api = Some(Api::Anthropic);
-        config.model = Some("gpt-3".to_string());
-        config.max_tokens = Some(200);
-        config.min_tokens = Some(20);
-        config.env = Some("ANTHROPIC_API_KEY".to_string());
-        config.version = Some("0.2.0".to_string());
-        config.key = Some("456".to_string());
-        config.base_url = Some("https://api.anthropic.com/v1".to_string());
-        config.quiet = Some(false);
-        config.language = Some("html".to_string());
-        config.system = Some("Something Awesome".to_string());
+        config.templates = Some(vec![Template {
+            name: template_name.to_string(),
+            description: Some("test".to_string()),
+            system: Some(system.to_string()),
+            template: Some("".to_string()),
+            ..Default::default()
+        }]);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "The system arg should overwrite the template system"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resulting_conversation_has_single_system_message_at_index_zero(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let system_option = "system option";
+        let system_conversation = "system conversation";
+        let mut args = Args::default();
+        args.system = vec![system_option.to_string()];
+
+        let mut expected = args.clone();
+        expected.conversation = vec![
+            ConversationMessage {
+                role: ConversationRole::System,
+                content: format!("{}\n\n{}", system_conversation, system_option),
+                ..Default::default()
+            },
+            ConversationMessage::default(),
+        ];
+
+        args.conversation = vec![ConversationMessage {
+            role: ConversationRole::System,
+            content: system_conversation.to_string(),
+            ..Default::default()
+        }];
+
+        let config: Config = Config::default();
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            expected.conversation, actual.conversation,
+            "There should be a single `system` message"
+        );
+
+        Ok(())
+    }
+
+    fn template_message(role: ConversationRole, content: &str) -> TemplateMessage {
+        TemplateMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn conversation_template() -> Template {
+        Template {
+            name: "examples".to_string(),
+            template: Some("{{ prompt }}".to_string()),
+            default_vars: Some(serde_json::json!({ "lang": "rust" })),
+            conversation: Some(vec![
+                template_message(ConversationRole::System, "You write {{ lang }}."),
+                template_message(ConversationRole::User, "Add two numbers"),
+                template_message(ConversationRole::Assistant, "fn add(a: i32, b: i32) -> i32"),
+                template_message(ConversationRole::System, "Only return code."),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_template_conversation_has_a_single_system_message(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config: Config = Config::default();
+        config.templates = Some(vec![conversation_template()]);
+
+        let mut args = Args::default();
+        args.template = Some("examples".to_string());
+        args.prompt = Some("Multiply two numbers".to_string());
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(
+            actual.conversation,
+            vec![
+                ConversationMessage {
+                    role: ConversationRole::System,
+                    content: "You write rust.\n\nOnly return code.".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::User,
+                    content: "Add two numbers".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::Assistant,
+                    content: "fn add(a: i32, b: i32) -> i32".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::User,
+                    content: "Multiply two numbers".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_conversation_keeps_the_cached_system_message(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config: Config = Config::default();
+        config.templates = Some(vec![conversation_template()]);
+
+        let mut args = Args::default();
+        args.template = Some("examples".to_string());
+        args.conversation = vec![ConversationMessage {
+            role: ConversationRole::System,
+            content: "cached system".to_string(),
+            ..Default::default()
+        }];
+
+        let actual = merge_args_and_config(args, config)?;
+
+        let system = actual
+            .conversation
+            .iter()
+            .filter(|m| m.role == ConversationRole::System)
+            .collect::<Vec<&ConversationMessage>>();
+
+        assert_eq!(system.len(), 1, "There should be a single `system` message");
+        assert_eq!(system[0].content, "cached system");
+        assert_eq!(actual.conversation[0].role, ConversationRole::System);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_parts_are_compared_as_whole_entries(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut args = Args::default();
+        args.system = vec!["Be brief.".to_string(), "Answer in French.".to_string()];
+        args.conversation = vec![ConversationMessage {
+            role: ConversationRole::System,
+            content: "Be brief.\n\nAnswer in French. Be brief.".to_string(),
+            ..Default::default()
+        }];
+
+        let actual = merge_args_and_config(args, Config::default())?;
+
+        assert_eq!(
+            actual.conversation[0].content,
+            "Be brief.\n\nAnswer in French. Be brief.\n\nAnswer in French."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_system_keeps_the_cached_system_message(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config: Config = Config::default();
+        config.templates = Some(vec![Template {
+            name: "translate".to_string(),
+            system: Some("template system".to_string()),
+            template: Some("".to_string()),
+            ..Default::default()
+        }]);
+
+        let mut args = Args::default();
+        args.template = Some("translate".to_string());
+        args.conversation = vec![ConversationMessage {
+            role: ConversationRole::System,
+            content: "cached system".to_string(),
+            ..Default::default()
+        }];
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(actual.conversation[0].content, "cached system");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vars_override_vars_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("llm-stream-vars-{}.yaml", xid::new()));
+        std::fs::write(&path, "language: rust\nfiles:\n  - main.rs\n")?;
+
+        let mut args = Args::default();
+        args.vars_file = Some(path.to_string_lossy().to_string());
+        args.vars = Some(serde_json::json!({ "language": "go" }));
+
+        let actual = template_vars(&mut args);
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(
+            actual?,
+            serde_json::json!({ "language": "go", "files": ["main.rs"] })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_options_over_config() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config: Config = Config::default();
+        config.model = Some("config model".to_string());
         config.temperature = Some(0.7);
-        config.top_p = Some(0.7);
-        config.top_k = Some(70);
+        config.max_tokens = Some(1000);
+        config.templates = Some(vec![Template {
+            name: "template".to_string(),
+            api: Some(Api::Google),
+            model: Some("template model".to_string()),
+            temperature: Some(0.1),
+            ..Default::default()
+        }]);
+
+        let mut args = Args::default();
+        args.template = Some("template".to_string());
+        args.temperature = Some(1.0);
+
+        let actual = merge_args_and_config(args, config)?;
+
+        assert_eq!(actual.api, Some(Api::Google));
+        assert_eq!(actual.model, Some("template model".to_string()));
+        assert_eq!(
+            actual.temperature,
+            Some(1.0),
+            "The command line should win over the template"
+        );
+        assert_eq!(actual.max_tokens, Some(1000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_config_is_used_for_the_selected_api(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            api = "openai"
+            model = "gpt-4o"
+            base_url = "https://api.openai.com/v1"
+
+            [apis.anthropic]
+            model = "claude-3-5-sonnet-latest"
+            base_url = "https://api.anthropic.com/v1"
+            headers = { anthropic-beta = "prompt-caching-2024-07-31", x-team = "config" }
+            "#,
+        )?;
+
+        let mut args = Args::default();
+        args.api = Some(Api::Anthropic);
+        args.headers = vec![("X-Team".to_string(), "cli".to_string())];
+
+        let actual = merge_args_and_config(args, config.clone())?;
+
+        assert_eq!(actual.model, Some("claude-3-5-sonnet-latest".to_string()));
+        assert_eq!(
+            actual.api_base_url,
+            Some("https://api.anthropic.com/v1".to_string())
+        );
+        assert_eq!(
+            actual.headers,
+            vec![
+                ("X-Team".to_string(), "cli".to_string()),
+                (
+                    "anthropic-beta".to_string(),
+                    "prompt-caching-2024-07-31".to_string()
+                ),
+            ],
+            "Headers given on the command line should win"
+        );
+
+        let actual = merge_args_and_config(Args::default(), config)?;
+
+        assert_eq!(
+            actual.model,
+            Some("gpt-4o".to_string()),
+            "Other apis should keep the top-level values"
+        );
+        assert!(actual.headers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_body_is_merged_under_the_command_line(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            extra_body = { user = "config", metadata = { team = "config", project = "cli" } }
+
+            [apis.openai]
+            extra_body = { service_tier = "flex", metadata = { team = "api" } }
+            "#,
+        )?;
+
+        let mut args = Args::default();
+        args.api = Some(Api::OpenAi);
+        args.extra_body = Some(serde_json::json!({
+            "user": null,
+            "metadata": { "project": "llm-stream" }
+        }));
 
         let actual = merge_args_and_config(args, config)?;
 
         assert_eq!(
-            expected, actual,
-            "merge_args_and_config changed the default values"
+            actual.extra_body,
+            Some(serde_json::json!({
+                "user": null,
+                "service_tier": "flex",
+                "metadata": { "team": "api", "project": "llm-stream" }
+            })),
+            "The null should be kept until the body is merged"
+        );
+
+        let hook = extra_body_hook(&actual).ok_or("the hook should be registered")?;
+        let mut body =
+            serde_json::json!({ "model": "gpt-4o", "service_tier": "auto", "user": "body" });
+        hook(&mut body);
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "model": "gpt-4o",
+                "service_tier": "flex",
+                "metadata": { "team": "api", "project": "llm-stream" }
+            })
         );
+        assert!(extra_body_hook(&Args::default()).is_none());
 
         Ok(())
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_system_arg_over_config_preset() -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let system = "param system";
-        let preset_name = "preset_name";
-
+    fn test_pre_request_runs_after_the_other_hooks(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let mut args = Args::default();
-        args.system = Some(system.to_string());
-        args.preset = Some(preset_name.to_string());
-
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+        args.extra_body = Some(serde_json::json!({ "service_tier": "flex" }));
+        args.hooks.pre_request = Some("sed s/flex/priority/".to_string());
 
-        let mut config: Config = Config::default();
-        config.presets = Some(vec![Preset {
-            name: preset_name.to_string(),
-            system: Some("preset system".to_string()),
-            ..Default::default()
-        }]);
+        let body = serde_json::json!({ "model": "gpt-4o" });
+        let mut request = body.clone();
 
-        let actual = merge_args_and_config(args, config)?;
+        for hook in request_hooks(&args, &body, None)? {
+            hook(&mut request);
+        }
 
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "The system arg should overwrite the preset system"
+            request,
+            serde_json::json!({ "model": "gpt-4o", "service_tier": "priority" })
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_prest_system_over_config_system() -> std::result::Result<(), Box<dyn std::error::Error>>
-    {
-        let system = "preset system";
-        let config_system = "config system";
-        let preset_name = "preset_name";
-
-        let mut args = Args::default();
-        args.preset = Some(preset_name.to_string());
+    fn test_extra_query_of_the_api_wins() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            extra_query = { api-version = "2024-06-01", route = "default" }
 
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+            [apis.openai]
+            base_url = "https://example.openai.azure.com/openai/deployments/gpt-4o"
+            extra_query = { api-version = "2024-10-21" }
+            "#,
+        )?;
 
-        let mut config: Config = Config::default();
-        config.system = Some(config_system.to_string());
-        config.presets = Some(vec![Preset {
-            name: preset_name.to_string(),
-            system: Some(system.to_string()),
-            ..Default::default()
-        }]);
+        let mut args = Args::default();
+        args.api = Some(Api::OpenAi);
 
         let actual = merge_args_and_config(args, config)?;
 
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "The system arg should overwrite the preset system"
+            actual.extra_query,
+            vec![
+                ("api-version".to_string(), "2024-10-21".to_string()),
+                ("route".to_string(), "default".to_string()),
+            ]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_system_arg_over_system_template() -> std::result::Result<(), Box<dyn std::error::Error>>
-    {
-        let system = "param system";
-        let template_name = "template_name";
+    fn test_first_text_skips_the_events_without_text() {
+        let mut stream = futures::stream::iter(vec![
+            Ok(String::new()),
+            Ok("Hello".to_string()),
+            Ok(" world".to_string()),
+        ]);
 
-        let mut args = Args::default();
-        args.system = Some(system.to_string());
-        args.template = Some(template_name.to_string());
+        let first = futures::executor::block_on(first_text(&mut stream));
 
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+        assert_eq!(first.ok().flatten(), Some("Hello".to_string()));
 
-        let mut config: Config = Config::default();
-        config.templates = Some(vec![Template {
-            name: template_name.to_string(),
-            description: Some("test".to_string()),
-            system: Some("template system".to_string()),
-            template: Some("".to_string()),
-            ..Default::default()
-        }]);
+        let mut stream = futures::stream::iter(vec![
+            Ok(String::new()),
+            Err(llm_stream::error::Error::StreamError {
+                kind: "overloaded_error".to_string(),
+                message: "Overloaded".to_string(),
+            }),
+        ]);
 
-        let actual = merge_args_and_config(args, config)?;
+        let first = futures::executor::block_on(first_text(&mut stream));
+
+        assert!(first.is_err_and(|e| e.is_retryable()));
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_gives_up_on_silent_streams() {
+        let timeouts = Timeouts {
+            first_token: Some(std::time::Duration::from_millis(10)),
+            stall: Some(std::time::Duration::from_millis(10)),
+        };
+
+        let silent = || {
+            Ok(futures::stream::pending::<
+                std::result::Result<String, llm_stream::error::Error>,
+            >())
+        };
+        let result = open_stream(silent, true, timeouts).await;
+
+        assert!(matches!(result, Err(Error::FirstTokenTimeout(_))));
+
+        let open = || {
+            Ok(
+                futures::stream::iter(vec![Ok("Hello".to_string()), Ok(String::new())])
+                    .chain(futures::stream::pending()),
+            )
+        };
+        let mut stream = match open_stream(open, true, timeouts).await {
+            Ok(stream) => stream,
+            Err(e) => panic!("the first token arrived in time: {:?}", e),
+        };
 
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "The system arg should overwrite the template system"
+            stream.try_next().await.ok().flatten(),
+            Some("Hello".to_string())
         );
+        assert!(matches!(
+            stream.try_next().await,
+            Err(llm_stream::error::Error::RequestError(_))
+        ));
+        assert!(stream.next().await.is_none());
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn test_open_stream_counts_the_events_without_text_as_progress() {
+        let timeouts = Timeouts {
+            first_token: Some(std::time::Duration::from_millis(50)),
+            stall: Some(std::time::Duration::from_millis(50)),
+        };
+
+        // Like the deltas of a tool call, which send no text for longer than the timeouts.
+        let open = || {
+            Ok(Box::pin(futures::stream::unfold(0, |n| async move {
+                match n {
+                    0..=5 => {
+                        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        Some((Ok(String::new()), n + 1))
+                    }
+                    6 => Some((Ok("Done".to_string()), n + 1)),
+                    _ => None,
+                }
+            })))
+        };
+        let mut stream = match open_stream(open, true, timeouts).await {
+            Ok(stream) => stream,
+            Err(e) => panic!("the events arrived in time: {:?}", e),
+        };
+
+        assert_eq!(
+            stream.try_next().await.ok().flatten(),
+            Some("Done".to_string())
+        );
+        assert!(stream.next().await.is_none());
     }
 
     #[test]
-    fn test_system_template_over_system_preset(
+    fn test_fallback_args_switch_the_provider(
     ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let system = "template system";
-        let template_name = "template_name";
-        let preset_name = "preset_name";
+        let config: Config = toml::from_str(
+            r#"
+            [[presets]]
+            name = "mini"
+            api = "openai"
+            model = "gpt-4o-mini"
+
+            [apis.openai]
+            headers = { x-team = "openai" }
+            "#,
+        )?;
+
+        let args = Args {
+            api: Some(Api::Anthropic),
+            model: Some("claude-3-5-sonnet-latest".to_string()),
+            headers: vec![("anthropic-beta".to_string(), "prompt-caching".to_string())],
+            temperature: Some(0.2),
+            ..Default::default()
+        };
 
-        let mut args = Args::default();
-        args.template = Some(template_name.to_string());
-        args.preset = Some(preset_name.to_string());
+        let actual = fallback_args(&args, "mini", &config);
 
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+        assert_eq!(actual.api, Some(Api::OpenAi));
+        assert_eq!(actual.model, Some("gpt-4o-mini".to_string()));
+        assert_eq!(
+            actual.headers,
+            vec![("x-team".to_string(), "openai".to_string())]
+        );
+        assert_eq!(actual.temperature, Some(0.2));
 
-        let mut config: Config = Config::default();
-        config.templates = Some(vec![Template {
-            name: template_name.to_string(),
-            description: Some("test".to_string()),
-            system: Some(system.to_string()),
-            template: Some("".to_string()),
-            ..Default::default()
-        }]);
-        config.presets = Some(vec![Preset {
-            name: preset_name.to_string(),
-            system: Some("preset system".to_string()),
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_fences_the_text_after_the_instructions() {
+        let mut args = Args {
+            prompt: Some("Sort the lines".to_string()),
+            stdin: Some("    b\n    a".to_string()),
             ..Default::default()
-        }]);
+        };
 
-        let actual = merge_args_and_config(args, config)?;
+        filter(&mut args);
 
+        assert!(args.quiet);
+        assert_eq!(args.stdin, None);
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "The template system should overwrite the preset system"
+            args.prompt,
+            Some(
+                "Sort the lines\n\nAnswer only with the transformed text, without explanations or code fences.\n\n```\n    b\n    a\n```"
+                    .to_string()
+            )
         );
-
-        Ok(())
     }
 
     #[test]
-    fn test_template_system_should_not_be_duplicated(
-    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let system = "template system";
-        let template_name = "template_name";
+    fn test_read_prompt_file() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("llm-stream-prompt-{}.md", xid::new()));
+        std::fs::write(&path, "\nSummarize the changelog.\n\n")?;
 
-        let mut args = Args::default();
-        args.template = Some(template_name.to_string());
+        let actual = read_prompt_file(&path.to_string_lossy());
+        std::fs::remove_file(&path)?;
 
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+        assert_eq!(actual?, "Summarize the changelog.");
+        assert!(read_prompt_file(&path.to_string_lossy()).is_err());
 
-        let mut config: Config = Config::default();
-        config.templates = Some(vec![Template {
-            name: template_name.to_string(),
-            description: Some("test".to_string()),
-            system: Some(system.to_string()),
-            template: Some("".to_string()),
+        Ok(())
+    }
+
+    #[test]
+    fn test_attachments_are_kept_without_stdin(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let args = Args {
+            prompt: Some("Explain it".to_string()),
+            stdin: Some("piped".to_string()),
+            stdin_placement: Some(StdinPlacement::None),
+            attachments: Some("Files:\n\nmain.rs".to_string()),
             ..Default::default()
-        }]);
+        };
 
-        let actual = merge_args_and_config(args, config)?;
+        let actual = merge_args_and_config(args, Config::default())?;
 
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "The system arg should overwrite the template system"
+            actual.prompt,
+            Some("Files:\n\nmain.rs\nExplain it".to_string())
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_resulting_conversation_has_single_system_message_at_index_zero(
-    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let system_option = "system option";
-        let system_conversation = "system conversation";
-        let mut args = Args::default();
-        args.system = Some(system_option.to_string());
-
-        let mut expected = args.clone();
-        expected.conversation = vec![
-            ConversationMessage {
-                role: ConversationRole::System,
-                content: system_option.to_string(),
-            },
-            ConversationMessage::default(),
-        ];
+    fn test_place_stdin() {
+        let stdin = "fn main() {}";
 
-        args.conversation = vec![ConversationMessage {
-            role: ConversationRole::System,
-            content: system_conversation.to_string(),
-        }];
+        assert_eq!(
+            place_stdin("Explain it", stdin, StdinPlacement::Before),
+            "fn main() {}\nExplain it"
+        );
+        assert_eq!(
+            place_stdin("Explain it", stdin, StdinPlacement::After),
+            "Explain it\nfn main() {}"
+        );
+        assert_eq!(
+            place_stdin("Explain it", stdin, StdinPlacement::None),
+            "Explain it"
+        );
+        assert_eq!(
+            place_stdin("Explain\n\n{stdin}\n\nbriefly", stdin, StdinPlacement::None),
+            "Explain\n\nfn main() {}\n\nbriefly"
+        );
+    }
 
-        let config: Config = Config::default();
-        let actual = merge_args_and_config(args, config)?;
+    #[test]
+    fn test_format_request_redacts_the_credentials(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let request = llm_stream::request::Request {
+            method: "POST".to_string(),
+            url: "https://example.com/models/gemini?alt=sse&key=secret".to_string(),
+            headers: vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("x-api-key".to_string(), "secret".to_string()),
+            ],
+            body: serde_json::json!({ "model": "gemini" }),
+        };
 
         assert_eq!(
-            expected.conversation, actual.conversation,
-            "There should be a single `system` message"
+            format_request(&request.redacted())?,
+            "POST https://example.com/models/gemini?alt=sse&key=<redacted>\n\
+             content-type: application/json\n\
+             x-api-key: <redacted>\n\
+             \n\
+             {\n  \"model\": \"gemini\"\n}"
         );
 
         Ok(())
     }
 }
 
+/// Summary of a cached conversation.
+#[derive(Debug, Serialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub parent: Option<String>,
+    pub created: chrono::DateTime<chrono::Local>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub model: Option<String>,
+    pub api: Option<Api>,
+    pub message: Option<String>,
+    /// Tokens of the answers, added up.
+    pub usage: crate::report::Usage,
+}
+
+impl ConversationSummary {
+    /// Reads the summary of the cache file at `path`.
+    pub fn from_path(path: &std::path::Path) -> Result<Self> {
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let args = crate::cache::parse(&path.to_string_lossy())?;
+
+        // Conversations cached before messages had timestamps use the time of the file.
+        let created = match args.conversation.iter().find_map(|m| m.created_at) {
+            Some(created) => created,
+            None => {
+                let metadata = std::fs::metadata(path)?;
+                metadata.created().or_else(|_| metadata.modified())?.into()
+            }
+        };
+
+        let usage = args
+            .conversation
+            .iter()
+            .filter_map(|m| m.usage)
+            .fold(crate::report::Usage::default(), |total, usage| {
+                total + usage
+            });
+
+        let message = args
+            .conversation
+            .iter()
+            .find(|m| m.role == ConversationRole::User)
+            .map(|m| m.content.clone());
+
+        Ok(Self {
+            id,
+            parent: args.parent,
+            created,
+            title: args.title,
+            description: args.description,
+            model: args.model,
+            api: args.api,
+            message,
+            usage,
+        })
+    }
+}
+
 #[derive(Table)]
 struct ConversationLine {
     #[table(title = "ID", justify = "Justify::Left", color = "Color::Cyan")]
     id: String,
-    #[table(title = "Parent", justify = "Justify::Left", color = "Color::Magenta")]
-    parent: String,
+    #[table(title = "Created", justify = "Justify::Left", color = "Color::Magenta")]
+    created: String,
     #[table(title = "Title", justify = "Justify::Left")]
     title: String,
-    #[table(title = "Description", justify = "Justify::Left")]
-    description: String,
+    #[table(title = "Model", justify = "Justify::Left")]
+    model: String,
+    #[table(title = "API", justify = "Justify::Left")]
+    api: String,
+    #[table(title = "Message", justify = "Justify::Left")]
+    message: String,
 }
 
-impl ConversationLine {
-    pub fn new(
-        id: String,
-        parent: Option<String>,
-        title: Option<String>,
-        description: Option<String>,
-    ) -> Self {
+impl From<ConversationSummary> for ConversationLine {
+    fn from(summary: ConversationSummary) -> Self {
         Self {
-            id,
-            parent: parent.unwrap_or_default(),
-            title: title.unwrap_or_default(),
-            description: description.unwrap_or_default(),
+            id: summary.id,
+            created: summary.created.format("%Y-%m-%d %H:%M").to_string(),
+            title: summary.title.unwrap_or_default(),
+            model: summary.model.unwrap_or_default(),
+            api: summary.api.map(|api| api.to_string()).unwrap_or_default(),
+            message: summary
+                .message
+                .unwrap_or_default()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .take(80)
+                .collect(),
         }
     }
 }
 
-fn get_sorted_cache_files(cache_dir: &str) -> Result<Vec<std::path::PathBuf>> {
+pub fn get_sorted_cache_files(cache_dir: &str) -> Result<Vec<std::path::PathBuf>> {
     let mut cache_files = std::fs::read_dir(cache_dir)?
         .filter_map(|entry| {
             let entry = entry.ok()?;
@@ -946,49 +2796,44 @@ fn get_sorted_cache_files(cache_dir: &str) -> Result<Vec<std::path::PathBuf>> {
 
 /// Prints a list of existing conversations
 pub fn list(args: Args) -> Result<()> {
-    let config_dir = args.config_dir.clone().expect("can't find cache directory");
-    let cache_dir = format!("{}/cache", &config_dir);
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let cache_dir = crate::cache::cache_dir(&data_dir);
 
     // Get a list of all the `toml` files inside the `cache_dir`
     let cache_files = get_sorted_cache_files(&cache_dir)?;
 
-    let lines = cache_files
+    let summaries = cache_files
         .iter()
-        .map(|path| {
-            let id = path.file_stem().unwrap().to_str().unwrap();
-            let cache_toml = std::fs::read_to_string(&path).unwrap();
-            let args: Args = toml::from_str(&cache_toml).unwrap();
-            let description = Some(
-                if let Some(description) = args.description {
-                    description
-                } else if args.conversation.is_empty() {
-                    "Empty".to_string()
-                } else {
-                    // Get the first message in `args.conversation` whose `role` is
-                    // `ConversationRole::Assistant.
-                    let message = args
-                        .conversation
-                        .iter()
-                        .rev()
-                        .find(|m| m.role == ConversationRole::Assistant)
-                        .unwrap_or(args.conversation.first().expect("No messages"));
-                    message
-                        .content
-                        .clone()
-                        .split("\n")
-                        .next()
-                        .unwrap()
-                        .to_string()
-                }
-                .chars()
-                .take(120)
-                .collect::<String>()
-                .to_string(),
-            );
-            ConversationLine::new(id.to_string(), args.parent, args.title, description)
+        .filter_map(|path| match ConversationSummary::from_path(path) {
+            Ok(summary) => Some(summary),
+            Err(e) => {
+                log::warn!("unable to read cache file {}: {:?}", path.display(), e);
+                None
+            }
         })
+        .collect::<Vec<ConversationSummary>>();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    let lines = summaries
+        .into_iter()
+        .map(ConversationLine::from)
         .collect::<Vec<ConversationLine>>();
 
+    let table = if atty::is(atty::Stream::Stdout) {
+        lines.with_title()
+    } else {
+        lines.table()
+    };
+
+    print_table(table, &args)
+}
+
+/// Prints a table to stdout without borders, coloring it when writing to a terminal.
+pub fn print_table(table: cli_table::TableStruct, args: &Args) -> Result<()> {
     let vert_line = cli_table::format::VerticalLine::new(' ');
     let horz_line = cli_table::format::HorizontalLine::new(' ', ' ', ' ', ' ');
     let border = cli_table::format::Border::builder()
@@ -1004,12 +2849,6 @@ pub fn list(args: Args) -> Result<()> {
         .build();
     let is_terminal = atty::is(atty::Stream::Stdout);
 
-    let table = if is_terminal {
-        lines.with_title()
-    } else {
-        lines.table()
-    };
-
     print_stdout(table.separator(separator).border(border).color_choice(
         if args.no_color || !is_terminal {
             ColorChoice::Never
@@ -1020,34 +2859,3 @@ pub fn list(args: Args) -> Result<()> {
 
     Ok(())
 }
-
-/// Prints the given conversation to stdout
-pub fn show(args: Args) -> Result<()> {
-    // Read the cache file from `args.config_dir/args.from`
-    let cache_file = format!(
-        "{}/cache/{}.toml",
-        args.config_dir.clone().expect("can't find cache directory"),
-        args.from
-            .clone()
-            .expect("--from or --from-last needs to be defined when run with --show")
-    );
-
-    // Read the contents of cache_file
-    let text = std::fs::read_to_string(&cache_file)?;
-
-    let language = "toml";
-    let theme = Some(args.theme.clone().unwrap_or("ansi".to_string()));
-
-    if args.no_color {
-        println!("{}", text);
-    } else {
-        let output = crate::printer::CustomPrinter::new(&language, theme.as_deref())?
-            .input_from_bytes(&text.as_bytes())
-            .print()?;
-
-        println!("{}", output);
-        std::io::stdout().flush()?;
-    }
-
-    Ok(())
-}
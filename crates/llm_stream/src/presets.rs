@@ -0,0 +1,63 @@
+use cli_table::{format::Justify, Color, Table, WithTitle};
+use serde::Serialize;
+
+use crate::args::PresetsCommand;
+use crate::config::Preset;
+use crate::prelude::*;
+
+/// Preset as printed by `presets list`.
+#[derive(Debug, Serialize, Table)]
+struct PresetLine {
+    #[table(title = "Name", justify = "Justify::Left", color = "Color::Cyan")]
+    name: String,
+    #[table(title = "API", justify = "Justify::Left")]
+    api: String,
+    #[table(title = "Model", justify = "Justify::Left")]
+    model: String,
+}
+
+impl From<&Preset> for PresetLine {
+    fn from(preset: &Preset) -> Self {
+        match &preset.routes {
+            Some(routes) => Self {
+                name: preset.name.clone(),
+                api: "router".to_string(),
+                model: routes.join(", "),
+            },
+            None => Self {
+                name: preset.name.clone(),
+                api: preset.api.to_string(),
+                model: preset.model.clone().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// Runs the `presets` commands.
+pub fn run(command: PresetsCommand, args: Args) -> Result<()> {
+    let (args, config) = build_config(args)?;
+
+    match command {
+        PresetsCommand::List => {
+            let lines = config
+                .presets
+                .unwrap_or_default()
+                .iter()
+                .map(PresetLine::from)
+                .collect::<Vec<PresetLine>>();
+
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&lines)?);
+                return Ok(());
+            }
+
+            let table = if atty::is(atty::Stream::Stdout) {
+                lines.with_title()
+            } else {
+                lines.table()
+            };
+
+            print_table(table, &args)
+        }
+    }
+}
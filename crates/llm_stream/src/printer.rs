@@ -1,10 +1,82 @@
 use crossterm::terminal;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use crate::prelude::*;
+use crate::render::{visible_width, Render};
 
 // Markdown language constant string
 const DEFAULT_THEME: &str = "tokyonight-storm";
 
+/// Directory with the assets built from the user themes and syntaxes, if any.
+static ASSETS_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Returns the most recent modification time of the files inside `dir`.
+fn last_modified(dir: &Path) -> Option<std::time::SystemTime> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                last_modified(&path)
+            } else {
+                entry.metadata().and_then(|m| m.modified()).ok()
+            }
+        })
+        .max()
+}
+
+/// Builds the themes and syntaxes found in the `themes/` and `syntaxes/` directories of
+/// `config_dir` into `state_dir/assets`, so they can be used along with the integrated ones. The
+/// assets are only rebuilt when those directories change.
+pub fn init_assets(config_dir: &str, state_dir: &str) -> Result<()> {
+    let source = Path::new(config_dir);
+    let themes = source.join("themes");
+    let syntaxes = source.join("syntaxes");
+
+    if !themes.is_dir() && !syntaxes.is_dir() {
+        return Ok(());
+    }
+
+    let target = Path::new(state_dir).join("assets");
+    let source_modified = [themes, syntaxes]
+        .iter()
+        .filter_map(|dir| last_modified(dir))
+        .max();
+    let target_modified = std::fs::metadata(target.join("themes.bin"))
+        .and_then(|m| m.modified())
+        .ok();
+
+    let outdated = match (source_modified, target_modified) {
+        (_, None) => true,
+        (Some(source), Some(target)) => source > target,
+        (None, Some(_)) => false,
+    };
+
+    if outdated {
+        log::info!("building assets: {}", target.display());
+        std::fs::create_dir_all(&target)?;
+        bat::assets::build(source, true, false, &target, env!("CARGO_PKG_VERSION"))?;
+    }
+
+    let _ = ASSETS_DIR.set(target);
+
+    Ok(())
+}
+
+/// Loads the highlighting assets, including the user ones when available.
+pub fn load_assets() -> bat::assets::HighlightingAssets {
+    match ASSETS_DIR.get() {
+        Some(dir) => bat::assets::HighlightingAssets::from_cache(dir).unwrap_or_else(|e| {
+            log::warn!("unable to load the user assets: {:?}", e);
+            bat::assets::HighlightingAssets::from_binary()
+        }),
+        None => bat::assets::HighlightingAssets::from_binary(),
+    }
+}
+
 pub struct CustomPrinter<'a> {
     inputs: Vec<bat::input::Input<'a>>,
     config: bat::config::Config<'a>,
@@ -33,7 +105,7 @@ impl<'a> CustomPrinter<'a> {
         Ok(CustomPrinter {
             inputs: vec![],
             config,
-            assets: bat::assets::HighlightingAssets::from_binary(),
+            assets: load_assets(),
             term_width: None,
         })
     }
@@ -66,3 +138,122 @@ impl<'a> CustomPrinter<'a> {
         Ok(output)
     }
 }
+
+/// Prints a stream of text to the terminal, rendering it as it arrives.
+///
+/// Every complete line is rendered and printed once; the unterminated last line is rendered
+/// without changing the renderer state and printed again, over itself, until its line ending
+/// arrives.
+pub struct StreamPrinter {
+    renderer: Box<dyn Render>,
+    /// Text after the last line ending.
+    pending: String,
+    /// Terminal rows used by the last printed preview.
+    pending_rows: u16,
+}
+
+impl StreamPrinter {
+    pub fn new(renderer: Box<dyn Render>) -> Self {
+        Self {
+            renderer,
+            pending: String::new(),
+            pending_rows: 0,
+        }
+    }
+
+    /// Erases the rows of the last printed preview.
+    fn erase(&mut self, stdout: &mut impl Write) -> Result<()> {
+        if self.pending_rows > 0 {
+            if self.pending_rows > 1 {
+                crossterm::queue!(stdout, crossterm::cursor::MoveUp(self.pending_rows - 1))?;
+            }
+            crossterm::queue!(
+                stdout,
+                crossterm::cursor::MoveToColumn(0),
+                terminal::Clear(terminal::ClearType::FromCursorDown)
+            )?;
+        }
+
+        self.pending_rows = 0;
+
+        Ok(())
+    }
+
+    /// Prints a new chunk of text.
+    pub fn print(&mut self, text: &str) -> Result<()> {
+        let mut stdout = std::io::stdout().lock();
+
+        self.erase(&mut stdout)?;
+        self.pending.push_str(text);
+
+        while let Some(index) = self.pending.find('\n') {
+            let line = self.pending[..index].to_string();
+            self.pending.drain(..=index);
+
+            for row in self.renderer.line(&line)? {
+                writeln!(stdout, "{}", row)?;
+            }
+        }
+
+        let rows = self.renderer.preview(&self.pending)?;
+        let width = terminal::size().map(|(w, _)| w.max(1)).unwrap_or(80);
+
+        self.pending_rows = rows
+            .iter()
+            .map(|row| {
+                let columns = u16::try_from(visible_width(row)).unwrap_or(u16::MAX);
+                columns.div_ceil(width).max(1)
+            })
+            .sum();
+
+        write!(stdout, "{}", rows.join("\n"))?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+
+    /// Prints the lines held back by the renderer once the stream ends.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut stdout = std::io::stdout().lock();
+
+        self.erase(&mut stdout)?;
+
+        let pending = std::mem::take(&mut self.pending);
+
+        if !pending.is_empty() {
+            for row in self.renderer.line(&pending)? {
+                writeln!(stdout, "{}", row)?;
+            }
+        }
+
+        for row in self.renderer.finish()? {
+            writeln!(stdout, "{}", row)?;
+        }
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Prints the names of the themes that can be used with `--theme`.
+pub fn list_themes() -> Result<()> {
+    let assets = load_assets();
+
+    for theme in assets.themes() {
+        println!("{}", theme);
+    }
+
+    Ok(())
+}
+
+/// Prints the languages that can be used with `--language`, along with their file extensions.
+pub fn list_languages() -> Result<()> {
+    let assets = load_assets();
+
+    for syntax in assets.get_syntaxes()?.iter().filter(|s| !s.hidden) {
+        println!("{}: {}", syntax.name, syntax.file_extensions.join(", "));
+    }
+
+    Ok(())
+}
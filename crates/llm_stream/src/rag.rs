@@ -0,0 +1,514 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::prelude::*;
+use crate::tokens::Tokenizer;
+
+/// Files bigger than this aren't indexed.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Amount of texts embedded in a single request.
+const BATCH_SIZE: usize = 64;
+
+/// Returns the path of the index `name`, which can only hold letters, digits, `-`, and `_`, so it
+/// stays in the indexes directory.
+fn index_file(data_dir: &str, name: &str) -> Result<String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidIndex(name.to_string()));
+    }
+
+    Ok(format!("{}/indexes/{}.sqlite", data_dir, name))
+}
+
+/// Returns the default url, model, and key environment variable of the embeddings of `api`.
+fn defaults(api: Api) -> Result<(&'static str, &'static str, &'static str)> {
+    match api {
+        Api::OpenAi => Ok((
+            "https://api.openai.com/v1",
+            "text-embedding-3-small",
+            "OPENAI_API_KEY",
+        )),
+        Api::Mistral => Ok((
+            "https://api.mistral.ai/v1",
+            "mistral-embed",
+            "MISTRAL_API_KEY",
+        )),
+        api => Err(Error::Embeddings(format!(
+            "{} doesn't have an embeddings api, use openai or mistral",
+            api
+        ))),
+    }
+}
+
+/// Client of an OpenAI compatible `/embeddings` endpoint.
+struct Embedder {
+    url: String,
+    model: String,
+    key: String,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<Embedding>,
+}
+
+impl Embedder {
+    /// Builds the client of the `api` embeddings with its `[apis.<api>]` section, and the
+    /// top-level key options when it's the default api.
+    fn new(api: Api, model: &str, config: &Config) -> Result<Self> {
+        let (url, _, env) = defaults(api)?;
+
+        let mut args = api_args(api, config);
+        let key = crate::keys::resolve(&mut args, api, env)?;
+
+        Ok(Self {
+            url: args.api_base_url.unwrap_or(url.to_string()),
+            model: model.to_string(),
+            key,
+            headers: args.headers,
+        })
+    }
+
+    /// Returns the embeddings of `inputs`, in the same order.
+    fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(inputs.len());
+
+        for batch in inputs.chunks(BATCH_SIZE) {
+            let request = self.headers.iter().fold(
+                ureq::post(&format!("{}/embeddings", self.url))
+                    .set("Authorization", &format!("Bearer {}", self.key))
+                    .set("Content-Type", "application/json"),
+                |request, (name, value)| request.set(name, value),
+            );
+
+            let body = serde_json::json!({ "model": self.model, "input": batch });
+            let response = request
+                .send_string(&body.to_string())
+                .map_err(|e| Error::Embeddings(e.to_string()))?
+                .into_string()?;
+
+            let mut data = serde_json::from_str::<EmbeddingsResponse>(&response)?.data;
+            data.sort_by_key(|embedding| embedding.index);
+
+            embeddings.extend(data.into_iter().map(|embedding| embedding.embedding));
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// Part of an indexed file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub content: String,
+}
+
+impl Chunk {
+    /// Returns where the chunk comes from, like `src/main.rs:10-42`.
+    pub fn source(&self) -> String {
+        format!("{}:{}-{}", self.path, self.start_line, self.end_line)
+    }
+}
+
+/// Splits `content` into chunks of at most `max_tokens`, at line boundaries.
+fn split(path: &str, content: &str, max_tokens: usize, tokenizer: &Tokenizer) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut tokens = 0;
+
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        let line_tokens = tokenizer.count(line);
+
+        match chunks.last_mut() {
+            Some(chunk) if tokens + line_tokens <= max_tokens => {
+                chunk.content.push_str(line);
+                chunk.end_line = i + 1;
+                tokens += line_tokens;
+            }
+            _ => {
+                chunks.push(Chunk {
+                    path: path.to_string(),
+                    start_line: i + 1,
+                    end_line: i + 1,
+                    content: line.to_string(),
+                });
+                tokens = line_tokens;
+            }
+        }
+    }
+
+    chunks.retain(|chunk| !chunk.content.trim().is_empty());
+
+    chunks
+}
+
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    match norm(a) * norm(b) {
+        norm if norm == 0.0 => 0.0,
+        norm => dot / norm,
+    }
+}
+
+/// Returns the SHA-256 of `content`, which stays the same across versions of Rust, unlike the
+/// `DefaultHasher`.
+fn hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// SQLite database with the chunks of the indexed files and their embeddings.
+struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    fn open(path: &str) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    fn init(connection: Connection) -> Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS files (path TEXT PRIMARY KEY, hash TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS chunks (
+                 id INTEGER PRIMARY KEY,
+                 path TEXT NOT NULL,
+                 start_line INTEGER NOT NULL,
+                 end_line INTEGER NOT NULL,
+                 content TEXT NOT NULL,
+                 embedding BLOB NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS chunks_path ON chunks (path);",
+        )?;
+
+        Ok(Self { connection })
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            [key, value],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns true if the file was indexed with the same contents.
+    fn is_current(&self, path: &str, hash: &str) -> Result<bool> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT 1 FROM files WHERE path = ?1 AND hash = ?2",
+                [path, hash],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Replaces the chunks of the file at `path`.
+    fn replace(&mut self, path: &str, hash: &str, chunks: &[(Chunk, Vec<f32>)]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+
+        transaction.execute("DELETE FROM chunks WHERE path = ?1", [path])?;
+
+        for (chunk, embedding) in chunks {
+            transaction.execute(
+                "INSERT INTO chunks (path, start_line, end_line, content, embedding)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    chunk.path,
+                    chunk.start_line,
+                    chunk.end_line,
+                    chunk.content,
+                    to_blob(embedding)
+                ],
+            )?;
+        }
+
+        transaction.execute(
+            "INSERT OR REPLACE INTO files (path, hash) VALUES (?1, ?2)",
+            [path, hash],
+        )?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns the `top_k` chunks most similar to `embedding`.
+    fn search(&self, embedding: &[f32], top_k: usize) -> Result<Vec<Chunk>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT path, start_line, end_line, content, embedding FROM chunks")?;
+
+        let mut scored = statement
+            .query_map([], |row| {
+                Ok((
+                    Chunk {
+                        path: row.get(0)?,
+                        start_line: row.get(1)?,
+                        end_line: row.get(2)?,
+                        content: row.get(3)?,
+                    },
+                    from_blob(&row.get::<_, Vec<u8>>(4)?),
+                ))
+            })?
+            .map(|row| row.map(|(chunk, e)| (cosine_similarity(embedding, &e), chunk)))
+            .collect::<std::result::Result<Vec<(f32, Chunk)>, rusqlite::Error>>()?;
+
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Ok(scored
+            .into_iter()
+            .take(top_k)
+            .map(|(_, chunk)| chunk)
+            .collect())
+    }
+}
+
+/// Chunks, embeds, and stores the files of `paths` in the index `name`. Files that didn't change
+/// since they were indexed are skipped.
+///
+/// The embeddings api and model are stored in the index, so the same ones are used to query it.
+pub fn index(
+    paths: &[String],
+    name: &str,
+    model: Option<String>,
+    chunk_tokens: usize,
+    args: Args,
+) -> Result<()> {
+    let (args, config) = build_config(args)?;
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+
+    std::fs::create_dir_all(format!("{}/indexes", data_dir))?;
+
+    let mut store = Store::open(&index_file(&data_dir, name)?)?;
+
+    let api = match store.meta("api")? {
+        Some(api) => api.parse::<Api>().map_err(|_| Error::InvalidAPI)?,
+        None => args
+            .api
+            .or(config.api.filter(|api| defaults(*api).is_ok()))
+            .unwrap_or(Api::OpenAi),
+    };
+    let model = match (store.meta("model")?, model) {
+        (Some(stored), Some(model)) if stored != model => {
+            return Err(Error::Embeddings(format!(
+                "the {} index uses {}, delete it to change the model",
+                name, stored
+            )))
+        }
+        (Some(stored), _) => stored,
+        (None, model) => model.unwrap_or(defaults(api)?.1.to_string()),
+    };
+
+    store.set_meta("api", &api.to_string())?;
+    store.set_meta("model", &model)?;
+
+    let embedder = Embedder::new(api, &model, &config)?;
+    let tokenizer = Tokenizer::new(None, None);
+
+    let mut files = Vec::new();
+    for path in paths {
+        files.extend(crate::files::expand(path)?);
+    }
+
+    for path in files {
+        if std::fs::metadata(&path)
+            .map(|m| m.len())
+            .unwrap_or_default()
+            > MAX_FILE_SIZE
+        {
+            log::warn!("skipping {}: the file is too big", path);
+            continue;
+        }
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("skipping {}: {}", path, e);
+                continue;
+            }
+        };
+
+        let hash = hash(&content);
+
+        if store.is_current(&path, &hash)? {
+            continue;
+        }
+
+        let chunks = split(&path, &content, chunk_tokens, &tokenizer);
+        let inputs = chunks
+            .iter()
+            .map(|chunk| format!("{}\n\n{}", chunk.path, chunk.content))
+            .collect::<Vec<String>>();
+        let embeddings = embedder.embed(&inputs)?;
+
+        store.replace(
+            &path,
+            &hash,
+            &chunks.into_iter().zip(embeddings).collect::<Vec<_>>(),
+        )?;
+
+        eprintln!("Indexed {}", path);
+    }
+
+    Ok(())
+}
+
+/// Adds the chunks of the `--rag` index most similar to the prompt to the context sent before it,
+/// returning the sources of the chunks.
+pub fn attach(mut args: Args, config: &Config) -> Result<(Args, Vec<String>)> {
+    let Some(name) = args.rag.clone() else {
+        return Ok((args, Vec::new()));
+    };
+
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+    let path = index_file(&data_dir, &name)?;
+
+    if !std::path::Path::new(&path).exists() {
+        return Err(Error::IndexNotFound(name));
+    }
+
+    let store = Store::open(&path)?;
+    let api = store
+        .meta("api")?
+        .and_then(|api| api.parse::<Api>().ok())
+        .ok_or(Error::IndexNotFound(name.clone()))?;
+    let model = store
+        .meta("model")?
+        .ok_or(Error::IndexNotFound(name.clone()))?;
+
+    let query = args
+        .prompt
+        .clone()
+        .filter(|prompt| !prompt.trim().is_empty())
+        .or(args.stdin.clone())
+        .filter(|query| !query.trim().is_empty())
+        .ok_or_else(|| {
+            Error::Embeddings(format!(
+                "there's no prompt to search the {} index with",
+                name
+            ))
+        })?;
+
+    let embedding = Embedder::new(api, &model, config)?
+        .embed(&[query])?
+        .pop()
+        .unwrap_or_default();
+    let chunks = store.search(&embedding, args.rag_top_k.unwrap_or(5))?;
+
+    let context = chunks
+        .iter()
+        .map(|chunk| {
+            crate::files::fence(&chunk.path, &chunk.content).replacen(
+                &chunk.path,
+                &chunk.source(),
+                1,
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    args.attachments = Some(
+        [args.attachments.take().unwrap_or_default(), context]
+            .into_iter()
+            .filter(|section| !section.is_empty())
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+    );
+
+    Ok((args, chunks.iter().map(Chunk::source).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_keeps_line_ranges() {
+        let tokenizer = Tokenizer::new(None, None);
+        let content = "fn a() {}\nfn b() {}\n\nfn c() {}\n";
+        let line = tokenizer.count("fn a() {}\n");
+
+        let chunks = split("src/lib.rs", content, line * 2, &tokenizer);
+
+        assert_eq!(chunks[0].source(), "src/lib.rs:1-2");
+        assert_eq!(chunks[0].content, "fn a() {}\nfn b() {}\n");
+        assert!(chunks
+            .last()
+            .is_some_and(|chunk| chunk.content.ends_with("fn c() {}\n")));
+    }
+
+    #[test]
+    fn test_index_file_keeps_the_indexes_in_their_directory() {
+        assert_eq!(
+            index_file("/data", "docs_v2").ok(),
+            Some("/data/indexes/docs_v2.sqlite".to_string())
+        );
+
+        for name in ["", "../config", "a/b", "a\\b", ".."] {
+            assert!(index_file("/data", name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_search_returns_the_most_similar_chunks(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut store = Store::init(Connection::open_in_memory()?)?;
+        let chunk = |path: &str| Chunk {
+            path: path.to_string(),
+            start_line: 1,
+            end_line: 1,
+            content: path.to_string(),
+        };
+
+        store.replace("a.md", "1", &[(chunk("a.md"), vec![1.0, 0.0])])?;
+        store.replace("b.md", "2", &[(chunk("b.md"), vec![0.0, 1.0])])?;
+        store.replace("c.md", "3", &[(chunk("c.md"), vec![0.7, 0.7])])?;
+
+        let chunks = store.search(&[0.1, 1.0], 2)?;
+
+        assert_eq!(chunks, vec![chunk("b.md"), chunk("c.md")]);
+        assert!(store.is_current("a.md", "1")?);
+        assert!(!store.is_current("a.md", "2")?);
+
+        Ok(())
+    }
+}
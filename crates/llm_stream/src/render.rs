@@ -0,0 +1,595 @@
+use syntect::highlighting::{
+    Color, FontStyle, HighlightIterator, HighlightState, Highlighter, Style,
+};
+use syntect::parsing::{ParseState, ScopeStack};
+
+use crate::prelude::*;
+use crate::wrap::Wrapper;
+
+/// Turns the lines of a streamed response into terminal rows.
+pub trait Render {
+    /// Renders a complete line, returning the rows that won't change anymore. Lines can be held
+    /// back, like the rows of a table, until more of the response arrives.
+    fn line(&mut self, line: &str) -> Result<Vec<String>>;
+
+    /// Renders the held back lines and the unterminated `partial` line without changing the
+    /// renderer state.
+    fn preview(&mut self, partial: &str) -> Result<Vec<String>>;
+
+    /// Renders the lines held back at the end of the response.
+    fn finish(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Creates the renderer selected with `--renderer`.
+pub fn new(
+    renderer: Renderer,
+    language: &str,
+    theme: &str,
+    wrap: Option<usize>,
+) -> Result<Box<dyn Render>> {
+    Ok(match renderer {
+        Renderer::Markdown => Box::new(MarkdownRenderer::new(theme, wrap)),
+        Renderer::Syntax => Box::new(SyntaxRenderer::new(language, theme, wrap)?),
+        Renderer::Plain => Box::new(PlainRenderer::new(wrap)),
+    })
+}
+
+/// Returns the width of `text` on the terminal, ignoring its escape sequences.
+pub fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += 1;
+        }
+    }
+
+    width
+}
+
+/// Returns the escape sequence that sets `color` as the foreground color.
+///
+/// Follows the `bat` convention for the `ansi` themes: an alpha of `0` stores an ANSI color
+/// number in the red channel, and an alpha of `1` means the terminal default color.
+fn foreground(color: Color) -> String {
+    match color.a {
+        0 if color.r < 8 => format!("\x1b[3{}m", color.r),
+        0 => format!("\x1b[38;5;{}m", color.r),
+        1 => String::new(),
+        _ => format!("\x1b[38;2;{};{};{}m", color.r, color.g, color.b),
+    }
+}
+
+/// Renders highlighted ranges as terminal escape sequences, without the line ending.
+fn escape(ranges: &[(Style, &str)]) -> String {
+    let mut output = String::new();
+
+    for (style, text) in ranges {
+        let text = text.trim_end_matches('\n');
+
+        if text.is_empty() {
+            continue;
+        }
+
+        output.push_str(&foreground(style.foreground));
+
+        if style.font_style.contains(FontStyle::BOLD) {
+            output.push_str("\x1b[1m");
+        }
+        if style.font_style.contains(FontStyle::ITALIC) {
+            output.push_str("\x1b[3m");
+        }
+        if style.font_style.contains(FontStyle::UNDERLINE) {
+            output.push_str("\x1b[4m");
+        }
+
+        output.push_str(text);
+        output.push_str("\x1b[0m");
+    }
+
+    output
+}
+
+/// Parser and highlighter states of a syntax, kept between lines.
+#[derive(Clone)]
+struct Highlight {
+    parse_state: ParseState,
+    highlight_state: HighlightState,
+}
+
+impl Highlight {
+    fn new(assets: &bat::assets::HighlightingAssets, theme: &str, language: &str) -> Result<Self> {
+        let syntax_set = assets.get_syntax_set()?;
+        let syntax = syntax_set
+            .find_syntax_by_token(language)
+            .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+        Ok(Self {
+            parse_state: ParseState::new(syntax),
+            highlight_state: HighlightState::new(
+                &Highlighter::new(assets.get_theme(theme)),
+                ScopeStack::new(),
+            ),
+        })
+    }
+
+    /// Highlights a single line, advancing the states.
+    fn line(
+        &mut self,
+        assets: &bat::assets::HighlightingAssets,
+        theme: &str,
+        line: &str,
+    ) -> Result<String> {
+        let line = format!("{}\n", line);
+        let syntax_set = assets.get_syntax_set()?;
+        let highlighter = Highlighter::new(assets.get_theme(theme));
+        let ops = self
+            .parse_state
+            .parse_line(&line, syntax_set)
+            .map_err(syntect::Error::from)?;
+        let ranges = HighlightIterator::new(&mut self.highlight_state, &ops, &line, &highlighter)
+            .collect::<Vec<_>>();
+
+        Ok(escape(&ranges))
+    }
+}
+
+/// Wraps the lines without styling them.
+pub struct PlainRenderer {
+    wrapper: Option<Wrapper>,
+}
+
+impl PlainRenderer {
+    pub fn new(wrap: Option<usize>) -> Self {
+        Self {
+            wrapper: wrap.map(Wrapper::new),
+        }
+    }
+}
+
+impl Render for PlainRenderer {
+    fn line(&mut self, line: &str) -> Result<Vec<String>> {
+        Ok(match &mut self.wrapper {
+            Some(wrapper) => wrapper.wrap(line),
+            None => vec![line.to_string()],
+        })
+    }
+
+    fn preview(&mut self, partial: &str) -> Result<Vec<String>> {
+        Ok(match (&self.wrapper, partial.is_empty()) {
+            (_, true) => Vec::new(),
+            (Some(wrapper), false) => wrapper.clone().wrap(partial),
+            (None, false) => vec![partial.to_string()],
+        })
+    }
+}
+
+/// Highlights the response with the syntax of `--language`.
+pub struct SyntaxRenderer {
+    assets: bat::assets::HighlightingAssets,
+    theme: String,
+    wrapper: Option<Wrapper>,
+    highlight: Highlight,
+}
+
+impl SyntaxRenderer {
+    pub fn new(language: &str, theme: &str, wrap: Option<usize>) -> Result<Self> {
+        let assets = crate::printer::load_assets();
+        let highlight = Highlight::new(&assets, theme, language)?;
+
+        Ok(Self {
+            assets,
+            theme: theme.to_string(),
+            wrapper: wrap.map(Wrapper::new),
+            highlight,
+        })
+    }
+
+    fn render(
+        &self,
+        line: &str,
+        wrapper: &mut Option<Wrapper>,
+        highlight: &mut Highlight,
+    ) -> Result<Vec<String>> {
+        let rows = match wrapper {
+            Some(wrapper) => wrapper.wrap(line),
+            None => vec![line.to_string()],
+        };
+
+        rows.iter()
+            .map(|row| highlight.line(&self.assets, &self.theme, row))
+            .collect()
+    }
+}
+
+impl Render for SyntaxRenderer {
+    fn line(&mut self, line: &str) -> Result<Vec<String>> {
+        let mut wrapper = self.wrapper.take();
+        let mut highlight = self.highlight.clone();
+        let rows = self.render(line, &mut wrapper, &mut highlight);
+
+        self.wrapper = wrapper;
+        self.highlight = highlight;
+
+        rows
+    }
+
+    fn preview(&mut self, partial: &str) -> Result<Vec<String>> {
+        if partial.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.render(
+            partial,
+            &mut self.wrapper.clone(),
+            &mut self.highlight.clone(),
+        )
+    }
+}
+
+/// State of the Markdown renderer between lines.
+#[derive(Clone, Default)]
+struct MarkdownState {
+    wrapper: Option<Wrapper>,
+    /// Fence and highlighter of the code block being rendered.
+    code: Option<(String, Highlight)>,
+    /// Rows of the table being rendered. Tables are held back until they end so their columns
+    /// can be aligned.
+    table: Vec<String>,
+}
+
+/// Formats headings, emphasis, lists, quotes, and tables with terminal styles, and highlights the
+/// code blocks with their language.
+pub struct MarkdownRenderer {
+    assets: bat::assets::HighlightingAssets,
+    theme: String,
+    state: MarkdownState,
+}
+
+/// Renders the inline Markdown of `text`: strong and emphasized text, code spans, and links.
+fn inline(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let find = |from: usize, needle: char| (from..chars.len()).find(|&j| chars[j] == needle);
+
+    let mut output = String::new();
+    let mut strong = false;
+    let mut emphasis = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '`' {
+            if let Some(end) = find(i + 1, '`') {
+                let code = chars[i + 1..end].iter().collect::<String>();
+                output.push_str(&format!("\x1b[36m{}\x1b[39m", code));
+                i = end + 1;
+                continue;
+            }
+        }
+
+        if c == '*' && next == Some('*') {
+            strong = !strong;
+            output.push_str(if strong { "\x1b[1m" } else { "\x1b[22m" });
+            i += 2;
+            continue;
+        }
+
+        if c == '*' && (emphasis || next.is_some_and(|n| !n.is_whitespace())) {
+            emphasis = !emphasis;
+            output.push_str(if emphasis { "\x1b[3m" } else { "\x1b[23m" });
+            i += 1;
+            continue;
+        }
+
+        if c == '[' {
+            let link = find(i + 1, ']')
+                .filter(|&end| chars.get(end + 1) == Some(&'('))
+                .and_then(|end| find(end + 2, ')').map(|close| (end, close)));
+
+            if let Some((end, close)) = link {
+                let label = chars[i + 1..end].iter().collect::<String>();
+                let url = chars[end + 2..close].iter().collect::<String>();
+                output.push_str(&format!(
+                    "\x1b[4m{}\x1b[24m \x1b[2m({})\x1b[22m",
+                    label, url
+                ));
+                i = close + 1;
+                continue;
+            }
+        }
+
+        output.push(c);
+        i += 1;
+    }
+
+    if strong || emphasis {
+        output.push_str("\x1b[0m");
+    }
+
+    output
+}
+
+/// Splits a table row into its trimmed cells.
+fn cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+
+    row.split('|').map(|cell| inline(cell.trim())).collect()
+}
+
+/// Renders the rows of a table with aligned columns.
+fn table(rows: &[String]) -> Vec<String> {
+    let is_separator = |row: &str| {
+        row.trim()
+            .chars()
+            .all(|c| matches!(c, '|' | '-' | ':' | ' '))
+    };
+
+    let rows = rows
+        .iter()
+        .filter(|row| !is_separator(row))
+        .map(|row| cells(row))
+        .collect::<Vec<Vec<String>>>();
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| visible_width(cell))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<usize>>();
+
+    let mut output = Vec::new();
+
+    for (index, row) in rows.iter().enumerate() {
+        let line = widths
+            .iter()
+            .enumerate()
+            .map(|(column, width)| {
+                let cell = row.get(column).map(String::as_str).unwrap_or_default();
+                let padding = " ".repeat(width - visible_width(cell));
+                if index == 0 {
+                    format!("\x1b[1m{}\x1b[22m{}", cell, padding)
+                } else {
+                    format!("{}{}", cell, padding)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" │ ");
+
+        output.push(line);
+
+        if index == 0 {
+            output.push(
+                widths
+                    .iter()
+                    .map(|width| "─".repeat(*width))
+                    .collect::<Vec<String>>()
+                    .join("─┼─"),
+            );
+        }
+    }
+
+    output
+}
+
+impl MarkdownRenderer {
+    pub fn new(theme: &str, wrap: Option<usize>) -> Self {
+        Self {
+            assets: crate::printer::load_assets(),
+            theme: theme.to_string(),
+            state: MarkdownState {
+                wrapper: wrap.map(Wrapper::new),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Renders the table held back in `state`, if any.
+    fn flush(state: &mut MarkdownState) -> Vec<String> {
+        if state.table.is_empty() {
+            Vec::new()
+        } else {
+            table(&std::mem::take(&mut state.table))
+        }
+    }
+
+    fn render(&self, state: &mut MarkdownState, line: &str) -> Result<Vec<String>> {
+        let content = line.trim_start();
+
+        if let Some((fence, highlight)) = state.code.as_mut() {
+            if content.starts_with(fence.as_str()) {
+                state.code = None;
+                return Ok(vec![format!("\x1b[2m{}\x1b[22m", line)]);
+            }
+
+            return Ok(vec![highlight.line(&self.assets, &self.theme, line)?]);
+        }
+
+        if content.starts_with('|') {
+            state.table.push(line.to_string());
+            return Ok(Vec::new());
+        }
+
+        let mut output = Self::flush(state);
+
+        if content.starts_with("```") || content.starts_with("~~~") {
+            let first = content.chars().next().unwrap_or_default();
+            let fence = content
+                .chars()
+                .take_while(|c| *c == first)
+                .collect::<String>();
+            let language = content[fence.len()..].split_whitespace().next();
+            let highlight = Highlight::new(&self.assets, &self.theme, language.unwrap_or("txt"))?;
+
+            state.code = Some((fence, highlight));
+            output.push(format!("\x1b[2m{}\x1b[22m", line));
+
+            return Ok(output);
+        }
+
+        let level = content.chars().take_while(|c| *c == '#').count();
+
+        if (1..=6).contains(&level) && content[level..].starts_with(' ') {
+            let color = if level == 1 {
+                "\x1b[1;4;35m"
+            } else {
+                "\x1b[1;35m"
+            };
+            output.push(format!(
+                "{}{}\x1b[0m",
+                color,
+                inline(content[level..].trim())
+            ));
+            return Ok(output);
+        }
+
+        if content.len() >= 3
+            && ['-', '*', '_']
+                .iter()
+                .any(|r| content.chars().all(|c| c == *r))
+        {
+            output.push(format!("\x1b[2m{}\x1b[22m", "─".repeat(40)));
+            return Ok(output);
+        }
+
+        let rows = match state.wrapper.as_mut() {
+            Some(wrapper) => wrapper.wrap(line),
+            None => vec![line.to_string()],
+        };
+
+        for (index, row) in rows.iter().enumerate() {
+            let content = row.trim_start();
+            let indent = &row[..row.len() - content.len()];
+
+            let row = if let Some(quote) = content.strip_prefix("> ") {
+                format!("{}\x1b[2m│\x1b[22m {}", indent, inline(quote))
+            } else if let Some(item) = ["- ", "* ", "+ "]
+                .iter()
+                .find_map(|marker| content.strip_prefix(marker))
+                .filter(|_| index == 0)
+            {
+                format!("{}\x1b[33m•\x1b[39m {}", indent, inline(item))
+            } else {
+                format!("{}{}", indent, inline(content))
+            };
+
+            output.push(row);
+        }
+
+        Ok(output)
+    }
+}
+
+impl Render for MarkdownRenderer {
+    fn line(&mut self, line: &str) -> Result<Vec<String>> {
+        let mut state = std::mem::take(&mut self.state);
+        let rows = self.render(&mut state, line);
+
+        self.state = state;
+
+        rows
+    }
+
+    fn preview(&mut self, partial: &str) -> Result<Vec<String>> {
+        let mut state = self.state.clone();
+        let mut rows = if partial.is_empty() {
+            Vec::new()
+        } else {
+            self.render(&mut state, partial)?
+        };
+
+        rows.extend(Self::flush(&mut state));
+
+        Ok(rows)
+    }
+
+    fn finish(&mut self) -> Result<Vec<String>> {
+        Ok(Self::flush(&mut self.state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_foreground_follows_the_ansi_theme_convention() {
+        assert_eq!(
+            foreground(Color {
+                r: 2,
+                g: 0,
+                b: 0,
+                a: 0
+            }),
+            "\x1b[32m"
+        );
+        assert_eq!(
+            foreground(Color {
+                r: 208,
+                g: 0,
+                b: 0,
+                a: 0
+            }),
+            "\x1b[38;5;208m"
+        );
+        assert_eq!(
+            foreground(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 1
+            }),
+            ""
+        );
+        assert_eq!(
+            foreground(Color {
+                r: 1,
+                g: 2,
+                b: 3,
+                a: 255
+            }),
+            "\x1b[38;2;1;2;3m"
+        );
+    }
+
+    #[test]
+    fn test_visible_width_ignores_escape_sequences() {
+        assert_eq!(visible_width("\x1b[1mbold\x1b[22m text"), 9);
+    }
+
+    #[test]
+    fn test_table_aligns_columns() {
+        let rows = vec![
+            "| Name | Age |".to_string(),
+            "|------|-----|".to_string(),
+            "| Alexander | 7 |".to_string(),
+        ];
+
+        let actual = table(&rows)
+            .iter()
+            .map(|row| visible_width(row))
+            .collect::<Vec<usize>>();
+
+        assert_eq!(
+            actual,
+            vec![15, 15, 15],
+            "Every row should have the same width"
+        );
+    }
+}
@@ -0,0 +1,647 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::prelude::*;
+
+/// Tokens reported by the provider.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Usage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+}
+
+impl std::ops::Add for Usage {
+    type Output = Usage;
+
+    /// Adds up the tokens. Counts that neither side reported stay unknown.
+    fn add(self, other: Usage) -> Usage {
+        let add = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+        };
+
+        Usage {
+            input_tokens: add(self.input_tokens, other.input_tokens),
+            output_tokens: add(self.output_tokens, other.output_tokens),
+        }
+    }
+}
+
+/// Information about the response found in the streamed events, besides its text.
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct Metadata {
+    pub finish_reason: Option<String>,
+    pub usage: Usage,
+    /// Rate limit headers of the response, like `x-ratelimit-remaining-tokens`.
+    pub rate_limits: Vec<(String, String)>,
+}
+
+/// Metadata filled by the `on_event` hook of the provider clients.
+pub type SharedMetadata = Arc<Mutex<Metadata>>;
+
+/// Timing of the response, in milliseconds.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Timing {
+    pub elapsed_ms: u128,
+    pub first_token_ms: Option<u128>,
+}
+
+/// Document printed by `--json` once the response is complete.
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub id: Option<String>,
+    pub message: String,
+    pub model: Option<String>,
+    pub api: Option<Api>,
+    pub finish_reason: Option<String>,
+    pub usage: Usage,
+    pub timing: Timing,
+}
+
+/// Dollars per million input and output tokens, matched against the start of the model name.
+/// More specific names go first.
+pub const PRICES: &[(&str, f64, f64)] = &[
+    ("claude-3-5-sonnet", 3.0, 15.0),
+    ("claude-3-5-haiku", 0.8, 4.0),
+    ("claude-3-opus", 15.0, 75.0),
+    ("claude-3-sonnet", 3.0, 15.0),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("o1-mini", 3.0, 12.0),
+    ("o1", 15.0, 60.0),
+    ("gemini-1.5-flash", 0.075, 0.3),
+    ("gemini-1.5-pro", 1.25, 5.0),
+    ("mistral-large", 2.0, 6.0),
+    ("mistral-small", 0.2, 0.6),
+    ("codestral", 0.2, 0.6),
+    ("open-mistral-nemo", 0.15, 0.15),
+];
+
+/// Tokens of the context window, matched against the start of the model name.
+pub const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("claude-3", 200_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 128_000),
+    ("gemini-1.5-flash", 1_000_000),
+    ("gemini-1.5-pro", 2_000_000),
+    ("mistral-large", 128_000),
+    ("mistral-small", 32_000),
+    ("codestral", 256_000),
+    ("open-mistral-nemo", 128_000),
+];
+
+/// Returns the context window of `model`, when it's known.
+pub fn context_window(model: &str) -> Option<usize> {
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(name, _)| model.starts_with(name))
+        .map(|(_, tokens)| *tokens)
+}
+
+/// Returns true if the finish reason means the answer was cut by the token limit.
+pub fn is_truncated(finish_reason: Option<&str>) -> bool {
+    matches!(
+        finish_reason,
+        Some("length" | "model_length" | "max_tokens" | "MAX_TOKENS")
+    )
+}
+
+/// Returns true if the finish reason means the answer, or the prompt, was blocked by a content
+/// filter of the api.
+pub fn is_filtered(finish_reason: Option<&str>) -> bool {
+    matches!(
+        finish_reason,
+        Some(
+            "content_filter"
+                | "refusal"
+                | "SAFETY"
+                | "RECITATION"
+                | "BLOCKLIST"
+                | "PROHIBITED_CONTENT"
+                | "SPII"
+                | "IMAGE_SAFETY"
+        )
+    )
+}
+
+/// Returns the warning printed when the finish reason means the answer isn't complete.
+pub fn warning(finish_reason: Option<&str>) -> Option<String> {
+    if is_truncated(finish_reason) {
+        Some("the answer was cut by the token limit".to_string())
+    } else if is_filtered(finish_reason) {
+        Some(format!(
+            "the answer was stopped by a content filter ({})",
+            finish_reason.unwrap_or_default()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Estimates the cost, in dollars, of the tokens used by `model`.
+pub fn cost(model: &str, usage: Usage) -> Option<f64> {
+    let (_, input, output) = PRICES.iter().find(|(name, _, _)| model.starts_with(name))?;
+
+    Some(
+        (usage.input_tokens.unwrap_or_default() as f64 * input
+            + usage.output_tokens.unwrap_or_default() as f64 * output)
+            / 1_000_000.0,
+    )
+}
+
+/// Returns the remaining `kind` of a rate limit, from headers like `x-ratelimit-remaining-tokens`
+/// or `anthropic-ratelimit-tokens-remaining`.
+fn remaining(headers: &[(String, String)], kind: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(name, _)| {
+            name.ends_with(&format!("remaining-{}", kind))
+                || name.ends_with(&format!("{}-remaining", kind))
+        })
+        .map(|(_, value)| value.clone())
+}
+
+/// Statistics printed by `--stats` once the response is complete.
+#[derive(Debug)]
+pub struct Stats {
+    pub elapsed: std::time::Duration,
+    pub first_token: Option<std::time::Duration>,
+    pub usage: Usage,
+    /// The provider didn't report the usage, so the tokens were counted locally.
+    pub estimated: bool,
+    pub cost: Option<f64>,
+    /// Requests and tokens left in the rate limits of the api.
+    pub remaining_requests: Option<String>,
+    pub remaining_tokens: Option<String>,
+}
+
+impl Stats {
+    pub fn new(
+        args: &Args,
+        content: &str,
+        metadata: &Metadata,
+        elapsed: std::time::Duration,
+        first_token: Option<std::time::Duration>,
+    ) -> Self {
+        let mut usage = metadata.usage;
+        let estimated = usage.input_tokens.is_none() || usage.output_tokens.is_none();
+
+        if estimated {
+            let tokenizer = crate::tokens::Tokenizer::new(args.api, args.model.as_deref());
+
+            usage.input_tokens = usage
+                .input_tokens
+                .or(Some(tokenizer.count_conversation(&args.conversation) as u64));
+            usage.output_tokens = usage
+                .output_tokens
+                .or(Some(tokenizer.count(content) as u64));
+        }
+
+        Self {
+            elapsed,
+            first_token,
+            usage,
+            estimated,
+            cost: args.model.as_deref().and_then(|model| cost(model, usage)),
+            remaining_requests: remaining(&metadata.rate_limits, "requests"),
+            remaining_tokens: remaining(&metadata.rate_limits, "tokens"),
+        }
+    }
+
+    /// Output tokens per second, measured from the first token.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let generation = self.elapsed - self.first_token.unwrap_or_default();
+        let seconds = generation.as_secs_f64();
+
+        if seconds > 0.0 {
+            Some(self.usage.output_tokens.unwrap_or_default() as f64 / seconds)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let approximate = if self.estimated { "~" } else { "" };
+
+        write!(f, "Elapsed: {:.2}s", self.elapsed.as_secs_f64())?;
+
+        if let Some(first_token) = self.first_token {
+            write!(f, " | First token: {:.2}s", first_token.as_secs_f64())?;
+        }
+
+        write!(
+            f,
+            " | Tokens: {}{} in, {}{} out",
+            approximate,
+            self.usage.input_tokens.unwrap_or_default(),
+            approximate,
+            self.usage.output_tokens.unwrap_or_default()
+        )?;
+
+        if let Some(tokens_per_second) = self.tokens_per_second() {
+            write!(f, " | {:.1} tok/s", tokens_per_second)?;
+        }
+
+        if let Some(cost) = self.cost {
+            write!(f, " | Cost: {}${:.4}", approximate, cost)?;
+        }
+
+        match (&self.remaining_requests, &self.remaining_tokens) {
+            (Some(requests), Some(tokens)) => {
+                write!(f, " | Remaining: {} requests, {} tokens", requests, tokens)?
+            }
+            (Some(requests), None) => write!(f, " | Remaining: {} requests", requests)?,
+            (None, Some(tokens)) => write!(f, " | Remaining: {} tokens", tokens)?,
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Event printed by `--ndjson` as a single JSON line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StreamEvent<'a> {
+    Delta {
+        text: &'a str,
+    },
+    Usage(Usage),
+    Stop {
+        id: Option<String>,
+        finish_reason: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl StreamEvent<'_> {
+    /// Prints the event to stdout as a JSON line.
+    pub fn print(&self) -> Result<()> {
+        self.write(&mut std::io::stdout().lock())
+    }
+
+    /// Writes the event to `writer` as a JSON line.
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        serde_json::to_writer(&mut *writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Reads the finish reason and usage from the raw data of a streamed event.
+pub fn observe(api: Api, data: &str, metadata: &mut Metadata) {
+    let Ok(value) = serde_json::from_str::<Value>(data) else {
+        return;
+    };
+
+    let (finish_reason, input_tokens, output_tokens) = match api {
+        Api::Anthropic => (
+            "/delta/stop_reason",
+            "/message/usage/input_tokens",
+            "/usage/output_tokens",
+        ),
+        Api::Google => (
+            "/candidates/0/finishReason",
+            "/usageMetadata/promptTokenCount",
+            "/usageMetadata/candidatesTokenCount",
+        ),
+        Api::OpenAi | Api::Mistral | Api::MistralFim => (
+            "/choices/0/finish_reason",
+            "/usage/prompt_tokens",
+            "/usage/completion_tokens",
+        ),
+        Api::OllamaFim => ("/done_reason", "/prompt_eval_count", "/eval_count"),
+    };
+
+    if let Some(reason) = value.pointer(finish_reason).and_then(Value::as_str) {
+        metadata.finish_reason = Some(reason.to_string());
+    }
+    // Google answers prompts it blocks without candidates, only with the reason.
+    if let Some(reason) = value
+        .pointer("/promptFeedback/blockReason")
+        .and_then(Value::as_str)
+    {
+        metadata.finish_reason = Some(reason.to_string());
+    }
+    if let Some(tokens) = value.pointer(input_tokens).and_then(Value::as_u64) {
+        metadata.usage.input_tokens = Some(tokens);
+    }
+    if let Some(tokens) = value.pointer(output_tokens).and_then(Value::as_u64) {
+        metadata.usage.output_tokens = Some(tokens);
+    }
+}
+
+/// When the rate limit of each api resets, once one of its responses used it up.
+static QUOTAS: Mutex<Vec<(Api, std::time::Instant)>> = Mutex::new(Vec::new());
+
+/// Parses a duration like `1m30s` or `250ms`, the format of the OpenAI reset headers.
+fn parse_duration(value: &str) -> Option<std::time::Duration> {
+    let mut seconds = 0.0;
+    let mut rest = value;
+
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number = rest[..number_end].parse::<f64>().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+
+        seconds += number * unit;
+    }
+
+    (!value.is_empty() && seconds.is_finite()).then(|| std::time::Duration::from_secs_f64(seconds))
+}
+
+/// Returns how long until the rate limits used up in `headers` reset, if any is down to zero.
+/// Each `remaining` header is paired with the `reset` one of the same name, which OpenAI sends as
+/// a duration and Anthropic as a date.
+fn exhausted(headers: &[(String, String)]) -> Option<std::time::Duration> {
+    headers
+        .iter()
+        .filter(|(name, value)| {
+            name.contains("remaining") && value.trim().parse::<u64>().ok() == Some(0)
+        })
+        .filter_map(|(name, _)| {
+            let reset = name.replace("remaining", "reset");
+            let (_, value) = headers.iter().find(|(name, _)| *name == reset)?;
+            let value = value.trim();
+
+            match chrono::DateTime::parse_from_rfc3339(value) {
+                Ok(date) => Some(
+                    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                        .to_std()
+                        .unwrap_or_default(),
+                ),
+                Err(_) => parse_duration(value),
+            }
+        })
+        .max()
+        .map(|reset| reset.min(crate::prelude::MAX_RETRY_DELAY))
+}
+
+/// Returns an `on_headers` hook that records the rate limit headers of the response into
+/// `metadata`, and when the rate limit of `api` resets if the response used it up.
+pub fn headers_hook(
+    api: Api,
+    metadata: SharedMetadata,
+) -> impl Fn(&[(String, String)]) + Send + Sync + 'static {
+    move |headers| {
+        if let Ok(mut metadata) = metadata.lock() {
+            metadata.rate_limits = headers.to_vec();
+        }
+
+        if let Some(reset) = exhausted(headers) {
+            let mut quotas = QUOTAS.lock().unwrap_or_else(|e| e.into_inner());
+            quotas.retain(|(a, _)| *a != api);
+            quotas.push((api, std::time::Instant::now() + reset));
+        }
+    }
+}
+
+/// Waits until the rate limit of `api` resets, if one of its responses used it up, instead of
+/// sending a request that would be refused.
+pub async fn wait_for_quota(api: Option<Api>, notices: bool) {
+    let reset = QUOTAS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(a, _)| Some(*a) == api)
+        .and_then(|(_, reset)| reset.checked_duration_since(std::time::Instant::now()));
+
+    let (Some(api), Some(reset)) = (api, reset) else {
+        return;
+    };
+
+    if notices {
+        crate::status::notice(&format!(
+            "The rate limit of {} is used up, waiting {:.0}s...",
+            api,
+            reset.as_secs_f64()
+        ));
+    }
+
+    tokio::time::sleep(reset).await;
+}
+
+/// Returns an `on_event` hook that records the metadata of the response into `metadata`.
+pub fn hook(api: Api, metadata: SharedMetadata) -> impl Fn(&str) + Send + Sync + 'static {
+    move |data| {
+        if let Ok(mut metadata) = metadata.lock() {
+            observe(api, data, &mut metadata);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            parse_duration("1m30s"),
+            Some(std::time::Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_duration("250ms"),
+            Some(std::time::Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_duration("6.5s"),
+            Some(std::time::Duration::from_millis(6500))
+        );
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("12"), None);
+        assert_eq!(parse_duration("3d"), None);
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let headers = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<(String, String)>>()
+        };
+
+        let openai = headers(&[
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-reset-requests", "20s"),
+            ("x-ratelimit-remaining-tokens", "1200"),
+            ("x-ratelimit-reset-tokens", "2s"),
+        ]);
+        assert_eq!(exhausted(&openai), Some(std::time::Duration::from_secs(20)));
+
+        let reset = chrono::Utc::now() - chrono::Duration::seconds(5);
+        let anthropic = headers(&[
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            ("anthropic-ratelimit-tokens-reset", &reset.to_rfc3339()),
+        ]);
+        assert_eq!(exhausted(&anthropic), Some(std::time::Duration::ZERO));
+
+        let left = headers(&[
+            ("x-ratelimit-remaining-requests", "3"),
+            ("x-ratelimit-reset-requests", "20s"),
+        ]);
+        assert_eq!(exhausted(&left), None);
+
+        let hours = headers(&[
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-tokens", "2h"),
+        ]);
+        assert_eq!(exhausted(&hours), Some(crate::prelude::MAX_RETRY_DELAY));
+    }
+
+    #[test]
+    fn test_observe_anthropic_events() {
+        let mut metadata = Metadata::default();
+
+        observe(
+            Api::Anthropic,
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            &mut metadata,
+        );
+        observe(
+            Api::Anthropic,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":42}}"#,
+            &mut metadata,
+        );
+
+        assert_eq!(
+            metadata,
+            Metadata {
+                finish_reason: Some("end_turn".to_string()),
+                usage: Usage {
+                    input_tokens: Some(12),
+                    output_tokens: Some(42),
+                },
+                rate_limits: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_remaining_reads_openai_and_anthropic_headers() {
+        let openai = vec![(
+            "x-ratelimit-remaining-tokens".to_string(),
+            "79000".to_string(),
+        )];
+        let anthropic = vec![(
+            "anthropic-ratelimit-requests-remaining".to_string(),
+            "49".to_string(),
+        )];
+
+        assert_eq!(remaining(&openai, "tokens"), Some("79000".to_string()));
+        assert_eq!(remaining(&openai, "requests"), None);
+        assert_eq!(remaining(&anthropic, "requests"), Some("49".to_string()));
+    }
+
+    #[test]
+    fn test_stream_events_are_tagged() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            serde_json::to_string(&StreamEvent::Delta { text: "Hi" })?,
+            r#"{"type":"delta","text":"Hi"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&StreamEvent::Usage(Usage {
+                input_tokens: Some(1),
+                output_tokens: Some(2),
+            }))?,
+            r#"{"type":"usage","input_tokens":1,"output_tokens":2}"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cost_matches_the_most_specific_model() {
+        let usage = Usage {
+            input_tokens: Some(1_000_000),
+            output_tokens: Some(1_000_000),
+        };
+
+        assert_eq!(cost("gpt-4o-mini-2024-07-18", usage), Some(0.75));
+        assert_eq!(cost("gpt-4o", usage), Some(12.5));
+        assert_eq!(cost("unknown", usage), None);
+    }
+
+    #[test]
+    fn test_observe_openai_events() {
+        let mut metadata = Metadata::default();
+
+        observe(
+            Api::OpenAi,
+            r#"{"choices":[{"delta":{},"finish_reason":"length"}]}"#,
+            &mut metadata,
+        );
+        observe(Api::OpenAi, "[DONE]", &mut metadata);
+
+        assert_eq!(metadata.finish_reason, Some("length".to_string()));
+        assert_eq!(metadata.usage, Usage::default());
+    }
+
+    #[test]
+    fn test_observe_ollama_events() {
+        let mut metadata = Metadata::default();
+
+        observe(
+            Api::OllamaFim,
+            r#"{"model":"qwen2.5-coder","response":"x","done":false}"#,
+            &mut metadata,
+        );
+        observe(
+            Api::OllamaFim,
+            r#"{"model":"qwen2.5-coder","response":"","done":true,"done_reason":"stop","prompt_eval_count":26,"eval_count":8}"#,
+            &mut metadata,
+        );
+
+        assert_eq!(metadata.finish_reason, Some("stop".to_string()));
+        assert_eq!(metadata.usage.input_tokens, Some(26));
+        assert_eq!(metadata.usage.output_tokens, Some(8));
+    }
+
+    #[test]
+    fn test_warning_on_incomplete_answers() {
+        let mut metadata = Metadata::default();
+
+        observe(
+            Api::Google,
+            r#"{"promptFeedback":{"blockReason":"SAFETY"}}"#,
+            &mut metadata,
+        );
+
+        assert_eq!(
+            warning(metadata.finish_reason.as_deref()),
+            Some("the answer was stopped by a content filter (SAFETY)".to_string())
+        );
+        assert_eq!(
+            warning(Some("MAX_TOKENS")),
+            Some("the answer was cut by the token limit".to_string())
+        );
+        assert_eq!(warning(Some("end_turn")), None);
+        assert_eq!(warning(None), None);
+    }
+}
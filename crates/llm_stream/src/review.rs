@@ -0,0 +1,326 @@
+use serde_json::Value;
+
+use crate::args::DiffArgs;
+use crate::commit::git;
+use crate::config::Template;
+use crate::prelude::*;
+use crate::tokens::Tokenizer;
+
+/// Tokens of each part of the diff when neither `--chunk-tokens` nor `--max-context-tokens` are
+/// given.
+const DEFAULT_CHUNK_TOKENS: usize = 16_000;
+
+const REVIEW_SYSTEM: &str = "You are a senior software engineer reviewing a change. Point out \
+bugs, security issues, missing tests, and unclear code, naming the file and lines of each \
+finding. Order the findings by severity, and skip the style nitpicks a formatter would fix. If \
+the change looks good, say so.";
+
+const PR_SYSTEM: &str = "You write pull request descriptions. Start with a short title, then \
+explain what the change does and why in a few sentences, followed by a list of the notable \
+changes and anything the reviewers should check. Answer in Markdown.";
+
+const PROMPT: &str = "{% if notes %}The diff was too long for a single request, so it was read in \
+parts. These are the notes of each part:
+{% for note in notes %}
+## Part {{ loop.index }}
+
+{{ note }}
+{% endfor %}
+Write the {task} of the whole change from them.
+{% else %}Write the {task} of this diff:
+
+```diff
+{{ diff }}
+```
+{% endif %}{% if prompt %}
+{{ prompt }}
+{% endif %}";
+
+/// What the `review` and `pr` commands write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Review,
+    Pr,
+}
+
+impl Mode {
+    /// Name of the template used by the mode. Define a template with the same name to override it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Review => "review",
+            Mode::Pr => "pr",
+        }
+    }
+
+    fn task(&self) -> &'static str {
+        match self {
+            Mode::Review => "review",
+            Mode::Pr => "pull request description",
+        }
+    }
+
+    /// Returns the template used when there isn't one named after the mode in the configuration.
+    pub fn template(&self) -> Template {
+        let (description, system) = match self {
+            Mode::Review => ("Reviews a diff", REVIEW_SYSTEM),
+            Mode::Pr => ("Writes a pull request description for a diff", PR_SYSTEM),
+        };
+
+        Template {
+            name: self.name().to_string(),
+            description: Some(description.to_string()),
+            system: Some(system.to_string()),
+            template: Some(PROMPT.replace("{task}", self.task())),
+            default_vars: Some(serde_json::json!({ "notes": [] })),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the prompt of a part of a diff too long for a single request.
+    fn part_prompt(&self, part: usize, parts: usize, chunk: &str) -> String {
+        let task = match self {
+            Mode::Review => {
+                "Review it, listing the issues you find with the file and lines of each one. The \
+                 other parts are reviewed separately, so don't comment on code you can't see."
+            }
+            Mode::Pr => {
+                "Summarize what it changes, file by file. The summaries of every part are used to \
+                 write a pull request description."
+            }
+        };
+
+        format!(
+            "This is part {} of {} of a diff. {}\n\n```diff\n{}\n```",
+            part, parts, task, chunk
+        )
+    }
+}
+
+/// Diff of a `review` or `pr` command.
+pub struct Diff {
+    mode: Mode,
+    text: String,
+    chunk_tokens: Option<usize>,
+}
+
+/// Returns the default branch of `origin`, or `main` when it isn't known.
+fn default_branch() -> String {
+    git(&["rev-parse", "--abbrev-ref", "origin/HEAD"])
+        .map(|branch| branch.trim().to_string())
+        .unwrap_or_else(|_| "main".to_string())
+}
+
+/// Reads the diff given with `--diff`, or the one of `--range`. Without them, `review` uses the
+/// uncommitted changes, and `pr` the changes since the default branch. Selects the template of the
+/// mode, unless another one was given.
+pub fn prepare(args: &mut Args, mode: Mode, diff: DiffArgs) -> Result<Diff> {
+    let text = match (diff.diff, diff.range) {
+        (Some(path), _) => std::fs::read_to_string(crate::paths::expand(&path)?)?,
+        (None, Some(range)) => git(&["diff", &range])?,
+        (None, None) => match mode {
+            Mode::Review => git(&["diff", "HEAD"])?,
+            Mode::Pr => git(&["diff", &format!("{}...HEAD", default_branch())])?,
+        },
+    };
+
+    if text.trim().is_empty() {
+        return Err(Error::Git("the diff is empty".to_string()));
+    }
+
+    args.template.get_or_insert(mode.name().to_string());
+
+    Ok(Diff {
+        mode,
+        text,
+        chunk_tokens: diff.chunk_tokens,
+    })
+}
+
+/// Splits `text` before every line that starts with `prefix`.
+fn split_before(text: &str, prefix: &str) -> Vec<String> {
+    let mut pieces: Vec<String> = Vec::new();
+
+    for line in text.split_inclusive('\n') {
+        match pieces.last_mut() {
+            Some(piece) if !line.starts_with(prefix) => piece.push_str(line),
+            _ => pieces.push(line.to_string()),
+        }
+    }
+
+    pieces
+}
+
+/// Joins consecutive pieces into chunks of at most `max_tokens`, keeping their order. Pieces
+/// longer than that get a chunk of their own.
+fn pack(pieces: Vec<String>, max_tokens: usize, tokenizer: &Tokenizer) -> Vec<String> {
+    let mut chunks: Vec<(String, usize)> = Vec::new();
+
+    for piece in pieces {
+        let tokens = tokenizer.count(&piece);
+
+        match chunks.last_mut() {
+            Some((chunk, total)) if *total + tokens <= max_tokens => {
+                chunk.push_str(&piece);
+                *total += tokens;
+            }
+            _ => chunks.push((piece, tokens)),
+        }
+    }
+
+    chunks.into_iter().map(|(chunk, _)| chunk).collect()
+}
+
+/// Splits a diff into chunks of at most `max_tokens`. Files are kept whole when they fit,
+/// otherwise they are split by hunk, and hunks by line, repeating the header of the file in each
+/// chunk.
+fn chunks(diff: &str, max_tokens: usize, tokenizer: &Tokenizer) -> Vec<String> {
+    let pieces = split_before(diff, "diff --git ")
+        .into_iter()
+        .flat_map(|file| {
+            if tokenizer.count(&file) <= max_tokens {
+                return vec![file];
+            }
+
+            let mut hunks = split_before(&file, "@@");
+            let header = match hunks.first() {
+                Some(first) if !first.starts_with("@@") => hunks.remove(0),
+                _ => String::new(),
+            };
+            let budget = max_tokens.saturating_sub(tokenizer.count(&header)).max(1);
+
+            let hunks = hunks
+                .into_iter()
+                .flat_map(|hunk| {
+                    if tokenizer.count(&hunk) <= budget {
+                        vec![hunk]
+                    } else {
+                        let lines = hunk.split_inclusive('\n').map(String::from).collect();
+                        pack(lines, budget, tokenizer)
+                    }
+                })
+                .collect();
+
+            pack(hunks, budget, tokenizer)
+                .into_iter()
+                .map(|hunks| format!("{}{}", header, hunks))
+                .collect()
+        })
+        .collect();
+
+    pack(pieces, max_tokens, tokenizer)
+}
+
+impl Diff {
+    /// Passes the diff to the template as the `diff` variable. Diffs that don't fit in a chunk are
+    /// sent in parts first, and the notes written for each part are passed as `notes` instead.
+    ///
+    /// With `--dry-run` or `--count-tokens` the parts aren't sent, and placeholders are used.
+    pub async fn run(self, mut args: Args, config: &Config) -> Result<Args> {
+        let max_tokens = self
+            .chunk_tokens
+            .or(args.max_context_tokens.map(|tokens| tokens / 2))
+            .unwrap_or(DEFAULT_CHUNK_TOKENS);
+        let tokenizer = Tokenizer::new(args.api, args.model.as_deref());
+        let chunks = chunks(&self.text, max_tokens, &tokenizer);
+
+        let mut vars = if chunks.len() == 1 {
+            serde_json::json!({ "diff": self.text })
+        } else if args.dry_run || args.count_tokens {
+            let notes = (1..=chunks.len())
+                .map(|part| format!("<notes of part {}>", part))
+                .collect::<Vec<String>>();
+
+            serde_json::json!({ "diff": "", "notes": notes })
+        } else {
+            let mut notes = Vec::new();
+
+            for (i, chunk) in chunks.iter().enumerate() {
+                if args.shows_notices() {
+                    eprintln!("Reading part {}/{} of the diff", i + 1, chunks.len());
+                }
+
+                let request = Args {
+                    prompt: Some(self.mode.part_prompt(i + 1, chunks.len(), chunk)),
+                    stdin: None,
+                    template: None,
+                    system: Vec::new(),
+                    vars: None,
+                    conversation: Conversation::new(),
+                    output: None,
+                    quiet: true,
+                    no_cache: true,
+                    ..args.clone()
+                };
+
+                let request = merge_args_and_config(request, config.clone())?;
+
+                notes.push(Value::String(complete(request, |_| {}).await?));
+            }
+
+            serde_json::json!({ "diff": "", "notes": notes })
+        };
+
+        merge(&mut vars, args.vars.take().unwrap_or_default());
+        args.vars = Some(vars);
+
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "diff --git a/a.rs b/a.rs
+--- a/a.rs
++++ b/a.rs
+@@ -1 +1 @@
+-fn a() {}
++fn a() -> u32 { 1 }
+@@ -10 +10 @@
+-fn b() {}
++fn b() -> u32 { 2 }
+diff --git a/c.rs b/c.rs
+--- a/c.rs
++++ b/c.rs
+@@ -1 +1 @@
+-fn c() {}
++fn c() -> u32 { 3 }
+";
+
+    #[test]
+    fn test_chunks_keep_files_whole_when_they_fit() {
+        let tokenizer = Tokenizer::new(None, None);
+
+        assert_eq!(chunks(DIFF, 1000, &tokenizer), vec![DIFF.to_string()]);
+
+        let files = chunks(DIFF, tokenizer.count(DIFF) - 1, &tokenizer);
+
+        assert_eq!(files.len(), 2);
+        assert!(files[1].starts_with("diff --git a/c.rs"));
+    }
+
+    #[test]
+    fn test_chunks_split_long_files_by_hunk() {
+        let tokenizer = Tokenizer::new(None, None);
+        let file = &split_before(DIFF, "diff --git ")[0];
+        let header = "diff --git a/a.rs b/a.rs\n--- a/a.rs\n+++ b/a.rs\n";
+
+        let chunks = chunks(DIFF, tokenizer.count(file) - 1, &tokenizer);
+
+        assert!(chunks[0].starts_with(header) && !chunks[0].contains("@@ -10"));
+        assert!(chunks[1].starts_with(header) && chunks[1].contains("@@ -10"));
+    }
+
+    #[test]
+    fn test_template_needs_the_diff() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let variables = crate::templates::variables(&Mode::Pr.template())?;
+
+        assert_eq!(
+            variables.required.into_iter().collect::<Vec<String>>(),
+            vec!["diff".to_string()]
+        );
+
+        Ok(())
+    }
+}
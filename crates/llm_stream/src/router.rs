@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::IsTerminal;
+
+use crate::config::RoutePreference;
+use crate::ledger::Entry;
+use crate::prelude::*;
+use crate::report::{context_window, cost, Usage};
+use crate::tokens::Tokenizer;
+
+/// Output tokens expected when neither `--max-tokens` nor the preset set them.
+const DEFAULT_OUTPUT_TOKENS: u32 = 1000;
+
+/// Amount of the most recent ledger entries of each model used to average its latency.
+const LATENCY_SAMPLES: usize = 20;
+
+/// Preset a router can pick, with what is known about its model.
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    name: String,
+    context_window: Option<usize>,
+    cost: Option<f64>,
+    latency_ms: Option<u64>,
+}
+
+/// Returns the average latency of the most recent requests of each model in the ledger.
+fn latencies(entries: &[Entry]) -> HashMap<String, u64> {
+    let mut samples: HashMap<String, Vec<u64>> = HashMap::new();
+
+    for entry in entries.iter().rev() {
+        if let (Some(model), Some(elapsed_ms)) = (&entry.model, entry.elapsed_ms) {
+            let model_samples = samples.entry(model.clone()).or_default();
+
+            if model_samples.len() < LATENCY_SAMPLES {
+                model_samples.push(elapsed_ms);
+            }
+        }
+    }
+
+    samples
+        .into_iter()
+        .map(|(model, samples)| {
+            let average = samples.iter().sum::<u64>() / samples.len() as u64;
+            (model, average)
+        })
+        .collect()
+}
+
+/// Picks the candidate whose context window fits the request and whose cost is within the
+/// budget, preferring the cheapest one, or the fastest one. Unknown costs and latencies are
+/// considered last, and unknown costs never fit a budget.
+fn select(
+    candidates: Vec<Candidate>,
+    tokens: usize,
+    budget: Option<f64>,
+    prefer: RoutePreference,
+) -> Option<Candidate> {
+    let mut fitting = candidates
+        .into_iter()
+        .filter(|c| c.context_window.map_or(true, |window| window >= tokens))
+        .filter(|c| budget.map_or(true, |budget| c.cost.is_some_and(|cost| cost <= budget)))
+        .collect::<Vec<Candidate>>();
+
+    let cost = |c: &Candidate| c.cost.unwrap_or(f64::INFINITY);
+    let latency = |c: &Candidate| c.latency_ms.unwrap_or(u64::MAX);
+
+    // The sort is stable, so candidates that tie keep the order of `routes`.
+    match prefer {
+        RoutePreference::Cost => fitting.sort_by(|a, b| {
+            cost(a)
+                .total_cmp(&cost(b))
+                .then(latency(a).cmp(&latency(b)))
+        }),
+        RoutePreference::Latency => fitting.sort_by(|a, b| {
+            latency(a)
+                .cmp(&latency(b))
+                .then(cost(a).total_cmp(&cost(b)))
+        }),
+    }
+
+    fitting.into_iter().next()
+}
+
+/// Resolves a router preset, one with `routes`, to the preset that fits the prompt, and merges
+/// it into the arguments. Other presets are left as they are.
+///
+/// The size of the request is the conversation and prompt, plus the output tokens expected from
+/// `--max-tokens`. Costs are estimated with the known prices, and latencies are averaged from the
+/// usage ledger.
+pub fn route(mut args: Args, config: &Config) -> Result<Args> {
+    let presets = config.presets.clone().unwrap_or_default();
+
+    let Some(router) = args
+        .preset
+        .as_ref()
+        .and_then(|name| presets.iter().find(|p| &p.name == name))
+        .filter(|p| p.routes.is_some())
+        .cloned()
+    else {
+        return Ok(args);
+    };
+
+    let tokenizer = Tokenizer::new(None, None);
+    let input_tokens = tokenizer.count_conversation(&args.conversation)
+        + args.prompt.as_deref().map_or(0, |p| tokenizer.count(p))
+        + args.stdin.as_deref().map_or(0, |s| tokenizer.count(s));
+    let output_tokens = args
+        .max_tokens
+        .or(router.max_tokens)
+        .unwrap_or(DEFAULT_OUTPUT_TOKENS);
+    let usage = Usage {
+        input_tokens: Some(input_tokens as u64),
+        output_tokens: Some(output_tokens as u64),
+    };
+
+    let state_dir = args.state_dir.clone().expect("can't find state directory");
+    let latencies = latencies(&crate::ledger::read(&state_dir).unwrap_or_default());
+
+    let candidates = router
+        .routes
+        .iter()
+        .flatten()
+        .map(|name| {
+            let preset = presets
+                .iter()
+                .find(|p| &p.name == name && p.routes.is_none())
+                .ok_or_else(|| Error::Route(format!("{} isn't a preset", name)))?;
+            let model = preset.model.as_deref().unwrap_or_default();
+
+            Ok(Candidate {
+                name: name.clone(),
+                context_window: context_window(model),
+                cost: cost(model, usage),
+                latency_ms: latencies.get(model).copied(),
+            })
+        })
+        .collect::<Result<Vec<Candidate>>>()?;
+
+    let Some(candidate) = select(
+        candidates,
+        input_tokens + output_tokens as usize,
+        router.budget,
+        router.prefer.unwrap_or_default(),
+    ) else {
+        return Err(Error::Route(format!(
+            "no preset of {} fits {} tokens within the budget",
+            router.name,
+            input_tokens + output_tokens as usize
+        )));
+    };
+
+    if std::io::stderr().is_terminal() && args.shows_notices() {
+        eprintln!("Routing to the {} preset", candidate.name);
+    }
+
+    log::info!("routed {} to {:#?}", router.name, candidate);
+
+    args.preset = Some(candidate.name);
+    merge_preset(&mut args, config);
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(name: &str, window: usize, cost: f64, latency_ms: u64) -> Candidate {
+        Candidate {
+            name: name.to_string(),
+            context_window: Some(window),
+            cost: Some(cost),
+            latency_ms: Some(latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_select_prefers_the_cheapest_preset_that_fits() {
+        let candidates = vec![
+            candidate("sonnet", 200_000, 0.05, 3000),
+            candidate("mini", 16_000, 0.001, 800),
+            candidate("haiku", 200_000, 0.01, 1200),
+        ];
+
+        let select_name = |tokens, budget, prefer| {
+            select(candidates.clone(), tokens, budget, prefer).map(|c| c.name)
+        };
+
+        assert_eq!(
+            select_name(1000, None, RoutePreference::Cost),
+            Some("mini".to_string())
+        );
+        assert_eq!(
+            select_name(50_000, None, RoutePreference::Cost),
+            Some("haiku".to_string()),
+            "Small context windows should be skipped"
+        );
+        assert_eq!(
+            select_name(50_000, None, RoutePreference::Latency),
+            Some("haiku".to_string())
+        );
+        assert_eq!(
+            select_name(50_000, Some(0.005), RoutePreference::Cost),
+            None
+        );
+    }
+}
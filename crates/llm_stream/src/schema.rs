@@ -0,0 +1,218 @@
+use toml_edit::{value, DocumentMut};
+
+use crate::prelude::*;
+
+/// Version of the format of the configuration file. Files without a version are read as version 1.
+pub const CONFIG_SCHEMA_VERSION: u32 = 1;
+
+/// File written in an older format, with the version of its format.
+struct Outdated {
+    path: std::path::PathBuf,
+    version: u32,
+    latest: u32,
+}
+
+/// Returns the `schema_version` of a file, or 1 for the files written before it existed. It's
+/// `None` when the version isn't a positive integer.
+fn version(document: &DocumentMut) -> Option<u32> {
+    match document.get("schema_version") {
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version > 0),
+        None => Some(1),
+    }
+}
+
+/// Returns the configuration file and the cache files written in an older format.
+fn outdated(config_file: &str, data_dir: &str) -> Result<Vec<Outdated>> {
+    let mut files = Vec::new();
+
+    if std::path::Path::new(config_file).exists() {
+        files.push((config_file.into(), CONFIG_SCHEMA_VERSION));
+    }
+
+    let cache_dir = crate::cache::cache_dir(data_dir);
+    if std::path::Path::new(&cache_dir).is_dir() {
+        for path in get_sorted_cache_files(&cache_dir)? {
+            files.push((path, crate::cache::SCHEMA_VERSION));
+        }
+    }
+
+    let mut outdated = Vec::new();
+
+    for (path, latest) in files {
+        let document = match std::fs::read_to_string(&path)
+            .map_err(Error::from)
+            .and_then(|text| Ok(text.parse::<DocumentMut>()?))
+        {
+            Ok(document) => document,
+            Err(e) => {
+                eprintln!("Skipping {}, it can't be read: {}", path.display(), e);
+                continue;
+            }
+        };
+        let Some(version) = version(&document) else {
+            eprintln!(
+                "Skipping {}, its schema_version isn't a positive integer",
+                path.display()
+            );
+            continue;
+        };
+
+        if version > latest {
+            eprintln!(
+                "Skipping {}, it was written by a newer version of llm-stream",
+                path.display()
+            );
+        } else if version < latest {
+            outdated.push(Outdated {
+                path,
+                version,
+                latest,
+            });
+        }
+    }
+
+    Ok(outdated)
+}
+
+/// Stamps the latest version on a file in place, keeping its modification time, which orders the
+/// cached conversations. The formats so far only added optional fields, so the rest of the file
+/// is kept as it is.
+fn stamp_file(file: &Outdated) -> Result<()> {
+    let modified = std::fs::metadata(&file.path)?.modified()?;
+    let mut document = std::fs::read_to_string(&file.path)?.parse::<DocumentMut>()?;
+
+    document.insert("schema_version", value(file.latest as i64));
+    std::fs::write(&file.path, document.to_string())?;
+
+    std::fs::File::options()
+        .write(true)
+        .open(&file.path)?
+        .set_modified(modified)?;
+
+    Ok(())
+}
+
+/// Stamps the latest version on the configuration and cache files written in an older format,
+/// copying them to a `backups/<date>` directory of the data directory first. With `dry_run`, only
+/// lists them.
+fn stamp(config_file: &str, data_dir: &str, dry_run: bool) -> Result<usize> {
+    let outdated = outdated(config_file, data_dir)?;
+
+    if dry_run || outdated.is_empty() {
+        for file in &outdated {
+            println!(
+                "{}: version {} to {}",
+                file.path.display(),
+                file.version,
+                file.latest
+            );
+        }
+
+        return Ok(outdated.len());
+    }
+
+    let backup_dir = std::path::Path::new(data_dir)
+        .join("backups")
+        .join(chrono::Local::now().format("%Y%m%d-%H%M%S").to_string());
+    std::fs::create_dir_all(&backup_dir)?;
+
+    for file in &outdated {
+        let name = file.path.file_name().unwrap_or_default();
+        let backup = if file.path.starts_with(crate::cache::cache_dir(data_dir)) {
+            backup_dir.join("cache").join(name)
+        } else {
+            backup_dir.join(name)
+        };
+
+        std::fs::create_dir_all(backup.parent().unwrap_or(&backup_dir))?;
+        std::fs::copy(&file.path, &backup)?;
+
+        stamp_file(file)?;
+    }
+
+    eprintln!(
+        "Stamped {} files, the old versions are in {}",
+        outdated.len(),
+        backup_dir.display()
+    );
+
+    Ok(outdated.len())
+}
+
+/// Runs the `migrate` command.
+pub fn run(dry_run: bool, args: Args) -> Result<()> {
+    let config_file = args.config_file.clone().expect("can't find config file");
+    let data_dir = args.data_dir.clone().expect("can't find data directory");
+
+    if stamp(&config_file, &data_dir, dry_run)? == 0 {
+        eprintln!("Everything is up to date.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_backs_up_and_stamps() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-schema-{}", xid::new()));
+        let config_file = dir.join("config.toml").to_string_lossy().to_string();
+        let data_dir = dir.join("data").to_string_lossy().to_string();
+        let cache_dir = crate::cache::cache_dir(&data_dir);
+        std::fs::create_dir_all(&cache_dir)?;
+
+        let config = "# Defaults\nmodel = \"gpt-4o\"\n";
+        let old = "model = \"gpt-4o\"\n\n[[conversation]]\nrole = \"user\"\ncontent = \"Hi\"\n";
+        std::fs::write(&config_file, config)?;
+        std::fs::write(format!("{}/old.toml", cache_dir), old)?;
+        std::fs::write(
+            format!("{}/new.toml", cache_dir),
+            format!("schema_version = {}\n", crate::cache::SCHEMA_VERSION),
+        )?;
+        std::fs::write(format!("{}/corrupt.toml", cache_dir), "model = \"gpt-4o")?;
+
+        let listed = stamp(&config_file, &data_dir, true);
+        let stamped = stamp(&config_file, &data_dir, false);
+        let again = stamp(&config_file, &data_dir, false);
+
+        let cached = crate::cache::read(&data_dir, "old");
+        let backups = std::fs::read_dir(format!("{}/backups", data_dir))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<std::io::Result<Vec<std::path::PathBuf>>>()?;
+        let backed_up = std::fs::read_to_string(backups[0].join("cache/old.toml"));
+        let config_backup = std::fs::read_to_string(backups[0].join("config.toml"));
+        let stamped_config = std::fs::read_to_string(&config_file);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(listed?, 2);
+        assert_eq!(stamped?, 2);
+        assert_eq!(again?, 0);
+        assert_eq!(cached?.schema_version, Some(crate::cache::SCHEMA_VERSION));
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backed_up?, old);
+        assert_eq!(config_backup?, config);
+        assert!(stamped_config?.starts_with(config));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let version_of = |text: &str| text.parse::<DocumentMut>().map(|d| version(&d));
+
+        assert_eq!(version_of("model = \"gpt-4o\"\n")?, Some(1));
+        assert_eq!(version_of("schema_version = 2\n")?, Some(2));
+        assert_eq!(version_of("schema_version = -1\n")?, None);
+        assert_eq!(version_of("schema_version = 0\n")?, None);
+        assert_eq!(version_of("schema_version = 4294967297\n")?, None);
+        assert_eq!(version_of("schema_version = \"2\"\n")?, None);
+
+        Ok(())
+    }
+}
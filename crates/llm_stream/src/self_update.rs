@@ -0,0 +1,250 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+use crate::prelude::*;
+
+/// Latest release of the repository, published by `cargo xtask github`.
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/cloudbridgeuy/llm-stream/releases/latest";
+
+/// Minisign public key the release binaries are signed with. It's set when the release binaries
+/// are built, so builds from source can't update themselves.
+const RELEASE_KEY: Option<&str> = option_env!("LLM_STREAM_RELEASE_KEY");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| {
+                Error::SelfUpdate(format!("release {} has no {} file", self.tag_name, name))
+            })
+    }
+}
+
+/// Returns the name of the release binary built for this platform, like
+/// `llm-stream-x86_64-linux`.
+fn asset_name() -> String {
+    format!(
+        "llm-stream-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// Returns the numbers of a version like `v0.3.1`, ignoring its build metadata. Pre-releases,
+/// like `v1.0.0-rc.1`, have no numbers, so they are never offered.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim_start_matches('v').split('+').next()?;
+
+    if version.contains('-') {
+        return None;
+    }
+
+    version.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// Returns true when the `latest` version is newer than the `current` one.
+fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+fn get(url: &str) -> Result<ureq::Response> {
+    ureq::get(url)
+        .set("User-Agent", "llm-stream")
+        .call()
+        .map_err(|e| Error::SelfUpdate(format!("{}: {}", url, e)))
+}
+
+/// Checks the SHA-256 of the downloaded binary against the checksum file of the release, written
+/// like the output of `sha256sum`.
+fn verify(binary: &[u8], checksum_file: &str) -> Result<()> {
+    let expected = checksum_file
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let actual = format!("{:x}", Sha256::digest(binary));
+
+    if expected != actual {
+        return Err(Error::SelfUpdate(format!(
+            "the checksum of the download is {}, expected {}",
+            actual, expected
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks the minisign `signature_file` of the downloaded binary against the release `key`, so a
+/// binary replaced on the release page along with its checksum is refused.
+fn verify_signature(binary: &[u8], signature_file: &str, key: &str) -> Result<()> {
+    let key = minisign_verify::PublicKey::from_base64(key)
+        .map_err(|e| Error::SelfUpdate(format!("invalid release key: {}", e)))?;
+    let signature = minisign_verify::Signature::decode(signature_file)
+        .map_err(|e| Error::SelfUpdate(format!("invalid signature: {}", e)))?;
+
+    key.verify(binary, &signature, false).map_err(|_| {
+        Error::SelfUpdate("the download isn't signed with the release key".to_string())
+    })
+}
+
+/// Replaces the executable at `path` with `binary`. The new binary is written next to it first,
+/// so a failed write never leaves a broken executable behind. Windows can't write over a running
+/// executable, so it's moved aside to `<name>.old` instead.
+fn replace(path: &Path, binary: &[u8]) -> Result<()> {
+    let new = path.with_extension("new");
+
+    std::fs::write(&new, binary)?;
+    std::fs::set_permissions(&new, std::fs::metadata(path)?.permissions())?;
+
+    if cfg!(windows) {
+        let old = path.with_extension("old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(path, &old)?;
+    }
+
+    if let Err(e) = std::fs::rename(&new, path) {
+        let _ = std::fs::remove_file(&new);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Runs the `self-update` command. With `check`, it only prints whether there's a newer release.
+pub fn run(check: bool, yes: bool) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = serde_json::from_str::<Release>(&get(LATEST_RELEASE_URL)?.into_string()?)?;
+    let latest = release.tag_name.trim_start_matches('v');
+
+    if release.prerelease || !is_newer(latest, current) {
+        eprintln!("llm-stream {} is up to date", current);
+        return Ok(());
+    }
+
+    if check {
+        eprintln!("llm-stream {} is available, this is {}", latest, current);
+        return Ok(());
+    }
+
+    let Some(key) = RELEASE_KEY else {
+        return Err(Error::SelfUpdate(
+            "this build has no release key to check the download with, update it the way it was \
+             installed"
+                .to_string(),
+        ));
+    };
+
+    let name = asset_name();
+    let binary = release.asset(&name)?;
+    let checksum = release.asset(&format!("{}.sha256", name))?;
+    let signature = release.asset(&format!("{}.minisig", name))?;
+
+    if !yes && !confirm(&format!("Update llm-stream {} to {}?", current, latest))? {
+        return Ok(());
+    }
+
+    eprintln!("Downloading {}", binary.browser_download_url);
+
+    let mut contents = Vec::new();
+    get(&binary.browser_download_url)?
+        .into_reader()
+        .read_to_end(&mut contents)?;
+
+    verify(
+        &contents,
+        &get(&checksum.browser_download_url)?.into_string()?,
+    )?;
+    verify_signature(
+        &contents,
+        &get(&signature.browser_download_url)?.into_string()?,
+        key,
+    )?;
+    replace(&std::env::current_exe()?, &contents)?;
+
+    eprintln!("Updated llm-stream to {}", latest);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("v0.4.0", "0.3.1"));
+        assert!(is_newer("0.3.10", "0.3.9"));
+        assert!(is_newer("1.0.0+build.2", "0.9.0"));
+        assert!(!is_newer("1.0.0-rc.1", "0.9.0"));
+        assert!(!is_newer("0.3.1", "0.3.1"));
+        assert!(!is_newer("0.2.9", "0.3.0"));
+        assert!(!is_newer("nightly", "0.3.0"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let checksum = format!("{:x}  llm-stream-x86_64-linux\n", Sha256::digest(b"binary"));
+
+        assert!(verify(b"binary", &checksum).is_ok());
+        assert!(verify(b"tampered", &checksum).is_err());
+        assert!(verify(b"binary", "").is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let key = "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4";
+        let other_key = "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG5";
+        let signature = "untrusted comment: signature from minisign secret key
+RUQBAgMEBQYHCJu7hk58avTO4USkM5NGnVwvIx2WkgKCCiVXIemMFi/dd8xCHadcpqgwIhmMVcNeJuWy//pg+mjCYAUHan6/pAA=
+trusted comment: timestamp:1700000000\tfile:llm-stream-x86_64-linux\thashed
+3XnZuvkth7aDXDT6DmcWGL/PJJMfycCIB98LPKqHpDsoDtGlYmyL8tX7GDnzDoC9Djp6Bq0Fkx1oXHf6s91TCQ==
+";
+
+        assert!(verify_signature(b"binary", signature, key).is_ok());
+        assert!(verify_signature(b"tampered", signature, key).is_err());
+        assert!(verify_signature(b"binary", signature, other_key).is_err());
+        assert!(verify_signature(b"binary", "", key).is_err());
+    }
+
+    #[test]
+    fn test_replace() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-update-{}", xid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("llm-stream");
+        std::fs::write(&path, "old")?;
+
+        let replaced = replace(&path, b"new");
+        let contents = std::fs::read_to_string(&path)?;
+        let leftover = dir.join("llm-stream.new").exists();
+
+        std::fs::remove_dir_all(&dir)?;
+
+        replaced?;
+        assert_eq!(contents, "new");
+        assert!(!leftover);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,709 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::prelude::*;
+
+/// Largest request body accepted by the proxy.
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Longest request line or header accepted by the proxy.
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+/// Most headers accepted in a request.
+const MAX_HEADERS: usize = 64;
+
+/// Most connections handled at the same time. Further connections are answered with a 503.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Time to wait for the client to send the request, and to read the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Value,
+}
+
+/// Body of an OpenAI chat completions request. Unknown fields are ignored.
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    max_completion_tokens: Option<u32>,
+}
+
+/// Returns the text of a message content, which can be a string or a list of parts.
+fn text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<Vec<&str>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn role(role: &str) -> ConversationRole {
+    match role {
+        "system" | "developer" => ConversationRole::System,
+        "assistant" => ConversationRole::Assistant,
+        _ => ConversationRole::User,
+    }
+}
+
+/// Builds the arguments of a chat request on top of the ones given to `serve`. The model can be
+/// the name of a preset, `<api>/<model>`, or a model of the default api.
+fn request_args(request: &ChatRequest, base: &Args, config: &Config) -> Result<Args> {
+    let mut args = Args {
+        prompt: None,
+        stdin: None,
+        template: None,
+        conversation: Conversation::new(),
+        no_cache: true,
+        quiet: true,
+        ..base.clone()
+    };
+
+    match &request.model {
+        Some(model) if config.presets.iter().flatten().any(|p| &p.name == model) => {
+            args.preset = Some(model.clone());
+        }
+        Some(model) => match model
+            .split_once('/')
+            .and_then(|(api, model)| Some((api.parse::<Api>().ok()?, model)))
+        {
+            Some((api, model)) => {
+                args.api = Some(api);
+                args.model = Some(model.to_string());
+            }
+            None => args.model = Some(model.clone()),
+        },
+        None => {}
+    }
+
+    args.temperature = request.temperature.or(args.temperature);
+    args.top_p = request.top_p.or(args.top_p);
+    args.max_tokens = request
+        .max_completion_tokens
+        .or(request.max_tokens)
+        .or(args.max_tokens);
+
+    let mut conversation = request
+        .messages
+        .iter()
+        .map(|message| ConversationMessage {
+            role: role(&message.role),
+            content: text(&message.content),
+            ..Default::default()
+        })
+        .collect::<Conversation>();
+
+    match conversation.pop() {
+        Some(message) if message.role == ConversationRole::User => {
+            args.prompt = Some(message.content);
+        }
+        _ => {
+            return Err(Error::InvalidConversation(
+                "the last message must be a user message".to_string(),
+            ))
+        }
+    }
+
+    args.conversation = conversation;
+
+    let mut args = crate::redact::apply(args, config)?;
+
+    merge_preset(&mut args, config);
+    crate::budget::check(&args, config)?;
+
+    let args = crate::router::route(args, config)?;
+    merge_args_and_config(args, config.clone())
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the header `name`, ignoring its case.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the length of the body, or an error if it isn't a valid number.
+    fn content_length(&self) -> std::result::Result<usize, std::num::ParseIntError> {
+        self.header("content-length").map_or(Ok(0), str::parse)
+    }
+}
+
+/// Reads a line of the request head, refusing lines longer than `MAX_HEADER_SIZE`.
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .by_ref()
+        .take(MAX_HEADER_SIZE as u64)
+        .read_line(&mut line)?;
+
+    if !line.ends_with('\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the request head is too long or incomplete",
+        )
+        .into());
+    }
+
+    Ok(line.trim_end().to_string())
+}
+
+/// Reads the request line and the headers. The body is read once the request was accepted.
+fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest> {
+    let line = read_line(reader)?;
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+
+    loop {
+        let header = read_line(reader)?;
+
+        if header.is_empty() {
+            break;
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the request has too many headers",
+            )
+            .into());
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+    })
+}
+
+/// Returns the host of a `Host` header, without its port.
+fn host_name(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// Compares the tokens in a time that doesn't depend on where they differ.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Returns the status and the message of the response to a request that isn't allowed. Requests
+/// sent by browsers, which carry an `Origin`, and requests for other hosts than the one the proxy
+/// listens on are refused, so web pages can't reach the proxy, and every request needs the token.
+fn reject(request: &HttpRequest, host: &str, token: &str) -> Option<(&'static str, &'static str)> {
+    if request.header("origin").is_some() {
+        return Some(("403 Forbidden", "cross origin requests aren't allowed"));
+    }
+
+    let allowed = ["localhost", "127.0.0.1", "::1", host_name(host)];
+
+    if !request.header("host").map(host_name).is_some_and(|name| {
+        allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }) {
+        return Some(("403 Forbidden", "the host isn't allowed"));
+    }
+
+    if !request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| same_token(value.trim(), token))
+    {
+        return Some(("401 Unauthorized", "invalid or missing bearer token"));
+    }
+
+    None
+}
+
+/// Returns a random token for the requests to the proxy, from the random source of the operating
+/// system, since it's all that guards the api keys behind it.
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(std::io::Error::other)?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &Value) -> Result<()> {
+    let body = body.to_string();
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+fn error_body(message: &str) -> Value {
+    serde_json::json!({ "error": { "message": message, "type": "invalid_request_error" } })
+}
+
+fn event(stream: &mut TcpStream, data: &str) {
+    if let Err(e) = write!(stream, "data: {}\n\n", data).and_then(|_| stream.flush()) {
+        log::warn!("unable to write to the client: {}", e);
+    }
+}
+
+/// Returns the OpenAI finish reason of an answer that ended with `finish_reason`.
+fn openai_finish_reason(finish_reason: Option<&str>) -> &'static str {
+    if crate::report::is_truncated(finish_reason) {
+        "length"
+    } else if crate::report::is_filtered(finish_reason) {
+        "content_filter"
+    } else if matches!(finish_reason, Some("tool_calls" | "tool_use")) {
+        "tool_calls"
+    } else {
+        "stop"
+    }
+}
+
+/// Answers a chat completions request, streaming it as server-sent events when asked to.
+fn chat(stream: &mut TcpStream, body: &[u8], server: &Server) -> Result<()> {
+    let request = match serde_json::from_slice::<ChatRequest>(body) {
+        Ok(request) => request,
+        Err(e) => return respond(stream, "400 Bad Request", &error_body(&e.to_string())),
+    };
+
+    let args = match request_args(&request, &server.args, &server.config) {
+        Ok(args) => args,
+        Err(e) => return respond(stream, "400 Bad Request", &error_body(&e.to_string())),
+    };
+
+    let id = format!("chatcmpl-{}", xid::new());
+    let created = chrono::Utc::now().timestamp();
+    let model = request
+        .model
+        .clone()
+        .or(args.model.clone())
+        .unwrap_or_default();
+
+    let metadata = SharedMetadata::default();
+    let finish_reason = || {
+        let metadata = metadata.lock().map(|m| m.clone()).unwrap_or_default();
+        openai_finish_reason(metadata.finish_reason.as_deref())
+    };
+
+    if !request.stream {
+        return match server
+            .runtime
+            .block_on(complete_with_metadata(args, metadata.clone(), |_| {}))
+        {
+            Ok(content) => respond(
+                stream,
+                "200 OK",
+                &serde_json::json!({
+                    "id": id,
+                    "object": "chat.completion",
+                    "created": created,
+                    "model": model,
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": content },
+                        "finish_reason": finish_reason(),
+                    }],
+                }),
+            ),
+            Err(e) => respond(stream, "502 Bad Gateway", &error_body(&e.to_string())),
+        };
+    }
+
+    let chunk = |delta: Value, finish_reason: Option<&str>| {
+        serde_json::json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+        .to_string()
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+
+    event(
+        stream,
+        &chunk(
+            serde_json::json!({ "role": "assistant", "content": "" }),
+            None,
+        ),
+    );
+
+    let result = server
+        .runtime
+        .block_on(complete_with_metadata(args, metadata.clone(), |delta| {
+            event(
+                stream,
+                &chunk(serde_json::json!({ "content": delta }), None),
+            );
+        }));
+
+    match result {
+        Ok(_) => event(stream, &chunk(serde_json::json!({}), Some(finish_reason()))),
+        Err(e) => event(stream, &error_body(&e.to_string()).to_string()),
+    }
+
+    event(stream, "[DONE]");
+
+    Ok(())
+}
+
+/// Lists the presets as models.
+fn models(stream: &mut TcpStream, config: &Config) -> Result<()> {
+    let data = config
+        .presets
+        .iter()
+        .flatten()
+        .map(|preset| {
+            serde_json::json!({ "id": preset.name, "object": "model", "owned_by": preset.api.to_string() })
+        })
+        .collect::<Vec<Value>>();
+
+    respond(
+        stream,
+        "200 OK",
+        &serde_json::json!({ "object": "list", "data": data }),
+    )
+}
+
+/// Settings shared by the connections.
+struct Server {
+    host: String,
+    token: String,
+    args: Args,
+    config: Config,
+    /// Runtime of the server, which outlives the requests, so the connections to the apis they
+    /// open are reused by the next ones.
+    runtime: tokio::runtime::Handle,
+}
+
+fn handle(mut stream: TcpStream, server: &Server) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_request(&mut reader)?;
+
+    log::info!("{} {}", request.method, request.path);
+
+    if let Some((status, message)) = reject(&request, &server.host, &server.token) {
+        return respond(&mut stream, status, &error_body(message));
+    }
+
+    let length = match request.content_length() {
+        Ok(length) if length <= MAX_BODY_SIZE => length,
+        Ok(_) => {
+            return respond(
+                &mut stream,
+                "413 Payload Too Large",
+                &error_body("the request body is too large"),
+            )
+        }
+        Err(_) => {
+            return respond(
+                &mut stream,
+                "400 Bad Request",
+                &error_body("invalid Content-Length"),
+            )
+        }
+    };
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/v1/chat/completions") => chat(&mut stream, &body, server),
+        ("GET", "/v1/models") => models(&mut stream, &server.config),
+        _ => respond(&mut stream, "404 Not Found", &error_body("not found")),
+    }
+}
+
+/// Counts a connection until it's dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves an OpenAI compatible `/v1/chat/completions` endpoint that sends the requests to the
+/// api of the requested model, using the configuration, presets, and keys of `llm-stream`. Every
+/// request needs `token` as a bearer token, a random one is printed if it isn't set.
+pub fn run(host: &str, port: u16, token: Option<String>, args: Args) -> Result<()> {
+    let (args, config) = build_config(args)?;
+
+    let listener = TcpListener::bind((host, port))?;
+
+    eprintln!("Listening on http://{}:{}/v1", host, port);
+
+    let token = match token.filter(|token| !token.is_empty()) {
+        Some(token) => token,
+        None => {
+            let token = new_token()?;
+            eprintln!("Bearer token: {}", token);
+            token
+        }
+    };
+
+    let server = Arc::new(Server {
+        host: host.to_string(),
+        token,
+        args,
+        config,
+        runtime: tokio::runtime::Handle::current(),
+    });
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    // Accepting blocks, so the worker of the runtime is handed over to the other tasks first.
+    tokio::task::block_in_place(|| accept(&listener, &server, &connections));
+
+    Ok(())
+}
+
+/// Accepts the connections to the proxy, answering each one on its own thread.
+fn accept(listener: &TcpListener, server: &Arc<Server>, connections: &Arc<AtomicUsize>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("unable to accept the connection: {}", e);
+                continue;
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("too many connections, refusing one");
+
+            let refused = stream
+                .set_write_timeout(Some(TIMEOUT))
+                .map_err(Error::from)
+                .and_then(|_| {
+                    respond(
+                        &mut stream,
+                        "503 Service Unavailable",
+                        &error_body("too many connections"),
+                    )
+                });
+
+            if let Err(e) = refused {
+                log::warn!("unable to refuse the connection: {:?}", e);
+            }
+            continue;
+        }
+
+        let connection = Connection(Arc::clone(connections));
+        let server = Arc::clone(server);
+
+        std::thread::spawn(move || {
+            let _connection = connection;
+
+            if let Err(e) = handle(stream, &server) {
+                log::error!("unable to handle the request: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Preset;
+
+    fn request(model: &str) -> std::result::Result<ChatRequest, serde_json::Error> {
+        serde_json::from_value(serde_json::json!({
+            "model": model,
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": [{ "type": "text", "text": "Hi" }] },
+            ],
+        }))
+    }
+
+    #[test]
+    fn test_new_token() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let token = new_token()?;
+
+        assert_eq!(token.len(), 64);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, new_token()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_openai_finish_reason() {
+        assert_eq!(openai_finish_reason(Some("end_turn")), "stop");
+        assert_eq!(openai_finish_reason(Some("MAX_TOKENS")), "length");
+        assert_eq!(openai_finish_reason(Some("SAFETY")), "content_filter");
+        assert_eq!(openai_finish_reason(None), "stop");
+    }
+
+    fn head(headers: &[&str]) -> Result<HttpRequest> {
+        let head = format!(
+            "POST /v1/chat/completions HTTP/1.1\r\n{}\r\n\r\n",
+            headers.join("\r\n")
+        );
+        read_request(&mut head.as_bytes())
+    }
+
+    #[test]
+    fn test_reject() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let authorized = "Authorization: Bearer secret";
+
+        assert_eq!(
+            reject(
+                &head(&["Host: localhost:8080", authorized])?,
+                "127.0.0.1",
+                "secret"
+            ),
+            None
+        );
+        assert_eq!(
+            reject(
+                &head(&["Host: [::1]:8080", authorized])?,
+                "127.0.0.1",
+                "secret"
+            ),
+            None
+        );
+        assert_eq!(
+            reject(
+                &head(&["Host: localhost:8080", "Authorization: Bearer other"])?,
+                "127.0.0.1",
+                "secret"
+            ),
+            Some(("401 Unauthorized", "invalid or missing bearer token"))
+        );
+        assert_eq!(
+            reject(&head(&["Host: localhost:8080"])?, "127.0.0.1", "secret"),
+            Some(("401 Unauthorized", "invalid or missing bearer token"))
+        );
+        assert_eq!(
+            reject(
+                &head(&["Host: attacker.example", authorized])?,
+                "127.0.0.1",
+                "secret"
+            ),
+            Some(("403 Forbidden", "the host isn't allowed"))
+        );
+        assert_eq!(
+            reject(
+                &head(&[
+                    "Host: localhost:8080",
+                    "Origin: https://attacker.example",
+                    authorized
+                ])?,
+                "127.0.0.1",
+                "secret"
+            ),
+            Some(("403 Forbidden", "cross origin requests aren't allowed"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_request_limits() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let request = head(&["Host: localhost", "Content-Length: 99999999999"])?;
+        assert!(request.content_length()? > MAX_BODY_SIZE);
+
+        let long = format!("X-Long: {}", "a".repeat(MAX_HEADER_SIZE));
+        assert!(head(&[&long]).is_err());
+
+        let many = vec!["X-Header: 1"; MAX_HEADERS + 1];
+        assert!(head(&many).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_args() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::default();
+        config.presets = Some(vec![Preset {
+            name: "sonnet".to_string(),
+            api: Api::Anthropic,
+            model: Some("claude-3-5-sonnet-latest".to_string()),
+            ..Default::default()
+        }]);
+
+        let args = request_args(&request("sonnet")?, &Args::default(), &config)?;
+
+        assert_eq!(args.api, Some(Api::Anthropic));
+        assert_eq!(args.model, Some("claude-3-5-sonnet-latest".to_string()));
+        assert_eq!(
+            args.conversation,
+            vec![
+                ConversationMessage {
+                    role: ConversationRole::System,
+                    content: "Be brief.".to_string(),
+                    ..Default::default()
+                },
+                ConversationMessage {
+                    role: ConversationRole::User,
+                    content: "Hi".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let args = request_args(
+            &request("google/gemini-1.5-pro")?,
+            &Args::default(),
+            &config,
+        )?;
+
+        assert_eq!(args.api, Some(Api::Google));
+        assert_eq!(args.model, Some("gemini-1.5-pro".to_string()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Read;
+
+use crate::prelude::*;
+
+/// Request given with `--spec`, so other programs can send a whole request without building the
+/// command line. Options given on the command line take precedence over it.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api: Option<Api>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Files and other context added before the prompt, apart from `stdin`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin_placement: Option<StdinPlacement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conversation: Conversation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vars: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+}
+
+/// Reads the spec of `path`, or of stdin if it's `-`. JSON is valid YAML, so both are parsed as
+/// YAML.
+pub fn read(path: &str) -> Result<Spec> {
+    let contents = if path == "-" {
+        let mut contents = String::new();
+        std::io::stdin().lock().read_to_string(&mut contents)?;
+        contents
+    } else {
+        std::fs::read_to_string(path).map_err(|e| Error::ReadFile(format!("{}: {}", path, e)))?
+    };
+
+    Ok(serde_yaml::from_str(&contents)?)
+}
+
+impl From<&Args> for Spec {
+    fn from(args: &Args) -> Self {
+        Self {
+            api: args.api,
+            model: args.model.clone(),
+            preset: args.preset.clone(),
+            alias: args.alias.clone(),
+            system: (!args.system.is_empty()).then(|| args.system.join("\n\n")),
+            prompt: args.prompt.clone(),
+            stdin: args.stdin.clone(),
+            attachments: args.attachments.clone(),
+            stdin_placement: args.stdin_placement,
+            suffix: args.suffix.clone(),
+            conversation: args.conversation.clone(),
+            template: args.template.clone(),
+            vars: args.vars.clone(),
+            max_tokens: args.max_tokens,
+            min_tokens: args.min_tokens,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            top_k: args.top_k,
+        }
+    }
+}
+
+impl Spec {
+    /// Fills the arguments that weren't set on the command line with the values of the spec. Its
+    /// variables are merged under the ones of `--vars`.
+    pub fn apply(self, args: &mut Args) {
+        args.api = args.api.or(self.api);
+        args.model = args.model.take().or(self.model);
+        args.preset = args.preset.take().or(self.preset);
+        args.alias = args.alias.take().or(self.alias);
+        args.system.splice(0..0, self.system);
+        args.prompt = args.prompt.take().or(self.prompt);
+        args.suffix = args.suffix.take().or(self.suffix);
+        args.attachments = args.attachments.take().or(self.attachments);
+        args.stdin_placement = args.stdin_placement.or(self.stdin_placement);
+        args.template = args.template.take().or(self.template);
+        args.max_tokens = args.max_tokens.or(self.max_tokens);
+        args.min_tokens = args.min_tokens.or(self.min_tokens);
+        args.temperature = args.temperature.or(self.temperature);
+        args.top_p = args.top_p.or(self.top_p);
+        args.top_k = args.top_k.or(self.top_k);
+
+        if args.stdin.as_deref().map_or(true, str::is_empty) {
+            args.stdin = self.stdin;
+        }
+
+        if args.conversation.is_empty() {
+            args.conversation = self.conversation;
+        }
+
+        if let Some(mut vars) = self.vars.filter(|v| !v.is_null()) {
+            if let Some(overrides) = args.vars.take().filter(|v| !v.is_null()) {
+                merge(&mut vars, overrides);
+            }
+            args.vars = Some(vars);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let spec: Spec = serde_yaml::from_str(
+            r#"{
+                "api": "openai",
+                "model": "gpt-4o",
+                "template": "translate",
+                "vars": { "language": "French", "tone": "formal" },
+                "conversation": [{ "role": "user", "content": "Hi" }]
+            }"#,
+        )?;
+
+        let mut args = Args {
+            model: Some("gpt-4o-mini".to_string()),
+            vars: Some(serde_json::json!({ "tone": "casual" })),
+            ..Default::default()
+        };
+
+        spec.apply(&mut args);
+
+        assert_eq!(args.api, Some(Api::OpenAi));
+        assert_eq!(args.model, Some("gpt-4o-mini".to_string()));
+        assert_eq!(args.template, Some("translate".to_string()));
+        assert_eq!(
+            args.vars,
+            Some(serde_json::json!({ "language": "French", "tone": "casual" }))
+        );
+        assert_eq!(args.conversation.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        assert!(serde_yaml::from_str::<Spec>("modle: gpt-4o").is_err());
+    }
+}
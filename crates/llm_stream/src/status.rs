@@ -0,0 +1,166 @@
+use crossterm::{cursor, queue, style, terminal};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::tokens::Tokenizer;
+
+/// Frames drawn at the start of the status line, one after the other.
+const FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Time between two redraws of the status line.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Held while the status line, or a notice over it, is written, so they don't mix.
+static LINE: Mutex<()> = Mutex::new(());
+
+/// Line on stderr that shows the progress of a response until its text is printed: the elapsed
+/// time, the tokens received so far, and the model. It's cleared when dropped, so an error never
+/// leaves it behind.
+pub struct Status {
+    api: Option<crate::args::Api>,
+    model: Option<String>,
+    /// Loaded with the first text, so it doesn't hold up the request.
+    tokenizer: OnceLock<Tokenizer>,
+    tokens: Arc<AtomicUsize>,
+    running: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+/// Returns the text of the status line.
+fn line(frame: usize, elapsed: Duration, tokens: usize, model: &str) -> String {
+    let mut parts = vec![format!("{:.1}s", elapsed.as_secs_f64())];
+
+    if tokens > 0 {
+        parts.push(format!("~{} tokens", tokens));
+    }
+    if !model.is_empty() {
+        parts.push(model.to_string());
+    }
+
+    format!("{} {}", FRAMES[frame % FRAMES.len()], parts.join(" · "))
+}
+
+/// Draws `text` over the current line of stderr, or clears it when it's `None`. The cursor is left
+/// visible, so it isn't lost if the process is killed while the line is drawn.
+fn draw(text: Option<&str>) -> std::io::Result<()> {
+    let _line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stderr = std::io::stderr();
+
+    queue!(
+        stderr,
+        cursor::MoveToColumn(0),
+        terminal::Clear(terminal::ClearType::CurrentLine)
+    )?;
+
+    if let Some(text) = text {
+        queue!(stderr, style::Print(text))?;
+    }
+
+    stderr.flush()
+}
+
+/// Prints `text` on stderr over the status line, if any, which is drawn again under it.
+pub fn notice(text: &str) {
+    let _line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stderr = std::io::stderr();
+
+    if stderr.is_terminal() {
+        let _ = queue!(
+            stderr,
+            cursor::MoveToColumn(0),
+            terminal::Clear(terminal::ClearType::CurrentLine)
+        );
+    }
+
+    let _ = writeln!(stderr, "{}", text).and_then(|_| stderr.flush());
+}
+
+impl Status {
+    /// Starts drawing the status line of a response of `model`.
+    pub fn start(api: Option<crate::args::Api>, model: Option<&str>) -> Self {
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        let name = model.unwrap_or_default().to_string();
+
+        let thread = {
+            let tokens = tokens.clone();
+            let running = running.clone();
+            let start = Instant::now();
+
+            std::thread::spawn(move || {
+                let mut frame = 0;
+
+                while running.load(Ordering::Relaxed) {
+                    let text = line(
+                        frame,
+                        start.elapsed(),
+                        tokens.load(Ordering::Relaxed),
+                        &name,
+                    );
+
+                    if let Err(e) = draw(Some(&text)) {
+                        log::warn!("unable to draw the status line: {:?}", e);
+                        return;
+                    }
+
+                    frame += 1;
+                    std::thread::park_timeout(INTERVAL);
+                }
+            })
+        };
+
+        Self {
+            api,
+            model: model.map(String::from),
+            tokenizer: OnceLock::new(),
+            tokens,
+            running,
+            thread: Some(thread),
+        }
+    }
+
+    /// Adds the tokens of `text` to the ones received so far.
+    pub fn received(&self, text: &str) {
+        let tokenizer = self
+            .tokenizer
+            .get_or_init(|| Tokenizer::new(self.api, self.model.as_deref()));
+
+        self.tokens
+            .fetch_add(tokenizer.count(text), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Status {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+
+        if let Err(e) = draw(None) {
+            log::warn!("unable to clear the status line: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!(
+            line(0, Duration::from_millis(1340), 0, "gpt-4o"),
+            "⠋ 1.3s · gpt-4o"
+        );
+        assert_eq!(
+            line(11, Duration::from_secs(3), 42, "gpt-4o"),
+            "⠙ 3.0s · ~42 tokens · gpt-4o"
+        );
+        assert_eq!(line(0, Duration::ZERO, 0, ""), "⠋ 0.0s");
+    }
+}
@@ -0,0 +1,138 @@
+use opentelemetry::trace::{Span, SpanKind, Tracer};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+
+use crate::ledger::Entry;
+use crate::prelude::*;
+
+/// Name of the service, tracer, and meter.
+const NAME: &str = "llm-stream";
+
+/// Exporters of the spans and metrics, flushed when dropped.
+pub struct Telemetry {
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            log::warn!("unable to export the spans: {:?}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            log::warn!("unable to export the metrics: {:?}", e);
+        }
+    }
+}
+
+/// Starts exporting the spans and metrics of the requests with OTLP, when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The exporters read the rest of the standard `OTEL_*`
+/// variables, like `OTEL_EXPORTER_OTLP_HEADERS`.
+pub fn init() -> Result<Option<Telemetry>> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+
+    let resource = Resource::new([KeyValue::new("service.name", NAME)]);
+
+    let tracer_provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(
+            opentelemetry_sdk::trace::Config::default().with_resource(resource.clone()),
+        )
+        .install_batch(runtime::Tokio)
+        .map_err(|e| Error::Telemetry(e.to_string()))?;
+
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_resource(resource)
+        .build()
+        .map_err(|e| Error::Telemetry(e.to_string()))?;
+
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+
+    Ok(Some(Telemetry {
+        tracer_provider,
+        meter_provider,
+    }))
+}
+
+/// Returns the attributes shared by the span and the metrics of a request.
+fn attributes(entry: &Entry) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(
+            "llm.api",
+            entry.api.map(|api| api.to_string()).unwrap_or_default(),
+        ),
+        KeyValue::new("llm.model", entry.model.clone().unwrap_or_default()),
+    ];
+
+    if let Some(tag) = &entry.tag {
+        attributes.push(KeyValue::new("llm.tag", tag.clone()));
+    }
+
+    attributes
+}
+
+/// Exports a request recorded in the usage ledger: a span from when it was sent until its
+/// response ended, and the requests, tokens, cost, and duration metrics.
+pub fn record(entry: &Entry) {
+    let attributes = attributes(entry);
+    let elapsed = std::time::Duration::from_millis(entry.elapsed_ms.unwrap_or_default());
+    let end = std::time::SystemTime::from(entry.date);
+    let start = end.checked_sub(elapsed).unwrap_or(end);
+
+    let tracer = global::tracer(NAME);
+    let mut span_attributes = attributes.clone();
+    span_attributes.extend([
+        KeyValue::new("llm.usage.input_tokens", entry.input_tokens as i64),
+        KeyValue::new("llm.usage.output_tokens", entry.output_tokens as i64),
+        KeyValue::new("llm.usage.estimated", entry.estimated),
+    ]);
+
+    let mut span = tracer
+        .span_builder("llm.request")
+        .with_kind(SpanKind::Client)
+        .with_start_time(start)
+        .with_attributes(span_attributes)
+        .start(&tracer);
+    span.end_with_timestamp(end);
+
+    let meter = global::meter(NAME);
+    let with_type = |kind: &'static str| {
+        let mut attributes = attributes.clone();
+        attributes.push(KeyValue::new("llm.token.type", kind));
+        attributes
+    };
+
+    meter
+        .u64_counter("llm_stream.requests")
+        .init()
+        .add(1, &attributes);
+
+    let tokens = meter
+        .u64_counter("llm_stream.tokens")
+        .with_unit("{token}")
+        .init();
+    tokens.add(entry.input_tokens, &with_type("input"));
+    tokens.add(entry.output_tokens, &with_type("output"));
+
+    meter
+        .f64_histogram("llm_stream.duration")
+        .with_unit("ms")
+        .init()
+        .record(elapsed.as_secs_f64() * 1000.0, &attributes);
+
+    if let Some(cost) = entry.cost {
+        meter
+            .f64_counter("llm_stream.cost")
+            .with_unit("USD")
+            .init()
+            .add(cost, &attributes);
+    }
+}
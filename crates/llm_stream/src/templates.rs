@@ -0,0 +1,706 @@
+use cli_table::{format::Justify, Color, Table, WithTitle};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::io::{IsTerminal, Write};
+use tera::ast::{Expr, ExprVal, Node};
+
+use crate::args::{NewTemplate, TemplatesCommand};
+use crate::config::Template;
+use crate::prelude::*;
+
+/// Variables that are always available inside the templates.
+const BUILTIN_VARIABLES: &[&str] = &["prompt", "stdin", "suffix", "language"];
+
+/// Returns the directory where the template files are stored.
+pub fn templates_dir(config_dir: &str) -> String {
+    format!("{}/templates", config_dir)
+}
+
+/// Parses a Markdown template, whose frontmatter, between `---` lines for YAML or `+++` lines for
+/// TOML, holds the template fields, and whose body is the prompt template. The name defaults to
+/// `name`, usually the file name.
+pub fn from_markdown(name: &str, contents: &str) -> Result<Template> {
+    let mut lines = contents.trim_start_matches('\u{feff}').lines();
+    let delimiter = lines.next().map(str::trim_end).unwrap_or_default();
+
+    if delimiter != "---" && delimiter != "+++" {
+        return Err(Error::InvalidTemplate(format!(
+            "{name}: missing `---` or `+++` frontmatter"
+        )));
+    }
+
+    let frontmatter = lines
+        .by_ref()
+        .take_while(|line| line.trim_end() != delimiter)
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let body = lines.collect::<Vec<&str>>().join("\n");
+
+    let mut value: Value = match delimiter {
+        "+++" => toml::from_str(&frontmatter)?,
+        _ if frontmatter.trim().is_empty() => Value::Object(Default::default()),
+        _ => serde_yaml::from_str(&frontmatter)?,
+    };
+
+    let Some(fields) = value.as_object_mut() else {
+        return Err(Error::InvalidTemplate(format!(
+            "{name}: the frontmatter isn't a table"
+        )));
+    };
+
+    fields
+        .entry("name")
+        .or_insert(Value::String(name.to_string()));
+
+    if !body.trim().is_empty() {
+        fields.insert(
+            "template".to_string(),
+            Value::String(body.trim().to_string()),
+        );
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Parses the contents of a `.toml` or `.md` template file.
+pub fn parse(path: &std::path::Path, contents: &str) -> Result<Template> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("md") => from_markdown(
+            &path.file_stem().unwrap_or_default().to_string_lossy(),
+            contents,
+        ),
+        _ => Ok(toml::from_str(contents)?),
+    }
+}
+
+/// Reads a template file, returning `None` for files that aren't `.toml` or `.md`.
+pub fn from_file(path: &std::path::Path) -> Result<Option<Template>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") | Some("md") => Ok(Some(parse(path, &std::fs::read_to_string(path)?)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Variables used by a template.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Variables {
+    /// Variables that have to be given with `--vars`.
+    pub required: BTreeSet<String>,
+    /// Variables with a default value, or only used in conditions or with the `default` filter.
+    pub optional: BTreeSet<String>,
+}
+
+/// Collects the variables referenced in the AST of a template.
+#[derive(Default)]
+struct Collector {
+    variables: Variables,
+    locals: BTreeSet<String>,
+}
+
+impl Collector {
+    fn add(&mut self, ident: &str, required: bool) {
+        // Only the root of `user.name` or `items[0]` comes from the context.
+        let name = ident
+            .split(['.', '['])
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        if name == "loop" || name.starts_with("__tera") || self.locals.contains(&name) {
+            return;
+        }
+
+        if required {
+            self.variables.required.insert(name);
+        } else {
+            self.variables.optional.insert(name);
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::VariableBlock(_, expr) => self.expr(expr, true),
+            Node::Set(_, set) => {
+                self.expr(&set.value, true);
+                self.locals.insert(set.key.clone());
+            }
+            Node::FilterSection(_, section, _) => {
+                for expr in section.filter.args.values() {
+                    self.expr(expr, true);
+                }
+                self.nodes(&section.body);
+            }
+            Node::Block(_, block, _) => self.nodes(&block.body),
+            Node::Forloop(_, forloop, _) => {
+                self.expr(&forloop.container, true);
+                self.locals.extend(forloop.key.clone());
+                self.locals.insert(forloop.value.clone());
+                self.nodes(&forloop.body);
+                self.nodes(forloop.empty_body.as_deref().unwrap_or_default());
+            }
+            Node::If(condition, _) => {
+                // Undefined variables are falsy in conditions.
+                for (_, expr, body) in &condition.conditions {
+                    self.expr(expr, false);
+                    self.nodes(body);
+                }
+
+                if let Some((_, body)) = &condition.otherwise {
+                    self.nodes(body);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, required: bool) {
+        let required = required && !expr.filters.iter().any(|f| f.name == "default");
+
+        self.value(&expr.val, required);
+
+        for filter in &expr.filters {
+            for arg in filter.args.values() {
+                self.expr(arg, required);
+            }
+        }
+    }
+
+    fn value(&mut self, value: &ExprVal, required: bool) {
+        match value {
+            ExprVal::Ident(ident) => self.add(ident, required),
+            ExprVal::Math(math) => {
+                self.expr(&math.lhs, required);
+                self.expr(&math.rhs, required);
+            }
+            ExprVal::Logic(logic) => {
+                self.expr(&logic.lhs, required);
+                self.expr(&logic.rhs, required);
+            }
+            ExprVal::Test(test) => {
+                self.add(&test.ident, false);
+
+                for arg in &test.args {
+                    self.expr(arg, required);
+                }
+            }
+            ExprVal::FunctionCall(call) => {
+                for arg in call.args.values() {
+                    self.expr(arg, required);
+                }
+            }
+            ExprVal::MacroCall(call) => {
+                for arg in call.args.values() {
+                    self.expr(arg, required);
+                }
+            }
+            ExprVal::Array(values) => {
+                for value in values {
+                    self.expr(value, required);
+                }
+            }
+            ExprVal::StringConcat(concat) => {
+                for value in &concat.values {
+                    self.value(value, required);
+                }
+            }
+            ExprVal::In(expr) => {
+                self.expr(&expr.lhs, required);
+                self.expr(&expr.rhs, required);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the variables used by the system, prompt, and conversation of the template, leaving
+/// out the built-in ones. Variables with a value in `default_vars` are optional.
+pub fn variables(template: &Template) -> Result<Variables> {
+    let sources = template
+        .system
+        .iter()
+        .chain(template.template.iter())
+        .chain(
+            template
+                .conversation
+                .iter()
+                .flatten()
+                .map(|message| &message.content),
+        )
+        .chain(
+            template
+                .steps
+                .iter()
+                .flatten()
+                .flat_map(|step| std::iter::once(&step.template).chain(step.system.iter())),
+        );
+
+    let mut required = BTreeSet::new();
+    let mut optional = BTreeSet::new();
+
+    for source in sources {
+        let ast = tera::Template::new(&template.name, None, source)?.ast;
+        let mut collector = Collector::default();
+
+        collector.nodes(&ast);
+
+        required.extend(collector.variables.required);
+        optional.extend(collector.variables.optional);
+    }
+
+    let defaults = template
+        .default_vars
+        .as_ref()
+        .and_then(|vars| vars.as_object())
+        .map(|vars| vars.keys().cloned().collect::<BTreeSet<String>>())
+        .unwrap_or_default();
+
+    let builtin = |name: &String| {
+        BUILTIN_VARIABLES.contains(&name.as_str())
+            || (template.steps.is_some() && crate::pipeline::VARIABLES.contains(&name.as_str()))
+    };
+
+    required.retain(|name| !builtin(name) && !defaults.contains(name));
+    optional.retain(|name| !builtin(name) && !required.contains(name));
+    optional.extend(defaults);
+
+    Ok(Variables { required, optional })
+}
+
+/// Returns the required variables of the template that aren't in `vars`.
+fn missing(template: &Template, vars: Option<&Value>) -> Result<Vec<String>> {
+    Ok(variables(template)?
+        .required
+        .into_iter()
+        .filter(|name| vars.and_then(|vars| vars.get(name)).is_none())
+        .collect())
+}
+
+/// Template as printed by `templates list`.
+#[derive(Debug, Serialize, Table)]
+struct TemplateLine {
+    #[table(title = "Name", justify = "Justify::Left", color = "Color::Cyan")]
+    name: String,
+    #[table(title = "Description", justify = "Justify::Left")]
+    description: String,
+    #[table(
+        title = "Required variables",
+        justify = "Justify::Left",
+        display_fn = "display_variables"
+    )]
+    required: BTreeSet<String>,
+}
+
+fn display_variables(variables: &BTreeSet<String>) -> String {
+    variables
+        .iter()
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl TemplateLine {
+    fn new(template: &Template) -> Result<Self> {
+        Ok(Self {
+            name: template.name.clone(),
+            description: template.description.clone().unwrap_or_default(),
+            required: variables(template)?.required,
+        })
+    }
+}
+
+/// Template as printed by `templates show --json`.
+#[derive(Debug, Serialize)]
+struct TemplateDetails<'a> {
+    #[serde(flatten)]
+    template: &'a Template,
+    variables: Variables,
+}
+
+/// Renders the template definition as Markdown.
+fn to_markdown(template: &Template, variables: &Variables) -> Result<String> {
+    let mut markdown = format!("# {}\n\n", template.name);
+
+    if let Some(description) = &template.description {
+        markdown.push_str(&format!("{}\n\n", description));
+    }
+
+    if !variables.required.is_empty() || !variables.optional.is_empty() {
+        markdown.push_str("## Variables\n\n");
+
+        for name in &variables.required {
+            markdown.push_str(&format!("- `{}` (required)\n", name));
+        }
+
+        for name in &variables.optional {
+            match template
+                .default_vars
+                .as_ref()
+                .and_then(|vars| vars.get(name))
+            {
+                Some(value) => markdown.push_str(&format!("- `{}` (default: `{}`)\n", name, value)),
+                None => markdown.push_str(&format!("- `{}`\n", name)),
+            }
+        }
+
+        markdown.push('\n');
+    }
+
+    if let Some(system) = &template.system {
+        markdown.push_str(&format!(
+            "## System\n\n```jinja\n{}\n```\n\n",
+            system.trim()
+        ));
+    }
+
+    if let Some(prompt) = &template.template {
+        markdown.push_str(&format!(
+            "## Template\n\n```jinja\n{}\n```\n\n",
+            prompt.trim()
+        ));
+    }
+
+    for (i, step) in template.steps.iter().flatten().enumerate() {
+        markdown.push_str(&format!(
+            "## Step {}: {}\n\n```jinja\n{}\n```\n\n",
+            i + 1,
+            step.name,
+            step.template.trim()
+        ));
+    }
+
+    if let Some(conversation) = &template.conversation {
+        markdown.push_str("## Conversation\n\n");
+        let conversation = conversation
+            .iter()
+            .map(|message| ConversationMessage {
+                role: message.role,
+                content: message.content.clone(),
+                ..Default::default()
+            })
+            .collect::<Conversation>();
+
+        // Nest the message headers under the conversation header.
+        markdown.push_str(
+            &format!(
+                "\n{}",
+                crate::cache::conversation_to_markdown(&conversation)
+            )
+            .replace("\n## ", "\n### ")[1..],
+        );
+    }
+
+    Ok(markdown.trim_end().to_string())
+}
+
+/// Checks that the template name can be used as a file name in the templates directory.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(Error::InvalidTemplate(format!(
+            "invalid template name: {name}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Asks for a value on stderr, returning `None` for an empty answer.
+fn ask(label: &str) -> Result<Option<String>> {
+    eprint!("{}: ", label);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    let answer = answer.trim();
+
+    Ok((!answer.is_empty()).then(|| answer.to_string()))
+}
+
+/// Parses a `name=default` variable. Defaults are read as JSON, so numbers and booleans keep
+/// their type, and as strings otherwise.
+fn parse_var(var: &str) -> Result<(String, Value)> {
+    let (name, value) = var.split_once('=').ok_or_else(|| {
+        Error::InvalidTemplate(format!("invalid variable: {var}, use `name=default`"))
+    })?;
+    let value = serde_json::from_str(value).unwrap_or(Value::String(value.to_string()));
+
+    Ok((name.trim().to_string(), value))
+}
+
+/// Builds a template from the `templates new` flags, asking for the missing values when running
+/// in a terminal.
+fn scaffold(mut new: NewTemplate) -> Result<Template> {
+    let interactive = new.name.is_none() && std::io::stdin().is_terminal();
+
+    if interactive {
+        new.name = ask("Name")?;
+        new.description = ask("Description")?;
+        new.system = ask("System prompt")?;
+        new.template = ask("Prompt template (defaults to `{{ prompt }}`)")?;
+
+        while let Some(var) = ask("Variable as `name=default` (empty to finish)")? {
+            new.vars.push(var);
+        }
+    }
+
+    let name = new
+        .name
+        .ok_or_else(|| Error::InvalidTemplate("missing template name".to_string()))?;
+
+    validate_name(&name)?;
+
+    let default_vars = new
+        .vars
+        .iter()
+        .map(|var| parse_var(var))
+        .collect::<Result<serde_json::Map<String, Value>>>()?;
+
+    let template = Template {
+        name,
+        description: new.description,
+        template: Some(new.template.unwrap_or("{{ prompt }}".to_string())),
+        default_vars: (!default_vars.is_empty()).then_some(Value::Object(default_vars)),
+        system: new.system,
+        ..Default::default()
+    };
+
+    // Fail before writing a template that can't be rendered.
+    variables(&template)?;
+
+    Ok(template)
+}
+
+/// Runs the `templates` commands.
+pub fn run(command: TemplatesCommand, args: Args) -> Result<()> {
+    let (args, config) = build_config(args)?;
+    let templates = config.templates.clone().unwrap_or_default();
+
+    match command {
+        TemplatesCommand::List => {
+            let lines = templates
+                .iter()
+                .map(TemplateLine::new)
+                .collect::<Result<Vec<TemplateLine>>>()?;
+
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&lines)?);
+                return Ok(());
+            }
+
+            let table = if atty::is(atty::Stream::Stdout) {
+                lines.with_title()
+            } else {
+                lines.table()
+            };
+
+            print_table(table, &args)
+        }
+        TemplatesCommand::Show { name } => {
+            let template = templates
+                .iter()
+                .find(|t| t.name == name)
+                .ok_or(Error::TemplateNotFound)?;
+            let variables = variables(template)?;
+
+            if args.json {
+                let details = TemplateDetails {
+                    template,
+                    variables,
+                };
+
+                println!("{}", serde_json::to_string_pretty(&details)?);
+                return Ok(());
+            }
+
+            crate::cache::print(&to_markdown(template, &variables)?, "markdown", &args)
+        }
+        TemplatesCommand::Render {
+            name,
+            prompt,
+            vars,
+            vars_file,
+            suffix,
+        } => {
+            let template = templates
+                .iter()
+                .find(|t| t.name == name)
+                .ok_or(Error::TemplateNotFound)?;
+            let mut vars = Some(template_vars(&mut Args {
+                vars,
+                vars_file,
+                ..Default::default()
+            })?);
+
+            let missing = missing(template, vars.as_ref())?;
+
+            if !missing.is_empty() {
+                return Err(Error::MissingVariables(missing.join(", ")));
+            }
+
+            let stdin = if std::io::stdin().is_terminal() {
+                None
+            } else {
+                Some(std::io::read_to_string(std::io::stdin())?)
+            };
+
+            // Steps aren't sent, so their outputs are replaced by placeholders.
+            if template.steps.is_some() {
+                merge(
+                    vars.get_or_insert(serde_json::json!({})),
+                    crate::pipeline::placeholders(template),
+                );
+            }
+
+            let rendered = merge_args_and_config(
+                Args {
+                    template: Some(name),
+                    prompt,
+                    stdin,
+                    suffix,
+                    vars,
+                    language: config.language.clone(),
+                    ..Default::default()
+                },
+                config,
+            )?;
+
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&rendered.conversation)?);
+                return Ok(());
+            }
+
+            crate::cache::print(
+                &crate::cache::conversation_to_markdown(&rendered.conversation),
+                "markdown",
+                &args,
+            )
+        }
+        TemplatesCommand::New(new) => {
+            let force = new.force;
+            let template = scaffold(*new)?;
+
+            if !force && templates.iter().any(|t| t.name == template.name) {
+                return Err(Error::TemplateExists(template.name));
+            }
+
+            let path = format!(
+                "{}/{}.toml",
+                templates_dir(args.config_dir.as_deref().unwrap_or_default()),
+                template.name
+            );
+
+            std::fs::write(&path, toml::to_string(&template)?)?;
+            eprintln!("Created template: {}", path);
+
+            Ok(())
+        }
+        TemplatesCommand::Install { source, force } => {
+            crate::install::install(&source, force, &templates, &args)
+        }
+        TemplatesCommand::Update { names } => crate::install::update(&names, &templates, &args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_markdown() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let yaml = "---\ndescription: Reviews code\ndefault_vars:\n  focus: errors\n---\n\nReview this, focusing on {{ focus }}:\n\n{{ stdin }}\n";
+
+        let template = from_markdown("review", yaml)?;
+
+        assert_eq!(
+            template.name, "review",
+            "The name should default to the file name"
+        );
+        assert_eq!(template.description, Some("Reviews code".to_string()));
+        assert_eq!(
+            template.default_vars,
+            Some(serde_json::json!({ "focus": "errors" }))
+        );
+        assert_eq!(
+            template.template,
+            Some("Review this, focusing on {{ focus }}:\n\n{{ stdin }}".to_string())
+        );
+
+        let toml = "+++\nname = \"haiku\"\nmodel = \"claude-3-5-haiku-latest\"\n+++\nWrite a haiku about {{ prompt }}";
+
+        let template = from_markdown("file", toml)?;
+
+        assert_eq!(template.name, "haiku");
+        assert_eq!(template.model, Some("claude-3-5-haiku-latest".to_string()));
+
+        assert!(from_markdown("readme", "# Prompts").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let template = Template {
+            name: "translate".to_string(),
+            template: Some("Translate {{ prompt }} from {{ source }} to {{ target }}".to_string()),
+            default_vars: Some(serde_json::json!({ "source": "english" })),
+            ..Default::default()
+        };
+
+        assert_eq!(missing(&template, None)?, vec!["target".to_string()]);
+        assert!(missing(&template, Some(&serde_json::json!({ "target": "spanish" })))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_var() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            parse_var("language=rust")?,
+            ("language".to_string(), serde_json::json!("rust"))
+        );
+        assert_eq!(
+            parse_var("limit=3")?,
+            ("limit".to_string(), serde_json::json!(3))
+        );
+        assert!(parse_var("language").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variables() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let template = Template {
+            name: "review".to_string(),
+            system: Some("Answer in {{ lang }}.".to_string()),
+            template: Some(
+                "{% if focus %}Focus on {{ focus }}.{% endif %}\n\
+                 {% for file in files %}{{ file.path }}{% endfor %}\n\
+                 {{ tone | default(value=\"formal\") }} {{ prompt }} {{ stdin }}"
+                    .to_string(),
+            ),
+            default_vars: Some(serde_json::json!({ "lang": "english" })),
+            ..Default::default()
+        };
+
+        let actual = variables(&template)?;
+
+        assert_eq!(
+            actual.required,
+            BTreeSet::from(["files".to_string(), "focus".to_string()]),
+            "Variables printed in a block are required, even inside a condition"
+        );
+        assert_eq!(
+            actual.optional,
+            BTreeSet::from(["lang".to_string(), "tone".to_string()])
+        );
+
+        Ok(())
+    }
+}
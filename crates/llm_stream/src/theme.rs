@@ -0,0 +1,186 @@
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::prelude::*;
+
+/// Theme used with `--theme auto` on dark terminals, unless `dark_theme` is set.
+const DEFAULT_DARK_THEME: &str = "OneHalfDark";
+/// Theme used with `--theme auto` on light terminals, unless `light_theme` is set.
+const DEFAULT_LIGHT_THEME: &str = "OneHalfLight";
+
+/// How long to wait for the terminal to answer the background color query.
+const QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Background of the terminal, detected once per run.
+static BACKGROUND: OnceLock<Background> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Dark,
+    Light,
+}
+
+/// Parses the answer to an OSC 11 query, like `\x1b]11;rgb:ffff/ffff/ffff\x07`.
+fn parse_osc11(response: &str) -> Option<Background> {
+    let start = response.find("rgb:")? + 4;
+    let channels = response[start..]
+        .split(|c: char| !c.is_ascii_hexdigit() && c != '/')
+        .next()?
+        .split('/')
+        .map(|channel| {
+            let max = 16_f64.powi(channel.len() as i32) - 1.0;
+            u32::from_str_radix(channel, 16)
+                .ok()
+                .map(|value| f64::from(value) / max)
+        })
+        .collect::<Option<Vec<f64>>>()?;
+
+    let [r, g, b] = channels[..] else {
+        return None;
+    };
+
+    let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+
+    Some(if luminance > 0.5 {
+        Background::Light
+    } else {
+        Background::Dark
+    })
+}
+
+/// Parses the `COLORFGBG` variable set by some terminals, like `15;0`, whose last field is the
+/// ANSI number of the background color.
+fn parse_colorfgbg(value: &str) -> Option<Background> {
+    let background = value.rsplit(';').next()?.parse::<u8>().ok()?;
+
+    Some(match background {
+        7 | 9..=15 => Background::Light,
+        _ => Background::Dark,
+    })
+}
+
+/// Reads the answers to the queries from `tty` until the device attributes one. Each byte is
+/// waited for with `poll`, so nothing is left blocked on a terminal that doesn't answer once
+/// `QUERY_TIMEOUT` passes.
+#[cfg(unix)]
+fn read_response(tty: &mut std::fs::File) -> String {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    let deadline = std::time::Instant::now() + QUERY_TIMEOUT;
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+
+    while let Some(left) = deadline.checked_duration_since(std::time::Instant::now()) {
+        let mut fd = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = libc::c_int::try_from(left.as_millis()).unwrap_or(libc::c_int::MAX);
+
+        // SAFETY: `fd` is a single pollfd, valid for the whole call.
+        if unsafe { libc::poll(&mut fd, 1, timeout) } <= 0 || tty.read_exact(&mut byte).is_err() {
+            break;
+        }
+
+        response.push(byte[0]);
+
+        // The device attributes answer, `\x1b[?...c`, is the last one.
+        if byte[0] == b'c' && response.windows(2).any(|w| w == b"[?") {
+            break;
+        }
+    }
+
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[cfg(not(unix))]
+fn read_response(_tty: &mut std::fs::File) -> String {
+    String::new()
+}
+
+/// Asks the terminal for its background color with an OSC 11 query.
+///
+/// The query is followed by a device attributes request, which every terminal answers, so the
+/// reader stops even when the background color isn't supported.
+fn query() -> Option<Background> {
+    if !atty::is(atty::Stream::Stdout) || !atty::is(atty::Stream::Stdin) {
+        return None;
+    }
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+
+    crossterm::terminal::enable_raw_mode().ok()?;
+
+    let response = tty
+        .write_all(b"\x1b]11;?\x07\x1b[c")
+        .and_then(|_| tty.flush())
+        .ok()
+        .map(|_| read_response(&mut tty));
+
+    let _ = crossterm::terminal::disable_raw_mode();
+
+    parse_osc11(&response?)
+}
+
+/// Returns the background of the terminal, assuming it's dark when it can't be detected.
+pub fn background() -> Background {
+    *BACKGROUND.get_or_init(|| {
+        query()
+            .or_else(|| {
+                std::env::var("COLORFGBG")
+                    .ok()
+                    .and_then(|v| parse_colorfgbg(&v))
+            })
+            .unwrap_or(Background::Dark)
+    })
+}
+
+/// Returns the theme used to highlight the output. With `--theme auto` it's the `dark_theme` or
+/// the `light_theme`, depending on the terminal background.
+pub fn resolve(args: &Args) -> String {
+    match args.theme.as_deref() {
+        Some("auto") => match background() {
+            Background::Dark => args
+                .dark_theme
+                .clone()
+                .unwrap_or(DEFAULT_DARK_THEME.to_string()),
+            Background::Light => args
+                .light_theme
+                .clone()
+                .unwrap_or(DEFAULT_LIGHT_THEME.to_string()),
+        },
+        Some(theme) => theme.to_string(),
+        None => "ansi".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_osc11() {
+        assert_eq!(
+            parse_osc11("\x1b]11;rgb:ffff/fefe/f0f0\x07\x1b[?62;22c"),
+            Some(Background::Light)
+        );
+        assert_eq!(
+            parse_osc11("\x1b]11;rgb:1e/1e/2e\x1b\\"),
+            Some(Background::Dark)
+        );
+        assert_eq!(parse_osc11("\x1b[?62;22c"), None);
+    }
+
+    #[test]
+    fn test_parse_colorfgbg() {
+        assert_eq!(parse_colorfgbg("15;0"), Some(Background::Dark));
+        assert_eq!(parse_colorfgbg("0;default;15"), Some(Background::Light));
+        assert_eq!(parse_colorfgbg("default"), None);
+    }
+}
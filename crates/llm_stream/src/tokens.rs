@@ -0,0 +1,387 @@
+use cli_table::{format::Justify, Table, WithTitle};
+use serde::Serialize;
+
+use crate::prelude::*;
+
+/// Estimates the amount of tokens in `text`.
+///
+/// Uses the usual approximation of four characters per token. Only used when no tokenizer could
+/// be loaded.
+pub fn estimate(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Counts tokens using the `tiktoken` encodings.
+///
+/// OpenAI models use their own encoding. Other providers don't publish their tokenizers, so
+/// `cl100k_base` is used as an estimate.
+pub struct Tokenizer {
+    bpe: Option<tiktoken_rs::CoreBPE>,
+}
+
+impl Tokenizer {
+    pub fn new(api: Option<Api>, model: Option<&str>) -> Self {
+        let bpe = match (api, model) {
+            (Some(Api::OpenAi), Some(model)) => {
+                tiktoken_rs::get_bpe_from_model(model).or_else(|_| tiktoken_rs::cl100k_base())
+            }
+            _ => tiktoken_rs::cl100k_base(),
+        };
+
+        if let Err(e) = &bpe {
+            log::warn!("unable to load tokenizer: {}", e);
+        }
+
+        Self { bpe: bpe.ok() }
+    }
+
+    /// Counts the tokens in `text`.
+    pub fn count(&self, text: &str) -> usize {
+        match &self.bpe {
+            Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+            None => estimate(text),
+        }
+    }
+
+    /// Counts the tokens of every message in the conversation.
+    pub fn count_conversation(&self, conversation: &Conversation) -> usize {
+        conversation.iter().map(|m| self.count(&m.content)).sum()
+    }
+
+    /// Removes `tokens` tokens from the start of `text`.
+    pub fn trim_start(&self, text: &str, tokens: usize) -> String {
+        match &self.bpe {
+            Some(bpe) => {
+                let encoded = bpe.encode_with_special_tokens(text);
+                bpe.decode(encoded[tokens.min(encoded.len())..].to_vec())
+                    .unwrap_or_default()
+            }
+            None => text.chars().skip(tokens * 4).collect(),
+        }
+    }
+}
+
+/// Summarizes the first line of a message for warnings.
+fn preview(message: &ConversationMessage) -> String {
+    message
+        .content
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .chars()
+        .take(60)
+        .collect()
+}
+
+/// Drops, or trims, the oldest messages of the conversation until it fits in
+/// `--max-context-tokens`. The system message and the latest message are always kept. Only the
+/// request is truncated, the whole conversation is kept for the cache.
+pub fn truncate(mut args: Args) -> Args {
+    let Some(max_context_tokens) = args.max_context_tokens else {
+        return args;
+    };
+
+    let tokenizer = Tokenizer::new(args.api, args.model.as_deref());
+    let start = usize::from(
+        args.conversation
+            .first()
+            .is_some_and(|m| m.role == ConversationRole::System),
+    );
+
+    let mut total = tokenizer.count_conversation(&args.conversation);
+    let notices = args.shows_notices();
+
+    if total > max_context_tokens {
+        args.keep_full_conversation();
+    }
+
+    while total > max_context_tokens && args.conversation.len() > start + 1 {
+        let overflow = total - max_context_tokens;
+        let tokens = tokenizer.count(&args.conversation[start].content);
+
+        if tokens <= overflow {
+            let message = args.conversation.remove(start);
+            if notices {
+                eprintln!(
+                    "Dropped {} message ({} tokens): {}",
+                    message.role,
+                    tokens,
+                    preview(&message)
+                );
+            }
+            total -= tokens;
+        } else {
+            let message = &mut args.conversation[start];
+            message.content = tokenizer.trim_start(&message.content, overflow);
+            if notices {
+                eprintln!(
+                    "Trimmed {} message ({} tokens): {}",
+                    message.role,
+                    overflow,
+                    preview(message)
+                );
+            }
+            total = tokenizer.count_conversation(&args.conversation);
+            break;
+        }
+    }
+
+    if total > max_context_tokens && notices {
+        eprintln!(
+            "The conversation still has {} tokens, over the {} tokens limit",
+            total, max_context_tokens
+        );
+    }
+
+    args
+}
+
+/// Returns the context window of the model. Google is asked for it, the other apis use the known
+/// context windows.
+pub fn context_window(args: &Args) -> Option<usize> {
+    let asked = match args.api {
+        Some(Api::Google) => match crate::google::model(args.clone()) {
+            Ok(model) => Some(model.input_token_limit as usize),
+            Err(e) => {
+                log::warn!("unable to get the context window of the model: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    asked
+        .filter(|tokens| *tokens > 0)
+        .or_else(|| crate::report::context_window(args.model.as_deref()?))
+}
+
+/// Times the conversation is shrunk when the api says it doesn't fit in the context window.
+pub const MAX_SHRINKS: usize = 3;
+
+/// Shrinks a conversation that the api rejected for being longer than the context window of the
+/// model, to the known context window, or to three quarters of its size. Returns `None` if only
+/// the system message and the latest message are left.
+pub fn shrink(args: &Args) -> Option<Args> {
+    let tokenizer = Tokenizer::new(args.api, args.model.as_deref());
+    let total = tokenizer.count_conversation(&args.conversation);
+    let output_tokens = args.max_tokens.unwrap_or_default() as usize;
+
+    let limit = context_window(args)
+        .map(|window| window.saturating_sub(output_tokens))
+        .filter(|limit| *limit < total)
+        .unwrap_or(total * 3 / 4);
+
+    if args.shows_notices() {
+        eprintln!(
+            "The conversation doesn't fit in the context window of the model, shrinking it to {} tokens",
+            limit
+        );
+    }
+
+    let shrunk = truncate(Args {
+        max_context_tokens: Some(limit),
+        ..args.clone()
+    });
+
+    if tokenizer.count_conversation(&shrunk.conversation) >= total {
+        return None;
+    }
+
+    Some(Args {
+        max_context_tokens: args.max_context_tokens,
+        ..shrunk
+    })
+}
+
+/// Tokens of a single message, printed by `--count-tokens`.
+#[derive(Debug, Serialize, Table)]
+struct MessageTokens {
+    #[table(title = "#", justify = "Justify::Right")]
+    index: usize,
+    #[table(title = "Role", justify = "Justify::Left")]
+    role: String,
+    #[table(title = "Tokens", justify = "Justify::Right")]
+    tokens: usize,
+    #[table(title = "Message", justify = "Justify::Left")]
+    message: String,
+}
+
+/// Returns the tokens of the whole conversation counted by the api, for the apis that can count
+/// them. Falls back to the estimate when the api can't be reached.
+fn count_exact(args: &Args) -> Option<usize> {
+    let count = match args.api {
+        Some(Api::Anthropic) => crate::anthropic::count_tokens(args.clone()),
+        Some(Api::Google) => crate::google::count_tokens(args.clone()),
+        _ => return None,
+    };
+
+    match count {
+        Ok(tokens) => Some(tokens as usize),
+        Err(e) => {
+            log::warn!("unable to count the tokens with the api: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Prints the tokens of every message of the conversation and their total, without sending it.
+/// The tokens of each message are estimated, the total is exact when the api can count it.
+pub fn count(args: Args) -> Result<()> {
+    let tokenizer = Tokenizer::new(args.api, args.model.as_deref());
+
+    let messages = args
+        .conversation
+        .iter()
+        .enumerate()
+        .map(|(index, message)| MessageTokens {
+            index,
+            role: message.role.to_string(),
+            tokens: tokenizer.count(&message.content),
+            message: preview(message),
+        })
+        .collect::<Vec<MessageTokens>>();
+    let exact = count_exact(&args);
+    let total = exact.unwrap_or_else(|| messages.iter().map(|m| m.tokens).sum::<usize>());
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "messages": messages,
+                "total": total,
+                "estimated": exact.is_none(),
+            }))?
+        );
+        return Ok(());
+    }
+
+    let table = if atty::is(atty::Stream::Stdout) {
+        messages.with_title()
+    } else {
+        messages.table()
+    };
+
+    print_table(table, &args)?;
+    match (exact, args.api) {
+        (Some(_), Some(api)) => println!("Total: {} tokens, counted by {}", total, api),
+        _ => println!("Total: {} tokens", total),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_drops_oldest_messages() {
+        let mut args = Args::default();
+        args.max_context_tokens = Some(10);
+        args.conversation = vec![
+            ConversationMessage::new(ConversationRole::System, "system"),
+            ConversationMessage::new(ConversationRole::User, &"old ".repeat(50)),
+            ConversationMessage::new(ConversationRole::Assistant, &"answer ".repeat(50)),
+            ConversationMessage::new(ConversationRole::User, "latest"),
+        ];
+
+        let actual = truncate(args);
+
+        assert_eq!(
+            actual.conversation.first(),
+            Some(&ConversationMessage::new(
+                ConversationRole::System,
+                "system"
+            )),
+            "The system message should be kept"
+        );
+        assert_eq!(
+            actual.conversation.last(),
+            Some(&ConversationMessage::new(ConversationRole::User, "latest")),
+            "The latest message should be kept"
+        );
+        assert!(
+            !actual
+                .conversation
+                .iter()
+                .any(|m| m.content.starts_with("old")),
+            "The oldest message should be dropped"
+        );
+    }
+
+    #[test]
+    fn test_truncate_keeps_the_cached_conversation() {
+        let conversation = vec![
+            ConversationMessage::new(ConversationRole::User, &"old ".repeat(50)),
+            ConversationMessage::new(ConversationRole::Assistant, &"answer ".repeat(50)),
+            ConversationMessage::new(ConversationRole::User, "latest"),
+        ];
+        let mut args = Args::default();
+        args.max_context_tokens = Some(10);
+        args.conversation = conversation.clone();
+
+        let actual = truncate(args);
+
+        assert!(actual.conversation.len() < conversation.len());
+        assert_eq!(actual.full_conversation, Some(conversation));
+
+        let mut args = Args::default();
+        args.max_context_tokens = Some(10_000);
+        args.conversation = vec![ConversationMessage::new(ConversationRole::User, "latest")];
+
+        assert_eq!(truncate(args).full_conversation, None);
+    }
+
+    #[test]
+    fn test_shrink_keeps_the_latest_message() {
+        let mut args = Args::default();
+        args.max_context_tokens = Some(100_000);
+        args.conversation = vec![
+            ConversationMessage::new(ConversationRole::User, &"old ".repeat(50)),
+            ConversationMessage::new(ConversationRole::Assistant, &"answer ".repeat(50)),
+            ConversationMessage::new(ConversationRole::User, "latest"),
+        ];
+
+        let shrunk = shrink(&args).expect("the conversation should shrink");
+
+        let tokenizer = Tokenizer::new(None, None);
+
+        assert!(
+            tokenizer.count_conversation(&shrunk.conversation)
+                < tokenizer.count_conversation(&args.conversation)
+        );
+        assert_eq!(
+            shrunk.conversation.last(),
+            Some(&ConversationMessage::new(ConversationRole::User, "latest"))
+        );
+        assert_eq!(shrunk.max_context_tokens, Some(100_000));
+        assert_eq!(
+            shrunk.full_conversation.as_ref(),
+            Some(&args.conversation),
+            "The whole conversation should be cached"
+        );
+
+        args.conversation = vec![ConversationMessage::new(ConversationRole::User, "latest")];
+
+        assert_eq!(shrink(&args), None, "The latest message can't be dropped");
+    }
+
+    #[test]
+    fn test_truncate_keeps_conversation_under_the_limit() {
+        let mut args = Args::default();
+        args.max_context_tokens = Some(1000);
+        args.conversation = vec![
+            ConversationMessage::new(ConversationRole::User, "question"),
+            ConversationMessage::new(ConversationRole::Assistant, "answer"),
+            ConversationMessage::new(ConversationRole::User, "latest"),
+        ];
+
+        let expected = args.conversation.clone();
+        let actual = truncate(args);
+
+        assert_eq!(
+            actual.conversation, expected,
+            "The conversation shouldn't change"
+        );
+    }
+}
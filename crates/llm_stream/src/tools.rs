@@ -0,0 +1,446 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Tool;
+use crate::prelude::*;
+
+/// Maximum amount of requests sent for a single prompt while the model keeps calling tools, unless
+/// an agent sets `max_iterations`.
+const MAX_ROUNDS: usize = 10;
+
+/// Largest tool output sent back to the model.
+const MAX_OUTPUT_SIZE: usize = 64 * 1024;
+
+/// Shape of the tool requests and messages of each api.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// Used by OpenAI and Mistral.
+    OpenAi,
+    Anthropic,
+}
+
+/// Tool call requested by the model.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Call {
+    id: String,
+    name: String,
+    /// Arguments as a JSON string.
+    arguments: String,
+}
+
+/// Text and tool calls streamed in a response.
+#[derive(Debug, Default)]
+struct Round {
+    text: String,
+    /// Calls by their index in the response.
+    calls: BTreeMap<u64, Call>,
+}
+
+impl Round {
+    /// Adds the text and tool call fragments of a streamed event.
+    fn collect(&mut self, format: Format, data: &str) {
+        let Ok(event) = serde_json::from_str::<Value>(data) else {
+            return;
+        };
+
+        match format {
+            Format::OpenAi => {
+                let delta = &event["choices"][0]["delta"];
+
+                if let Some(text) = delta["content"].as_str() {
+                    self.text.push_str(text);
+                }
+
+                for (i, fragment) in delta["tool_calls"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                {
+                    let index = fragment["index"].as_u64().unwrap_or(i as u64);
+                    let call = self.calls.entry(index).or_default();
+
+                    if let Some(id) = fragment["id"].as_str() {
+                        call.id = id.to_string();
+                    }
+                    if let Some(name) = fragment["function"]["name"].as_str() {
+                        call.name.push_str(name);
+                    }
+                    if let Some(arguments) = fragment["function"]["arguments"].as_str() {
+                        call.arguments.push_str(arguments);
+                    }
+                }
+            }
+            Format::Anthropic => {
+                let index = event["index"].as_u64().unwrap_or_default();
+
+                match event["type"].as_str() {
+                    Some("content_block_start") if event["content_block"]["type"] == "tool_use" => {
+                        self.calls.insert(
+                            index,
+                            Call {
+                                id: event["content_block"]["id"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                name: event["content_block"]["name"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                arguments: String::new(),
+                            },
+                        );
+                    }
+                    Some("content_block_delta") => {
+                        let delta = &event["delta"];
+
+                        if let Some(text) = delta["text"].as_str() {
+                            self.text.push_str(text);
+                        }
+                        if let (Some(json), Some(call)) =
+                            (delta["partial_json"].as_str(), self.calls.get_mut(&index))
+                        {
+                            call.arguments.push_str(json);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Tools enabled with `--tool`, and the tool calls and outputs of the conversation so far.
+pub struct Session {
+    format: Format,
+    tools: Vec<Tool>,
+    round: Arc<Mutex<Round>>,
+    /// Messages with the tool calls and their outputs, added after the prompt.
+    messages: Arc<Mutex<Vec<Value>>>,
+    /// Prints the calls that run without confirmation.
+    notices: bool,
+}
+
+impl Session {
+    fn new(format: Format, tools: Vec<Tool>, notices: bool) -> Self {
+        Self {
+            format,
+            tools,
+            notices,
+            round: Default::default(),
+            messages: Default::default(),
+        }
+    }
+
+    /// Returns the `on_request` hook that advertises the tools and adds the previous calls to the
+    /// messages.
+    pub fn request_hook(&self) -> impl Fn(&mut Value) + Send + Sync + 'static {
+        let tools = self
+            .tools
+            .iter()
+            .map(|tool| match self.format {
+                Format::OpenAi => serde_json::json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.parameters,
+                    },
+                }),
+                Format::Anthropic => serde_json::json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                    "input_schema": tool.parameters,
+                }),
+            })
+            .collect::<Vec<Value>>();
+        let messages = Arc::clone(&self.messages);
+
+        move |body: &mut Value| {
+            body["tools"] = Value::Array(tools.clone());
+
+            if let (Some(body_messages), Ok(messages)) =
+                (body["messages"].as_array_mut(), messages.lock())
+            {
+                body_messages.extend(messages.iter().cloned());
+            }
+        }
+    }
+
+    /// Returns the `on_event` hook that collects the tool calls of the response.
+    pub fn event_hook(&self) -> impl Fn(&str) + Send + Sync + 'static {
+        let format = self.format;
+        let round = Arc::clone(&self.round);
+
+        move |data: &str| {
+            if let Ok(mut round) = round.lock() {
+                round.collect(format, data);
+            }
+        }
+    }
+
+    /// Runs the calls of the last response, and adds them and their outputs to the messages.
+    /// Returns false when the model didn't call any tool.
+    fn answer(&self) -> Result<bool> {
+        let round = match self.round.lock() {
+            Ok(mut round) => std::mem::take(&mut *round),
+            Err(_) => return Ok(false),
+        };
+
+        if round.calls.is_empty() {
+            return Ok(false);
+        }
+
+        let calls = round.calls.into_values().collect::<Vec<Call>>();
+        let outputs = calls
+            .iter()
+            .map(|call| self.call(call))
+            .collect::<Result<Vec<String>>>()?;
+
+        let mut messages = match self.messages.lock() {
+            Ok(messages) => messages,
+            Err(_) => return Ok(false),
+        };
+
+        match self.format {
+            Format::OpenAi => {
+                messages.push(serde_json::json!({
+                    "role": "assistant",
+                    "content": round.text,
+                    "tool_calls": calls.iter().map(|call| serde_json::json!({
+                        "id": call.id,
+                        "type": "function",
+                        "function": { "name": call.name, "arguments": call.arguments },
+                    })).collect::<Vec<Value>>(),
+                }));
+
+                for (call, output) in calls.iter().zip(outputs) {
+                    messages.push(serde_json::json!({
+                        "role": "tool",
+                        "tool_call_id": call.id,
+                        "content": output,
+                    }));
+                }
+            }
+            Format::Anthropic => {
+                let text = Some(round.text)
+                    .filter(|text| !text.trim().is_empty())
+                    .map(|text| serde_json::json!({ "type": "text", "text": text }));
+                let uses = calls.iter().map(|call| {
+                    serde_json::json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": serde_json::from_str::<Value>(&call.arguments)
+                            .unwrap_or(serde_json::json!({})),
+                    })
+                });
+
+                messages.push(serde_json::json!({
+                    "role": "assistant",
+                    "content": text.into_iter().chain(uses).collect::<Vec<Value>>(),
+                }));
+                messages.push(serde_json::json!({
+                    "role": "user",
+                    "content": calls.iter().zip(outputs).map(|(call, output)| serde_json::json!({
+                        "type": "tool_result",
+                        "tool_use_id": call.id,
+                        "content": output,
+                    })).collect::<Vec<Value>>(),
+                }));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Runs a tool call, returning the output sent back to the model. Calls the user declines, or
+    /// of tools that weren't enabled, are answered with an explanation instead.
+    fn call(&self, call: &Call) -> Result<String> {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name == call.name) else {
+            return Ok(format!("The {} tool isn't available.", call.name));
+        };
+
+        if tool.confirm || self.notices {
+            eprintln!("\n\nTool call: {} {}", call.name, call.arguments);
+        }
+
+        if tool.confirm && !confirm("Run it?")? {
+            return Ok("The user declined to run the tool.".to_string());
+        }
+
+        run_command(&tool.command, &call.arguments)
+    }
+}
+
+/// Runs `command` with the shell, writing `arguments` to its standard input. Returns its output,
+/// with the exit status and standard error when it fails.
+fn run_command(command: &str, arguments: &str) -> Result<String> {
+    let mut child = crate::pipe::shell(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    // The arguments are written from another thread, so commands that print before reading all of
+    // them don't block on a full pipe.
+    let writer = child.stdin.take().map(|mut stdin| {
+        let arguments = arguments.to_string();
+        // Commands that don't read their input close it early, so the error is ignored.
+        std::thread::spawn(move || stdin.write_all(arguments.as_bytes()).ok())
+    });
+
+    let output = child.wait_with_output()?;
+
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+
+    if !output.status.success() {
+        text.push_str(&format!(
+            "\n{}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    if text.len() > MAX_OUTPUT_SIZE {
+        let end = (0..=MAX_OUTPUT_SIZE)
+            .rev()
+            .find(|i| text.is_char_boundary(*i))
+            .unwrap_or_default();
+        text.truncate(end);
+        text.push_str("\n[output truncated]");
+    }
+
+    Ok(text)
+}
+
+/// Returns the session of the tools of `--tool`, in the format of the api.
+pub fn session(args: &Args, definitions: &[Tool]) -> Result<Session> {
+    let format = match args.api {
+        Some(Api::OpenAi) | Some(Api::Mistral) => Format::OpenAi,
+        Some(Api::Anthropic) => Format::Anthropic,
+        Some(api) => return Err(Error::Tool(format!("{} doesn't support tools", api))),
+        None => return Err(Error::ApiNotSpecified),
+    };
+
+    let tools = args
+        .tools
+        .iter()
+        .map(|name| {
+            definitions
+                .iter()
+                .find(|tool| &tool.name == name)
+                .cloned()
+                .ok_or_else(|| Error::Tool(format!("{} isn't defined in `tools`", name)))
+        })
+        .collect::<Result<Vec<Tool>>>()?;
+
+    Ok(Session::new(format, tools, args.shows_notices()))
+}
+
+/// Sends the prompt with the tools of `--tool`, running the tools the model calls and sending
+/// their outputs back, until it answers without calling any.
+pub async fn run(args: Args, definitions: &[Tool]) -> Result<()> {
+    let session = session(&args, definitions)?;
+    let max_rounds = args.max_iterations.unwrap_or(MAX_ROUNDS);
+
+    for _ in 0..max_rounds {
+        crate::report::wait_for_quota(args.api, args.shows_notices()).await;
+
+        match args.api {
+            Some(Api::OpenAi) => crate::openai::run_with_tools(args.clone(), &session).await?,
+            Some(Api::Mistral) => crate::mistral::run_with_tools(args.clone(), &session).await?,
+            Some(Api::Anthropic) => {
+                crate::anthropic::run_with_tools(args.clone(), &session).await?
+            }
+            _ => unreachable!("the api supports tools"),
+        };
+
+        if !session.answer()? {
+            return Ok(());
+        }
+    }
+
+    Err(Error::Tool(format!(
+        "the model was still calling tools after {} requests",
+        max_rounds
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_openai_tool_calls() {
+        let mut round = Round::default();
+
+        for data in [
+            r#"{"choices":[{"delta":{"content":"Let me check."}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"read","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"path\":"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"a.md\"}"}}]}}]}"#,
+            "[DONE]",
+        ] {
+            round.collect(Format::OpenAi, data);
+        }
+
+        assert_eq!(round.text, "Let me check.");
+        assert_eq!(
+            round.calls.into_values().collect::<Vec<Call>>(),
+            vec![Call {
+                id: "call_1".to_string(),
+                name: "read".to_string(),
+                arguments: r#"{"path":"a.md"}"#.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_collect_anthropic_tool_calls() {
+        let mut round = Round::default();
+
+        for data in [
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Sure."}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"read","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"path\": \"a.md\"}"}}"#,
+        ] {
+            round.collect(Format::Anthropic, data);
+        }
+
+        assert_eq!(round.text, "Sure.");
+        assert_eq!(
+            round.calls.get(&1),
+            Some(&Call {
+                id: "toolu_1".to_string(),
+                name: "read".to_string(),
+                arguments: r#"{"path": "a.md"}"#.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_command_reads_the_arguments() -> std::result::Result<(), Box<dyn std::error::Error>>
+    {
+        if cfg!(windows) {
+            return Ok(());
+        }
+
+        assert_eq!(
+            run_command("cat", r#"{"path":"a.md"}"#)?,
+            r#"{"path":"a.md"}"#
+        );
+        assert!(run_command("exit 3", "{}")?.contains("3"));
+
+        // Larger than the pipes, so `cat` fills its output before it has read all of it.
+        let arguments = "a".repeat(1024 * 1024);
+        assert!(run_command("cat", &arguments)?.ends_with("[output truncated]"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::prelude::*;
+
+/// Width of the conversations pane.
+const SIDEBAR_WIDTH: u16 = 32;
+
+/// Lines moved by `PageUp` and `PageDown`.
+const PAGE: u16 = 10;
+
+/// Messages sent by the thread that streams the LLM response.
+enum Update {
+    Delta(String),
+    /// The request as it was sent, after it was prepared, and the answer.
+    Done(std::result::Result<(Box<Args>, String), String>),
+}
+
+/// Pane that receives the key events.
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Conversations,
+    Input,
+}
+
+/// State of the terminal interface.
+struct App {
+    /// Arguments used as the base of every conversation.
+    args: Args,
+    config: Config,
+    conversations: Vec<ConversationSummary>,
+    list: ListState,
+    /// Id of the open conversation. `None` until a new conversation is cached.
+    id: Option<String>,
+    /// Arguments of the open conversation.
+    current: Args,
+    input: String,
+    focus: Focus,
+    scroll: u16,
+    /// Keep the history scrolled to its bottom.
+    follow: bool,
+    /// Request being streamed, with the text received so far.
+    pending: Option<(Args, String)>,
+    /// Prompt of the pending request, given back if it fails.
+    sent: String,
+    receiver: Option<mpsc::Receiver<Update>>,
+    /// Runtime the requests are sent on. It outlives them, so the connections they open are
+    /// reused by the next ones.
+    runtime: tokio::runtime::Handle,
+    status: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    fn new(mut args: Args, config: Config, runtime: tokio::runtime::Handle) -> Result<Self> {
+        // Notices on stderr would be drawn over the interface.
+        args.no_notices = true;
+
+        let current = merge_args_and_cache(args.clone())?;
+
+        args.from = None;
+        args.from_last = false;
+
+        let mut app = Self {
+            args,
+            config,
+            conversations: Vec::new(),
+            list: ListState::default(),
+            id: current.from.clone(),
+            current,
+            input: String::new(),
+            focus: Focus::Input,
+            scroll: 0,
+            follow: true,
+            pending: None,
+            sent: String::new(),
+            receiver: None,
+            runtime,
+            status: None,
+            quit: false,
+        };
+
+        app.load_conversations()?;
+
+        Ok(app)
+    }
+
+    /// Reads the cached conversations, newest first, and selects the open one.
+    fn load_conversations(&mut self) -> Result<()> {
+        let data_dir = self
+            .args
+            .data_dir
+            .clone()
+            .expect("can't find data directory");
+        let cache_dir = crate::cache::cache_dir(&data_dir);
+
+        self.conversations = if std::path::Path::new(&cache_dir).exists() {
+            get_sorted_cache_files(&cache_dir)?
+                .iter()
+                .rev()
+                .filter_map(|path| ConversationSummary::from_path(path).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        self.list.select(
+            self.conversations
+                .iter()
+                .position(|c| Some(&c.id) == self.id.as_ref()),
+        );
+
+        Ok(())
+    }
+
+    /// Opens the conversation selected in the conversations pane.
+    fn open(&mut self) -> Result<()> {
+        let Some(summary) = self.list.selected().and_then(|i| self.conversations.get(i)) else {
+            return Ok(());
+        };
+
+        let mut args = self.args.clone();
+        args.from = Some(summary.id.clone());
+
+        self.current = merge_args_and_cache(args)?;
+        self.id = self.current.from.clone();
+        self.follow = true;
+        self.focus = Focus::Input;
+
+        Ok(())
+    }
+
+    /// Starts an empty conversation.
+    fn new_conversation(&mut self) {
+        self.current = self.args.clone();
+        self.id = None;
+        self.list.select(None);
+        self.follow = true;
+        self.focus = Focus::Input;
+    }
+
+    /// Sends the input as the next user message, streaming the response on a separate thread.
+    fn submit(&mut self) -> Result<()> {
+        if self.pending.is_some() || self.input.trim().is_empty() {
+            return Ok(());
+        }
+
+        let mut request = self.current.clone();
+        request.prompt = Some(self.input.clone());
+
+        let mut request = merge_args_and_config(request, self.config.clone())?;
+        self.sent = std::mem::take(&mut self.input);
+
+        if let Some(prompt) = request.conversation.last_mut() {
+            prompt.created_at = Some(chrono::Local::now());
+        }
+
+        // Piped input is only used as context for the first message.
+        self.args.stdin = None;
+
+        let (sender, receiver) = mpsc::channel();
+        let thread_args = request.clone();
+        let config = self.config.clone();
+        let runtime = self.runtime.clone();
+
+        std::thread::spawn(move || {
+            let result = runtime.block_on(async {
+                let request = prepare(thread_args, &config).await?;
+                let content = complete(request.clone(), |delta| {
+                    let _ = sender.send(Update::Delta(delta.to_string()));
+                })
+                .await?;
+
+                Ok::<_, Error>((Box::new(request), content))
+            });
+
+            let _ = sender.send(Update::Done(result.map_err(|e| e.to_string())));
+        });
+
+        self.pending = Some((request, String::new()));
+        self.receiver = Some(receiver);
+        self.follow = true;
+        self.status = None;
+
+        Ok(())
+    }
+
+    /// Applies the updates received from the streaming thread.
+    fn poll(&mut self) -> Result<()> {
+        let Some(receiver) = &self.receiver else {
+            return Ok(());
+        };
+
+        let mut done = None;
+
+        while let Ok(update) = receiver.try_recv() {
+            match update {
+                Update::Delta(text) => {
+                    if let Some((_, content)) = self.pending.as_mut() {
+                        content.push_str(&text);
+                    }
+                }
+                Update::Done(result) => {
+                    done = Some(result);
+                    break;
+                }
+            }
+        }
+
+        let Some(result) = done else {
+            return Ok(());
+        };
+
+        self.receiver = None;
+
+        if self.pending.take().is_none() {
+            return Ok(());
+        }
+
+        match result {
+            Ok((request, content)) => {
+                let mut request = *request;
+                request.conversation.push(ConversationMessage {
+                    role: ConversationRole::Assistant,
+                    content: content.trim().to_string(),
+                    ..Default::default()
+                });
+                crate::cache::stamp_answer(&mut request, chrono::Local::now(), Default::default());
+                request.stdin = None;
+
+                if !request.no_cache {
+                    let data_dir = request.data_dir.clone().expect("can't find data directory");
+                    let id = self.id.clone().unwrap_or(xid::new().to_string());
+
+                    crate::cache::write(&data_dir, &id, &request)?;
+
+                    request.from = Some(id.clone());
+                    self.id = Some(id);
+                }
+
+                self.current = request;
+                self.load_conversations()?;
+            }
+            Err(e) => {
+                // Give the prompt back so it can be sent again.
+                self.input = std::mem::take(&mut self.sent);
+                self.status = Some(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('n') if ctrl && self.pending.is_none() => self.new_conversation(),
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::Conversations => Focus::Input,
+                    Focus::Input => Focus::Conversations,
+                }
+            }
+            KeyCode::PageUp => {
+                self.follow = false;
+                self.scroll = self.scroll.saturating_sub(PAGE);
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(PAGE),
+            _ if self.focus == Focus::Conversations => match key.code {
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Enter if self.pending.is_none() => self.open()?,
+                _ => {}
+            },
+            KeyCode::Up => {
+                self.follow = false;
+                self.scroll = self.scroll.saturating_sub(1);
+            }
+            KeyCode::Down => self.scroll = self.scroll.saturating_add(1),
+            KeyCode::Enter => self.submit()?,
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Renders the messages of the open conversation, including the one being streamed.
+    fn history(&self) -> Vec<Line<'static>> {
+        let (conversation, streamed) = match &self.pending {
+            Some((request, content)) => (&request.conversation, Some(content)),
+            None => (&self.current.conversation, None),
+        };
+
+        let messages = conversation
+            .iter()
+            .filter(|m| m.role != ConversationRole::System && !m.content.trim().is_empty())
+            .map(|m| (m.role, m.content.as_str()))
+            .chain(streamed.map(|content| (ConversationRole::Assistant, content.as_str())));
+
+        let mut lines = Vec::new();
+
+        for (role, content) in messages {
+            let color = match role {
+                ConversationRole::User => Color::Cyan,
+                _ => Color::Magenta,
+            };
+
+            lines.push(Line::styled(
+                role.to_string(),
+                Style::default().fg(color).add_modifier(Modifier::BOLD),
+            ));
+            lines.extend(content.lines().map(|line| Line::raw(line.to_string())));
+            lines.push(Line::default());
+        }
+
+        lines
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [sidebar, main] =
+            Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(0)])
+                .areas(frame.area());
+        let [history, input, status] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(main);
+
+        let active = self.focus;
+        let focused = move |focus: Focus| {
+            if active == focus {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            }
+        };
+
+        let items = self
+            .conversations
+            .iter()
+            .map(|c| {
+                let label = c
+                    .title
+                    .clone()
+                    .or(c.message.clone())
+                    .unwrap_or(c.id.clone());
+                ListItem::new(label.lines().next().unwrap_or_default().to_string())
+            })
+            .collect::<Vec<_>>();
+
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(focused(Focus::Conversations))
+                    .title("Conversations"),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        frame.render_stateful_widget(list, sidebar, &mut self.list);
+
+        let lines = self.history();
+
+        // Count the lines after wrapping them to keep the view scrolled to the bottom.
+        let width = usize::from(history.width.saturating_sub(2)).max(1);
+        let height = history.height.saturating_sub(2);
+        let total = lines
+            .iter()
+            .map(|line| line.width().div_ceil(width).max(1))
+            .sum::<usize>();
+        let max_scroll = u16::try_from(total)
+            .unwrap_or(u16::MAX)
+            .saturating_sub(height);
+
+        if self.follow || self.scroll >= max_scroll {
+            self.follow = true;
+            self.scroll = max_scroll;
+        }
+
+        let title = match (&self.current.title, &self.id) {
+            (Some(title), _) => title.clone(),
+            (None, Some(id)) => id.clone(),
+            (None, None) => "New conversation".to_string(),
+        };
+
+        frame.render_widget(
+            Paragraph::new(Text::from(lines))
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .wrap(Wrap { trim: false })
+                .scroll((self.scroll, 0)),
+            history,
+        );
+
+        let input_width = input.width.saturating_sub(2);
+        let input_offset = u16::try_from(self.input.chars().count()).unwrap_or(u16::MAX);
+        let input_scroll = input_offset.saturating_sub(input_width.saturating_sub(1));
+
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(focused(Focus::Input))
+                        .title("Message"),
+                )
+                .scroll((0, input_scroll)),
+            input,
+        );
+
+        if self.focus == Focus::Input {
+            frame.set_cursor_position((input.x + 1 + input_offset - input_scroll, input.y + 1));
+        }
+
+        let text = match (&self.status, &self.pending) {
+            (Some(error), _) => Line::styled(error.clone(), Style::default().fg(Color::Red)),
+            (None, Some(_)) => Line::raw(format!(
+                "Streaming from {}...",
+                self.current.model.clone().unwrap_or_default()
+            )),
+            (None, None) => Line::styled(
+                "Tab: switch pane  Enter: send/open  Ctrl-N: new  PgUp/PgDn: scroll  Esc: quit",
+                Style::default().add_modifier(Modifier::DIM),
+            ),
+        };
+
+        frame.render_widget(Paragraph::new(text), status);
+    }
+
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>) -> Result<()> {
+        while !self.quit {
+            if let Err(e) = self.poll() {
+                self.status = Some(e.to_string());
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(Duration::from_millis(50))? {
+                continue;
+            }
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    if let Err(e) = self.handle_key(key) {
+                        self.status = Some(e.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens the terminal interface: a list of cached conversations, the history of the open one,
+/// and an input box to continue it.
+pub fn run(args: Args, config: Config) -> Result<()> {
+    let mut app = App::new(args, config, tokio::runtime::Handle::current())?;
+
+    enable_raw_mode()?;
+    execute!(std::io::stdout(), EnterAlternateScreen)?;
+
+    let result = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+        .map_err(Error::from)
+        // The interface blocks while it waits for keys, so the worker of the runtime is handed
+        // over to the requests first.
+        .and_then(|mut terminal| tokio::task::block_in_place(|| app.run(&mut terminal)));
+
+    disable_raw_mode()?;
+    execute!(std::io::stdout(), LeaveAlternateScreen)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an interface over a new data directory, waiting for the answer to "Hi", which
+    /// ends with `result`.
+    fn pending_app(dir: &std::path::Path, result: std::result::Result<&str, &str>) -> Result<App> {
+        let args = Args {
+            data_dir: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let mut app = App::new(args, Config::default(), runtime.handle().clone())?;
+
+        let mut request = app.current.clone();
+        request.conversation.push(ConversationMessage {
+            role: ConversationRole::User,
+            content: "Hi".to_string(),
+            ..Default::default()
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        let _ = sender.send(Update::Delta("Hel".to_string()));
+        let _ = sender.send(Update::Done(
+            result
+                .map(|content| (Box::new(request.clone()), content.to_string()))
+                .map_err(String::from),
+        ));
+
+        app.pending = Some((request, String::new()));
+        app.sent = "Hi".to_string();
+        app.receiver = Some(receiver);
+
+        Ok(app)
+    }
+
+    #[test]
+    fn test_poll_caches_the_answer() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-tui-{}", xid::new()));
+        let data_dir = dir.to_string_lossy().to_string();
+
+        let result = pending_app(&dir, Ok("Hello\n")).and_then(|mut app| {
+            app.poll()?;
+            let cached = match &app.id {
+                Some(id) => Some(crate::cache::read(&data_dir, id)?),
+                None => None,
+            };
+            Ok((app, cached))
+        });
+
+        std::fs::remove_dir_all(&dir)?;
+
+        let (app, cached) = result?;
+        let contents = app
+            .current
+            .conversation
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<&str>>();
+
+        assert!(app.pending.is_none());
+        assert!(app.receiver.is_none());
+        assert_eq!(contents, vec!["Hi", "Hello"]);
+        assert_eq!(app.conversations.len(), 1);
+        assert_eq!(app.current.from, app.id);
+        assert_eq!(
+            cached.map(|cached| cached.conversation),
+            Some(app.current.conversation)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_gives_the_prompt_back_when_it_fails(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("llm-stream-tui-{}", xid::new()));
+        let result = pending_app(&dir, Err("overloaded")).and_then(|mut app| {
+            app.poll()?;
+            Ok(app)
+        });
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let app = result?;
+
+        assert!(app.pending.is_none());
+        assert_eq!(app.input, "Hi");
+        assert_eq!(app.status.as_deref(), Some("overloaded"));
+        assert_eq!(app.id, None);
+        assert!(app.current.conversation.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::prelude::*;
+
+/// Times an invalid answer is asked again when `--validate-retries` isn't given.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Returns the message that asks for the answer again, with the errors of the last one.
+pub fn retry_prompt(errors: &str) -> String {
+    format!(
+        "Your answer isn't valid: {}. Answer again with only the corrected JSON.",
+        errors
+    )
+}
+
+/// Reads and compiles the JSON schema at `path`.
+fn read_schema(path: &str) -> Result<JSONSchema> {
+    let invalid = |e: String| Error::InvalidSchema(format!("{}: {}", path, e));
+
+    let contents = std::fs::read_to_string(crate::paths::expand(path)?)?;
+    let schema = serde_json::from_str::<Value>(&contents).map_err(|e| invalid(e.to_string()))?;
+
+    JSONSchema::compile(&schema).map_err(|e| invalid(e.to_string()))
+}
+
+/// Returns the errors of the answer, or `None` if it's JSON that matches the schema. Answers that
+/// are a single fenced code block are checked without the fence.
+fn errors(answer: &str, schema: Option<&JSONSchema>) -> Option<String> {
+    let value = match serde_json::from_str::<Value>(&crate::extract::unwrap_fence(answer)) {
+        Ok(value) => value,
+        Err(e) => return Some(e.to_string()),
+    };
+
+    let Err(errors) = schema?.validate(&value) else {
+        return None;
+    };
+
+    let errors = errors
+        .map(|e| match e.instance_path.to_string() {
+            path if path.is_empty() => e.to_string(),
+            path => format!("{}: {}", path, e),
+        })
+        .collect::<Vec<String>>();
+
+    Some(errors.join("; "))
+}
+
+/// Checks the answer for `--validate-json`, against the schema at `schema` unless it's empty.
+/// Returns the errors, or `None` if the answer is valid.
+pub fn check(answer: &str, schema: &str) -> Result<Option<String>> {
+    let schema = if schema.is_empty() {
+        None
+    } else {
+        Some(read_schema(schema)?)
+    };
+
+    Ok(errors(answer, schema.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let schema = JSONSchema::compile(&serde_json::json!({
+            "type": "object",
+            "required": ["planets"],
+            "properties": {
+                "planets": { "type": "array", "items": { "type": "string" } },
+            },
+        }))
+        .map_err(|e| e.to_string())?;
+
+        assert_eq!(errors("{\"planets\": []}", None), None);
+        assert_eq!(
+            errors("```json\n{\"planets\": [\"Mars\"]}\n```", Some(&schema)),
+            None
+        );
+        assert!(errors("The planets are Mars and Venus.", None).is_some());
+        assert_eq!(
+            errors("{\"planets\": [\"Mars\", 4]}", Some(&schema)),
+            Some("/planets/1: 4 is not of type \"string\"".to_string())
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+/// Returns the column where prose lines are wrapped, if any. Wrapping defaults to the terminal
+/// width.
+pub fn width(wrap: Option<&str>) -> Option<usize> {
+    match wrap {
+        Some("off") => None,
+        None | Some("auto") => crossterm::terminal::size()
+            .ok()
+            .map(|(columns, _)| usize::from(columns)),
+        Some(columns) => columns.parse().ok(),
+    }
+}
+
+/// Returns the indentation used by the continuation lines of `line`, keeping them aligned with
+/// the content of list items and inside block quotes.
+fn continuation(line: &str) -> String {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+    let marker = content.split(' ').next().unwrap_or_default();
+
+    let is_list_item = matches!(marker, "-" | "*" | "+")
+        || (marker.len() > 1
+            && marker.ends_with(['.', ')'])
+            && marker[..marker.len() - 1]
+                .chars()
+                .all(|c| c.is_ascii_digit()));
+
+    if marker == ">" {
+        format!("{indent}> ")
+    } else if is_list_item {
+        format!("{indent}{}", " ".repeat(marker.len() + 1))
+    } else {
+        indent.to_string()
+    }
+}
+
+/// Wraps a single prose line at word boundaries.
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    if line.chars().count() <= width {
+        return vec![line.to_string()];
+    }
+
+    let content = line.trim_start();
+    let continuation = continuation(line);
+
+    let mut lines = Vec::new();
+    let mut current = line[..line.len() - content.len()].to_string();
+    let mut empty = true;
+
+    for word in content.split(' ').filter(|word| !word.is_empty()) {
+        if !empty && current.chars().count() + 1 + word.chars().count() > width {
+            lines.push(current);
+            current = continuation.clone();
+            empty = true;
+        }
+
+        if !empty {
+            current.push(' ');
+        }
+
+        current.push_str(word);
+        empty = false;
+    }
+
+    lines.push(current);
+    lines
+}
+
+/// Wraps the prose lines of a Markdown document, one line at a time, at `width` columns. Code
+/// blocks and tables are left untouched.
+#[derive(Debug, Clone)]
+pub struct Wrapper {
+    width: usize,
+    /// Fence of the code block the last line was in, if any.
+    fence: Option<String>,
+}
+
+impl Wrapper {
+    pub fn new(width: usize) -> Self {
+        Self { width, fence: None }
+    }
+
+    /// Wraps a single line, without its line ending.
+    pub fn wrap(&mut self, line: &str) -> Vec<String> {
+        let content = line.trim_start();
+
+        if let Some(marker) = &self.fence {
+            if content.starts_with(marker.as_str()) {
+                self.fence = None;
+            }
+            return vec![line.to_string()];
+        }
+
+        if content.starts_with("```") || content.starts_with("~~~") {
+            let first = content.chars().next().unwrap_or_default();
+            self.fence = Some(content.chars().take_while(|c| *c == first).collect());
+            return vec![line.to_string()];
+        }
+
+        if content.starts_with('|') || line.starts_with("    ") || line.starts_with('\t') {
+            return vec![line.to_string()];
+        }
+
+        wrap_line(line, self.width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapper_aligns_list_items() {
+        let mut wrapper = Wrapper::new(12);
+
+        assert_eq!(
+            wrapper.wrap("- one two three four five"),
+            vec!["- one two", "  three four", "  five"]
+        );
+        assert_eq!(wrapper.wrap("short"), vec!["short"]);
+    }
+
+    #[test]
+    fn test_wrapper_skips_code_blocks() {
+        let mut wrapper = Wrapper::new(20);
+        let line = "let value = some_function(first_argument, second_argument);";
+
+        wrapper.wrap("```rust");
+
+        assert_eq!(wrapper.wrap(line), vec![line]);
+        assert_eq!(wrapper.wrap("```"), vec!["```"]);
+        assert_eq!(
+            wrapper.wrap("after the code block ends"),
+            vec!["after the code block", "ends"]
+        );
+    }
+}
@@ -12,6 +12,7 @@ async fn main() -> Result<()> {
     let messages = vec![Message {
         role: Role::User,
         content: "What is the capital of the United States?".to_string(),
+        images: None,
     }];
 
     let body = MessageBody::new("llama3.2:latest", messages);
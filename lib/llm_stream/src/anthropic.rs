@@ -1,13 +1,16 @@
-use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions, SSE};
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
 use futures::stream::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::Duration;
 
-use crate::error::Error;
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
 
 // Messages API
 const MESSAGES_CREATE: &str = "/messages";
+const MESSAGES_COUNT_TOKENS: &str = "/messages/count_tokens";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Usage {
@@ -82,6 +85,23 @@ impl MessageBody {
     }
 }
 
+/// Body of a `count_tokens` request. The endpoint rejects the generation parameters of
+/// `MessageBody`, like `max_tokens`, so only the messages and the system prompt are sent.
+#[derive(Debug, Serialize)]
+struct CountTokensBody<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+}
+
+/// Tokens counted by the `count_tokens` endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenCount {
+    /// The total number of tokens across the messages and the system prompt.
+    pub input_tokens: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageResponse {
     /// Unique object identifier.
@@ -102,8 +122,9 @@ pub struct MessageResponse {
     pub usage: Usage,
 }
 
+/// Message sent in the `message_start` event, before its content.
 #[derive(Debug, Serialize, Deserialize)]
-struct MessageEventResponse {
+pub struct MessageEventResponse {
     /// Unique object identifier.
     pub id: String,
     /// Object type.
@@ -122,19 +143,23 @@ struct MessageEventResponse {
     pub usage: Usage,
 }
 
+/// Delta of a `content_block_delta` or `message_delta` event.
 #[derive(Debug, Serialize, Deserialize)]
-struct Delta {
+pub struct Delta {
     /// Determines the content shape.
     pub r#type: Option<String>,
     /// Response content
     pub text: Option<String>,
+    /// The reason that the model stopped, in the `message_delta` event.
     pub stop_reason: Option<String>,
+    /// Which custom stop sequence was generated, if any.
+    pub stop_sequence: Option<String>,
     pub end_turn: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
-enum MessageEventType {
+pub enum MessageEventType {
     #[default]
     Error,
     MessageStart,
@@ -147,8 +172,18 @@ enum MessageEventType {
     Comment,
 }
 
+/// Error sent in an `error` event.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventError {
+    /// Error type, like `overloaded_error`.
+    pub r#type: String,
+    /// Error message.
+    pub message: String,
+}
+
+/// Event of a streamed response.
 #[derive(Debug, Serialize, Deserialize, Default)]
-struct MessageEvent {
+pub struct MessageEvent {
     /// Event type
     pub r#type: MessageEventType,
     /// Init message
@@ -163,6 +198,8 @@ struct MessageEvent {
     pub usage: Option<Usage>,
     /// Comment
     pub comment: Option<String>,
+    /// Error of an `error` event
+    pub error: Option<EventError>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -191,6 +228,11 @@ impl Auth {
 pub struct Client {
     pub auth: Auth,
     pub api_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    pub query: Vec<(String, String)>,
+    pub hooks: Hooks,
 }
 
 impl Client {
@@ -198,69 +240,176 @@ impl Client {
         Self {
             auth,
             api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
         }
     }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
 }
 
 impl Client {
-    pub fn delta<'a>(
-        &'a self,
-        message_body: &'a MessageBody,
-    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+    /// Returns the headers sent with every request.
+    fn request_headers(&self) -> Vec<(String, String)> {
+        let anthropic_version = self.auth.version.as_deref().unwrap_or("2023-06-01");
+
+        let mut headers = vec![
+            (
+                "anthropic-version".to_string(),
+                anthropic_version.to_string(),
+            ),
+            ("content-type".to_string(), "application/json".to_string()),
+            ("x-api-key".to_string(), self.auth.api_key.clone()),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        headers
+    }
+
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
         log::debug!("message_body: {:#?}", message_body);
 
-        let request_body = match serde_json::to_value(message_body) {
-            Ok(body) => body,
-            Err(e) => return Err(Error::Serde(e)),
+        Request::post(
+            with_query(self.api_url.clone() + MESSAGES_CREATE, &self.query),
+            self.request_headers(),
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Counts the tokens of the messages and the system prompt of `message_body`, without
+    /// creating a message. The `on_request` hooks don't run over this request.
+    pub fn count_tokens(&self, message_body: &MessageBody) -> Result<TokenCount, Error> {
+        let body = CountTokensBody {
+            model: &message_body.model,
+            messages: &message_body.messages,
+            system: message_body.system.as_deref(),
         };
-        log::debug!("request_body: {:#?}", request_body);
+        log::debug!("count_tokens_body: {:#?}", body);
 
-        let anthropic_version = self.auth.version.as_deref().unwrap_or("2023-06-01");
+        Request {
+            method: "POST".to_string(),
+            url: with_query(self.api_url.clone() + MESSAGES_COUNT_TOKENS, &self.query),
+            headers: self.request_headers(),
+            body: serde_json::to_value(&body)?,
+        }
+        .send()
+    }
 
-        let client = ClientBuilder::for_url(&(self.api_url.clone() + MESSAGES_CREATE))?
-            .header("anthropic-version", anthropic_version)?
-            .header("content-type", "application/json")?
-            .header("x-api-key", &self.auth.api_key)?
-            .method("POST".into())
-            .body(request_body.to_string())
-            .reconnect(
-                ReconnectOptions::reconnect(true)
-                    .retry_initial(false)
-                    .delay(Duration::from_secs(1))
-                    .backoff_factor(2)
-                    .delay_max(Duration::from_secs(60))
-                    .build(),
-            )
-            .build();
+    /// Streams the events of the response, with their stop reasons, indices, and usage. `error`
+    /// events end the stream with an `Error::StreamError`.
+    pub fn events<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<MessageEvent, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
+
+        let hooks = &self.hooks;
 
         let stream = Box::pin(client.stream())
-            .map_err(Error::from)
-            .map_ok(|event| match event {
-                SSE::Connected(_) => String::default(),
-                SSE::Event(ev) => match serde_json::from_str::<MessageEvent>(&ev.data) {
-                    Ok(ev) => {
-                        if matches!(ev.r#type, MessageEventType::ContentBlockDelta) {
-                            if let Some(delta) = ev.delta {
-                                delta.text.map_or_else(String::default, |text| text)
-                            } else {
-                                String::default()
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
+                }
+            })
+            .try_filter_map(move |event| {
+                future::ready(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        Ok(None)
+                    }
+                    SSE::Event(ev) => {
+                        hooks.event(&ev.data);
+                        match serde_json::from_str::<MessageEvent>(&ev.data) {
+                            Ok(MessageEvent {
+                                r#type: MessageEventType::Error,
+                                error: Some(error),
+                                ..
+                            }) => Err(Error::StreamError {
+                                kind: error.r#type,
+                                message: error.message,
+                            }),
+                            Ok(event) => Ok(Some(event)),
+                            Err(e) => {
+                                log::error!("Error parsing event: {:#?}", ev);
+                                log::error!("Error: {:#?}", e);
+                                Ok(None)
                             }
-                        } else {
-                            String::default()
                         }
                     }
-                    Err(e) => {
-                        log::error!("Error parsing event: {:#?}", ev);
-                        log::error!("Error: {:#?}", e);
-                        String::default()
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        Ok(None)
                     }
-                },
-                SSE::Comment(comment) => {
-                    log::debug!("Comment: {:#?}", comment);
-                    String::default()
-                }
+                })
             });
 
         Ok(stream)
     }
+
+    /// Streams the text of the response.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|event| match event {
+            MessageEvent {
+                r#type: MessageEventType::ContentBlockDelta,
+                delta: Some(delta),
+                ..
+            } => delta.text.unwrap_or_default(),
+            _ => String::default(),
+        });
+
+        Ok(stream)
+    }
 }
@@ -14,6 +14,16 @@ pub enum Error {
     /// An Error returned by the API
     #[error("API Error: {0}")]
     ApiError(String),
+    /// An error response of the API, with its status, body, and rate limit headers.
+    #[error("API Error: {status}: {body}")]
+    Response {
+        status: u16,
+        body: String,
+        headers: Vec<(String, String)>,
+    },
+    /// An error event sent by the API in the middle of the stream, like `overloaded_error`.
+    #[error("API Error: {kind}: {message}")]
+    StreamError { kind: String, message: String },
     /// An Error not related to the API
     #[error("Request Error: {0}")]
     RequestError(String),
@@ -24,3 +34,71 @@ pub enum Error {
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
 }
+
+/// Messages the APIs use to reject requests that don't fit in the context window of the model.
+const CONTEXT_LENGTH_MESSAGES: &[&str] = &[
+    "context_length_exceeded",
+    "prompt is too long",
+    "exceeds the maximum number of tokens",
+    "maximum context length",
+];
+
+/// Converts the errors of the SSE stream, reading the body of unexpected responses so the message
+/// of the API is part of the error.
+pub(crate) async fn from_stream<T>(e: eventsource_client::Error) -> Result<T, Error> {
+    match e {
+        eventsource_client::Error::UnexpectedResponse(response, body) => {
+            let body = match body.body_bytes().await {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+                Err(e) => {
+                    log::warn!("unable to read the error response: {}", e);
+                    String::default()
+                }
+            };
+
+            Err(Error::Response {
+                status: response.status(),
+                body,
+                headers: crate::headers::collect(&response),
+            })
+        }
+        e => Err(Error::EventsourceClient(e)),
+    }
+}
+
+impl Error {
+    /// Returns true if the API rejected the request because it doesn't fit in the context window
+    /// of the model.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        match self {
+            Error::Response { status, body, .. } if *status == 400 || *status == 413 => {
+                let body = body.to_lowercase();
+                CONTEXT_LENGTH_MESSAGES.iter().any(|m| body.contains(m))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the request can be sent again after a while, like when the API is
+    /// overloaded.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::StreamError { kind, .. } => matches!(
+                kind.as_str(),
+                "overloaded_error" | "api_error" | "rate_limit_error"
+            ),
+            Error::Response { status, .. } => {
+                matches!(status, 408 | 429 | 500 | 502 | 503 | 504 | 529)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the time the API asked to wait before sending the request again.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::Response { headers, .. } => crate::headers::retry_after(headers),
+            _ => None,
+        }
+    }
+}
@@ -1,13 +1,16 @@
-use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions, SSE};
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
 use futures::stream::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
-use crate::error::Error;
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
 
 // Chat Completions Api
-const STREAM_GENERATE_CONTENT_TEMPLATE: &str =
-    "/models/{{model}}:streamGenerateContent?alt=sse&key={{key}}";
+const STREAM_GENERATE_CONTENT_TEMPLATE: &str = "/models/{{model}}:streamGenerateContent?alt=sse";
+const COUNT_TOKENS_TEMPLATE: &str = "/models/{{model}}:countTokens";
+const GET_MODEL_TEMPLATE: &str = "/models/{{model}}";
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "lowercase")]
@@ -101,13 +104,84 @@ pub struct MessageBody {
     pub generation_config: Option<GenerationConfig>,
 }
 
+/// Safety rating of a candidate, for one harm category.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SafetyRating {
+    /// The category for this rating, like `HARM_CATEGORY_HARASSMENT`.
+    pub category: String,
+    /// The probability of harm for this content, like `NEGLIGIBLE`.
+    pub probability: String,
+    /// Was this content blocked because of this rating?
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+/// A response candidate generated from the model.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Candidate {
+    /// Generated content returned from the model. Candidates blocked for safety have none.
+    #[serde(default)]
     pub content: Content,
+    /// The reason why the model stopped generating tokens, like `STOP` or `SAFETY`.
+    pub finish_reason: Option<String>,
+    /// List of ratings for the safety of a response candidate.
+    #[serde(default)]
+    pub safety_ratings: Vec<SafetyRating>,
+    /// Index of the candidate in the list of response candidates.
+    #[serde(default)]
+    pub index: u32,
+}
+
+/// Body of a `countTokens` request, with the contents of a `MessageBody`.
+#[derive(Debug, Serialize)]
+struct CountTokensBody<'a> {
+    contents: &'a [Content],
+}
+
+/// Tokens counted by the `countTokens` endpoint.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenCount {
+    /// The number of tokens that the model tokenizes the prompt into.
+    pub total_tokens: u32,
 }
 
+/// Information about a Generative Language Model.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Model {
+    /// The resource name of the Model, like `models/gemini-1.5-pro`.
+    pub name: String,
+    /// The version number of the model.
+    pub version: String,
+    /// The human-readable name of the model.
+    pub display_name: String,
+    /// A short description of the model.
+    pub description: String,
+    /// Maximum number of input tokens allowed for this model.
+    pub input_token_limit: u32,
+    /// Maximum number of output tokens available for this model.
+    pub output_token_limit: u32,
+    /// The model's supported generation methods, like `generateContent` or `countTokens`.
+    pub supported_generation_methods: Vec<String>,
+    /// Controls the randomness of the output, used by default.
+    pub temperature: Option<f32>,
+    /// The maximum temperature this model can use.
+    pub max_temperature: Option<f32>,
+    /// For Nucleus sampling, used by default.
+    pub top_p: Option<f32>,
+    /// For Top-k sampling, used by default.
+    pub top_k: Option<u32>,
+}
+
+/// Streamed chunk of a response.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Root {
+    /// Candidate responses from the model. Empty when the prompt was blocked.
+    #[serde(default)]
     pub candidates: Vec<Candidate>,
 }
 
@@ -147,6 +221,11 @@ impl Auth {
 pub struct Client {
     pub auth: Auth,
     pub api_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    pub query: Vec<(String, String)>,
+    pub hooks: Hooks,
 }
 
 impl Client {
@@ -154,62 +233,172 @@ impl Client {
         Self {
             auth,
             api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
         }
     }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
 }
 
 impl Client {
-    pub fn delta<'a>(
-        &'a self,
-        message_body: &'a MessageBody,
-    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
         log::debug!("message_body: {:#?}", message_body);
 
-        let request_body = match serde_json::to_value(message_body) {
-            Ok(body) => body,
-            Err(e) => return Err(Error::Serde(e)),
+        Request::post(
+            self.url(STREAM_GENERATE_CONTENT_TEMPLATE, &message_body.model),
+            self.request_headers(),
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Returns the url of an endpoint of `model`, from its template.
+    fn url(&self, template: &str, model: &str) -> String {
+        let sub_url = template.replace("{{model}}", model);
+        with_query(self.api_url.clone() + &sub_url, &self.query)
+    }
+
+    /// Returns the headers sent with every request. The key goes in a header, so it never shows
+    /// up in the url of a request, or in its errors.
+    fn request_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("x-goog-api-key".to_string(), self.auth.api_key.clone()),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        headers
+    }
+
+    /// Counts the tokens of the contents of `message_body`, without generating a response. The
+    /// `on_request` hooks don't run over this request.
+    pub fn count_tokens(&self, message_body: &MessageBody) -> Result<TokenCount, Error> {
+        let body = CountTokensBody {
+            contents: &message_body.contents,
         };
-        log::debug!("request_body: {:#?}", request_body);
-
-        let sub_url =
-            STREAM_GENERATE_CONTENT_TEMPLATE.replace("{{model}}", message_body.model.as_str());
-        let url = &(self.api_url.clone() + &sub_url);
-        let url = url.replace("{{key}}", &self.auth.api_key);
-
-        let client = ClientBuilder::for_url(&url)?
-            .header("content-type", "application/json")?
-            .method("POST".into())
-            .body(request_body.to_string())
-            .reconnect(
-                ReconnectOptions::reconnect(true)
-                    .retry_initial(false)
-                    .delay(Duration::from_secs(1))
-                    .backoff_factor(2)
-                    .delay_max(Duration::from_secs(60))
-                    .build(),
-            )
-            .build();
+
+        Request {
+            method: "POST".to_string(),
+            url: self.url(COUNT_TOKENS_TEMPLATE, &message_body.model),
+            headers: self.request_headers(),
+            body: serde_json::to_value(&body)?,
+        }
+        .send()
+    }
+
+    /// Returns the information of `model`, like its context window and supported methods.
+    pub fn get_model(&self, model: &str) -> Result<Model, Error> {
+        let model = model.trim_start_matches("models/");
+
+        Request {
+            method: "GET".to_string(),
+            url: self.url(GET_MODEL_TEMPLATE, model),
+            headers: self.request_headers(),
+            body: serde_json::Value::Null,
+        }
+        .send()
+    }
+
+    /// Streams the chunks of the response, with the finish reasons and safety ratings of their
+    /// candidates.
+    pub fn events<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<Root, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
+
+        let hooks = &self.hooks;
 
         let stream = Box::pin(client.stream())
-            .map_err(Error::from)
-            .map_ok(|event| match event {
-                SSE::Connected(_) => String::default(),
-                SSE::Event(ev) => match serde_json::from_str::<Root>(&ev.data) {
-                    Ok(root) => {
-                        if root.candidates[0].content.parts.is_empty() {
-                            String::default()
-                        } else {
-                            root.candidates[0].content.parts[0].text.clone()
-                        }
-                    }
-                    Err(_) => String::default(),
-                },
-                SSE::Comment(comment) => {
-                    log::debug!("Comment: {:#?}", comment);
-                    String::default()
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
                 }
+            })
+            .try_filter_map(move |event| {
+                future::ok(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        None
+                    }
+                    SSE::Event(ev) => {
+                        hooks.event(&ev.data);
+                        serde_json::from_str::<Root>(&ev.data).ok()
+                    }
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        None
+                    }
+                })
             });
 
         Ok(stream)
     }
+
+    /// Streams the text of the first candidate of the response.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|root| {
+            root.candidates
+                .into_iter()
+                .next()
+                .and_then(|candidate| candidate.content.parts.into_iter().next())
+                .map(|part| part.text)
+                .unwrap_or_default()
+        });
+
+        Ok(stream)
+    }
 }
@@ -0,0 +1,62 @@
+use std::time::{Duration, SystemTime};
+
+/// Returns true if `name` is a rate limit header, like `retry-after`,
+/// `x-ratelimit-remaining-tokens`, or `anthropic-ratelimit-requests-remaining`.
+pub fn is_rate_limit(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.starts_with("retry-after") || name.contains("ratelimit")
+}
+
+/// Returns the rate limit headers of a response, with their names in lowercase.
+pub(crate) fn collect(response: &eventsource_client::Response) -> Vec<(String, String)> {
+    response
+        .get_header_keys()
+        .into_iter()
+        .filter(|name| is_rate_limit(name))
+        .filter_map(|name| {
+            let value = response.get_header_value(name).ok().flatten()?;
+            Some((name.to_lowercase(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the rate limit headers of a response to a request that doesn't stream, with their
+/// names in lowercase.
+pub(crate) fn collect_ureq(response: &ureq::Response) -> Vec<(String, String)> {
+    response
+        .headers_names()
+        .into_iter()
+        .filter(|name| is_rate_limit(name))
+        .filter_map(|name| {
+            let value = response.header(&name)?;
+            Some((name.to_lowercase(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the time to wait before sending the request again, from the `retry-after-ms` or
+/// `retry-after` header, either in seconds or as an HTTP date.
+pub fn retry_after(headers: &[(String, String)]) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.trim())
+    };
+    let value = |name: &str| {
+        header(name)
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|value| value.is_finite() && *value >= 0.0)
+    };
+
+    value("retry-after-ms")
+        .map(|ms| Duration::from_secs_f64(ms / 1000.0))
+        .or_else(|| value("retry-after").map(Duration::from_secs_f64))
+        .or_else(|| {
+            let date = httpdate::parse_http_date(header("retry-after")?).ok()?;
+            Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        })
+}
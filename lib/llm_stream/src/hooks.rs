@@ -0,0 +1,93 @@
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Hook called with the serialized request body before it's sent. It can mutate the body.
+pub type RequestHook = Arc<dyn Fn(&mut Value) + Send + Sync>;
+
+/// Hook called with the raw `data` payload of every server-sent event.
+pub type EventHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Hook called with the rate limit headers of the response, once connected.
+pub type HeadersHook = Arc<dyn Fn(&[(String, String)]) + Send + Sync>;
+
+/// Hook called once the stream reaches its end.
+pub type CompleteHook = Arc<dyn Fn() + Send + Sync>;
+
+/// Set of interceptors shared by all the provider clients.
+///
+/// Hooks run in the order they were registered.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_request: Vec<RequestHook>,
+    on_event: Vec<EventHook>,
+    on_headers: Vec<HeadersHook>,
+    on_complete: Vec<CompleteHook>,
+}
+
+impl Hooks {
+    /// Registers a hook that can inspect or mutate the request body.
+    pub fn on_request(&mut self, hook: impl Fn(&mut Value) + Send + Sync + 'static) -> &mut Self {
+        self.on_request.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook that receives the raw data of each streamed event.
+    pub fn on_event(&mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> &mut Self {
+        self.on_event.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    pub fn on_headers(
+        &mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.on_headers.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook that runs when the stream finishes.
+    pub fn on_complete(&mut self, hook: impl Fn() + Send + Sync + 'static) -> &mut Self {
+        self.on_complete.push(Arc::new(hook));
+        self
+    }
+
+    /// Runs the `on_request` hooks over the request body.
+    pub fn request(&self, body: &mut Value) {
+        for hook in &self.on_request {
+            hook(body);
+        }
+    }
+
+    /// Runs the `on_event` hooks over the raw event data.
+    pub fn event(&self, data: &str) {
+        for hook in &self.on_event {
+            hook(data);
+        }
+    }
+
+    /// Runs the `on_headers` hooks over the rate limit headers.
+    pub fn headers(&self, headers: &[(String, String)]) {
+        for hook in &self.on_headers {
+            hook(headers);
+        }
+    }
+
+    /// Runs the `on_complete` hooks.
+    pub fn complete(&self) {
+        for hook in &self.on_complete {
+            hook();
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_request", &self.on_request.len())
+            .field("on_event", &self.on_event.len())
+            .field("on_headers", &self.on_headers.len())
+            .field("on_complete", &self.on_complete.len())
+            .finish()
+    }
+}
@@ -1,7 +1,11 @@
 pub mod anthropic;
 pub mod error;
 pub mod google;
+pub mod headers;
+pub mod hooks;
 pub mod mistral;
 pub mod mistral_fim;
 pub mod ollama;
+pub mod ollama_fim;
 pub mod openai;
+pub mod request;
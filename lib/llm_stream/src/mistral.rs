@@ -1,9 +1,11 @@
-use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions, SSE};
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
 use futures::stream::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
-use crate::error::Error;
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
 
 // Chat Completion API
 const CHAT_API: &str = "/chat/completions";
@@ -119,6 +121,11 @@ impl Auth {
 pub struct Client {
     pub auth: Auth,
     pub api_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    pub query: Vec<(String, String)>,
+    pub hooks: Hooks,
 }
 
 impl Client {
@@ -126,60 +133,134 @@ impl Client {
         Self {
             auth,
             api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
         }
     }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
 }
 
 impl Client {
-    pub fn delta<'a>(
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
+        log::debug!("message_body: {:#?}", message_body);
+
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            (
+                "authorization".to_string(),
+                format!("Bearer {}", self.auth.api_key),
+            ),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        Request::post(
+            with_query(self.api_url.clone() + CHAT_API, &self.query),
+            headers,
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Streams the chunks of the response, with the roles, indices, and finish reasons of their
+    /// choices.
+    pub fn events<'a>(
         &'a self,
         message_body: &'a MessageBody,
-    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
-        log::debug!("message_body: {:#?}", message_body);
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunk, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
 
-        let request_body = match serde_json::to_value(message_body) {
-            Ok(body) => body,
-            Err(e) => return Err(Error::Serde(e)),
-        };
-        log::debug!("request_body: {:#?}", request_body);
-
-        let authorization: &str = &format!("Bearer {}", self.auth.api_key);
-
-        let client = ClientBuilder::for_url(&(self.api_url.clone() + CHAT_API))?
-            .header("content-type", "application/json")?
-            .header("authorization", authorization)?
-            .method("POST".into())
-            .body(request_body.to_string())
-            .reconnect(
-                ReconnectOptions::reconnect(true)
-                    .retry_initial(false)
-                    .delay(Duration::from_secs(1))
-                    .backoff_factor(2)
-                    .delay_max(Duration::from_secs(60))
-                    .build(),
-            )
-            .build();
+        let hooks = &self.hooks;
 
         let stream = Box::pin(client.stream())
-            .map_err(Error::from)
-            .map_ok(|event| match event {
-                SSE::Connected(_) => String::default(),
-                SSE::Event(ev) => match serde_json::from_str::<ChatCompletionChunk>(&ev.data) {
-                    Ok(chunk) => {
-                        if chunk.choices.is_empty() {
-                            String::default()
-                        } else {
-                            chunk.choices.first().unwrap().delta.content.clone()
-                        }
-                    }
-                    Err(_) => String::default(),
-                },
-                SSE::Comment(comment) => {
-                    log::debug!("Comment: {:#?}", comment);
-                    String::default()
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
                 }
+            })
+            .try_filter_map(move |event| {
+                future::ok(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        None
+                    }
+                    SSE::Event(ev) => {
+                        hooks.event(&ev.data);
+                        serde_json::from_str::<ChatCompletionChunk>(&ev.data).ok()
+                    }
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        None
+                    }
+                })
             });
 
         Ok(stream)
     }
+
+    /// Streams the text of the first choice of the response.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|chunk| {
+            chunk
+                .choices
+                .into_iter()
+                .next()
+                .map(|choice| choice.delta.content)
+                .unwrap_or_default()
+        });
+
+        Ok(stream)
+    }
 }
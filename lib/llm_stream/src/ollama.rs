@@ -1,9 +1,11 @@
-use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions, SSE};
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
 use futures::stream::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
-use crate::error::Error;
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
 
 // Completion API
 const CHAT_API: &str = "/api/chat";
@@ -12,6 +14,9 @@ const CHAT_API: &str = "/api/chat";
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// A list of base64-encoded images, for multimodal models such as `llava`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,6 +36,15 @@ pub struct ChatCompletionChunk {
     pub message: Option<Message>,
     /// Flag that indicates that the stream is finished.
     pub done: bool,
+    /// The reason the stream finished, like `stop` or `length`, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    /// Number of tokens in the prompt, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_eval_count: Option<u32>,
+    /// Number of tokens in the response, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eval_count: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -46,6 +60,13 @@ pub struct MessageBody {
     pub options: Option<MessageBodyOptions>,
     /// The messages of the chat, this can be used to keep a chat memory.
     pub messages: Vec<Message>,
+    /// The format to return a response in. Currently the only accepted value is `json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Controls how long the model will stay loaded into memory following the request, like `5m`.
+    /// (Default 5m)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -69,6 +90,17 @@ pub struct MessageBodyOptions {
     /// 0.9)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    /// Sets the size of the context window used to generate the next token. (Default 2048)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    /// Maximum number of tokens to predict when generating text. (Default -1, infinite
+    /// generation)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i32>,
+    /// Sets the random number seed to use for generation. Setting this to a specific number will
+    /// make the model generate the same text for the same prompt. (Default 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
 }
 
 impl MessageBody {
@@ -87,6 +119,14 @@ impl MessageBody {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Client {
     pub api_url: String,
+    /// Extra headers sent with every request.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    #[serde(default)]
+    pub query: Vec<(String, String)>,
+    #[serde(skip)]
+    pub hooks: Hooks,
 }
 
 impl Client {
@@ -94,61 +134,129 @@ impl Client {
     pub fn new(api_url: impl Into<String>) -> Self {
         Self {
             api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
         }
     }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
 }
 
 impl Client {
-    pub fn delta<'a>(
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
+        log::debug!("message_body: {:#?}", message_body);
+
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("Accept".to_string(), "application/x-ndjson".to_string()),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        Request::post(
+            with_query(self.api_url.clone() + CHAT_API, &self.query),
+            headers,
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Streams the chunks of the response, with their messages and the reason it finished.
+    pub fn events<'a>(
         &'a self,
         message_body: &'a MessageBody,
-    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
-        log::debug!("message_body: {:#?}", message_body);
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunk, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
 
-        let request_body = match serde_json::to_value(message_body) {
-            Ok(body) => body,
-            Err(e) => return Err(Error::Serde(e)),
-        };
-        log::debug!("request_body: {:#?}", request_body);
-
-        let client = ClientBuilder::for_url(&(self.api_url.clone() + CHAT_API))?
-            .header("content-type", "application/json")?
-            .header("Accept", "application/x-ndjson")?
-            .method("POST".into())
-            .body(request_body.to_string())
-            .reconnect(
-                ReconnectOptions::reconnect(true)
-                    .retry_initial(false)
-                    .delay(Duration::from_secs(1))
-                    .backoff_factor(2)
-                    .delay_max(Duration::from_secs(60))
-                    .build(),
-            )
-            .build();
+        let hooks = &self.hooks;
 
         let stream = Box::pin(client.stream())
-            .map_err(Error::from)
-            .map_ok(|event| match event {
-                SSE::Connected(_) => String::default(),
-                SSE::Event(ev) => {
-                    log::info!("{:#?}", ev);
-                    match serde_json::from_str::<ChatCompletionChunk>(&ev.data) {
-                        Ok(chunk) => {
-                            if chunk.message.is_none() {
-                                String::default()
-                            } else {
-                                chunk.message.unwrap().content.clone()
-                            }
-                        }
-                        Err(_) => String::default(),
-                    }
-                }
-                SSE::Comment(comment) => {
-                    log::debug!("Comment: {:#?}", comment);
-                    String::default()
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
                 }
+            })
+            .try_filter_map(move |event| {
+                future::ok(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        None
+                    }
+                    SSE::Event(ev) => {
+                        log::info!("{:#?}", ev);
+                        hooks.event(&ev.data);
+                        serde_json::from_str::<ChatCompletionChunk>(&ev.data).ok()
+                    }
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        None
+                    }
+                })
             });
 
         Ok(stream)
     }
+
+    /// Streams the text of the response.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|chunk| {
+            chunk
+                .message
+                .map(|message| message.content)
+                .unwrap_or_default()
+        });
+
+        Ok(stream)
+    }
 }
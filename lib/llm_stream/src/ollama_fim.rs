@@ -0,0 +1,215 @@
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
+use futures::stream::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
+
+// Generate a completion API
+const GENERATE_API: &str = "/api/generate";
+
+pub use crate::ollama::MessageBodyOptions;
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct MessageBody {
+    /// The model name.
+    pub model: String,
+    /// The prompt to generate a response for.
+    pub prompt: String,
+    /// The text after the model response. When given a prompt and a suffix the model will fill
+    /// what is between them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    /// If `true` no formatting will be applied to the prompt. You may choose to use the `raw`
+    /// parameter if you are specifying a full templated prompt in your request to the API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+    /// If `false` the response will be returned as a single response object, rather than a stream
+    /// of objects.
+    pub stream: bool,
+    /// The format to return a response in. Currently the only accepted value is `json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Controls how long the model will stay loaded into memory following the request, like `5m`.
+    /// (Default 5m)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+    /// Additional model parameters listed in the documentation for the Modelfile such as
+    /// `temperature`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<MessageBodyOptions>,
+}
+
+impl MessageBody {
+    /// Creates a new `MessageBody`. Prompts with a suffix are sent as they are, without the
+    /// template of the model, since the template of most models has no room for the suffix.
+    #[must_use]
+    pub fn new(model: &str, prompt: String, suffix: Option<String>) -> Self {
+        Self {
+            model: model.into(),
+            prompt,
+            raw: suffix.is_some().then_some(true),
+            suffix,
+            stream: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct GenerateChunk {
+    /// The model name.
+    pub model: String,
+    /// The generated text of the chunk. Empty in the last chunk.
+    #[serde(default)]
+    pub response: String,
+    /// Flag that indicates that the stream is finished.
+    pub done: bool,
+    /// The reason the stream finished, like `stop` or `length`, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    /// Number of tokens in the prompt, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_eval_count: Option<u32>,
+    /// Number of tokens in the response, sent in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eval_count: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub api_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    pub query: Vec<(String, String)>,
+    pub hooks: Hooks,
+}
+
+impl Client {
+    #[must_use]
+    pub fn new(api_url: impl Into<String>) -> Self {
+        Self {
+            api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
+}
+
+impl Client {
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
+        log::debug!("message_body: {:#?}", message_body);
+
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("Accept".to_string(), "application/x-ndjson".to_string()),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        Request::post(
+            with_query(self.api_url.clone() + GENERATE_API, &self.query),
+            headers,
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Streams the chunks of the completion, with the reason it finished.
+    pub fn events<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<GenerateChunk, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
+
+        let hooks = &self.hooks;
+
+        let stream = Box::pin(client.stream())
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
+                }
+            })
+            .try_filter_map(move |event| {
+                future::ok(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        None
+                    }
+                    SSE::Event(ev) => {
+                        hooks.event(&ev.data);
+                        serde_json::from_str::<GenerateChunk>(&ev.data).ok()
+                    }
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        None
+                    }
+                })
+            });
+
+        Ok(stream)
+    }
+
+    /// Streams the text of the completion.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|chunk| chunk.response);
+
+        Ok(stream)
+    }
+}
@@ -1,10 +1,12 @@
-use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions, SSE};
+use eventsource_client::{Client as EsClient, SSE};
+use futures::future;
 use futures::stream::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::Duration;
 
-use crate::error::Error;
+use crate::error::{Error, EventsourceError};
+use crate::hooks::Hooks;
+use crate::request::{with_query, Request};
 
 // Chat Completions Api
 const CHAT_API: &str = "/chat/completions";
@@ -23,6 +25,13 @@ pub struct Message {
     pub content: String,
 }
 
+/// Options for streaming response.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct StreamOptions {
+    /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage field on this chunk shows the token usage statistics for the entire request, and the choices field will always be an empty array.
+    pub include_usage: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct MessageBody {
     /// ID of the model to use. See the model endpoint compatibility table for details on which models work with the Chat API.
@@ -76,6 +85,18 @@ pub struct MessageBody {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
 
+    /// Options for streaming response. Only set this when you set stream: true.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+
+    /// Specifies the latency tier to use for processing the request, like `auto` or `default`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+
+    /// Whether to enable parallel function calling during tool use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the output more random, while lower values like 0.2 will make it more focused and deterministic.
     ///
     /// We generally recommend altering this or top_p but not both.
@@ -109,22 +130,59 @@ impl MessageBody {
 /// A chat completion delta generated by the streamed model responses.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ChatCompletionChunkChoiceDelta {
+    /// The role of the author of this message, sent in the first chunk.
+    pub role: Option<String>,
     /// The contents of the chunk message.
-    content: Option<String>,
+    pub content: Option<String>,
+    /// The refusal message generated by the model.
+    pub refusal: Option<String>,
 }
 
 /// Represents a content choice of a streamed chunk of a chat completion response returned by model, based on the provided input.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ChatCompletionChunkChoice {
+    /// The index of the choice in the list of choices.
+    #[serde(default)]
+    pub index: u32,
     /// A chat completion delta generated by the streamed model responses.
-    delta: ChatCompletionChunkChoiceDelta,
+    pub delta: ChatCompletionChunkChoiceDelta,
+    /// The reason the model stopped generating tokens, like `stop` or `length`, sent in the last chunk of the choice.
+    pub finish_reason: Option<String>,
+    /// Log probability information for the choice.
+    pub logprobs: Option<serde_json::Value>,
+}
+
+/// Usage statistics for the completion request.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Usage {
+    /// Number of tokens in the prompt.
+    pub prompt_tokens: u32,
+    /// Number of tokens in the generated completion.
+    pub completion_tokens: u32,
+    /// Total number of tokens used in the request (prompt + completion).
+    pub total_tokens: u32,
 }
 
 /// Represents a streamed chunk of a chat completion response returned by model, based on the provided input.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ChatCompletionChunk {
+    /// A unique identifier for the chat completion. Each chunk has the same ID.
+    #[serde(default)]
+    pub id: String,
+    /// The Unix timestamp (in seconds) of when the chat completion was created. Each chunk has the same timestamp.
+    #[serde(default)]
+    pub created: u64,
+    /// The model to generate the completion.
+    #[serde(default)]
+    pub model: String,
+    /// This fingerprint represents the backend configuration that the model runs with.
+    pub system_fingerprint: Option<String>,
+    /// The service tier used for processing the request.
+    pub service_tier: Option<String>,
     /// A list of chat completion choices. Can contain more than one elements if n is greater than 1. Can also be empty for the last chunk if you set stream_options: {"include_usage": true}.
     pub choices: Vec<ChatCompletionChunkChoice>,
+    /// Usage statistics for the entire request, sent in the last chunk when `stream_options.include_usage` is set. It's null in the other chunks.
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -151,6 +209,11 @@ impl Auth {
 pub struct Client {
     pub auth: Auth,
     pub api_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<(String, String)>,
+    /// Extra query parameters added to the url of every request.
+    pub query: Vec<(String, String)>,
+    pub hooks: Hooks,
 }
 
 impl Client {
@@ -158,60 +221,135 @@ impl Client {
         Self {
             auth,
             api_url: api_url.into(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            hooks: Hooks::default(),
         }
     }
+
+    /// Adds a header sent with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds a query parameter to the url of every request, like the `api-version` of Azure.
+    #[must_use]
+    pub fn query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Registers a hook that can inspect or mutate the request body before it's sent.
+    #[must_use]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut serde_json::Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    /// Registers a hook that receives the raw data of every streamed event.
+    #[must_use]
+    pub fn on_event(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.hooks.on_event(hook);
+        self
+    }
+
+    /// Registers a hook that receives the rate limit headers of the response.
+    #[must_use]
+    pub fn on_headers(
+        mut self,
+        hook: impl Fn(&[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_headers(hook);
+        self
+    }
+
+    /// Registers a hook that runs once the stream finishes.
+    #[must_use]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_complete(hook);
+        self
+    }
 }
 
 impl Client {
-    pub fn delta<'a>(
+    /// Returns the request that `delta` and `events` send, without sending it.
+    pub fn request(&self, message_body: &MessageBody) -> Result<Request, Error> {
+        log::debug!("message_body: {:#?}", message_body);
+
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            (
+                "authorization".to_string(),
+                format!("Bearer {}", self.auth.api_key),
+            ),
+        ];
+        headers.extend(self.headers.iter().cloned());
+
+        Request::post(
+            with_query(self.api_url.clone() + CHAT_API, &self.query),
+            headers,
+            message_body,
+            &self.hooks,
+        )
+    }
+
+    /// Streams the chunks of the response, with the roles, indices, and finish reasons of their
+    /// choices. With `stream_options.include_usage`, the last chunk has the usage of the request
+    /// and no choices.
+    pub fn events<'a>(
         &'a self,
         message_body: &'a MessageBody,
-    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
-        log::debug!("message_body: {:#?}", message_body);
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunk, Error>> + 'a, Error> {
+        let client = self.request(message_body)?.client()?;
 
-        let request_body = match serde_json::to_value(message_body) {
-            Ok(body) => body,
-            Err(e) => return Err(Error::Serde(e)),
-        };
-        log::debug!("request_body: {:#?}", request_body);
-
-        let authorization: &str = &format!("Bearer {}", self.auth.api_key);
-
-        let client = ClientBuilder::for_url(&(self.api_url.clone() + CHAT_API))?
-            .header("content-type", "application/json")?
-            .header("authorization", authorization)?
-            .method("POST".into())
-            .body(request_body.to_string())
-            .reconnect(
-                ReconnectOptions::reconnect(true)
-                    .retry_initial(false)
-                    .delay(Duration::from_secs(1))
-                    .backoff_factor(2)
-                    .delay_max(Duration::from_secs(60))
-                    .build(),
-            )
-            .build();
+        let hooks = &self.hooks;
 
         let stream = Box::pin(client.stream())
-            .map_err(Error::from)
-            .map_ok(|event| match event {
-                SSE::Connected(_) => String::default(),
-                SSE::Event(ev) => match serde_json::from_str::<ChatCompletionChunk>(&ev.data) {
-                    Ok(mut chunk) => {
-                        if chunk.choices.is_empty() {
-                            String::default()
-                        } else {
-                            chunk.choices[0].delta.content.take().unwrap_or_default()
-                        }
-                    }
-                    Err(_) => String::default(),
-                },
-                SSE::Comment(comment) => {
-                    log::debug!("Comment: {:#?}", comment);
-                    String::default()
+            .or_else(crate::error::from_stream)
+            .inspect_err(move |e| {
+                if matches!(e, Error::EventsourceClient(EventsourceError::Eof)) {
+                    hooks.complete();
                 }
+            })
+            .try_filter_map(move |event| {
+                future::ok(match event {
+                    SSE::Connected(details) => {
+                        hooks.headers(&crate::headers::collect(details.response()));
+                        None
+                    }
+                    SSE::Event(ev) => {
+                        hooks.event(&ev.data);
+                        // The stream ends with a `[DONE]` event, which isn't a chunk.
+                        serde_json::from_str::<ChatCompletionChunk>(&ev.data).ok()
+                    }
+                    SSE::Comment(comment) => {
+                        log::debug!("Comment: {:#?}", comment);
+                        None
+                    }
+                })
             });
 
         Ok(stream)
     }
+
+    /// Streams the text of the first choice of the response.
+    pub fn delta<'a>(
+        &'a self,
+        message_body: &'a MessageBody,
+    ) -> Result<impl Stream<Item = Result<String, Error>> + 'a, Error> {
+        let stream = self.events(message_body)?.map_ok(|mut chunk| {
+            if chunk.choices.is_empty() {
+                String::default()
+            } else {
+                chunk.choices[0].delta.content.take().unwrap_or_default()
+            }
+        });
+
+        Ok(stream)
+    }
 }
@@ -0,0 +1,182 @@
+use eventsource_client::{Client as EsClient, ClientBuilder, ReconnectOptions};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::hooks::Hooks;
+
+/// Value shown instead of the credentials of a redacted request.
+const REDACTED: &str = "<redacted>";
+
+type HttpClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// Returns the HTTP client of the streams, shared by every request of the process, so a long
+/// running one keeps its connections open between requests instead of connecting every time.
+/// The connections are driven by the runtime that opened them, so the requests of a process have
+/// to run on a single runtime that outlives them.
+fn http_client() -> HttpClient {
+    static CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+    CLIENT
+        .get_or_init(|| {
+            let connector = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_or_http()
+                .enable_http1()
+                .enable_http2()
+                .build();
+
+            hyper::Client::builder().build(connector)
+        })
+        .clone()
+}
+
+/// HTTP request sent by a client, built without sending it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Value,
+}
+
+/// Returns true if the header `name` can hold a credential, like `authorization` or `x-api-key`.
+pub fn is_secret(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    ["authorization", "key", "token", "secret", "cookie"]
+        .iter()
+        .any(|part| name.contains(part))
+}
+
+/// Returns `url` with the `query` parameters added, percent-encoded.
+pub(crate) fn with_query(url: String, query: &[(String, String)]) -> String {
+    query.iter().fold(url, |url, (name, value)| {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}={}", url, separator, encode(name), encode(value))
+    })
+}
+
+/// Percent-encodes everything but the unreserved characters of a url.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+impl Request {
+    /// Returns the `POST` request of `body`, once the `on_request` hooks ran over it.
+    pub(crate) fn post(
+        url: String,
+        headers: Vec<(String, String)>,
+        body: &impl Serialize,
+        hooks: &Hooks,
+    ) -> Result<Self, Error> {
+        let mut body = serde_json::to_value(body)?;
+        hooks.request(&mut body);
+        log::debug!("request_body: {:#?}", body);
+
+        Ok(Self {
+            method: "POST".to_string(),
+            url,
+            headers,
+            body,
+        })
+    }
+
+    /// Returns the request with the values of the credential headers, and of the `key` query
+    /// parameter, replaced.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let url = match self.url.split_once('?') {
+            Some((path, query)) => {
+                let query = query
+                    .split('&')
+                    .map(|pair| match pair.split_once('=') {
+                        Some((name, _)) if is_secret(name) => format!("{}={}", name, REDACTED),
+                        _ => pair.to_string(),
+                    })
+                    .collect::<Vec<String>>();
+
+                format!("{}?{}", path, query.join("&"))
+            }
+            None => self.url.clone(),
+        };
+
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                if is_secret(name) {
+                    (name.clone(), REDACTED.to_string())
+                } else {
+                    (name.clone(), value.clone())
+                }
+            })
+            .collect();
+
+        Self {
+            url,
+            headers,
+            ..self.clone()
+        }
+    }
+
+    /// Sends the request to an endpoint that doesn't stream, like the ones that count tokens, and
+    /// deserializes its response. Requests without a body are sent without one.
+    pub(crate) fn send<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let request = self.headers.iter().fold(
+            ureq::request(&self.method, &self.url),
+            |request, (name, value)| request.set(name, value),
+        );
+
+        let response = if self.body.is_null() {
+            request.call()
+        } else {
+            request.send_json(&self.body)
+        };
+
+        match response {
+            Ok(response) => Ok(response.into_json()?),
+            Err(ureq::Error::Status(status, response)) => Err(Error::Response {
+                status,
+                headers: crate::headers::collect_ureq(&response),
+                body: response.into_string().unwrap_or_default(),
+            }),
+            Err(e) => Err(Error::RequestError(
+                e.to_string().replace(&self.url, &self.redacted().url),
+            )),
+        }
+    }
+
+    /// Builds the client that sends the request and streams its events.
+    pub(crate) fn client(&self) -> Result<impl EsClient, Error> {
+        let mut builder = ClientBuilder::for_url(&self.url)?;
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value)?;
+        }
+
+        Ok(builder
+            .method(self.method.clone())
+            .body(self.body.to_string())
+            .reconnect(
+                ReconnectOptions::reconnect(true)
+                    .retry_initial(false)
+                    .delay(Duration::from_secs(1))
+                    .backoff_factor(2)
+                    .delay_max(Duration::from_secs(60))
+                    .build(),
+            )
+            .build_with_http_client(http_client()))
+    }
+}
@@ -184,6 +184,53 @@ pub fn github(args: &cli::GithubArgs) -> Result<(), Box<dyn Error>> {
             ["release", "upload", version, &target_path, "--clobber"],
         )
         .run()?;
+
+        // `self-update` downloads the binary named after the platform and checks it against the
+        // `.sha256` file and the `.minisig` signature next to it. The signature is checked with
+        // the public key the binary was built with, in `LLM_STREAM_RELEASE_KEY`.
+        let asset = format!(
+            "{}-{}-{}{}",
+            bin,
+            std::env::consts::ARCH,
+            std::env::consts::OS,
+            std::env::consts::EXE_SUFFIX
+        );
+        let asset_path = "target/release/".to_string() + &asset;
+
+        println!(
+            "{$magenta}Uploading {[yellow]} and its checksum{/$}",
+            &asset
+        );
+        std::fs::copy(&target_path, &asset_path)?;
+        let checksum = cmd!("shasum", "-a", "256", &asset)
+            .dir("target/release")
+            .read()?;
+        std::fs::write(asset_path.clone() + ".sha256", checksum + "\n")?;
+
+        println!("{$magenta}Signing {[yellow]}{/$}", &asset);
+        cmd!(
+            "minisign",
+            "-S",
+            "-s",
+            std::env::var("LLM_STREAM_MINISIGN_KEY")?,
+            "-m",
+            &asset_path
+        )
+        .run()?;
+
+        cmd(
+            "gh",
+            [
+                "release",
+                "upload",
+                version,
+                &asset_path,
+                &(asset_path.clone() + ".sha256"),
+                &(asset_path.clone() + ".minisig"),
+                "--clobber",
+            ],
+        )
+        .run()?;
     }
 
     Ok(())